@@ -0,0 +1,291 @@
+// The VMM of rust-rvm-vmm on a plain host, with the interpreter standing in for RVM:
+//     cargo run --bin rvm-interp -- [--board rcore|aia|virt] [--initrd path/to/initrd]
+//         [--append bootargs] path/to/kernel
+// The kernel is an ELF file, a Linux `Image`, or a flat binary to run at 0x80200000.
+// The board is that of rCore on rCore by default, the same with an APLIC and IMSICs in place of
+// the PLIC, or laid out as the QEMU `virt` machine.
+// The guest console is stdin and stdout.
+use rust_rvm_vmm_devices as devices;
+
+use devices::board::qemu_virt::{qemu_virt, VIRT_DRAM_BASE};
+use devices::board::rcore_on_rcore::rcore_on_rcore;
+use devices::board::rcore_on_rcore_aia::rcore_on_rcore_aia;
+use devices::board::{Chosen, HartIrqLines, VmConfig, TIMEBASE_FREQUENCY};
+use devices::hypervisor::interp::Interpreter;
+use devices::hypervisor::Hypervisor;
+use devices::irq::IrqLatch;
+use devices::power::ResetRequest;
+use devices::serial::{BlockingConsole, Console, RingBufferedConsole, DEFAULT_CONSOLE_BUFFER_SIZE};
+use devices::timer::Clock;
+use devices::vmm::loader::{
+    fdt_region, image_header, initrd_gpa, load_image, load_initrd, load_linux, LoadError,
+    FDT_MAX_SIZE,
+};
+use devices::vmm::memory::GuestRam;
+use devices::vmm::Vmm;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Number of harts of the guest, each run by a thread of its own.
+const NUM_HARTS: usize = 2;
+const KERNEL_GPA: u64 = 0x8020_0000;
+/// Size of the RAM of the guest. It starts at the kernel, or at the start of DRAM on `virt`, and
+/// ends with the device tree.
+const RAM_SIZE: u64 = 384 * 1024 * 1024;
+
+/// Reads of stdin cannot be interrupted, so they happen on a thread of their own, and `getc` only
+/// waits for that thread.
+struct StdioConsole {
+    input: Mutex<Receiver<u8>>,
+    cancelled: AtomicBool,
+    // The thread of the buffered console that waits for room, once it has.
+    producer: Mutex<Option<Thread>>,
+}
+
+impl StdioConsole {
+    fn new() -> Self {
+        let (tx, rx) = channel();
+        std::thread::spawn(move || {
+            let mut c = [0u8];
+            while let Ok(1) = std::io::stdin().read(&mut c) {
+                if tx.send(c[0]).is_err() {
+                    break;
+                }
+            }
+        });
+        StdioConsole {
+            input: Mutex::new(rx),
+            cancelled: AtomicBool::new(false),
+            producer: Mutex::new(None),
+        }
+    }
+}
+
+impl BlockingConsole for StdioConsole {
+    fn getc(&self) -> Option<u8> {
+        let input = self.input.lock().unwrap();
+        while !self.cancelled.load(SeqCst) {
+            match input.recv_timeout(Duration::from_millis(10)) {
+                Ok(chr) => return Some(chr),
+                Err(RecvTimeoutError::Timeout) => {}
+                // Nothing more will come. Keep the reader thread from spinning.
+                Err(RecvTimeoutError::Disconnected) => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+            }
+        }
+        None
+    }
+    fn cancel_getc(&self) {
+        self.cancelled.store(true, SeqCst);
+    }
+    fn putc(&self, chr: u8) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(&[chr]);
+        let _ = stdout.flush();
+    }
+    fn start_task<F: FnOnce()>(f: F)
+    where
+        F: Send + 'static,
+    {
+        std::thread::spawn(f);
+    }
+    fn park(&self) {
+        *self.producer.lock().unwrap() = Some(std::thread::current());
+        // The timeout covers an unpark racing with the registration above.
+        std::thread::park_timeout(Duration::from_millis(10));
+    }
+    fn unpark(&self) {
+        if let Some(producer) = self.producer.lock().unwrap().as_ref() {
+            producer.unpark();
+        }
+    }
+}
+
+/// Host monotonic time, scaled to the timebase the guest is told about.
+struct StdClock(Instant);
+
+impl Clock for StdClock {
+    fn now(&self) -> u64 {
+        let elapsed = self.0.elapsed();
+        elapsed.as_secs() * TIMEBASE_FREQUENCY
+            + elapsed.subsec_nanos() as u64 * TIMEBASE_FREQUENCY / 1_000_000_000
+    }
+    fn frequency(&self) -> u64 {
+        TIMEBASE_FREQUENCY
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BoardKind {
+    RcoreOnRcore,
+    RcoreOnRcoreAia,
+    QemuVirt,
+}
+
+/// Board, kernel, initrd and kernel command line, from
+///     rvm-interp [--board rcore|aia|virt] [--initrd <file>] [--append <bootargs>] <kernel>
+struct Args {
+    board: BoardKind,
+    kernel: String,
+    initrd: Option<String>,
+    bootargs: Option<String>,
+}
+
+fn parse_args() -> Option<Args> {
+    let mut args = std::env::args().skip(1);
+    let mut board = BoardKind::RcoreOnRcore;
+    let mut kernel = None;
+    let mut initrd = None;
+    let mut bootargs = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--board" => {
+                board = match args.next()?.as_str() {
+                    "rcore" => BoardKind::RcoreOnRcore,
+                    "aia" => BoardKind::RcoreOnRcoreAia,
+                    "virt" => BoardKind::QemuVirt,
+                    _ => return None,
+                }
+            }
+            "--initrd" => initrd = Some(args.next()?),
+            "--append" => bootargs = Some(args.next()?),
+            _ if kernel.is_none() && !arg.starts_with("--") => kernel = Some(arg),
+            _ => return None,
+        }
+    }
+    Some(Args {
+        board,
+        kernel: kernel?,
+        initrd,
+        bootargs,
+    })
+}
+
+fn read(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| {
+        eprintln!("can't read {}: {}", path, e);
+        std::process::exit(1);
+    })
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|| {
+        eprintln!(
+            "usage: rvm-interp [--board rcore|aia|virt] [--initrd <file>] [--append <bootargs>] \
+             <kernel image>"
+        );
+        std::process::exit(2);
+    });
+    let kernel = read(&args.kernel);
+    let initrd = args.initrd.as_deref().map(read);
+    let clock: Arc<dyn Clock> = Arc::new(StdClock(Instant::now()));
+    let boot_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let vm: Arc<dyn Hypervisor> = Arc::new(Interpreter::new(Arc::clone(&clock)));
+    let stdio = Arc::new(StdioConsole::new());
+    let console: Arc<dyn Console> = Arc::new(RingBufferedConsole::new(
+        Arc::clone(&stdio),
+        DEFAULT_CONSOLE_BUFFER_SIZE,
+    ));
+    console
+        .as_any()
+        .downcast_ref::<RingBufferedConsole<StdioConsole>>()
+        .unwrap()
+        .start();
+    let sip: Vec<_> = (0..NUM_HARTS).map(|_| Arc::new(IrqLatch::new())).collect();
+    let run = || -> devices::hypervisor::Result<Arc<Vmm>> {
+        let ram_base = match args.board {
+            BoardKind::RcoreOnRcore | BoardKind::RcoreOnRcoreAia => KERNEL_GPA,
+            BoardKind::QemuVirt => VIRT_DRAM_BASE,
+        };
+        let fdt_gpa = fdt_region(&(ram_base..ram_base + RAM_SIZE)).start;
+        // The images get the RAM below the device tree, and can't run over it.
+        let mem = vm.add_memory_region(ram_base, (fdt_gpa - ram_base) as usize)?;
+        let mut ram = GuestRam::new();
+        ram.add_region(&mem);
+        let exit = |path: &str, e: LoadError| -> ! {
+            eprintln!("can't load {}: {:?}", path, e);
+            std::process::exit(1);
+        };
+        // Linux places itself from the start of RAM. Other kernels run where an SBI firmware
+        // would start them.
+        let loaded = match image_header(&kernel) {
+            Some(header) => load_linux(&kernel, &header, ram_base, &ram),
+            None => load_image(&kernel, KERNEL_GPA, &ram),
+        }
+        .unwrap_or_else(|e| exit(&args.kernel, e));
+        let mut config = VmConfig {
+            memory: vec![ram_base..ram_base + RAM_SIZE],
+            reserved: vec![fdt_gpa..fdt_gpa + FDT_MAX_SIZE],
+            chosen: Chosen {
+                bootargs: args.bootargs.clone(),
+                initrd: None,
+            },
+        };
+        if let Some(initrd) = &initrd {
+            let gpa = initrd_gpa(&loaded, ram_base, mem.data.len() as u64);
+            config.chosen.initrd = Some(
+                load_initrd(initrd, gpa, &ram)
+                    .unwrap_or_else(|e| exit(args.initrd.as_ref().unwrap(), e)),
+            );
+        }
+        let (uart, clock) = (Arc::clone(&console), Arc::clone(&clock));
+        let harts = sip.iter().map(HartIrqLines::latched).collect();
+        let reset = Arc::new(ResetRequest::new());
+        let board = match args.board {
+            BoardKind::RcoreOnRcore => rcore_on_rcore(uart, clock, harts, &config),
+            BoardKind::RcoreOnRcoreAia => rcore_on_rcore_aia(uart, clock, harts, &config),
+            BoardKind::QemuVirt => {
+                let reset = Arc::clone(&reset);
+                qemu_virt(uart, clock, harts, boot_time, reset, &config)
+            }
+        };
+        let fdt = &board.fdt;
+        let fdt_mem = vm.add_memory_region(fdt_gpa, FDT_MAX_SIZE as usize)?;
+        fdt_mem.data[..fdt.len()].copy_from_slice(fdt);
+        ram.add_region(&fdt_mem);
+        let mut vmm = Vmm::new(
+            Arc::clone(&vm),
+            Arc::clone(&console),
+            board.bus,
+            board.clint,
+            sip.clone(),
+            ram,
+        );
+        vmm.set_imsic_files(board.imsic_files);
+        vmm.set_reset_request(reset);
+        let vmm = Arc::new(vmm);
+        vmm.harts()
+            .start(0, loaded.entry as usize, fdt_gpa as usize);
+        let threads: Vec<_> = (0..NUM_HARTS)
+            .map(|hart| {
+                let vmm = Arc::clone(&vmm);
+                std::thread::spawn(move || {
+                    vmm.run_hart(hart, &|| std::thread::sleep(Duration::from_millis(1)))
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+        Ok(vmm)
+    };
+    match run() {
+        Ok(vmm) => {
+            if let Some((reset_type, reason)) = vmm.reset_reason() {
+                eprintln!("Guest reset {:?} (reason {}). Exit.", reset_type, reason);
+                std::process::exit(reason as i32);
+            }
+        }
+        Err(e) => {
+            eprintln!("Error in the VMM: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,629 @@
+use super::{
+    cpu_intc_phandle, node_name, write_config, write_cpus, write_device_node, DtDevice,
+    HartIrqLines, VmConfig,
+};
+use crate::fdt::FdtWriter;
+use crate::irq::aplic::{AplicMsi, MsiAddrConfig, APLIC};
+use crate::irq::imsic::{ImsicFiles, MsiRouter, IMSIC, IMSIC_PAGE_SIZE};
+use crate::irq::plic::PLIC;
+use crate::irq::{InterruptSink, IrqLine};
+use crate::timer::clint::Clint;
+use crate::timer::Clock;
+use crate::Device;
+use crate::MMIOBank;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+// Boards with an interrupt controller and a CLINT, put together device by device. Devices go
+// where they are told, or into the first free space of an MMIO window, and interrupt sources are
+// handed out the same way.
+
+/// Windows of devices placed automatically start on page boundaries.
+const MMIO_ALIGN: u64 = 0x1000;
+/// Sense of the interrupts of devices on an APLIC: level-high, as they all are.
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+/// The interrupt controller that the builder makes for the devices of a board.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IrqControllerKind {
+    /// A PLIC with `sources` sources, driving the external interrupt of each hart through its
+    /// supervisor-mode context.
+    Plic { sources: usize },
+    /// An APLIC with `sources` sources, sending MSIs to the supervisor-level IMSIC interrupt file
+    /// of each hart, which drives its external interrupt. The files have `imsic_ids` identities
+    /// and take a page each from `imsic_base`.
+    Aplic {
+        sources: usize,
+        imsic_ids: usize,
+        imsic_base: u64,
+    },
+}
+
+enum IrqController {
+    Plic(Arc<PLIC>),
+    // The files are placed from the start, as the first device.
+    Aplic {
+        aplic: Arc<APLIC>,
+        files: Arc<ImsicFiles>,
+    },
+}
+
+impl IrqController {
+    fn device(&self) -> (Arc<dyn DtDevice>, Arc<dyn Device>) {
+        match self {
+            IrqController::Plic(plic) => (Arc::clone(plic) as _, Arc::clone(plic) as _),
+            IrqController::Aplic { aplic, .. } => (Arc::clone(aplic) as _, Arc::clone(aplic) as _),
+        }
+    }
+    fn sink(&self) -> Arc<dyn InterruptSink> {
+        match self {
+            IrqController::Plic(plic) => Arc::clone(plic) as Arc<dyn InterruptSink>,
+            IrqController::Aplic { aplic, .. } => Arc::clone(aplic) as Arc<dyn InterruptSink>,
+        }
+    }
+    // `interrupts` of a device on `source`.
+    fn specifier(&self, source: u32) -> Vec<u32> {
+        match self {
+            IrqController::Plic(_) => alloc::vec![source],
+            IrqController::Aplic { .. } => alloc::vec![source, IRQ_TYPE_LEVEL_HIGH],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BoardError {
+    /// The window at `base` of a device, or a RAM region, overlaps a device placed before.
+    Overlap { base: u64, size: u64 },
+    /// No room left in the MMIO window for a device of `size` bytes.
+    NoMmioSpace { size: u64 },
+    /// An interrupt source that does not exist or is taken.
+    IrqUnavailable(u32),
+    /// An interrupt source of a device that was not handed out by `irq`.
+    IrqNotAllocated(u32),
+    /// All interrupt sources are taken.
+    NoIrqs,
+}
+
+/// Where a device of a board went.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    /// Name of the node of the device, e.g. "uart@10000000".
+    pub name: String,
+    pub base: u64,
+    pub size: u64,
+    /// PLIC sources of the device.
+    pub irqs: Vec<u32>,
+}
+
+/// A board, ready for the VMM.
+pub struct Board {
+    /// The MMIO of all devices.
+    pub bus: MMIOBank,
+    /// The interrupt controller of the devices, on boards with a PLIC.
+    pub plic: Option<Arc<PLIC>>,
+    /// The interrupt file of every hart, on boards with an APLIC, for the VMM to emulate the
+    /// siselect/sireg/stopei CSRs of the harts with.
+    pub imsic_files: Vec<Arc<IMSIC>>,
+    /// Timers and software interrupts of the harts, for the VMM to poll and to serve SBI calls.
+    pub clint: Arc<Clint>,
+    /// The device tree blob describing the board and the VM it was built for.
+    pub fdt: Vec<u8>,
+    /// Every device, in the order added.
+    pub placements: Vec<Placement>,
+}
+
+struct Entry {
+    device: Arc<dyn DtDevice>,
+    bus: Arc<dyn Device>,
+    placement: Placement,
+}
+
+pub struct BoardBuilder {
+    irq_controller: IrqController,
+    timer: Arc<Clint>,
+    // Rate of the clock of `timer`, in ticks per second.
+    timebase: u32,
+    num_harts: usize,
+    mmio_window: Range<u64>,
+    irqs_taken: Vec<bool>,
+    // `compatible` and `model` of the root node.
+    machine: Option<(String, String)>,
+    devices: Vec<Entry>,
+    // Indices of the interrupt controller and the CLINT in `devices`, once placed.
+    irq_controller_index: Option<usize>,
+    clint: Option<usize>,
+}
+
+impl BoardBuilder {
+    /// A board for `harts`, whose external interrupts come from the supervisor-mode contexts of a
+    /// PLIC with `plic_sources` sources, and whose timer and software interrupts come from a CLINT
+    /// running on `clock`. Devices placed automatically go into `mmio_window`.
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        harts: Vec<HartIrqLines>,
+        plic_sources: usize,
+        mmio_window: Range<u64>,
+    ) -> Self {
+        let plic = IrqControllerKind::Plic {
+            sources: plic_sources,
+        };
+        Self::with_irq_controller(clock, harts, plic, mmio_window)
+    }
+    /// Like `new`, with the external interrupts of the harts coming from `irq_controller`. The
+    /// IMSIC of an APLIC is placed at once, as the APLIC addresses its MSIs to it.
+    pub fn with_irq_controller(
+        clock: Arc<dyn Clock>,
+        harts: Vec<HartIrqLines>,
+        irq_controller: IrqControllerKind,
+        mmio_window: Range<u64>,
+    ) -> Self {
+        assert!(!harts.is_empty(), "no harts");
+        assert!(
+            clock.frequency() <= u32::MAX as u64,
+            "clock too fast for the device tree"
+        );
+        let timebase = clock.frequency() as u32;
+        let num_harts = harts.len();
+        let mut software = Vec::new();
+        let mut timer = Vec::new();
+        let mut external = Vec::new();
+        for hart in harts {
+            software.push(hart.software);
+            timer.push(hart.timer);
+            external.push(hart.external);
+        }
+        let (controller, sources, imsic) = match irq_controller {
+            IrqControllerKind::Plic { sources } => {
+                // Contexts 2i and 2i+1 are M-mode and S-mode of hart i. The guest never sees M-mode.
+                let mut contexts = Vec::new();
+                for line in external {
+                    contexts.push(IrqLine::disconnected());
+                    contexts.push(line);
+                }
+                let plic = Arc::new(PLIC::new(sources, contexts));
+                (IrqController::Plic(plic), sources, None)
+            }
+            IrqControllerKind::Aplic {
+                sources,
+                imsic_ids,
+                imsic_base,
+            } => {
+                let files = external
+                    .into_iter()
+                    .map(|line| Arc::new(IMSIC::new(imsic_ids, line)))
+                    .collect();
+                let files = Arc::new(ImsicFiles::new(files));
+                // The hart index is ORed into the page number of the files.
+                let size = files.mmio_region_size() as u64;
+                assert_eq!(imsic_base % size, 0, "IMSIC region not aligned to its size");
+                let mut router = MsiRouter::new();
+                for (hart, file) in files.files().iter().enumerate() {
+                    router.add_file(
+                        imsic_base + (hart * IMSIC_PAGE_SIZE) as u64,
+                        Arc::clone(file),
+                    );
+                }
+                let msi = AplicMsi {
+                    sink: Arc::new(router),
+                    config: MsiAddrConfig {
+                        base_ppn: imsic_base >> 12,
+                        lhxw: (size as usize / IMSIC_PAGE_SIZE).trailing_zeros(),
+                        ..Default::default()
+                    },
+                };
+                let aplic = Arc::new(APLIC::new(sources, Vec::new(), Some(msi)));
+                let imsic = Some((Arc::clone(&files), imsic_base));
+                (IrqController::Aplic { aplic, files }, sources, imsic)
+            }
+        };
+        let mut builder = BoardBuilder {
+            irq_controller: controller,
+            timer: Arc::new(Clint::new(clock, software, timer)),
+            timebase,
+            num_harts,
+            mmio_window,
+            // Source 0 does not exist.
+            irqs_taken: (0..=sources).map(|source| source == 0).collect(),
+            machine: None,
+            devices: Vec::new(),
+            irq_controller_index: None,
+            clint: None,
+        };
+        if let Some((files, base)) = imsic {
+            builder
+                .add_device(files, Some(base), &[])
+                .expect("IMSIC region past the end of the address space");
+        }
+        builder
+    }
+    /// Tell the guest which machine the board is, for guests that check.
+    pub fn set_machine(&mut self, compatible: &str, model: &str) {
+        self.machine = Some((compatible.into(), model.into()));
+    }
+    /// PLIC source `source`, or the lowest free one if None, and a line driving it.
+    pub fn irq(&mut self, source: Option<u32>) -> Result<(u32, IrqLine), BoardError> {
+        let source = match source {
+            Some(source) => source,
+            None => self
+                .irqs_taken
+                .iter()
+                .position(|&taken| !taken)
+                .ok_or(BoardError::NoIrqs)? as u32,
+        };
+        match self.irqs_taken.get_mut(source as usize) {
+            Some(taken) if !*taken => *taken = true,
+            _ => return Err(BoardError::IrqUnavailable(source)),
+        }
+        Ok((
+            source,
+            IrqLine::new(self.irq_controller.sink(), source as usize),
+        ))
+    }
+    /// Put `device`, with interrupt sources `irqs` from `irq`, at `base`, or into the first free
+    /// space of the MMIO window if None. Returns where it went.
+    pub fn add_device<D: DtDevice + 'static>(
+        &mut self,
+        device: Arc<D>,
+        base: Option<u64>,
+        irqs: &[u32],
+    ) -> Result<u64, BoardError> {
+        let bus = Arc::clone(&device) as Arc<dyn Device>;
+        self.add_entry(device, bus, base, irqs)
+    }
+    // `add_device`, with the device as the device tree and the bus see it.
+    fn add_entry(
+        &mut self,
+        device: Arc<dyn DtDevice>,
+        bus: Arc<dyn Device>,
+        base: Option<u64>,
+        irqs: &[u32],
+    ) -> Result<u64, BoardError> {
+        // Source 0 is marked taken, but it does not exist.
+        if let Some(&irq) = irqs
+            .iter()
+            .find(|&&irq| irq == 0 || self.irqs_taken.get(irq as usize) != Some(&true))
+        {
+            return Err(BoardError::IrqNotAllocated(irq));
+        }
+        let size = device.mmio_region_size() as u64;
+        let base = match base {
+            Some(base) => {
+                if self.overlapping(base, size) {
+                    return Err(BoardError::Overlap { base, size });
+                }
+                base
+            }
+            None => self.allocate(size)?,
+        };
+        self.devices.push(Entry {
+            placement: Placement {
+                name: node_name(&*device, base),
+                base,
+                size,
+                irqs: irqs.to_vec(),
+            },
+            bus,
+            device,
+        });
+        Ok(base)
+    }
+    /// Put the interrupt controller at `base`, or anywhere in the MMIO window if None. It goes
+    /// into the window when the board is built if it was not placed before.
+    pub fn place_irq_controller(&mut self, base: Option<u64>) -> Result<u64, BoardError> {
+        assert!(
+            self.irq_controller_index.is_none(),
+            "interrupt controller placed twice"
+        );
+        let (device, bus) = self.irq_controller.device();
+        let base = self.add_entry(device, bus, base, &[])?;
+        self.irq_controller_index = Some(self.devices.len() - 1);
+        Ok(base)
+    }
+    /// Put the CLINT at `base`, as `place_irq_controller` does the interrupt controller.
+    pub fn place_clint(&mut self, base: Option<u64>) -> Result<u64, BoardError> {
+        assert!(self.clint.is_none(), "CLINT placed twice");
+        let base = self.add_device(Arc::clone(&self.timer), base, &[])?;
+        self.clint = Some(self.devices.len() - 1);
+        Ok(base)
+    }
+    // Whether `size` bytes at `base` run past the end of the address space or over a device.
+    fn overlapping(&self, base: u64, size: u64) -> bool {
+        match base.checked_add(size) {
+            Some(end) => self.overlapped(base, end).is_some(),
+            None => true,
+        }
+    }
+    // The device placed over some of `base..end`. Devices end within the address space.
+    fn overlapped(&self, base: u64, end: u64) -> Option<&Placement> {
+        self.devices
+            .iter()
+            .map(|entry| &entry.placement)
+            .find(|other| base < other.base + other.size && other.base < end)
+    }
+    // The lowest aligned base in the MMIO window with room for `size` bytes.
+    fn allocate(&self, size: u64) -> Result<u64, BoardError> {
+        let no_space = BoardError::NoMmioSpace { size };
+        let mut base = align_up(self.mmio_window.start).ok_or_else(|| no_space.clone())?;
+        loop {
+            let end = match base.checked_add(size) {
+                Some(end) if end <= self.mmio_window.end => end,
+                _ => return Err(no_space),
+            };
+            match self.overlapped(base, end) {
+                Some(other) => {
+                    base = align_up(other.base + other.size).ok_or_else(|| no_space.clone())?
+                }
+                None => return Ok(base),
+            }
+        }
+    }
+    /// The bus, the interrupt controllers and the device tree of the board, which describes RAM
+    /// and boot parameters in `config` as well.
+    pub fn build(mut self, config: &VmConfig) -> Result<Board, BoardError> {
+        if self.clint.is_none() {
+            self.place_clint(None)?;
+        }
+        if self.irq_controller_index.is_none() {
+            self.place_irq_controller(None)?;
+        }
+        for region in config.memory.iter() {
+            if self.overlapped(region.start, region.end).is_some() {
+                return Err(BoardError::Overlap {
+                    base: region.start,
+                    size: region.end - region.start,
+                });
+            }
+        }
+        // Every device gets a phandle, in the order added, after those of the harts.
+        let first_phandle = cpu_intc_phandle(self.num_harts);
+        let phandle = |index: usize| first_phandle + index as u32;
+        let controller = self.irq_controller_index.unwrap();
+        let irq_phandle = phandle(controller);
+        let stdout_path = self
+            .devices
+            .iter()
+            .find(|entry| entry.device.dt_stdout())
+            .map(|entry| format!("/{}", entry.placement.name))
+            .unwrap_or_default();
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        if let Some((compatible, model)) = &self.machine {
+            fdt.property_string("compatible", compatible);
+            fdt.property_string("model", model);
+        }
+        write_config(&mut fdt, config, &stdout_path);
+        write_cpus(&mut fdt, self.num_harts, self.timebase);
+        let mut bus = MMIOBank::new();
+        let mut placements = Vec::new();
+        let irq_controller = self.irq_controller;
+        let msi_parent = match irq_controller {
+            IrqController::Plic(_) => None,
+            IrqController::Aplic { .. } => Some(phandle(0)),
+        };
+        for (index, entry) in self.devices.into_iter().enumerate() {
+            let interrupts: Vec<_> = entry
+                .placement
+                .irqs
+                .iter()
+                .flat_map(|&irq| irq_controller.specifier(irq))
+                .collect();
+            write_device_node(
+                &mut fdt,
+                &*entry.device,
+                entry.placement.base,
+                Some(phandle(index)),
+                &interrupts,
+                irq_phandle,
+                msi_parent.filter(|_| index == controller),
+            );
+            bus.add_device(entry.placement.base as usize, entry.bus);
+            placements.push(entry.placement);
+        }
+        fdt.end_node();
+        let (plic, imsic_files) = match irq_controller {
+            IrqController::Plic(plic) => (Some(plic), Vec::new()),
+            IrqController::Aplic { files, .. } => (None, files.files().to_vec()),
+        };
+        Ok(Board {
+            bus,
+            plic,
+            imsic_files,
+            clint: self.timer,
+            fdt: fdt.finish(),
+            placements,
+        })
+    }
+}
+
+fn align_up(addr: u64) -> Option<u64> {
+    addr.checked_add(MMIO_ALIGN - 1)
+        .map(|addr| addr & !(MMIO_ALIGN - 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::board::rcore_on_rcore::test::{check_interrupts, StdChannelConsole};
+    use crate::board::TIMEBASE_FREQUENCY;
+    use crate::fdt::Fdt;
+    use crate::irq::IrqLatch;
+    use crate::serial::uart16650::Uart16650;
+    use crate::serial::{Console, RingBufferedConsole};
+    use crate::timer::ManualClock;
+    const WINDOW: Range<u64> = 0x1000_0000..0x1000_3000;
+    fn builder(num_harts: usize, sources: usize, window: Range<u64>) -> BoardBuilder {
+        let sip: Vec<_> = (0..num_harts).map(|_| Arc::new(IrqLatch::new())).collect();
+        BoardBuilder::new(
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            sip.iter().map(HartIrqLines::latched).collect(),
+            sources,
+            window,
+        )
+    }
+    fn uart(line: IrqLine) -> Arc<Uart16650> {
+        let console: Arc<dyn Console> = Arc::new(RingBufferedConsole::new(
+            Arc::new(StdChannelConsole::new()),
+            16,
+        ));
+        Arc::new(Uart16650::new(console, line))
+    }
+    #[test]
+    fn placement() {
+        let mut builder = builder(2, 3, WINDOW);
+        let (irq, line) = builder.irq(None).unwrap();
+        assert_eq!(irq, 1);
+        assert_eq!(
+            builder.add_device(uart(line), None, &[irq]),
+            Ok(WINDOW.start)
+        );
+        // Fixed devices go anywhere but over others, and automatic ones around them.
+        let (irq, line) = builder.irq(Some(3)).unwrap();
+        assert_eq!(
+            builder.add_device(uart(line), Some(WINDOW.start + 0x1080), &[irq]),
+            Ok(WINDOW.start + 0x1080)
+        );
+        let (irq, line) = builder.irq(None).unwrap();
+        assert_eq!(irq, 2);
+        let uart = uart(line);
+        assert_eq!(
+            builder.add_device(Arc::clone(&uart), Some(WINDOW.start + 0x10ff), &[irq]),
+            Err(BoardError::Overlap {
+                base: WINDOW.start + 0x10ff,
+                size: 0x100,
+            })
+        );
+        assert_eq!(
+            builder.add_device(Arc::clone(&uart), None, &[irq]),
+            Ok(WINDOW.start + 0x2000)
+        );
+        assert_eq!(
+            builder.add_device(Arc::clone(&uart), None, &[]),
+            Err(BoardError::NoMmioSpace { size: 0x100 })
+        );
+        assert_eq!(
+            builder.add_device(Arc::clone(&uart), Some(u64::max_value() - 0xff), &[]),
+            Err(BoardError::Overlap {
+                base: u64::max_value() - 0xff,
+                size: 0x100,
+            })
+        );
+        assert_eq!(builder.irq(None).err(), Some(BoardError::NoIrqs));
+        assert_eq!(
+            builder.irq(Some(3)).err(),
+            Some(BoardError::IrqUnavailable(3))
+        );
+        assert_eq!(
+            builder.irq(Some(0)).err(),
+            Some(BoardError::IrqUnavailable(0))
+        );
+        // No room left for the CLINT and the PLIC.
+        assert_eq!(
+            builder.build(&VmConfig::default()).err(),
+            Some(BoardError::NoMmioSpace { size: 0x10000 })
+        );
+    }
+    #[test]
+    fn device_irqs() {
+        let mut builder = builder(1, 3, WINDOW);
+        let (irq, line) = builder.irq(Some(2)).unwrap();
+        let uart = uart(line);
+        for &bad in [0, 1, 4].iter() {
+            assert_eq!(
+                builder.add_device(Arc::clone(&uart), None, &[irq, bad]),
+                Err(BoardError::IrqNotAllocated(bad))
+            );
+        }
+        assert_eq!(
+            builder.add_device(Arc::clone(&uart), None, &[irq]),
+            Ok(WINDOW.start)
+        );
+    }
+    #[test]
+    fn window_at_the_end() {
+        let start = u64::max_value() - 0x1fff;
+        let mut builder = builder(1, 3, start..u64::max_value());
+        let (_, line) = builder.irq(None).unwrap();
+        let uart = uart(line);
+        assert_eq!(builder.add_device(Arc::clone(&uart), None, &[]), Ok(start));
+        assert_eq!(
+            builder.add_device(Arc::clone(&uart), None, &[]),
+            Ok(start + 0x1000)
+        );
+        // The next page would be past the end of the address space.
+        assert_eq!(
+            builder.add_device(Arc::clone(&uart), None, &[]),
+            Err(BoardError::NoMmioSpace { size: 0x100 })
+        );
+    }
+    #[test]
+    fn board() {
+        let mut builder = builder(2, 31, 0x1000_0000..0x2000_0000);
+        builder.place_irq_controller(Some(0xc00_0000)).unwrap();
+        let (irq, line) = builder.irq(None).unwrap();
+        builder.add_device(uart(line), None, &[irq]).unwrap();
+        assert_eq!(
+            builder
+                .build(&VmConfig {
+                    memory: vec![0x1000_0000..0x2000_0000],
+                    ..VmConfig::default()
+                })
+                .err(),
+            Some(BoardError::Overlap {
+                base: 0x1000_0000,
+                size: 0x1000_0000,
+            })
+        );
+        let mut builder = self::builder(2, 31, 0x1000_0000..0x2000_0000);
+        builder.place_irq_controller(Some(0xc00_0000)).unwrap();
+        let (irq, line) = builder.irq(None).unwrap();
+        let serial = uart(line);
+        builder
+            .add_device(Arc::clone(&serial), None, &[irq])
+            .unwrap();
+        let board = builder
+            .build(&VmConfig {
+                memory: vec![0x8000_0000..0x9000_0000],
+                ..VmConfig::default()
+            })
+            .unwrap();
+        // The CLINT went after the UART, in the next page.
+        let names: Vec<_> = board.placements.iter().map(|p| &p.name[..]).collect();
+        assert_eq!(
+            names,
+            vec!["plic@c000000", "uart@10000000", "clint@10001000"]
+        );
+        assert_eq!(board.placements[1].irqs, vec![1]);
+        let fdt = Fdt::parse(&board.fdt).unwrap();
+        check_interrupts(&fdt);
+        let uart = fdt.node("/uart@10000000").unwrap();
+        assert_eq!(uart.cells("reg"), Some(vec![0, 0x1000_0000, 0, 0x100]));
+        assert_eq!(uart.u32("interrupts"), Some(1));
+        assert_eq!(
+            fdt.node_by_phandle(uart.u32("interrupt-parent").unwrap())
+                .map(|node| &node.name[..]),
+            Some("plic@c000000")
+        );
+        assert_eq!(
+            fdt.node("/chosen").unwrap().string("stdout-path").unwrap(),
+            "/uart@10000000"
+        );
+        assert!(fdt.node("/clint@10001000").is_some());
+        // The bus reaches the devices where they went.
+        let mut lsr = 0;
+        assert_eq!(
+            board
+                .bus
+                .handle_mmio(0x1000_0000 + 5, &mut crate::MMIOAccess::LoadByte(&mut lsr)),
+            Some(true)
+        );
+        assert_eq!(board.clint.num_harts(), 2);
+        let plic = board.plic.unwrap();
+        assert_eq!(plic.num_sources(), 31);
+        assert_eq!(plic.num_contexts(), 4);
+        assert!(board.imsic_files.is_empty());
+    }
+}
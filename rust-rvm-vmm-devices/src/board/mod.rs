@@ -1 +1,190 @@
+use crate::fdt::FdtWriter;
+use crate::irq::{InterruptSink, IrqLatch, IrqLine};
+use crate::Device;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+pub mod builder;
+pub mod qemu_virt;
 pub mod rcore_on_rcore;
+pub mod rcore_on_rcore_aia;
+
+/// Timebase of QEMU virt harts, for clocks that can pick their rate. The device tree of a board
+/// advertises the rate of its clock, whatever it is.
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+/// Bit numbers of the supervisor interrupts in sip.
+pub const IRQ_S_SOFT: usize = 1;
+pub const IRQ_S_TIMER: usize = 5;
+pub const IRQ_S_EXT: usize = 9;
+
+/// Supervisor interrupt inputs of one guest hart.
+pub struct HartIrqLines {
+    pub software: IrqLine,
+    pub timer: IrqLine,
+    pub external: IrqLine,
+}
+
+impl HartIrqLines {
+    /// Lines latched at the sip bit numbers, for the vCPU run loop to pick up.
+    pub fn latched(latch: &Arc<IrqLatch>) -> Self {
+        let line = |id| IrqLine::new(Arc::clone(latch) as Arc<dyn InterruptSink>, id);
+        HartIrqLines {
+            software: line(IRQ_S_SOFT),
+            timer: line(IRQ_S_TIMER),
+            external: line(IRQ_S_EXT),
+        }
+    }
+}
+
+/// ISA and MMU of the guest harts in the device trees. Both backends implement them.
+const GUEST_ISA: &str = "rv64imafdc";
+const GUEST_MMU_TYPE: &str = "riscv,sv39";
+
+/// The guest side of the VM a board is built for, which its device tree tells the guest about.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmConfig {
+    /// Guest physical addresses of each RAM region, as added to the hypervisor.
+    pub memory: Vec<Range<u64>>,
+    /// Parts of RAM the guest must leave alone, such as the one the device tree is in.
+    pub reserved: Vec<Range<u64>>,
+    pub chosen: Chosen,
+}
+
+/// What the boot loader tells the guest kernel in `/chosen` of the device tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Chosen {
+    /// The kernel command line.
+    pub bootargs: Option<String>,
+    /// Guest physical addresses of the initrd.
+    pub initrd: Option<Range<u64>>,
+}
+
+// Device tree parts shared by the boards. Both have two address cells and two size cells at the root.
+
+/// phandle of the interrupt controller of `hart`. phandles above those of the harts are free.
+pub(crate) fn cpu_intc_phandle(hart: usize) -> u32 {
+    hart as u32 + 1
+}
+
+pub(crate) fn write_cpus(fdt: &mut FdtWriter, num_harts: usize, timebase: u32) {
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", timebase);
+    for hart in 0..num_harts {
+        fdt.begin_node(&format!("cpu@{:x}", hart));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart as u32);
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("status", "okay");
+        fdt.property_string("riscv,isa", GUEST_ISA);
+        fdt.property_string("mmu-type", GUEST_MMU_TYPE);
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("phandle", cpu_intc_phandle(hart));
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+}
+
+/// `interrupts-extended` with interrupt `irq` of every hart, in hart order.
+/// `None` leaves the slot of the hart unconnected.
+pub(crate) fn hart_interrupts(num_harts: usize, irqs: &[Option<usize>]) -> Vec<u32> {
+    let mut cells = Vec::new();
+    for hart in 0..num_harts {
+        for irq in irqs.iter() {
+            match irq {
+                Some(irq) => cells.extend_from_slice(&[cpu_intc_phandle(hart), *irq as u32]),
+                None => cells.extend_from_slice(&[cpu_intc_phandle(hart), !0]),
+            }
+        }
+    }
+    cells
+}
+
+/// A device that describes its own node in the device tree of a board.
+pub trait DtDevice: Device {
+    /// Name of the node, without the unit address.
+    fn dt_name(&self) -> &str;
+    /// Size in `reg` of the node. The whole MMIO region of the device unless it uses less of it.
+    fn dt_reg_size(&self) -> u64 {
+        self.mmio_region_size() as u64
+    }
+    /// Whether the guest console is on the device, for `stdout-path`.
+    fn dt_stdout(&self) -> bool {
+        false
+    }
+    /// Properties of the node other than `reg`, `phandle`, `interrupts` and `interrupt-parent`,
+    /// which depend on where the board puts the device.
+    fn write_dt_properties(&self, fdt: &mut FdtWriter);
+    /// Nodes next to that of the device, which refer to it by `phandle`. Drivers layered on
+    /// the device look for them at the root.
+    fn write_dt_siblings(&self, _fdt: &mut FdtWriter, _phandle: u32) {}
+}
+
+/// Name of the node of `device` at `base`, with the unit address.
+pub(crate) fn node_name(device: &dyn DtDevice, base: u64) -> String {
+    format!("{}@{:x}", device.dt_name(), base)
+}
+
+/// The node of `device` at `base`. `interrupts` are its interrupt specifiers on `parent`, and
+/// empty if it has none. MSIs of the device go to `msi_parent`, if any. Nodes next to it are only
+/// written if it has a `phandle`.
+pub(crate) fn write_device_node(
+    fdt: &mut FdtWriter,
+    device: &dyn DtDevice,
+    base: u64,
+    phandle: Option<u32>,
+    interrupts: &[u32],
+    parent: u32,
+    msi_parent: Option<u32>,
+) {
+    fdt.begin_node(&node_name(device, base));
+    fdt.property_reg64(base, device.dt_reg_size());
+    if let Some(phandle) = phandle {
+        fdt.property_u32("phandle", phandle);
+    }
+    if !interrupts.is_empty() {
+        fdt.property_cells("interrupts", interrupts);
+        fdt.property_u32("interrupt-parent", parent);
+    }
+    if let Some(msi_parent) = msi_parent {
+        fdt.property_u32("msi-parent", msi_parent);
+    }
+    device.write_dt_properties(fdt);
+    fdt.end_node();
+    if let Some(phandle) = phandle {
+        device.write_dt_siblings(fdt, phandle);
+    }
+}
+
+/// `/chosen`, with the console of the board at `stdout_path`, a memory node for each RAM region,
+/// and the reservations.
+pub(crate) fn write_config(fdt: &mut FdtWriter, config: &VmConfig, stdout_path: &str) {
+    let chosen = &config.chosen;
+    fdt.begin_node("chosen");
+    fdt.property_string("stdout-path", stdout_path);
+    if let Some(bootargs) = &chosen.bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some(initrd) = &chosen.initrd {
+        fdt.property_u64("linux,initrd-start", initrd.start);
+        fdt.property_u64("linux,initrd-end", initrd.end);
+    }
+    fdt.end_node();
+    for region in config.memory.iter() {
+        fdt.begin_node(&format!("memory@{:x}", region.start));
+        fdt.property_string("device_type", "memory");
+        fdt.property_reg64(region.start, region.end - region.start);
+        fdt.end_node();
+    }
+    for region in config.reserved.iter() {
+        fdt.reserve_memory(region.start, region.end - region.start);
+    }
+}
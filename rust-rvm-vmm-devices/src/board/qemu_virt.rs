@@ -0,0 +1,171 @@
+use super::builder::{Board, BoardBuilder};
+use super::{HartIrqLines, VmConfig};
+use crate::power::sifive_test::SifiveTest;
+use crate::power::ResetRequest;
+use crate::serial::uart16650::Uart16650;
+use crate::serial::Console;
+use crate::timer::goldfish_rtc::GoldfishRtc;
+use crate::timer::Clock;
+use crate::virtio::mmio::{VirtioMmioSlot, VIRTIO_MMIO_SIZE};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+// The memory map and interrupts of the QEMU `virt` machine, so that guests built for it run
+// with the device tree of this board instead of that of QEMU.
+const TEST_MMIO: u64 = 0x100000;
+const RTC_MMIO: u64 = 0x101000;
+const CLINT_MMIO: u64 = 0x2000000;
+const PLIC_MMIO: u64 = 0xc000000;
+const UART_MMIO: u64 = 0x10000000;
+const VIRTIO_MMIO: u64 = 0x10001000;
+const VIRTIO_COUNT: u32 = 8;
+// Slot i interrupts on source VIRTIO_IRQ + i.
+const VIRTIO_IRQ: u32 = 1;
+const UART_IRQ: u32 = 10;
+const RTC_IRQ: u32 = 11;
+const PLIC_SOURCES: usize = 53;
+// The platform bus of `virt`, where devices added later go.
+const MMIO_WINDOW: core::ops::Range<u64> = 0x4000000..0x6000000;
+/// Start of RAM on `virt`. The RAM of the VM goes here.
+pub const VIRT_DRAM_BASE: u64 = 0x80000000;
+
+/// A board laid out as `virt`, with harts as for `rcore_on_rcore`. The RTC tells the time as
+/// `boot_time`, in nanoseconds since the Unix epoch, plus the time on `clock`. The test
+/// finisher asks for power-off and reboot through `reset`, which the VMM must share.
+pub fn qemu_virt(
+    blocking_console: Arc<dyn Console>,
+    clock: Arc<dyn Clock>,
+    harts: Vec<HartIrqLines>,
+    boot_time: u64,
+    reset: Arc<ResetRequest>,
+    config: &VmConfig,
+) -> Board {
+    assert!(
+        config
+            .memory
+            .iter()
+            .all(|region| region.start >= VIRT_DRAM_BASE),
+        "RAM below {:#x}",
+        VIRT_DRAM_BASE
+    );
+    let mut builder = BoardBuilder::new(Arc::clone(&clock), harts, PLIC_SOURCES, MMIO_WINDOW);
+    builder.set_machine("riscv-virtio", "riscv-virtio,qemu");
+    let fixed = "fixed devices overlap";
+    builder
+        .add_device(Arc::new(SifiveTest::new(reset)), Some(TEST_MMIO), &[])
+        .expect(fixed);
+    let (irq, line) = builder.irq(Some(RTC_IRQ)).unwrap();
+    let rtc = Arc::new(GoldfishRtc::new(clock, boot_time, line));
+    builder
+        .add_device(rtc, Some(RTC_MMIO), &[irq])
+        .expect(fixed);
+    builder.place_clint(Some(CLINT_MMIO)).expect(fixed);
+    builder.place_irq_controller(Some(PLIC_MMIO)).expect(fixed);
+    let (irq, line) = builder.irq(Some(UART_IRQ)).unwrap();
+    let serial = Arc::new(Uart16650::new(blocking_console, line));
+    builder
+        .add_device(serial, Some(UART_MMIO), &[irq])
+        .expect(fixed);
+    for slot in 0..VIRTIO_COUNT {
+        // Nothing drives the line of an empty slot.
+        let (irq, _) = builder.irq(Some(VIRTIO_IRQ + slot)).unwrap();
+        let base = VIRTIO_MMIO + (slot as u64) * VIRTIO_MMIO_SIZE as u64;
+        builder
+            .add_device(Arc::new(VirtioMmioSlot), Some(base), &[irq])
+            .expect(fixed);
+    }
+    builder.build(config).expect("devices overlap RAM")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::board::rcore_on_rcore::test::{check_interrupts, MockMemOps, StdChannelConsole};
+    use crate::board::TIMEBASE_FREQUENCY;
+    use crate::fdt::Fdt;
+    use crate::irq::IrqLatch;
+    use crate::sbi::ResetType;
+    use crate::serial::RingBufferedConsole;
+    use crate::timer::ManualClock;
+    fn board(reset: &Arc<ResetRequest>, memory: Vec<core::ops::Range<u64>>) -> Board {
+        let sip: Vec<_> = (0..2).map(|_| Arc::new(IrqLatch::new())).collect();
+        qemu_virt(
+            Arc::new(RingBufferedConsole::new(
+                Arc::new(StdChannelConsole::new()),
+                16,
+            )),
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            sip.iter().map(HartIrqLines::latched).collect(),
+            1_600_000_000_000_000_000,
+            Arc::clone(reset),
+            &VmConfig {
+                memory,
+                ..VmConfig::default()
+            },
+        )
+    }
+    #[test]
+    fn device_tree() {
+        let reset = Arc::new(ResetRequest::new());
+        let board = board(&reset, vec![VIRT_DRAM_BASE..VIRT_DRAM_BASE + 0x800_0000]);
+        let fdt = Fdt::parse(&board.fdt).unwrap();
+        check_interrupts(&fdt);
+        assert_eq!(fdt.root.string("compatible").unwrap(), "riscv-virtio");
+        assert!(fdt.node("/memory@80000000").is_some());
+        assert!(fdt.node("/clint@2000000").is_some());
+        let plic = fdt.node("/plic@c000000").unwrap();
+        assert_eq!(plic.u32("riscv,ndev"), Some(53));
+        let interrupts = |name: &str| {
+            let node = fdt.node(name).unwrap();
+            assert_eq!(
+                fdt.node_by_phandle(node.u32("interrupt-parent").unwrap())
+                    .unwrap()
+                    .name,
+                "plic@c000000"
+            );
+            node.u32("interrupts")
+        };
+        assert_eq!(interrupts("/uart@10000000"), Some(10));
+        assert_eq!(interrupts("/rtc@101000"), Some(11));
+        assert_eq!(interrupts("/virtio_mmio@10001000"), Some(1));
+        assert_eq!(interrupts("/virtio_mmio@10008000"), Some(8));
+        assert_eq!(
+            fdt.node("/virtio_mmio@10008000").unwrap().cells("reg"),
+            Some(vec![0, 0x1000_8000, 0, 0x1000])
+        );
+        assert_eq!(
+            fdt.node("/chosen").unwrap().string("stdout-path").unwrap(),
+            "/uart@10000000"
+        );
+        // Linux powers off and reboots through the test finisher.
+        let test = fdt.node("/test@100000").unwrap();
+        assert_eq!(
+            test.property("compatible").unwrap(),
+            &b"sifive,test1\0sifive,test0\0syscon\0"[..]
+        );
+        for &(name, value) in [("/poweroff", 0x5555), ("/reboot", 0x7777)].iter() {
+            let node = fdt.node(name).unwrap();
+            assert_eq!(
+                fdt.node_by_phandle(node.u32("regmap").unwrap())
+                    .unwrap()
+                    .name,
+                "test@100000"
+            );
+            assert_eq!(node.u32("offset"), Some(0));
+            assert_eq!(node.u32("value"), Some(value));
+        }
+        // The devices are on the bus where the device tree says.
+        assert_eq!(board.bus.lw(0x1000_1000), Some(0x7472_6976));
+        assert!(board.bus.lw(0x10_1000).unwrap() > 0);
+        assert_eq!(reset.get(), None);
+        board.bus.sw(0x10_0000, 0x5555).unwrap();
+        assert_eq!(reset.get(), Some((ResetType::Shutdown, 0)));
+    }
+    #[test]
+    #[should_panic(expected = "RAM below 0x80000000")]
+    fn ram_below_dram() {
+        board(
+            &Arc::new(ResetRequest::new()),
+            vec![0x4000_0000..0x5000_0000],
+        );
+    }
+}
@@ -1,33 +1,55 @@
-use crate::irq::plic::PLIC;
+use super::builder::{Board, BoardBuilder};
+use super::{HartIrqLines, VmConfig};
 use crate::serial::uart16650::Uart16650;
-use crate::serial::{Console};
-use crate::Device;
-use crate::MMIOBank;
-use alloc::collections::BTreeMap;
+use crate::serial::Console;
+use crate::timer::Clock;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 /// device tree and mmio bank.
 const SERIAL_IRQ: usize = 10;
+const PLIC_SOURCES: usize = 31;
+// Fixed, as the guest was written for these addresses.
 const SERIAL_MMIO: usize = 0x10000000;
+const CLINT_MMIO: usize = 0x2000000;
 const PLIC_MMIO: usize = 0xc000000;
+// Room for more devices after the UART.
+const MMIO_WINDOW: core::ops::Range<u64> = 0x10000000..0x20000000;
 
+/// Hart `i` is driven by `harts[i]`: its external interrupt by the supervisor-mode PLIC context
+/// of the hart, and its timer and software interrupts by the CLINT, which runs on `clock`.
+/// The device tree also describes the RAM and boot parameters in `config`.
 pub fn rcore_on_rcore(
     blocking_console: Arc<dyn Console>,
-) -> (MMIOBank, Arc<dyn Device>, &'static [u8]) {
-    let serial: Arc<dyn Device> = Arc::new(Uart16650::new(Arc::clone(&blocking_console)));
-    let mut irqtree = BTreeMap::new();
-    irqtree.insert(SERIAL_IRQ, Arc::clone(&serial));
-    let irc: Arc<dyn Device> = Arc::new(PLIC::new(irqtree));
-    let mut bank = MMIOBank::new();
-    bank.add_device(PLIC_MMIO, Arc::clone(&irc));
-    bank.add_device(SERIAL_MMIO, serial);
-    (bank, irc, include_bytes!("rcore_on_rcore.dtb"))
+    clock: Arc<dyn Clock>,
+    harts: Vec<HartIrqLines>,
+    config: &VmConfig,
+) -> Board {
+    let mut builder = BoardBuilder::new(clock, harts, PLIC_SOURCES, MMIO_WINDOW);
+    let fixed = "fixed devices overlap";
+    builder.place_clint(Some(CLINT_MMIO as u64)).expect(fixed);
+    builder
+        .place_irq_controller(Some(PLIC_MMIO as u64))
+        .expect(fixed);
+    let (irq, line) = builder.irq(Some(SERIAL_IRQ as u32)).unwrap();
+    let serial = Arc::new(Uart16650::new(blocking_console, line));
+    builder
+        .add_device(serial, Some(SERIAL_MMIO as u64), &[irq])
+        .expect(fixed);
+    builder.build(config).expect("devices overlap RAM")
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
+    use crate::board::{Chosen, IRQ_S_EXT, IRQ_S_SOFT, IRQ_S_TIMER, TIMEBASE_FREQUENCY};
+    use crate::fdt::{Fdt, FdtNode};
+    use crate::irq::IrqLatch;
+    use crate::serial::{BlockingConsole, RingBufferedConsole, DEFAULT_CONSOLE_BUFFER_SIZE};
+    use crate::timer::ManualClock;
+    use crate::vmm::loader::{fdt_region, FDT_MAX_SIZE};
     use crate::MMIOAccess;
-    trait MockMemOps {
+    use crate::{Device, MMIOBank};
+    pub(crate) trait MockMemOps {
         fn lw(&self, addr: usize) -> Option<u32>;
         fn sw(&self, addr: usize, val: u32) -> Option<()>;
         fn lb(&self, addr: usize) -> Option<u8>;
@@ -67,12 +89,15 @@ mod test {
             Some(())
         }
     }
+    use core::sync::atomic::{AtomicBool, Ordering::SeqCst};
     use spin::Mutex;
     use std::sync::mpsc::{channel, Receiver, Sender};
-    struct StdChannelConsole {
+    use std::time::Duration;
+    pub(crate) struct StdChannelConsole {
         rx: Mutex<Receiver<u8>>,
         tx: Mutex<Sender<u8>>,
         output_buffer: Mutex<Vec<u8>>,
+        cancelled: AtomicBool,
     }
     impl StdChannelConsole {
         pub fn new() -> Self {
@@ -81,18 +106,28 @@ mod test {
                 rx: Mutex::new(rx),
                 tx: Mutex::new(tx),
                 output_buffer: Mutex::new(Vec::new()),
+                cancelled: AtomicBool::new(false),
             }
         }
         pub fn output(&self) -> Vec<u8> {
             self.output_buffer.lock().clone()
         }
         pub fn send(&self, chr: u8) {
-            self.tx.lock().send(chr);
+            self.tx.lock().send(chr).unwrap();
         }
     }
     impl BlockingConsole for StdChannelConsole {
-        fn getc(&self) -> u8 {
-            self.rx.lock().recv().unwrap_or(0)
+        fn getc(&self) -> Option<u8> {
+            let rx = self.rx.lock();
+            while !self.cancelled.load(SeqCst) {
+                if let Ok(chr) = rx.recv_timeout(Duration::from_millis(10)) {
+                    return Some(chr);
+                }
+            }
+            None
+        }
+        fn cancel_getc(&self) {
+            self.cancelled.store(true, SeqCst);
         }
         fn putc(&self, chr: u8) {
             self.output_buffer.lock().push(chr);
@@ -103,19 +138,36 @@ mod test {
         {
             std::thread::spawn(f);
         }
+        fn park(&self) {
+            std::thread::yield_now();
+        }
+        fn unpark(&self) {}
     }
     #[test]
     fn test_system() {
         use crate::serial::uart16650::*;
         let stdconsole = Arc::new(StdChannelConsole::new());
-        let console: Arc<dyn Console> =
-            Arc::new(SingleCharBufferedConsole::new(Arc::clone(&stdconsole)));
+        let console: Arc<dyn Console> = Arc::new(RingBufferedConsole::new(
+            Arc::clone(&stdconsole),
+            DEFAULT_CONSOLE_BUFFER_SIZE,
+        ));
         console
             .as_any()
-            .downcast_ref::<SingleCharBufferedConsole<StdChannelConsole>>()
+            .downcast_ref::<RingBufferedConsole<StdChannelConsole>>()
             .unwrap()
-            .start(Arc::clone(&console));
-        let (board, plic_i, _) = rcore_on_rcore(Arc::clone(&console));
+            .start();
+        let sip = Arc::new(IrqLatch::new());
+        let Board {
+            bus: board,
+            plic: plic_i,
+            ..
+        } = rcore_on_rcore(
+            Arc::clone(&console),
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            vec![HartIrqLines::latched(&sip)],
+            &VmConfig::default(),
+        );
+        let plic_i = plic_i.unwrap();
         // storing unrelated registers. taken from rcore.
         board.sb(SERIAL_MMIO + COM_FCR * MULTIPLIER, 0).unwrap();
         board
@@ -157,11 +209,16 @@ mod test {
             0,
             "No data."
         );
-        let DATA = 1;
+        const DATA: u8 = 1;
         stdconsole.send(DATA);
         // wait 100 ms.
         std::thread::sleep(std::time::Duration::from_millis(100));
         assert_eq!(plic_i.has_interrupt(), true, "Interrupt captured.");
+        assert_eq!(
+            sip.take_changes(),
+            Some(1 << IRQ_S_EXT),
+            "Delivered without polling."
+        );
         let pending = board.lw(PLIC_MMIO + 0x1000).unwrap();
         assert_eq!(pending, 1 << SERIAL_IRQ, "The irq is pending.");
         let claim = board.lw(PLIC_MMIO + 0x201004).unwrap();
@@ -255,4 +312,322 @@ mod test {
         board.sb(SERIAL_MMIO + COM_RX * MULTIPLIER, 5).unwrap();
         assert_eq!(stdconsole.output(), vec![1, 2, 3, 4, 5], "write");
     }
+    #[test]
+    fn timer() {
+        let stdconsole = Arc::new(StdChannelConsole::new());
+        let console = Arc::new(RingBufferedConsole::new(Arc::clone(&stdconsole), 16));
+        let clock = Arc::new(ManualClock::new(TIMEBASE_FREQUENCY));
+        let sip = Arc::new(IrqLatch::new());
+        let Board {
+            bus: board, clint, ..
+        } = rcore_on_rcore(
+            console,
+            Arc::clone(&clock) as Arc<dyn Clock>,
+            vec![HartIrqLines::latched(&sip)],
+            &VmConfig::default(),
+        );
+        sip.take_changes();
+        board.sw(CLINT_MMIO + 0x4000, 1000).unwrap();
+        board.sw(CLINT_MMIO + 0x4004, 0).unwrap();
+        assert_eq!(clint.next_event(), Some(1000));
+        clock.set(1000);
+        assert_eq!(sip.take_changes(), None, "Nothing happens until polled.");
+        clint.poll();
+        assert_eq!(sip.take_changes(), Some(1 << IRQ_S_TIMER));
+        board.sw(CLINT_MMIO, 1).unwrap();
+        assert_eq!(sip.take_changes(), Some(1 << IRQ_S_TIMER | 1 << IRQ_S_SOFT));
+        // sbi_set_timer.
+        assert!(clint.set_mtimecmp(0, 5000));
+        assert_eq!(sip.take_changes(), Some(1 << IRQ_S_SOFT));
+        clock.set(5000);
+        clint.poll();
+        assert_eq!(sip.take_changes(), Some(1 << IRQ_S_TIMER | 1 << IRQ_S_SOFT));
+    }
+    #[test]
+    fn smp() {
+        use crate::serial::uart16650::*;
+        let stdconsole = Arc::new(StdChannelConsole::new());
+        let console = Arc::new(RingBufferedConsole::new(Arc::clone(&stdconsole), 16));
+        let sip: Vec<_> = (0..2).map(|_| Arc::new(IrqLatch::new())).collect();
+        let Board {
+            bus: board, clint, ..
+        } = rcore_on_rcore(
+            Arc::clone(&console) as Arc<dyn Console>,
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            sip.iter().map(HartIrqLines::latched).collect(),
+            &VmConfig::default(),
+        );
+        assert_eq!(clint.num_harts(), 2);
+        for latch in sip.iter() {
+            latch.take_changes();
+        }
+        board.sw(CLINT_MMIO + 4, 1).unwrap();
+        assert_eq!(sip[0].take_changes(), None);
+        assert_eq!(sip[1].take_changes(), Some(1 << IRQ_S_SOFT));
+        // Route the UART to the S-mode context of hart 1, context 3.
+        board
+            .sw(PLIC_MMIO + 0x2000 + 3 * 0x80, 1 << SERIAL_IRQ)
+            .unwrap();
+        board.sw(PLIC_MMIO + SERIAL_IRQ * 4, 1).unwrap();
+        board
+            .sb(SERIAL_MMIO + COM_IER * MULTIPLIER, COM_IER_RDI)
+            .unwrap();
+        console.try_notify_char(b'x');
+        assert_eq!(sip[0].take_changes(), None);
+        assert_eq!(
+            sip[1].take_changes(),
+            Some(1 << IRQ_S_SOFT | 1 << IRQ_S_EXT)
+        );
+        assert_eq!(
+            board.lw(PLIC_MMIO + 0x200004 + 3 * 0x1000),
+            Some(SERIAL_IRQ as u32)
+        );
+        assert_eq!(
+            board.lw(PLIC_MMIO + 0x200004 + 4 * 0x1000),
+            None,
+            "No hart 2."
+        );
+    }
+    #[test]
+    fn timebase_from_clock() {
+        let stdconsole = Arc::new(StdChannelConsole::new());
+        let Board { fdt, .. } = rcore_on_rcore(
+            Arc::new(RingBufferedConsole::new(stdconsole, 16)),
+            Arc::new(ManualClock::new(1_000_000)),
+            vec![HartIrqLines::latched(&Arc::new(IrqLatch::new()))],
+            &VmConfig::default(),
+        );
+        let fdt = Fdt::parse(&fdt).unwrap();
+        let cpus = fdt.node("/cpus").unwrap();
+        assert_eq!(cpus.u32("timebase-frequency"), Some(1_000_000));
+    }
+    #[test]
+    fn vm_config() {
+        let stdconsole = Arc::new(StdChannelConsole::new());
+        let Board { fdt, .. } = rcore_on_rcore(
+            Arc::new(RingBufferedConsole::new(stdconsole, 16)),
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            vec![HartIrqLines::latched(&Arc::new(IrqLatch::new()))],
+            &VmConfig {
+                memory: vec![0x8020_0000..0x9820_0000, 0x1_0000_0000..0x1_4000_0000],
+                reserved: vec![0x9800_0000..0x9820_0000],
+                chosen: Chosen {
+                    bootargs: Some("console=ttyS0 rdinit=/init".into()),
+                    initrd: Some(0x8a00_0000..0x1_0000_0123),
+                },
+            },
+        );
+        let fdt = Fdt::parse(&fdt).unwrap();
+        let chosen = fdt.node("/chosen").unwrap();
+        assert_eq!(
+            chosen.string("bootargs").unwrap(),
+            "console=ttyS0 rdinit=/init"
+        );
+        assert_eq!(chosen.u64("linux,initrd-start"), Some(0x8a00_0000));
+        assert_eq!(chosen.u64("linux,initrd-end"), Some(0x1_0000_0123));
+        let stdout = fdt.node(&chosen.string("stdout-path").unwrap()).unwrap();
+        assert_eq!(stdout.string("compatible").unwrap(), "ns16550a");
+        let memory = fdt.node("/memory@80200000").unwrap();
+        assert_eq!(memory.string("device_type").unwrap(), "memory");
+        assert_eq!(
+            memory.cells("reg"),
+            Some(vec![0, 0x8020_0000, 0, 0x1800_0000])
+        );
+        let memory = fdt.node("/memory@100000000").unwrap();
+        assert_eq!(memory.cells("reg"), Some(vec![1, 0, 0, 0x4000_0000]));
+        assert_eq!(fdt.mem_reserve, vec![(0x9800_0000, 0x20_0000)]);
+        let cpus = fdt.node("/cpus").unwrap();
+        assert_eq!(
+            cpus.u32("timebase-frequency"),
+            Some(TIMEBASE_FREQUENCY as u32)
+        );
+        let cpu = cpus.child("cpu@0").unwrap();
+        assert_eq!(cpu.string("riscv,isa").unwrap(), "rv64imafdc");
+        assert_eq!(cpu.string("mmu-type").unwrap(), "riscv,sv39");
+        // Without a configuration, there is only the console to choose.
+        let Board { fdt, .. } = rcore_on_rcore(
+            Arc::new(RingBufferedConsole::new(
+                Arc::new(StdChannelConsole::new()),
+                16,
+            )),
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            vec![HartIrqLines::latched(&Arc::new(IrqLatch::new()))],
+            &VmConfig::default(),
+        );
+        let fdt = Fdt::parse(&fdt).unwrap();
+        assert_eq!(fdt.node("/chosen").unwrap().properties.len(), 1);
+        assert!(fdt
+            .root
+            .children
+            .iter()
+            .all(|node| !node.name.starts_with("memory")));
+    }
+    #[test]
+    fn fdt_in_ram() {
+        // RAM as the VMMs lay it out, from the kernel or from the start of DRAM. The device tree
+        // must be in RAM, or the guest can't map it, and reserved, or it may overwrite it.
+        for &base in [0x8020_0000, 0x8000_0000].iter() {
+            let ram = base..base + 0x1800_0000;
+            let region = fdt_region(&ram);
+            let Board { fdt, .. } = rcore_on_rcore(
+                Arc::new(RingBufferedConsole::new(
+                    Arc::new(StdChannelConsole::new()),
+                    16,
+                )),
+                Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+                vec![HartIrqLines::latched(&Arc::new(IrqLatch::new()))],
+                &VmConfig {
+                    memory: vec![ram.clone()],
+                    reserved: vec![region.clone()],
+                    ..VmConfig::default()
+                },
+            );
+            assert!(fdt.len() as u64 <= FDT_MAX_SIZE);
+            let fdt = Fdt::parse(&fdt).unwrap();
+            let memory = fdt.node(&format!("/memory@{:x}", base)).unwrap();
+            let reg = memory.cells("reg").unwrap();
+            let start = (reg[0] as u64) << 32 | reg[1] as u64;
+            let end = start + ((reg[2] as u64) << 32 | reg[3] as u64);
+            assert!(start <= region.start && region.end <= end);
+            assert_eq!(fdt.mem_reserve, vec![(region.start, FDT_MAX_SIZE)]);
+        }
+        assert_eq!(
+            fdt_region(&(0x8020_0000..0x9820_0000)),
+            0x9800_0000..0x9820_0000
+        );
+    }
+    #[test]
+    fn device_tree() {
+        let sip: Vec<_> = (0..3).map(|_| Arc::new(IrqLatch::new())).collect();
+        let Board { fdt: blob, .. } = rcore_on_rcore(
+            Arc::new(RingBufferedConsole::new(
+                Arc::new(StdChannelConsole::new()),
+                16,
+            )),
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            sip.iter().map(HartIrqLines::latched).collect(),
+            &VmConfig::default(),
+        );
+        let fdt = Fdt::parse(&blob).unwrap();
+        assert_eq!(fdt.to_blob(), blob);
+        check_interrupts(&fdt);
+        let cpus = fdt.node("/cpus").unwrap();
+        assert_eq!(cpus.children.len(), 3);
+        assert_eq!(cpus.child("cpu@2").unwrap().u32("reg"), Some(2));
+        let plic = fdt.node(&format!("/plic@{:x}", PLIC_MMIO)).unwrap();
+        let uart = fdt.node(&format!("/uart@{:x}", SERIAL_MMIO)).unwrap();
+        assert_eq!(
+            fdt.node_by_phandle(uart.u32("interrupt-parent").unwrap()),
+            Some(plic)
+        );
+        assert_eq!(uart.u32("interrupts"), Some(SERIAL_IRQ as u32));
+        assert_eq!(
+            plic.cells("reg"),
+            Some(vec![0, PLIC_MMIO as u32, 0, 0x206000])
+        );
+    }
+    /// Every interrupt parent in `fdt` is an interrupt controller, and each entry of
+    /// `interrupts-extended` has as many cells as its controller says.
+    pub(crate) fn check_interrupts(fdt: &Fdt) {
+        fn check(fdt: &Fdt, node: &FdtNode) {
+            let controller = |phandle| {
+                let controller = fdt
+                    .node_by_phandle(phandle)
+                    .unwrap_or_else(|| panic!("{}: no phandle {}", node.name, phandle));
+                assert!(
+                    controller.property("interrupt-controller").is_some(),
+                    "{}: {} is not an interrupt controller",
+                    node.name,
+                    controller.name
+                );
+                controller.u32("#interrupt-cells").unwrap() as usize
+            };
+            if let Some(parent) = node.u32("interrupt-parent") {
+                let cells = controller(parent);
+                assert_eq!(node.cells("interrupts").unwrap().len() % cells.max(1), 0);
+            }
+            if let Some(extended) = node.cells("interrupts-extended") {
+                let mut at = 0;
+                while at < extended.len() {
+                    at += 1 + controller(extended[at]);
+                }
+                assert_eq!(at, extended.len(), "{}", node.name);
+            }
+            for child in node.children.iter() {
+                check(fdt, child);
+            }
+        }
+        check(fdt, &fdt.root);
+    }
+    // xorshift64, so that failures are reproducible from the seed.
+    struct Rng(u64);
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+    #[test]
+    fn fuzz_mmio() {
+        let stdconsole = Arc::new(StdChannelConsole::new());
+        let console = Arc::new(RingBufferedConsole::new(Arc::clone(&stdconsole), 16));
+        let Board { bus: board, .. } = rcore_on_rcore(
+            Arc::clone(&console) as Arc<dyn Console>,
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            vec![HartIrqLines::latched(&Arc::new(IrqLatch::new()))],
+            &VmConfig::default(),
+        );
+        // Windows around the devices and the PLIC context registers.
+        fuzz(
+            &board,
+            &[
+                (SERIAL_MMIO, 0x200),
+                (CLINT_MMIO, 0x10000),
+                (PLIC_MMIO, 0x4000),
+                (PLIC_MMIO + 0x200000, 0x4000),
+                (PLIC_MMIO + 0x3ff_f000, 0x2000),
+            ],
+            &console,
+        );
+    }
+    /// Drive random accesses at `board`, mostly inside `windows` but also anywhere in the address space.
+    pub(crate) fn fuzz(
+        board: &MMIOBank,
+        windows: &[(usize, usize)],
+        console: &RingBufferedConsole<StdChannelConsole>,
+    ) {
+        let mut windows = windows.to_vec();
+        windows.push((0, usize::max_value()));
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..200_000 {
+            let (base, len) = windows[rng.below(windows.len() as u64) as usize];
+            let addr = base.wrapping_add(rng.below(len as u64) as usize);
+            // Mostly small values, so that guest-written IRQ numbers hit valid sources as well.
+            let val = match rng.below(3) {
+                0 => rng.below(64),
+                1 => rng.below(1 << 16),
+                _ => rng.next(),
+            };
+            if rng.below(16) == 0 {
+                console.try_notify_char(val as u8);
+            }
+            let (mut b, mut h, mut w, mut d) = (0u8, 0u16, 0u32, 0u64);
+            let mut access = match rng.below(8) {
+                0 => MMIOAccess::LoadByte(&mut b),
+                1 => MMIOAccess::LoadHalf(&mut h),
+                2 => MMIOAccess::LoadWord(&mut w),
+                3 => MMIOAccess::LoadDword(&mut d),
+                4 => MMIOAccess::StoreByte(val as u8),
+                5 => MMIOAccess::StoreHalf(val as u16),
+                6 => MMIOAccess::StoreWord(val as u32),
+                _ => MMIOAccess::StoreDword(val),
+            };
+            let _ = board.handle_mmio(addr, &mut access);
+        }
+    }
 }
@@ -0,0 +1,144 @@
+use super::builder::{Board, BoardBuilder, IrqControllerKind};
+use super::{HartIrqLines, VmConfig};
+use crate::serial::uart16650::Uart16650;
+use crate::serial::Console;
+use crate::timer::Clock;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+/// The rcore_on_rcore board with the PLIC replaced by an APLIC delivering MSIs to an IMSIC.
+const SERIAL_IRQ: usize = 10;
+const APLIC_SOURCES: usize = 31;
+const IMSIC_IDS: usize = 63;
+const SERIAL_MMIO: usize = 0x10000000;
+const CLINT_MMIO: usize = 0x2000000;
+const APLIC_MMIO: usize = 0xd000000;
+const IMSIC_MMIO: usize = 0x28000000;
+// Room for more devices after the UART, as on rcore_on_rcore.
+const MMIO_WINDOW: core::ops::Range<u64> = 0x10000000..0x20000000;
+
+/// The external interrupt of hart `i` is driven by the supervisor-level interrupt file of the hart,
+/// which sits in page `i` of the IMSIC region. The harts reach the files in `imsic_files` of the
+/// board through the siselect/sireg/stopei CSRs, which the VMM emulates once given the files.
+/// Only backends that exit on these CSRs, as the interpreter does, can run the board. Timers and
+/// `config` are the same as in `rcore_on_rcore`.
+pub fn rcore_on_rcore_aia(
+    blocking_console: Arc<dyn Console>,
+    clock: Arc<dyn Clock>,
+    harts: Vec<HartIrqLines>,
+    config: &VmConfig,
+) -> Board {
+    let aplic = IrqControllerKind::Aplic {
+        sources: APLIC_SOURCES,
+        imsic_ids: IMSIC_IDS,
+        imsic_base: IMSIC_MMIO as u64,
+    };
+    let mut builder = BoardBuilder::with_irq_controller(clock, harts, aplic, MMIO_WINDOW);
+    let fixed = "fixed devices overlap";
+    builder.place_clint(Some(CLINT_MMIO as u64)).expect(fixed);
+    builder
+        .place_irq_controller(Some(APLIC_MMIO as u64))
+        .expect(fixed);
+    let (irq, line) = builder.irq(Some(SERIAL_IRQ as u32)).unwrap();
+    let serial = Arc::new(Uart16650::new(blocking_console, line));
+    builder
+        .add_device(serial, Some(SERIAL_MMIO as u64), &[irq])
+        .expect(fixed);
+    builder.build(config).expect("devices overlap RAM")
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::rcore_on_rcore::test::{
+        check_interrupts, fuzz, MockMemOps, StdChannelConsole,
+    };
+    use super::*;
+    use crate::board::{IRQ_S_EXT, TIMEBASE_FREQUENCY};
+    use crate::fdt::Fdt;
+    use crate::irq::imsic::{IMSIC_EIDELIVERY, IMSIC_EIE0, IMSIC_PAGE_SIZE};
+    use crate::irq::IrqLatch;
+    use crate::serial::uart16650::*;
+    use crate::serial::RingBufferedConsole;
+    use crate::timer::ManualClock;
+    use crate::Device;
+    #[test]
+    fn test_system() {
+        let stdconsole = Arc::new(StdChannelConsole::new());
+        let console = Arc::new(RingBufferedConsole::new(Arc::clone(&stdconsole), 16));
+        let sip = Arc::new(IrqLatch::new());
+        let Board {
+            bus: board,
+            imsic_files: files,
+            ..
+        } = rcore_on_rcore_aia(
+            Arc::clone(&console) as Arc<dyn Console>,
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            vec![HartIrqLines::latched(&sip)],
+            &VmConfig::default(),
+        );
+        let imsic = &files[0];
+        imsic.write_indirect(IMSIC_EIDELIVERY, 1).unwrap();
+        imsic.write_indirect(IMSIC_EIE0, 1 << 5).unwrap();
+        board.sw(APLIC_MMIO + SERIAL_IRQ * 4, 6).unwrap(); // level-high
+        board.sw(APLIC_MMIO + 0x3000 + SERIAL_IRQ * 4, 5).unwrap(); // hart 0, EIID 5
+        board.sw(APLIC_MMIO + 0x1edc, SERIAL_IRQ as u32).unwrap(); // setienum
+        board.sw(APLIC_MMIO, 1 << 8).unwrap(); // domaincfg.IE
+        board
+            .sb(SERIAL_MMIO + COM_IER * MULTIPLIER, COM_IER_RDI)
+            .unwrap();
+        assert!(!imsic.has_interrupt());
+        console.try_notify_char(b'x');
+        assert!(sip.level(IRQ_S_EXT), "Delivered as an MSI.");
+        assert_eq!(imsic.claim_topei(), 5 << 16 | 5);
+        assert_eq!(board.lb(SERIAL_MMIO + COM_RX * MULTIPLIER), Some(b'x'));
+        assert!(!sip.level(IRQ_S_EXT));
+    }
+    #[test]
+    fn fuzz_mmio() {
+        let stdconsole = Arc::new(StdChannelConsole::new());
+        let console = Arc::new(RingBufferedConsole::new(Arc::clone(&stdconsole), 16));
+        let board = rcore_on_rcore_aia(
+            Arc::clone(&console) as Arc<dyn Console>,
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            vec![HartIrqLines::latched(&Arc::new(IrqLatch::new()))],
+            &VmConfig::default(),
+        );
+        fuzz(
+            &board.bus,
+            &[
+                (SERIAL_MMIO, 0x200),
+                (CLINT_MMIO, 0x10000),
+                (APLIC_MMIO, 0x5000),
+                (IMSIC_MMIO, 0x1000),
+            ],
+            &console,
+        );
+    }
+    #[test]
+    fn device_tree() {
+        let sip: Vec<_> = (0..3).map(|_| Arc::new(IrqLatch::new())).collect();
+        let blob = rcore_on_rcore_aia(
+            Arc::new(RingBufferedConsole::new(
+                Arc::new(StdChannelConsole::new()),
+                16,
+            )),
+            Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)),
+            sip.iter().map(HartIrqLines::latched).collect(),
+            &VmConfig::default(),
+        )
+        .fdt;
+        let fdt = Fdt::parse(&blob).unwrap();
+        assert_eq!(fdt.to_blob(), blob);
+        check_interrupts(&fdt);
+        let imsic = fdt.node(&format!("/imsic@{:x}", IMSIC_MMIO)).unwrap();
+        let aplic = fdt.node(&format!("/aplic@{:x}", APLIC_MMIO)).unwrap();
+        assert_eq!(
+            fdt.node_by_phandle(aplic.u32("msi-parent").unwrap()),
+            Some(imsic)
+        );
+        // Three harts take four pages, as the hart index is two bits of the address.
+        assert_eq!(
+            imsic.cells("reg"),
+            Some(vec![0, IMSIC_MMIO as u32, 0, 4 * IMSIC_PAGE_SIZE as u32])
+        );
+    }
+}
@@ -45,6 +45,11 @@ impl MMIOBank {
         }
     }
 }
+impl Default for MMIOBank {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl MMIOBank {
     pub fn add_device(&mut self, base: usize, device: Arc<dyn Device>) {
         self.devices.push(MMIODescription { base, device });
@@ -56,9 +61,8 @@ impl Device for MMIOBank {
     }
     fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
         for dev in self.devices.iter() {
-            if offset >= dev.base && offset < dev.base + dev.device.mmio_region_size() {
-                let o = offset - dev.base;
-                if dev.device.handle_mmio(o, access)? {
+            if let Some(o) = offset.checked_sub(dev.base) {
+                if o < dev.device.mmio_region_size() && dev.device.handle_mmio(o, access)? {
                     return Some(true);
                 }
             }
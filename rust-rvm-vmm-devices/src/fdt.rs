@@ -0,0 +1,496 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+// Flattened device tree (DTB) output, version 17, and a parser for it.
+// Nodes are written depth-first: `begin_node`, the properties of the node, its children, `end_node`.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+    mem_reserve: Vec<(u64, u64)>,
+    boot_cpuid_phys: u32,
+}
+
+impl FdtWriter {
+    /// Start a tree. The first node begun is the root node, named "".
+    pub fn new() -> Self {
+        FdtWriter {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            depth: 0,
+            mem_reserve: Vec::new(),
+            boot_cpuid_phys: 0,
+        }
+    }
+    /// Keep the guest off `size` bytes at `addr`, e.g. firmware, through the memory reservation
+    /// block.
+    pub fn reserve_memory(&mut self, addr: u64, size: u64) {
+        self.mem_reserve.push((addr, size));
+    }
+    /// `reg` of the cpu node of the boot hart. 0 unless set.
+    pub fn set_boot_cpuid_phys(&mut self, hart: u32) {
+        self.boot_cpuid_phys = hart;
+    }
+    fn push_u32(&mut self, val: u32) {
+        self.structure.extend_from_slice(&val.to_be_bytes());
+    }
+    fn align(&mut self) {
+        while self.structure.len() % 4 != 0 {
+            self.structure.push(0);
+        }
+    }
+    // Offset of `name` in the string table. Names are stored once.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&c| c == 0) {
+            if s == name.as_bytes() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no node to end");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        assert!(self.depth > 0, "property outside of nodes");
+        let nameoff = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(nameoff);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+    /// A property without value, e.g. `interrupt-controller`.
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        let mut bytes = Vec::with_capacity(value.len() + 1);
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells
+            .iter()
+            .flat_map(|c| c.to_be_bytes().to_vec())
+            .collect();
+        self.property(name, &bytes);
+    }
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+    /// A 64-bit value in two cells, most significant first.
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property_cells(name, &[(value >> 32) as u32, value as u32]);
+    }
+    /// `reg` of a node whose parent has two address cells and two size cells.
+    pub fn property_reg64(&mut self, base: u64, size: u64) {
+        self.property_cells(
+            "reg",
+            &[
+                (base >> 32) as u32,
+                base as u32,
+                (size >> 32) as u32,
+                size as u32,
+            ],
+        );
+    }
+    /// Finish the tree, which must have all of its nodes ended, and return the blob.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unterminated node");
+        self.push_u32(FDT_END);
+        // The memory reservation block ends with an empty entry.
+        self.mem_reserve.push((0, 0));
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16 * self.mem_reserve.len();
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let totalsize = off_dt_strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            totalsize as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid_phys,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob = Vec::with_capacity(totalsize);
+        for word in header.iter() {
+            blob.extend_from_slice(&word.to_be_bytes());
+        }
+        for (addr, size) in self.mem_reserve.iter() {
+            blob.extend_from_slice(&addr.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+impl Default for FdtWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FdtError {
+    /// Not a device tree blob.
+    BadMagic,
+    /// A version this parser does not read.
+    UnsupportedVersion(u32),
+    /// A block or a token runs past the end of the blob.
+    Truncated,
+    /// Tokens out of order, or unbalanced nodes.
+    BadStructure,
+    /// A node or property name that is not a NUL-terminated string.
+    BadName,
+}
+
+/// A node of a parsed device tree, with its properties and children in blob order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FdtNode {
+    pub name: String,
+    pub properties: Vec<(String, Vec<u8>)>,
+    pub children: Vec<FdtNode>,
+}
+
+/// A parsed device tree. `Fdt::parse(&fdt.to_blob())` gives back the same tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fdt {
+    /// Address and size of each entry of the memory reservation block.
+    pub mem_reserve: Vec<(u64, u64)>,
+    pub boot_cpuid_phys: u32,
+    pub root: FdtNode,
+}
+
+struct Reader<'a> {
+    blob: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], FdtError> {
+        let bytes = self
+            .blob
+            .get(self.at..self.at + len)
+            .ok_or(FdtError::Truncated)?;
+        self.at += len;
+        Ok(bytes)
+    }
+    fn u32(&mut self) -> Result<u32, FdtError> {
+        let mut word = [0u8; 4];
+        word.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_be_bytes(word))
+    }
+    fn u64(&mut self) -> Result<u64, FdtError> {
+        Ok((self.u32()? as u64) << 32 | self.u32()? as u64)
+    }
+    // A NUL-terminated name, and the padding after it.
+    fn name(&mut self) -> Result<String, FdtError> {
+        let len = self.blob[self.at.min(self.blob.len())..]
+            .iter()
+            .position(|&c| c == 0)
+            .ok_or(FdtError::Truncated)?;
+        let name = string(self.bytes(len + 1)?)?;
+        self.at = (self.at + 3) & !3;
+        Ok(name)
+    }
+    // The next token that is not a NOP.
+    fn token(&mut self) -> Result<u32, FdtError> {
+        loop {
+            match self.u32()? {
+                FDT_NOP => continue,
+                token => return Ok(token),
+            }
+        }
+    }
+}
+
+// `bytes` up to the NUL terminating them.
+fn string(bytes: &[u8]) -> Result<String, FdtError> {
+    match bytes.iter().position(|&c| c == 0) {
+        Some(len) => String::from_utf8(bytes[..len].to_vec()).map_err(|_| FdtError::BadName),
+        None => Err(FdtError::BadName),
+    }
+}
+
+impl Fdt {
+    pub fn parse(blob: &[u8]) -> Result<Self, FdtError> {
+        let mut header = Reader { blob, at: 0 };
+        if header.u32()? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let totalsize = header.u32()? as usize;
+        let off_dt_struct = header.u32()? as usize;
+        let off_dt_strings = header.u32()? as usize;
+        let off_mem_rsvmap = header.u32()? as usize;
+        let version = header.u32()?;
+        let last_comp_version = header.u32()?;
+        let boot_cpuid_phys = header.u32()?;
+        let size_dt_strings = header.u32()? as usize;
+        let size_dt_struct = header.u32()? as usize;
+        if version < FDT_VERSION || last_comp_version > FDT_VERSION {
+            return Err(FdtError::UnsupportedVersion(version));
+        }
+        let blob = blob.get(..totalsize).ok_or(FdtError::Truncated)?;
+        let strings = blob
+            .get(off_dt_strings..off_dt_strings + size_dt_strings)
+            .ok_or(FdtError::Truncated)?;
+        let mut mem_reserve = Vec::new();
+        let mut rsvmap = Reader {
+            blob,
+            at: off_mem_rsvmap,
+        };
+        loop {
+            let entry = (rsvmap.u64()?, rsvmap.u64()?);
+            if entry == (0, 0) {
+                break;
+            }
+            mem_reserve.push(entry);
+        }
+        let mut structure = Reader {
+            blob: blob
+                .get(..off_dt_struct + size_dt_struct)
+                .ok_or(FdtError::Truncated)?,
+            at: off_dt_struct,
+        };
+        if structure.token()? != FDT_BEGIN_NODE {
+            return Err(FdtError::BadStructure);
+        }
+        let root = Self::parse_node(&mut structure, strings)?;
+        if structure.token()? != FDT_END {
+            return Err(FdtError::BadStructure);
+        }
+        Ok(Fdt {
+            mem_reserve,
+            boot_cpuid_phys,
+            root,
+        })
+    }
+    // The node whose FDT_BEGIN_NODE was just read, up to and including its FDT_END_NODE.
+    fn parse_node(structure: &mut Reader, strings: &[u8]) -> Result<FdtNode, FdtError> {
+        let mut node = FdtNode {
+            name: structure.name()?,
+            properties: Vec::new(),
+            children: Vec::new(),
+        };
+        loop {
+            match structure.token()? {
+                FDT_PROP if node.children.is_empty() => {
+                    let len = structure.u32()? as usize;
+                    let nameoff = structure.u32()? as usize;
+                    let name = string(strings.get(nameoff..).ok_or(FdtError::BadName)?)?;
+                    let value = structure.bytes(len)?.to_vec();
+                    structure.at = (structure.at + 3) & !3;
+                    node.properties.push((name, value));
+                }
+                FDT_BEGIN_NODE => node.children.push(Self::parse_node(structure, strings)?),
+                FDT_END_NODE => return Ok(node),
+                _ => return Err(FdtError::BadStructure),
+            }
+        }
+    }
+    /// The blob of the tree, as `FdtWriter` writes it.
+    pub fn to_blob(&self) -> Vec<u8> {
+        fn write(fdt: &mut FdtWriter, node: &FdtNode) {
+            fdt.begin_node(&node.name);
+            for (name, value) in node.properties.iter() {
+                fdt.property(name, value);
+            }
+            for child in node.children.iter() {
+                write(fdt, child);
+            }
+            fdt.end_node();
+        }
+        let mut fdt = FdtWriter::new();
+        for &(addr, size) in self.mem_reserve.iter() {
+            fdt.reserve_memory(addr, size);
+        }
+        fdt.set_boot_cpuid_phys(self.boot_cpuid_phys);
+        write(&mut fdt, &self.root);
+        fdt.finish()
+    }
+    /// The node at `path`, e.g. "/cpus/cpu@0". Names are matched in full, unit address included.
+    pub fn node(&self, path: &str) -> Option<&FdtNode> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(&self.root, |node, name| node.child(name))
+    }
+    /// The node with `phandle`.
+    pub fn node_by_phandle(&self, phandle: u32) -> Option<&FdtNode> {
+        fn find(node: &FdtNode, phandle: u32) -> Option<&FdtNode> {
+            if node.u32("phandle") == Some(phandle) {
+                return Some(node);
+            }
+            node.children.iter().find_map(|child| find(child, phandle))
+        }
+        find(&self.root, phandle)
+    }
+}
+
+impl FdtNode {
+    pub fn child(&self, name: &str) -> Option<&FdtNode> {
+        self.children.iter().find(|child| child.name == name)
+    }
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(prop, _)| prop == name)
+            .map(|(_, value)| &value[..])
+    }
+    /// The value of a property of big-endian cells.
+    pub fn cells(&self, name: &str) -> Option<Vec<u32>> {
+        let value = self.property(name)?;
+        if value.len() % 4 != 0 {
+            return None;
+        }
+        Some(
+            value
+                .chunks(4)
+                .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+                .collect(),
+        )
+    }
+    pub fn u32(&self, name: &str) -> Option<u32> {
+        match self.cells(name)?.as_slice() {
+            [value] => Some(*value),
+            _ => None,
+        }
+    }
+    /// A property of two cells, e.g. `linux,initrd-start`.
+    pub fn u64(&self, name: &str) -> Option<u64> {
+        match self.cells(name)?.as_slice() {
+            [high, low] => Some((*high as u64) << 32 | *low as u64),
+            _ => None,
+        }
+    }
+    /// The first string of a string property.
+    pub fn string(&self, name: &str) -> Option<String> {
+        string(self.property(name)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn tree() -> Vec<u8> {
+        let mut fdt = FdtWriter::new();
+        fdt.reserve_memory(0x8000_0000, 0x20_0000);
+        fdt.reserve_memory(0x1_0000_0000, 0x1000);
+        fdt.set_boot_cpuid_phys(1);
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.begin_node("chosen");
+        fdt.property_string("bootargs", "console=ttyS0");
+        fdt.property_u64("linux,initrd-start", 0x1_8000_0000);
+        fdt.end_node();
+        fdt.begin_node("intc@c000000");
+        fdt.property_u32("phandle", 7);
+        fdt.property_null("interrupt-controller");
+        fdt.property_reg64(0xc00_0000, 0x40_0000);
+        fdt.end_node();
+        fdt.begin_node("uart@10000000");
+        // A name already in the string table.
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("interrupt-parent", 7);
+        fdt.end_node();
+        fdt.end_node();
+        fdt.finish()
+    }
+    #[test]
+    fn round_trip() {
+        let blob = tree();
+        let fdt = Fdt::parse(&blob).unwrap();
+        assert_eq!(
+            fdt.mem_reserve,
+            vec![(0x8000_0000, 0x20_0000), (0x1_0000_0000, 0x1000)]
+        );
+        assert_eq!(fdt.boot_cpuid_phys, 1);
+        assert_eq!(fdt.root.name, "");
+        assert_eq!(fdt.root.u32("#address-cells"), Some(2));
+        let chosen = fdt.node("/chosen").unwrap();
+        assert_eq!(chosen.string("bootargs").unwrap(), "console=ttyS0");
+        assert_eq!(chosen.u64("linux,initrd-start"), Some(0x1_8000_0000));
+        let uart = fdt.node("/uart@10000000").unwrap();
+        let intc = fdt.node_by_phandle(uart.u32("interrupt-parent").unwrap());
+        assert_eq!(intc.map(|node| &node.name[..]), Some("intc@c000000"));
+        assert_eq!(
+            intc.unwrap().property("interrupt-controller"),
+            Some(&[][..])
+        );
+        assert_eq!(
+            intc.unwrap().cells("reg"),
+            Some(vec![0, 0xc00_0000, 0, 0x40_0000])
+        );
+        assert_eq!(fdt.node("/uart"), None, "No partial names.");
+        assert_eq!(fdt.node_by_phandle(8), None);
+        assert_eq!(fdt.to_blob(), blob);
+        // A NOP before FDT_END, with the header following the structure block as it grows.
+        let end = struct_end(&blob) - 4;
+        let mut with_nop = blob[..end].to_vec();
+        with_nop.extend_from_slice(&FDT_NOP.to_be_bytes());
+        with_nop.extend_from_slice(&blob[end..]);
+        for &at in [4, 12, 36].iter() {
+            let word = header_word(&blob, at) + 4;
+            with_nop[at..at + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        assert_eq!(Fdt::parse(&with_nop), Ok(fdt));
+    }
+    fn header_word(blob: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes([blob[at], blob[at + 1], blob[at + 2], blob[at + 3]])
+    }
+    fn struct_end(blob: &[u8]) -> usize {
+        (header_word(blob, 8) + header_word(blob, 36)) as usize
+    }
+    #[test]
+    fn bad_blobs() {
+        let blob = tree();
+        let patched = |at: usize, word: u32| {
+            let mut blob = blob.clone();
+            blob[at..at + 4].copy_from_slice(&word.to_be_bytes());
+            Fdt::parse(&blob)
+        };
+        assert_eq!(patched(0, 0xfeed_d00d), Err(FdtError::BadMagic));
+        assert_eq!(patched(20, 16), Err(FdtError::UnsupportedVersion(16)));
+        assert_eq!(
+            Fdt::parse(&blob[..blob.len() - 1]),
+            Err(FdtError::Truncated)
+        );
+        assert_eq!(Fdt::parse(&blob[..20]), Err(FdtError::Truncated));
+        // The root node ends with a property after its children, or not at all.
+        let end = struct_end(&blob);
+        assert_eq!(patched(end - 8, FDT_PROP), Err(FdtError::BadStructure));
+        assert_eq!(patched(end - 8, FDT_END), Err(FdtError::BadStructure));
+        assert_eq!(patched(end - 4, FDT_END_NODE), Err(FdtError::BadStructure));
+    }
+}
@@ -0,0 +1,113 @@
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering::*};
+// Guest physical memory as the interpreter sees it. Other vCPUs run on other threads and use
+// the same memory at the same time, so aligned accesses are atomic as on real harts.
+
+/// RAM backing guest physical memory from `gpa`. Host and guest addresses agree modulo 8.
+pub struct Region {
+    pub gpa: u64,
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+// Regions are never freed, and all accesses through them go through the bus.
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+pub struct Bus<'a> {
+    regions: &'a [Region],
+}
+
+impl<'a> Bus<'a> {
+    pub fn new(regions: &'a [Region]) -> Self {
+        Bus { regions }
+    }
+    // Host address of `size` bytes at `pa`, if they are all RAM.
+    fn host(&self, pa: u64, size: u64) -> Option<*mut u8> {
+        self.regions
+            .iter()
+            .find(|region| {
+                pa >= region.gpa
+                    && pa - region.gpa < region.len as u64
+                    && region.len as u64 - (pa - region.gpa) >= size
+            })
+            .map(|region| unsafe { region.ptr.add((pa - region.gpa) as usize) })
+    }
+    /// Load `size` bytes at `pa`, zero-extended. None if they are not RAM.
+    pub fn read(&self, pa: u64, size: u64) -> Option<u64> {
+        let ptr = self.host(pa, size)?;
+        let val = unsafe {
+            match size {
+                _ if pa % size != 0 => {
+                    (0..size).fold(0, |val, i| val | (*ptr.add(i as usize) as u64) << (8 * i))
+                }
+                1 => (*ptr.cast::<AtomicU8>()).load(Relaxed) as u64,
+                2 => (*ptr.cast::<AtomicU16>()).load(Relaxed) as u64,
+                4 => (*ptr.cast::<AtomicU32>()).load(Relaxed) as u64,
+                _ => (*ptr.cast::<AtomicU64>()).load(Relaxed),
+            }
+        };
+        Some(val)
+    }
+    /// Store the low `size` bytes of `val` at `pa`. False if they are not RAM.
+    pub fn write(&self, pa: u64, size: u64, val: u64) -> bool {
+        let ptr = match self.host(pa, size) {
+            Some(ptr) => ptr,
+            None => return false,
+        };
+        unsafe {
+            match size {
+                _ if pa % size != 0 => {
+                    for i in 0..size {
+                        *ptr.add(i as usize) = (val >> (8 * i)) as u8;
+                    }
+                }
+                1 => (*ptr.cast::<AtomicU8>()).store(val as u8, Relaxed),
+                2 => (*ptr.cast::<AtomicU16>()).store(val as u16, Relaxed),
+                4 => (*ptr.cast::<AtomicU32>()).store(val as u32, Relaxed),
+                _ => (*ptr.cast::<AtomicU64>()).store(val, Relaxed),
+            }
+        }
+        true
+    }
+    /// Atomically replace the aligned word or doubleword at `pa` by `op` of it, and return what
+    /// it was. None if it is not RAM.
+    pub fn amo(&self, pa: u64, size: u64, op: impl Fn(u64) -> u64) -> Option<u64> {
+        let ptr = self.host(pa, size)?;
+        let old = unsafe {
+            if size == 4 {
+                let word = &*ptr.cast::<AtomicU32>();
+                let mut old = word.load(SeqCst);
+                while let Err(actual) =
+                    word.compare_exchange_weak(old, op(old as u64) as u32, SeqCst, SeqCst)
+                {
+                    old = actual;
+                }
+                old as u64
+            } else {
+                let dword = &*ptr.cast::<AtomicU64>();
+                let mut old = dword.load(SeqCst);
+                while let Err(actual) = dword.compare_exchange_weak(old, op(old), SeqCst, SeqCst) {
+                    old = actual;
+                }
+                old
+            }
+        };
+        Some(old)
+    }
+    /// Atomically store `new` at `pa` if it holds `current`. None if it is not RAM.
+    pub fn compare_exchange(&self, pa: u64, size: u64, current: u64, new: u64) -> Option<bool> {
+        let ptr = self.host(pa, size)?;
+        let exchanged = unsafe {
+            if size == 4 {
+                (*ptr.cast::<AtomicU32>())
+                    .compare_exchange(current as u32, new as u32, SeqCst, SeqCst)
+                    .is_ok()
+            } else {
+                (*ptr.cast::<AtomicU64>())
+                    .compare_exchange(current, new, SeqCst, SeqCst)
+                    .is_ok()
+            }
+        };
+        Some(exchanged)
+    }
+}
@@ -0,0 +1,702 @@
+use super::bus::Bus;
+use super::mmu::*;
+use crate::board::{IRQ_S_EXT, IRQ_S_SOFT, IRQ_S_TIMER};
+use crate::hypervisor::{
+    CsrExit, CsrWrite, MmioExit, VcpuExit, CSR_SIREG, CSR_SISELECT, CSR_STOPEI,
+};
+use crate::isa::*;
+use crate::timer::Clock;
+// A hart in S and U mode, and the RV64IMAC part of its instruction set. What would trap to
+// M-mode on real hardware exits to the VMM instead.
+
+pub const CAUSE_ILLEGAL_INSTRUCTION: u64 = 2;
+pub const CAUSE_BREAKPOINT: u64 = 3;
+pub const CAUSE_LOAD_MISALIGNED: u64 = 4;
+pub const CAUSE_LOAD_ACCESS: u64 = 5;
+pub const CAUSE_STORE_MISALIGNED: u64 = 6;
+pub const CAUSE_STORE_ACCESS: u64 = 7;
+pub const CAUSE_USER_ECALL: u64 = 8;
+pub const CAUSE_FETCH_ACCESS: u64 = 1;
+pub const CAUSE_FETCH_PAGE_FAULT: u64 = 12;
+pub const CAUSE_LOAD_PAGE_FAULT: u64 = 13;
+pub const CAUSE_STORE_PAGE_FAULT: u64 = 15;
+
+pub const SSTATUS_SIE: u64 = 1 << 1;
+pub const SSTATUS_SPIE: u64 = 1 << 5;
+pub const SSTATUS_SPP: u64 = 1 << 8;
+pub const SSTATUS_FS: u64 = 3 << 13;
+pub const SSTATUS_SUM: u64 = 1 << 18;
+pub const SSTATUS_MXR: u64 = 1 << 19;
+const SSTATUS_UXL_64: u64 = 2 << 32;
+const SSTATUS_SD: u64 = 1 << 63;
+const SSTATUS_WRITABLE: u64 =
+    SSTATUS_SIE | SSTATUS_SPIE | SSTATUS_SPP | SSTATUS_FS | SSTATUS_SUM | SSTATUS_MXR;
+
+const CSR_FFLAGS: u32 = 0x001;
+const CSR_FRM: u32 = 0x002;
+const CSR_FCSR: u32 = 0x003;
+const CSR_SSTATUS: u32 = 0x100;
+const CSR_SIE: u32 = 0x104;
+const CSR_STVEC: u32 = 0x105;
+const CSR_SCOUNTEREN: u32 = 0x106;
+const CSR_SENVCFG: u32 = 0x10a;
+const CSR_SSCRATCH: u32 = 0x140;
+const CSR_SEPC: u32 = 0x141;
+const CSR_SCAUSE: u32 = 0x142;
+const CSR_STVAL: u32 = 0x143;
+const CSR_SIP: u32 = 0x144;
+const CSR_SATP: u32 = 0x180;
+const CSR_CYCLE: u32 = 0xc00;
+const CSR_TIME: u32 = 0xc01;
+const CSR_INSTRET: u32 = 0xc02;
+const CSR_HPMCOUNTER31: u32 = 0xc1f;
+
+/// The interrupts of sip and sie.
+pub const SIP_MASK: u64 = 1 << IRQ_S_SOFT | 1 << IRQ_S_TIMER | 1 << IRQ_S_EXT;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    User,
+    Supervisor,
+}
+
+/// A synchronous exception, taken by the guest.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Exception {
+    pub cause: u64,
+    pub tval: u64,
+}
+
+pub fn illegal(raw: u32) -> Exception {
+    Exception {
+        cause: CAUSE_ILLEGAL_INSTRUCTION,
+        tval: raw as u64,
+    }
+}
+
+// Where a load or store went.
+pub enum Mem {
+    Ram(u64),
+    Mmio(u64),
+}
+
+pub struct Hart {
+    pub x: [u64; 32],
+    pub f: [u64; 32],
+    pub pc: u64,
+    pub mode: Mode,
+    pub sstatus: u64,
+    pub sie: u64,
+    /// SSIP is set by the VMM and cleared by either, STIP and SEIP follow the VMM.
+    pub sip: u64,
+    pub stvec: u64,
+    pub sscratch: u64,
+    pub sepc: u64,
+    pub scause: u64,
+    pub stval: u64,
+    pub satp: u64,
+    pub scounteren: u64,
+    pub frm: u64,
+    pub fflags: u64,
+    pub instret: u64,
+    // Address and value of the last lr, for sc to compare against.
+    reservation: Option<(u64, u64)>,
+    pub tlb: Tlb,
+}
+
+impl Hart {
+    /// A hart in S-mode at `entry`, with translation and interrupts off, as SBI firmware
+    /// leaves it.
+    pub fn new(entry: u64) -> Self {
+        Hart {
+            x: [0; 32],
+            f: [0; 32],
+            pc: entry,
+            mode: Mode::Supervisor,
+            sstatus: 0,
+            sie: 0,
+            sip: 0,
+            stvec: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            scounteren: 0,
+            frm: 0,
+            fflags: 0,
+            instret: 0,
+            reservation: None,
+            tlb: Tlb::new(),
+        }
+    }
+    pub fn set_x(&mut self, reg: u32, val: u64) {
+        if reg != 0 {
+            self.x[reg as usize] = val;
+        }
+    }
+    /// The interrupt the hart takes before its next instruction, if any.
+    pub fn pending_interrupt(&self) -> Option<u64> {
+        let pending = self.sip & self.sie;
+        if pending == 0 || (self.mode == Mode::Supervisor && self.sstatus & SSTATUS_SIE == 0) {
+            return None;
+        }
+        [IRQ_S_EXT, IRQ_S_SOFT, IRQ_S_TIMER]
+            .iter()
+            .find(|&&irq| pending & 1 << irq != 0)
+            .map(|&irq| irq as u64)
+    }
+    /// Take a trap into S-mode.
+    pub fn trap(&mut self, interrupt: bool, cause: u64, tval: u64) {
+        self.sepc = self.pc;
+        self.scause = (interrupt as u64) << 63 | cause;
+        self.stval = tval;
+        let mut sstatus = self.sstatus & !(SSTATUS_SPP | SSTATUS_SPIE | SSTATUS_SIE);
+        if self.mode == Mode::Supervisor {
+            sstatus |= SSTATUS_SPP;
+        }
+        if self.sstatus & SSTATUS_SIE != 0 {
+            sstatus |= SSTATUS_SPIE;
+        }
+        self.sstatus = sstatus;
+        self.mode = Mode::Supervisor;
+        self.pc = self.stvec & !3;
+        if interrupt && self.stvec & 3 == 1 {
+            self.pc += 4 * cause;
+        }
+        self.reservation = None;
+    }
+    fn fetch_parcel(&mut self, bus: &Bus, va: u64) -> Result<u64, Exception> {
+        let pa = self.translate(bus, va, AccessType::Fetch)?;
+        bus.read(pa, 2).ok_or(Exception {
+            cause: CAUSE_FETCH_ACCESS,
+            tval: va,
+        })
+    }
+    /// Run one instruction. Exceptions leave the hart at the instruction for the guest to take
+    /// them, as do MMIO and CSR exits, for the VMM to emulate the access.
+    pub fn step(&mut self, bus: &Bus, clock: &dyn Clock) -> Result<Option<VcpuExit>, Exception> {
+        let low = self.fetch_parcel(bus, self.pc)?;
+        let (inst, raw, len) = if low & 3 == 3 {
+            let high = self.fetch_parcel(bus, self.pc.wrapping_add(2))?;
+            let inst = (high << 16 | low) as u32;
+            (inst, inst, 4)
+        } else {
+            let inst = expand_compressed(low as u16).ok_or_else(|| illegal(low as u32))?;
+            (inst, low as u32, 2)
+        };
+        let exit = self.execute(bus, clock, inst, raw, len)?;
+        self.instret = self.instret.wrapping_add(1);
+        Ok(exit)
+    }
+    /// Load `size` bytes at `va`. Misaligned accesses to RAM are carried out as if the hart
+    /// supported them.
+    pub fn load(&mut self, bus: &Bus, va: u64, size: u64) -> Result<Mem, Exception> {
+        let fault = Exception {
+            cause: CAUSE_LOAD_ACCESS,
+            tval: va,
+        };
+        if va % size != 0 && (va & 0xfff) + size > 0x1000 {
+            let mut val = 0;
+            for i in 0..size {
+                let pa = self.translate(bus, va.wrapping_add(i), AccessType::Load)?;
+                val |= bus.read(pa, 1).ok_or(fault)? << (8 * i);
+            }
+            return Ok(Mem::Ram(val));
+        }
+        let pa = self.translate(bus, va, AccessType::Load)?;
+        match bus.read(pa, size) {
+            Some(val) => Ok(Mem::Ram(val)),
+            None if va % size == 0 => Ok(Mem::Mmio(pa)),
+            None => Err(fault),
+        }
+    }
+    /// Store the low `size` bytes of `val` at `va`. Ok(Some(pa)) if it is not RAM.
+    pub fn store(
+        &mut self,
+        bus: &Bus,
+        va: u64,
+        size: u64,
+        val: u64,
+    ) -> Result<Option<u64>, Exception> {
+        let fault = Exception {
+            cause: CAUSE_STORE_ACCESS,
+            tval: va,
+        };
+        if va % size != 0 && (va & 0xfff) + size > 0x1000 {
+            // Nothing is written unless every byte can be.
+            let mut pa = [0; 8];
+            for i in 0..size {
+                pa[i as usize] = self.translate(bus, va.wrapping_add(i), AccessType::Store)?;
+                if bus.read(pa[i as usize], 1).is_none() {
+                    return Err(fault);
+                }
+            }
+            for i in 0..size {
+                bus.write(pa[i as usize], 1, val >> (8 * i));
+            }
+            return Ok(None);
+        }
+        let pa = self.translate(bus, va, AccessType::Store)?;
+        if bus.write(pa, size, val) {
+            Ok(None)
+        } else if va % size == 0 {
+            Ok(Some(pa))
+        } else {
+            Err(fault)
+        }
+    }
+    fn execute(
+        &mut self,
+        bus: &Bus,
+        clock: &dyn Clock,
+        inst: u32,
+        raw: u32,
+        len: u64,
+    ) -> Result<Option<VcpuExit>, Exception> {
+        let opcode = inst & 0x7f;
+        let rd = inst >> 7 & 0x1f;
+        let funct3 = inst >> 12 & 7;
+        let rs1 = inst >> 15 & 0x1f;
+        let rs2 = inst >> 20 & 0x1f;
+        let funct7 = inst >> 25;
+        let a = self.x[rs1 as usize];
+        let b = self.x[rs2 as usize];
+        let mut next_pc = self.pc.wrapping_add(len);
+        match opcode {
+            LUI => self.set_x(rd, imm_u(inst) as u64),
+            AUIPC => self.set_x(rd, self.pc.wrapping_add(imm_u(inst) as u64)),
+            JAL => {
+                self.set_x(rd, next_pc);
+                next_pc = self.pc.wrapping_add(imm_j(inst) as u64);
+            }
+            JALR if funct3 == 0 => {
+                self.set_x(rd, next_pc);
+                next_pc = a.wrapping_add(imm_i(inst) as u64) & !1;
+            }
+            BRANCH => {
+                let taken = match funct3 {
+                    0 => a == b,
+                    1 => a != b,
+                    4 => (a as i64) < (b as i64),
+                    5 => (a as i64) >= (b as i64),
+                    6 => a < b,
+                    7 => a >= b,
+                    _ => return Err(illegal(raw)),
+                };
+                if taken {
+                    next_pc = self.pc.wrapping_add(imm_b(inst) as u64);
+                }
+            }
+            LOAD => {
+                let (size, sign_extend) = match funct3 {
+                    0 => (1, true),
+                    1 => (2, true),
+                    2 => (4, true),
+                    3 => (8, false),
+                    4 => (1, false),
+                    5 => (2, false),
+                    6 => (4, false),
+                    _ => return Err(illegal(raw)),
+                };
+                let va = a.wrapping_add(imm_i(inst) as u64);
+                match self.load(bus, va, size)? {
+                    Mem::Ram(val) => {
+                        let val = if sign_extend {
+                            let unused = 64 - 8 * size;
+                            ((val << unused) as i64 >> unused) as u64
+                        } else {
+                            val
+                        };
+                        self.set_x(rd, val);
+                    }
+                    Mem::Mmio(pa) => {
+                        return Ok(Some(VcpuExit::Mmio(MmioExit {
+                            addr: pa,
+                            size: size as usize,
+                            read: true,
+                            data: 0,
+                            dst_reg: rd as usize,
+                            sign_extend,
+                            inst_len: len as usize,
+                        })))
+                    }
+                }
+            }
+            STORE if funct3 < 4 => {
+                let size = 1 << funct3;
+                let va = a.wrapping_add(imm_s(inst) as u64);
+                if let Some(pa) = self.store(bus, va, size, b)? {
+                    return Ok(Some(VcpuExit::Mmio(MmioExit {
+                        addr: pa,
+                        size: size as usize,
+                        read: false,
+                        data: if size == 8 {
+                            b
+                        } else {
+                            b & ((1 << (8 * size)) - 1)
+                        },
+                        dst_reg: 0,
+                        sign_extend: false,
+                        inst_len: len as usize,
+                    })));
+                }
+            }
+            OP_IMM => {
+                let imm = imm_i(inst) as u64;
+                let shamt = imm & 0x3f;
+                let val = match (funct3, imm >> 6 & 0x3f) {
+                    (0, _) => a.wrapping_add(imm),
+                    (2, _) => ((a as i64) < (imm as i64)) as u64,
+                    (3, _) => (a < imm) as u64,
+                    (4, _) => a ^ imm,
+                    (6, _) => a | imm,
+                    (7, _) => a & imm,
+                    (1, 0) => a << shamt,
+                    (5, 0) => a >> shamt,
+                    (5, 0x10) => (a as i64 >> shamt) as u64,
+                    _ => return Err(illegal(raw)),
+                };
+                self.set_x(rd, val);
+            }
+            OP_IMM_32 => {
+                let a = a as u32;
+                let imm = imm_i(inst);
+                let val = match (funct3, funct7) {
+                    (0, _) => a.wrapping_add(imm as u32),
+                    (1, 0) => a << rs2,
+                    (5, 0) => a >> rs2,
+                    (5, 0x20) => (a as i32 >> rs2) as u32,
+                    _ => return Err(illegal(raw)),
+                };
+                self.set_x(rd, val as i32 as u64);
+            }
+            OP => {
+                let val = match (funct7, funct3) {
+                    (0, 0) => a.wrapping_add(b),
+                    (0x20, 0) => a.wrapping_sub(b),
+                    (0, 1) => a << (b & 0x3f),
+                    (0, 2) => ((a as i64) < (b as i64)) as u64,
+                    (0, 3) => (a < b) as u64,
+                    (0, 4) => a ^ b,
+                    (0, 5) => a >> (b & 0x3f),
+                    (0x20, 5) => (a as i64 >> (b & 0x3f)) as u64,
+                    (0, 6) => a | b,
+                    (0, 7) => a & b,
+                    (1, 0) => a.wrapping_mul(b),
+                    (1, 1) => ((a as i64 as i128 * b as i64 as i128) >> 64) as u64,
+                    (1, 2) => ((a as i64 as i128 * b as i128) >> 64) as u64,
+                    (1, 3) => ((a as u128 * b as u128) >> 64) as u64,
+                    (1, 4) => match b {
+                        0 => u64::max_value(),
+                        _ => (a as i64).wrapping_div(b as i64) as u64,
+                    },
+                    (1, 5) => a.checked_div(b).unwrap_or(u64::max_value()),
+                    (1, 6) => match b {
+                        0 => a,
+                        _ => (a as i64).wrapping_rem(b as i64) as u64,
+                    },
+                    (1, 7) => a.checked_rem(b).unwrap_or(a),
+                    _ => return Err(illegal(raw)),
+                };
+                self.set_x(rd, val);
+            }
+            OP_32 => {
+                let (a, b) = (a as u32, b as u32);
+                let val = match (funct7, funct3) {
+                    (0, 0) => a.wrapping_add(b),
+                    (0x20, 0) => a.wrapping_sub(b),
+                    (0, 1) => a << (b & 0x1f),
+                    (0, 5) => a >> (b & 0x1f),
+                    (0x20, 5) => (a as i32 >> (b & 0x1f)) as u32,
+                    (1, 0) => a.wrapping_mul(b),
+                    (1, 4) => match b {
+                        0 => u32::max_value(),
+                        _ => (a as i32).wrapping_div(b as i32) as u32,
+                    },
+                    (1, 5) => a.checked_div(b).unwrap_or(u32::max_value()),
+                    (1, 6) => match b {
+                        0 => a,
+                        _ => (a as i32).wrapping_rem(b as i32) as u32,
+                    },
+                    (1, 7) => a.checked_rem(b).unwrap_or(a),
+                    _ => return Err(illegal(raw)),
+                };
+                self.set_x(rd, val as i32 as u64);
+            }
+            // fence and fence.i. Accesses are already ordered, and nothing caches instructions.
+            MISC_MEM if funct3 < 2 => {}
+            AMO => return self.execute_amo(bus, inst, raw, len),
+            SYSTEM => return self.execute_system(clock, inst, raw, len),
+            LOAD_FP | STORE_FP | MADD | MSUB | NMSUB | NMADD | OP_FP => {
+                return self.execute_fp(bus, inst, raw, len).map(|_| None)
+            }
+            _ => return Err(illegal(raw)),
+        }
+        self.pc = next_pc;
+        Ok(None)
+    }
+    fn execute_amo(
+        &mut self,
+        bus: &Bus,
+        inst: u32,
+        raw: u32,
+        len: u64,
+    ) -> Result<Option<VcpuExit>, Exception> {
+        let rd = inst >> 7 & 0x1f;
+        let funct3 = inst >> 12 & 7;
+        let rs2 = inst >> 20 & 0x1f;
+        let funct5 = inst >> 27;
+        let size = match funct3 {
+            2 => 4,
+            3 => 8,
+            _ => return Err(illegal(raw)),
+        };
+        let va = self.x[(inst >> 15 & 0x1f) as usize];
+        let b = self.x[rs2 as usize];
+        // Results and operands of word operations are sign-extended.
+        let extend = |val: u64| if size == 4 { val as i32 as u64 } else { val };
+        let (access, misaligned) = match funct5 {
+            2 if rs2 == 0 => (AccessType::Load, CAUSE_LOAD_MISALIGNED),
+            2 => return Err(illegal(raw)),
+            _ => (AccessType::Store, CAUSE_STORE_MISALIGNED),
+        };
+        if va % size != 0 {
+            return Err(Exception {
+                cause: misaligned,
+                tval: va,
+            });
+        }
+        let pa = self.translate(bus, va, access)?;
+        let fault = Exception {
+            cause: access.access_fault(),
+            tval: va,
+        };
+        let val = match funct5 {
+            // lr
+            2 => {
+                let val = bus.read(pa, size).ok_or(fault)?;
+                self.reservation = Some((pa, val));
+                extend(val)
+            }
+            // sc, which fails if the reserved value changed in the meantime.
+            3 => {
+                let succeeded = match self.reservation.take() {
+                    Some((reserved, val)) if reserved == pa => {
+                        bus.compare_exchange(pa, size, val, b).ok_or(fault)?
+                    }
+                    _ => {
+                        bus.read(pa, size).ok_or(fault)?;
+                        false
+                    }
+                };
+                !succeeded as u64
+            }
+            _ => {
+                let op = amo(funct5, size).ok_or_else(|| illegal(raw))?;
+                let old = bus.amo(pa, size, |old| op(old, b)).ok_or(fault)?;
+                self.reservation = None;
+                extend(old)
+            }
+        };
+        self.set_x(rd, val);
+        self.pc = self.pc.wrapping_add(len);
+        Ok(None)
+    }
+    fn execute_system(
+        &mut self,
+        clock: &dyn Clock,
+        inst: u32,
+        raw: u32,
+        len: u64,
+    ) -> Result<Option<VcpuExit>, Exception> {
+        let rd = inst >> 7 & 0x1f;
+        let funct3 = inst >> 12 & 7;
+        let rs1 = inst >> 15 & 0x1f;
+        let supervisor = self.mode == Mode::Supervisor;
+        let next_pc = self.pc.wrapping_add(len);
+        if funct3 == 0 {
+            match inst {
+                // The SBI. The guest resumes after the ecall, as with RVM.
+                ECALL if supervisor => {
+                    self.pc = next_pc;
+                    return Ok(Some(VcpuExit::Ecall));
+                }
+                ECALL => {
+                    return Err(Exception {
+                        cause: CAUSE_USER_ECALL,
+                        tval: 0,
+                    })
+                }
+                EBREAK => {
+                    return Err(Exception {
+                        cause: CAUSE_BREAKPOINT,
+                        tval: self.pc,
+                    })
+                }
+                SRET if supervisor => {
+                    self.mode = if self.sstatus & SSTATUS_SPP != 0 {
+                        Mode::Supervisor
+                    } else {
+                        Mode::User
+                    };
+                    let mut sstatus = self.sstatus & !(SSTATUS_SIE | SSTATUS_SPP);
+                    if self.sstatus & SSTATUS_SPIE != 0 {
+                        sstatus |= SSTATUS_SIE;
+                    }
+                    self.sstatus = sstatus | SSTATUS_SPIE;
+                    self.pc = self.sepc;
+                    return Ok(None);
+                }
+                // Give the VMM the chance to deliver interrupts while the guest idles.
+                WFI if supervisor => {
+                    self.pc = next_pc;
+                    if self.sip & self.sie == 0 {
+                        return Ok(Some(VcpuExit::Yield));
+                    }
+                    return Ok(None);
+                }
+                // sfence.vma
+                _ if inst >> 25 == 0x09 && rd == 0 && supervisor => {
+                    self.tlb.flush();
+                    self.pc = next_pc;
+                    return Ok(None);
+                }
+                _ => return Err(illegal(raw)),
+            }
+        }
+        let csr = inst >> 20;
+        let src = if funct3 & 4 != 0 {
+            rs1 as u64
+        } else {
+            self.x[rs1 as usize]
+        };
+        // csrrs and csrrc with x0 or a zero immediate only read.
+        let writes = funct3 & 3 == 1 || rs1 != 0;
+        // The interrupt file of the hart, if the board has one, is in the VMM.
+        if let CSR_SISELECT | CSR_SIREG | CSR_STOPEI = csr {
+            if !supervisor {
+                return Err(illegal(raw));
+            }
+            let write = match funct3 & 3 {
+                0 => return Err(illegal(raw)),
+                _ if !writes => None,
+                1 => Some(CsrWrite::Write(src)),
+                2 => Some(CsrWrite::Set(src)),
+                _ => Some(CsrWrite::Clear(src)),
+            };
+            return Ok(Some(VcpuExit::Csr(CsrExit {
+                csr,
+                write,
+                dst_reg: rd as usize,
+            })));
+        }
+        let old = self.read_csr(csr, clock).ok_or_else(|| illegal(raw))?;
+        if writes {
+            let new = match funct3 & 3 {
+                1 => src,
+                2 => old | src,
+                3 => old & !src,
+                _ => return Err(illegal(raw)),
+            };
+            // Counters are read-only.
+            if csr >> 10 == 3 || self.write_csr(csr, new).is_none() {
+                return Err(illegal(raw));
+            }
+        }
+        self.set_x(rd, old);
+        self.pc = next_pc;
+        Ok(None)
+    }
+    pub fn fp_enabled(&self) -> bool {
+        self.sstatus & SSTATUS_FS != 0
+    }
+    /// Mark the floating-point state dirty.
+    pub fn fp_dirty(&mut self) {
+        self.sstatus |= SSTATUS_FS;
+    }
+    fn read_csr(&self, csr: u32, clock: &dyn Clock) -> Option<u64> {
+        let level = csr >> 8 & 3;
+        if level > (self.mode == Mode::Supervisor) as u32 {
+            return None;
+        }
+        let val = match csr {
+            CSR_FFLAGS | CSR_FRM | CSR_FCSR if !self.fp_enabled() => return None,
+            CSR_FFLAGS => self.fflags,
+            CSR_FRM => self.frm,
+            CSR_FCSR => self.frm << 5 | self.fflags,
+            CSR_CYCLE..=CSR_HPMCOUNTER31 => {
+                if self.mode == Mode::User && self.scounteren & 1 << (csr - CSR_CYCLE) == 0 {
+                    return None;
+                }
+                match csr {
+                    CSR_CYCLE | CSR_INSTRET => self.instret,
+                    CSR_TIME => clock.now(),
+                    _ => 0,
+                }
+            }
+            CSR_SSTATUS => {
+                let dirty = if self.sstatus & SSTATUS_FS == SSTATUS_FS {
+                    SSTATUS_SD
+                } else {
+                    0
+                };
+                self.sstatus | SSTATUS_UXL_64 | dirty
+            }
+            CSR_SIE => self.sie,
+            CSR_STVEC => self.stvec,
+            CSR_SCOUNTEREN => self.scounteren,
+            CSR_SENVCFG => 0,
+            CSR_SSCRATCH => self.sscratch,
+            CSR_SEPC => self.sepc,
+            CSR_SCAUSE => self.scause,
+            CSR_STVAL => self.stval,
+            CSR_SIP => self.sip,
+            CSR_SATP => self.satp,
+            _ => return None,
+        };
+        Some(val)
+    }
+    fn write_csr(&mut self, csr: u32, val: u64) -> Option<()> {
+        match csr {
+            CSR_FFLAGS => self.fflags = val & 0x1f,
+            CSR_FRM => self.frm = val & 7,
+            CSR_FCSR => {
+                self.fflags = val & 0x1f;
+                self.frm = val >> 5 & 7;
+            }
+            CSR_SSTATUS => {
+                let old = self.sstatus;
+                self.sstatus = val & SSTATUS_WRITABLE;
+                if (old ^ self.sstatus) & (SSTATUS_SUM | SSTATUS_MXR) != 0 {
+                    self.tlb.flush();
+                }
+            }
+            CSR_SIE => self.sie = val & SIP_MASK,
+            // Direct and vectored mode.
+            CSR_STVEC => self.stvec = val & !2,
+            CSR_SCOUNTEREN => self.scounteren = val & 0xffff_ffff,
+            CSR_SENVCFG => {}
+            CSR_SSCRATCH => self.sscratch = val,
+            CSR_SEPC => self.sepc = val & !1,
+            CSR_SCAUSE => self.scause = val,
+            CSR_STVAL => self.stval = val,
+            CSR_SIP => {
+                let ssip = 1 << IRQ_S_SOFT;
+                self.sip = self.sip & !ssip | val & ssip;
+            }
+            // Writes of unsupported modes have no effect.
+            CSR_SATP => {
+                if let SATP_BARE | SATP_SV39 | SATP_SV48 | SATP_SV57 = val >> 60 {
+                    self.satp = val;
+                    self.tlb.flush();
+                }
+            }
+            _ => return None,
+        }
+        if let CSR_FFLAGS | CSR_FRM | CSR_FCSR = csr {
+            self.fp_dirty();
+        }
+        Some(())
+    }
+}
@@ -0,0 +1,574 @@
+use super::bus::Bus;
+use super::cpu::*;
+use crate::isa::*;
+use core::ops::{Add, Div, Mul, Neg, Sub};
+// The F and D extensions. Arithmetic rounds to nearest, ties to even, whatever the rounding
+// mode; fused multiply-adds round twice; and of the exception flags only NV, DZ and OF are
+// raised, with NX on conversions to integers. Conversions to integers honor the rounding mode.
+
+const FLAG_NV: u64 = 0x10;
+const FLAG_DZ: u64 = 0x08;
+const FLAG_OF: u64 = 0x04;
+const FLAG_NX: u64 = 0x01;
+
+const RM_RNE: u64 = 0;
+const RM_RTZ: u64 = 1;
+const RM_RDN: u64 = 2;
+const RM_RUP: u64 = 3;
+const RM_RMM: u64 = 4;
+const RM_DYN: u64 = 7;
+
+trait Float:
+    Copy
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    const BITS: u32;
+    const MANTISSA_BITS: u32;
+    const CANONICAL_NAN: u64;
+    fn to_bits64(self) -> u64;
+    fn from_bits64(bits: u64) -> Self;
+    fn get(hart: &Hart, reg: u32) -> Self;
+    fn set(hart: &mut Hart, reg: u32, val: Self);
+    fn sqrt(self) -> Self;
+    fn to_f64(self) -> f64;
+    fn from_i64(val: i64) -> Self;
+    fn from_u64(val: u64) -> Self;
+    fn canonical_nan() -> Self {
+        Self::from_bits64(Self::CANONICAL_NAN)
+    }
+    fn is_nan(self) -> bool {
+        self.partial_cmp(&self).is_none()
+    }
+    fn is_signaling(self) -> bool {
+        self.is_nan() && self.to_bits64() >> (Self::MANTISSA_BITS - 1) & 1 == 0
+    }
+    fn is_finite(self) -> bool {
+        let exponent = (1 << (Self::BITS - 1 - Self::MANTISSA_BITS)) - 1;
+        self.to_bits64() >> Self::MANTISSA_BITS & exponent != exponent
+    }
+    fn sign(self) -> bool {
+        self.to_bits64() >> (Self::BITS - 1) & 1 != 0
+    }
+}
+
+impl Float for f32 {
+    const BITS: u32 = 32;
+    const MANTISSA_BITS: u32 = 23;
+    const CANONICAL_NAN: u64 = 0x7fc0_0000;
+    fn to_bits64(self) -> u64 {
+        self.to_bits() as u64
+    }
+    fn from_bits64(bits: u64) -> Self {
+        f32::from_bits(bits as u32)
+    }
+    // Single-precision values are NaN-boxed. Anything else reads as the canonical NaN.
+    fn get(hart: &Hart, reg: u32) -> Self {
+        let val = hart.f[reg as usize];
+        if val >> 32 == 0xffff_ffff {
+            f32::from_bits(val as u32)
+        } else {
+            Self::canonical_nan()
+        }
+    }
+    fn set(hart: &mut Hart, reg: u32, val: Self) {
+        hart.f[reg as usize] = 0xffff_ffff_0000_0000 | val.to_bits() as u64;
+        hart.fp_dirty();
+    }
+    // Rounding the double-precision root again is exact, as doubles have more than twice the bits.
+    fn sqrt(self) -> Self {
+        sqrt_f64(self as f64) as f32
+    }
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+    fn from_i64(val: i64) -> Self {
+        val as f32
+    }
+    fn from_u64(val: u64) -> Self {
+        val as f32
+    }
+}
+
+impl Float for f64 {
+    const BITS: u32 = 64;
+    const MANTISSA_BITS: u32 = 52;
+    const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+    fn to_bits64(self) -> u64 {
+        self.to_bits()
+    }
+    fn from_bits64(bits: u64) -> Self {
+        f64::from_bits(bits)
+    }
+    fn get(hart: &Hart, reg: u32) -> Self {
+        f64::from_bits(hart.f[reg as usize])
+    }
+    fn set(hart: &mut Hart, reg: u32, val: Self) {
+        hart.f[reg as usize] = val.to_bits();
+        hart.fp_dirty();
+    }
+    fn sqrt(self) -> Self {
+        sqrt_f64(self)
+    }
+    fn to_f64(self) -> f64 {
+        self
+    }
+    fn from_i64(val: i64) -> Self {
+        val as f64
+    }
+    fn from_u64(val: u64) -> Self {
+        val as f64
+    }
+}
+
+// Integer square root, rounded down.
+fn isqrt(n: u128) -> u128 {
+    let mut rem = n;
+    let mut root = 0;
+    let mut bit = 1 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+/// Correctly rounded square root, as core has none without std.
+fn sqrt_f64(x: f64) -> f64 {
+    if x.is_nan() || x == 0.0 || x == f64::INFINITY {
+        return x;
+    }
+    if x < 0.0 {
+        return f64::NAN;
+    }
+    let bits = x.to_bits();
+    let mut mantissa = bits & ((1 << 52) - 1);
+    let mut exponent = (bits >> 52) as i64;
+    if exponent == 0 {
+        let shift = mantissa.leading_zeros() - 11;
+        mantissa <<= shift;
+        exponent = 1 - shift as i64;
+    } else {
+        mantissa |= 1 << 52;
+    }
+    // x = mantissa * 2^exponent, with an even exponent.
+    let mut exponent = exponent - 1075;
+    if exponent & 1 != 0 {
+        mantissa <<= 1;
+        exponent -= 1;
+    }
+    // The root of mantissa << 56 has 55 bits, two more than a double. A nonzero remainder goes
+    // into the lowest bit, so that converting rounds as the exact root would.
+    let n = (mantissa as u128) << 56;
+    let root = isqrt(n);
+    let root = root as u64 | (root * root != n) as u64;
+    let scale = f64::from_bits((((exponent - 56) / 2 + 1023) as u64) << 52);
+    root as f64 * scale
+}
+
+fn abs(x: f64) -> f64 {
+    f64::from_bits(x.to_bits() & !(1 << 63))
+}
+
+// Round to an integer in the rounding mode `rm`.
+fn round(x: f64, rm: u64) -> f64 {
+    if x.is_nan() || abs(x) >= (1u64 << 52) as f64 {
+        return x;
+    }
+    let truncated = x as i64 as f64;
+    let fraction = x - truncated;
+    let away = truncated + if x < 0.0 { -1.0 } else { 1.0 };
+    match rm {
+        RM_RTZ => truncated,
+        RM_RDN if fraction < 0.0 => truncated - 1.0,
+        RM_RUP if fraction > 0.0 => truncated + 1.0,
+        RM_RMM if abs(fraction) >= 0.5 => away,
+        RM_RNE if abs(fraction) > 0.5 => away,
+        RM_RNE if abs(fraction) >= 0.5 && truncated as i64 & 1 != 0 => away,
+        _ => truncated,
+    }
+}
+
+// Convert to a `bits`-wide integer as fcvt does, saturating, and with W results sign-extended.
+fn to_int(x: f64, rm: u64, signed: bool, bits: u32) -> (u64, u64) {
+    let (min, max) = match (signed, bits) {
+        (true, 32) => (i32::min_value() as u64, i32::max_value() as u64),
+        (true, _) => (i64::min_value() as u64, i64::max_value() as u64),
+        (false, 32) => (0, u32::max_value() as i32 as u64),
+        (false, _) => (0, u64::max_value()),
+    };
+    if x.is_nan() {
+        return (max, FLAG_NV);
+    }
+    let rounded = round(x, rm);
+    // 2^(bits - 1) or 2^bits, the first value out of range above.
+    let limit = 2.0 * (1u64 << (bits - 2 + !signed as u32)) as f64;
+    let lowest = if signed { -limit } else { 0.0 };
+    if rounded >= limit {
+        return (max, FLAG_NV);
+    }
+    if rounded < lowest {
+        return (min, FLAG_NV);
+    }
+    let val = if signed {
+        rounded as i64 as u64
+    } else {
+        rounded as u64
+    };
+    let val = if bits == 32 { val as i32 as u64 } else { val };
+    (val, if x - rounded != 0.0 { FLAG_NX } else { 0 })
+}
+
+fn classify<F: Float>(x: F) -> u64 {
+    let bits = x.to_bits64();
+    let exponent_mask = (1 << (F::BITS - 1 - F::MANTISSA_BITS)) - 1;
+    let exponent = bits >> F::MANTISSA_BITS & exponent_mask;
+    let mantissa = bits & ((1 << F::MANTISSA_BITS) - 1);
+    let negative = x.sign();
+    let class = match (exponent, mantissa) {
+        _ if x.is_signaling() => 8,
+        _ if x.is_nan() => 9,
+        (e, 0) if e == exponent_mask => 7,
+        (0, 0) => 4,
+        (0, _) => 5,
+        _ => 6,
+    };
+    // Negative classes mirror positive ones from 3 down.
+    if negative && class < 8 {
+        1 << (7 - class)
+    } else {
+        1 << class
+    }
+}
+
+impl Hart {
+    fn rounding_mode(&self, rm: u64) -> Option<u64> {
+        let rm = if rm == RM_DYN { self.frm } else { rm };
+        if rm <= RM_RMM {
+            Some(rm)
+        } else {
+            None
+        }
+    }
+    // Canonicalize NaN results, and raise the flags of computing `result` from `inputs`.
+    fn arith<F: Float>(&mut self, inputs: &[F], result: F) -> F {
+        if inputs.iter().any(|x| x.is_signaling()) {
+            self.fflags |= FLAG_NV;
+        }
+        if result.is_nan() {
+            if !inputs.iter().any(|x| x.is_nan()) {
+                self.fflags |= FLAG_NV;
+            }
+            return F::canonical_nan();
+        }
+        if !result.is_finite() && inputs.iter().all(|x| x.is_finite()) {
+            self.fflags |= FLAG_OF | FLAG_NX;
+        }
+        result
+    }
+    // OP-FP and fused multiply-adds in one format.
+    fn op_fp<F: Float>(&mut self, inst: u32, raw: u32) -> Result<(), Exception> {
+        let rd = inst >> 7 & 0x1f;
+        let funct3 = inst >> 12 & 7;
+        let rs2 = inst >> 20 & 0x1f;
+        let a = F::get(self, inst >> 15 & 0x1f);
+        let b = F::get(self, rs2);
+        let uses_rm = match inst & 0x7f {
+            OP_FP => match inst >> 27 {
+                0x00..=0x03 | 0x08 | 0x0b | 0x18 | 0x1a => true,
+                _ => false,
+            },
+            _ => true,
+        };
+        let rm = match self.rounding_mode(funct3 as u64) {
+            Some(rm) => rm,
+            None if uses_rm => return Err(illegal(raw)),
+            None => 0,
+        };
+        let opcode = inst & 0x7f;
+        if opcode != OP_FP {
+            let c = F::get(self, inst >> 27);
+            let product = a * b;
+            let product = if opcode == NMSUB || opcode == NMADD {
+                -product
+            } else {
+                product
+            };
+            let c = if opcode == MSUB || opcode == NMADD {
+                -c
+            } else {
+                c
+            };
+            let result = self.arith(&[a, b, c], product + c);
+            F::set(self, rd, result);
+            return Ok(());
+        }
+        match (inst >> 27, funct3, rs2) {
+            (0x00, _, _) => {
+                let result = self.arith(&[a, b], a + b);
+                F::set(self, rd, result);
+            }
+            (0x01, _, _) => {
+                let result = self.arith(&[a, b], a - b);
+                F::set(self, rd, result);
+            }
+            (0x02, _, _) => {
+                let result = self.arith(&[a, b], a * b);
+                F::set(self, rd, result);
+            }
+            (0x03, _, _) => {
+                let zero = F::from_i64(0);
+                if b == zero && a.is_finite() && !a.is_nan() && a != zero {
+                    self.fflags |= FLAG_DZ;
+                    F::set(self, rd, a / b);
+                } else {
+                    let result = self.arith(&[a, b], a / b);
+                    F::set(self, rd, result);
+                }
+            }
+            (0x0b, _, 0) => {
+                let result = self.arith(&[a], a.sqrt());
+                F::set(self, rd, result);
+            }
+            // Sign injection works on the bits, NaNs included.
+            (0x04, 0..=2, _) => {
+                let sign = 1 << (F::BITS - 1);
+                let b_sign = match funct3 {
+                    0 => b.to_bits64(),
+                    1 => !b.to_bits64(),
+                    _ => a.to_bits64() ^ b.to_bits64(),
+                } & sign;
+                F::set(self, rd, F::from_bits64(a.to_bits64() & !sign | b_sign));
+            }
+            (0x05, 0..=1, _) => {
+                if a.is_signaling() || b.is_signaling() {
+                    self.fflags |= FLAG_NV;
+                }
+                let min = funct3 == 0;
+                let result = match (a.is_nan(), b.is_nan()) {
+                    (true, true) => F::canonical_nan(),
+                    (true, false) => b,
+                    (false, true) => a,
+                    // -0 is less than +0.
+                    _ if a == b => {
+                        if a.sign() == min {
+                            a
+                        } else {
+                            b
+                        }
+                    }
+                    _ if (a < b) == min => a,
+                    _ => b,
+                };
+                F::set(self, rd, result);
+            }
+            (0x14, 0..=2, _) => {
+                let quiet = funct3 == 2;
+                if a.is_signaling() || b.is_signaling() || (!quiet && (a.is_nan() || b.is_nan())) {
+                    self.fflags |= FLAG_NV;
+                }
+                let result = match funct3 {
+                    0 => a <= b,
+                    1 => a < b,
+                    _ => a == b,
+                };
+                self.set_x(rd, result as u64);
+            }
+            (0x18, _, 0..=3) => {
+                let (val, flags) = to_int(a.to_f64(), rm, rs2 & 1 == 0, 32 << (rs2 >> 1));
+                self.fflags |= flags;
+                self.set_x(rd, val);
+            }
+            (0x1a, _, 0..=3) => {
+                let src = self.x[(inst >> 15 & 0x1f) as usize];
+                let val = match rs2 {
+                    0 => F::from_i64(src as i32 as i64),
+                    1 => F::from_u64(src as u32 as u64),
+                    2 => F::from_i64(src as i64),
+                    _ => F::from_u64(src),
+                };
+                F::set(self, rd, val);
+            }
+            (0x1c, 0, 0) => {
+                let bits = self.f[(inst >> 15 & 0x1f) as usize];
+                self.set_x(
+                    rd,
+                    if F::BITS == 32 {
+                        bits as i32 as u64
+                    } else {
+                        bits
+                    },
+                );
+            }
+            (0x1c, 1, 0) => self.set_x(rd, classify(a)),
+            (0x1e, 0, 0) => {
+                let src = self.x[(inst >> 15 & 0x1f) as usize];
+                F::set(self, rd, F::from_bits64(src));
+            }
+            _ => return Err(illegal(raw)),
+        }
+        Ok(())
+    }
+    /// Run a floating-point instruction. They are illegal while sstatus.FS is off.
+    pub fn execute_fp(
+        &mut self,
+        bus: &Bus,
+        inst: u32,
+        raw: u32,
+        len: u64,
+    ) -> Result<(), Exception> {
+        if !self.fp_enabled() {
+            return Err(illegal(raw));
+        }
+        let rd = inst >> 7 & 0x1f;
+        let funct3 = inst >> 12 & 7;
+        let base = self.x[(inst >> 15 & 0x1f) as usize];
+        match inst & 0x7f {
+            LOAD_FP => {
+                let size = match funct3 {
+                    2 => 4,
+                    3 => 8,
+                    _ => return Err(illegal(raw)),
+                };
+                let va = base.wrapping_add(imm_i(inst) as u64);
+                let val = match self.load(bus, va, size)? {
+                    Mem::Ram(val) => val,
+                    // Floating-point registers are no place for device registers.
+                    Mem::Mmio(_) => {
+                        return Err(Exception {
+                            cause: CAUSE_LOAD_ACCESS,
+                            tval: va,
+                        })
+                    }
+                };
+                self.f[rd as usize] = if size == 4 {
+                    0xffff_ffff_0000_0000 | val
+                } else {
+                    val
+                };
+                self.fp_dirty();
+            }
+            STORE_FP => {
+                let size = match funct3 {
+                    2 => 4,
+                    3 => 8,
+                    _ => return Err(illegal(raw)),
+                };
+                let va = base.wrapping_add(imm_s(inst) as u64);
+                let val = self.f[(inst >> 20 & 0x1f) as usize];
+                if self.store(bus, va, size, val)?.is_some() {
+                    return Err(Exception {
+                        cause: CAUSE_STORE_ACCESS,
+                        tval: va,
+                    });
+                }
+            }
+            opcode => {
+                let rs2 = inst >> 20 & 0x1f;
+                match (inst >> 27, inst >> 25 & 3) {
+                    // Conversions between the formats.
+                    (0x08, 0) if opcode == OP_FP && rs2 == 1 => {
+                        self.rounding_mode(funct3 as u64)
+                            .ok_or_else(|| illegal(raw))?;
+                        let a = f64::get(self, inst >> 15 & 0x1f);
+                        let result = if a.is_nan() {
+                            self.arith(&[a], a);
+                            f32::canonical_nan()
+                        } else {
+                            let result = a as f32;
+                            if !Float::is_finite(result) && Float::is_finite(a) {
+                                self.fflags |= FLAG_OF | FLAG_NX;
+                            }
+                            result
+                        };
+                        f32::set(self, rd, result);
+                    }
+                    (0x08, 1) if opcode == OP_FP && rs2 == 0 => {
+                        self.rounding_mode(funct3 as u64)
+                            .ok_or_else(|| illegal(raw))?;
+                        let a = f32::get(self, inst >> 15 & 0x1f);
+                        let result = self.arith(&[a], a) as f64;
+                        f64::set(self, rd, result);
+                    }
+                    (0x08, _) if opcode == OP_FP => return Err(illegal(raw)),
+                    (_, 0) => self.op_fp::<f32>(inst, raw)?,
+                    (_, 1) => self.op_fp::<f64>(inst, raw)?,
+                    _ => return Err(illegal(raw)),
+                }
+            }
+        }
+        self.pc = self.pc.wrapping_add(len);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn sqrt() {
+        for &x in [
+            2.0,
+            0.25,
+            1e300,
+            3e-320,
+            5e-324,
+            1.0 - f64::EPSILON,
+            123456789.0,
+        ]
+        .iter()
+        {
+            let root = sqrt_f64(x);
+            assert_eq!(root.to_bits(), x.sqrt().to_bits(), "sqrt({})", x);
+        }
+        assert!(sqrt_f64(-1.0).is_nan());
+        assert_eq!(sqrt_f64(-0.0).to_bits(), (-0.0f64).to_bits());
+    }
+    #[test]
+    fn conversions() {
+        assert_eq!(to_int(2.5, RM_RNE, true, 32), (2, FLAG_NX));
+        assert_eq!(to_int(3.5, RM_RNE, true, 32), (4, FLAG_NX));
+        assert_eq!(to_int(-2.5, RM_RMM, true, 64), (-3i64 as u64, FLAG_NX));
+        assert_eq!(to_int(-2.5, RM_RDN, true, 64), (-3i64 as u64, FLAG_NX));
+        assert_eq!(to_int(-2.5, RM_RUP, true, 64), (-2i64 as u64, FLAG_NX));
+        assert_eq!(to_int(-2.5, RM_RTZ, false, 64), (0, FLAG_NV));
+        assert_eq!(
+            to_int(4e9, RM_RTZ, false, 32),
+            (4e9 as u32 as i32 as u64, 0)
+        );
+        assert_eq!(
+            to_int(3e9, RM_RTZ, true, 32),
+            (i32::max_value() as u64, FLAG_NV)
+        );
+        assert_eq!(
+            to_int(f64::NAN, RM_RTZ, true, 64),
+            (i64::max_value() as u64, FLAG_NV)
+        );
+        assert_eq!(
+            to_int(-1e30, RM_RTZ, true, 64),
+            (i64::min_value() as u64, FLAG_NV)
+        );
+        assert_eq!(
+            to_int(1.8e19, RM_RTZ, false, 64),
+            (18_000_000_000_000_000_000, 0)
+        );
+        assert_eq!(classify(-0.0f32), 1 << 3);
+        assert_eq!(classify(f64::NEG_INFINITY), 1);
+        assert_eq!(classify(1e-310), 1 << 5);
+        assert_eq!(classify(f32::NAN), 1 << 9);
+        assert_eq!(f64::canonical_nan().to_bits(), 0x7ff8_0000_0000_0000);
+        assert_eq!(f32::canonical_nan().to_bits(), 0x7fc0_0000);
+    }
+}
@@ -0,0 +1,159 @@
+use super::bus::Bus;
+use super::cpu::*;
+// Sv39, Sv48 and Sv57 address translation, with a small TLB per kind of access.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    Fetch = 0,
+    Load = 1,
+    Store = 2,
+}
+
+impl AccessType {
+    fn page_fault(self) -> u64 {
+        match self {
+            AccessType::Fetch => CAUSE_FETCH_PAGE_FAULT,
+            AccessType::Load => CAUSE_LOAD_PAGE_FAULT,
+            AccessType::Store => CAUSE_STORE_PAGE_FAULT,
+        }
+    }
+    pub fn access_fault(self) -> u64 {
+        match self {
+            AccessType::Fetch => CAUSE_FETCH_ACCESS,
+            AccessType::Load => CAUSE_LOAD_ACCESS,
+            AccessType::Store => CAUSE_STORE_ACCESS,
+        }
+    }
+}
+
+const PTE_V: u64 = 1;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PPN_MASK: u64 = (1 << 44) - 1;
+
+pub const SATP_BARE: u64 = 0;
+pub const SATP_SV39: u64 = 8;
+pub const SATP_SV48: u64 = 9;
+pub const SATP_SV57: u64 = 10;
+
+const TLB_SIZE: usize = 256;
+// Tags are the virtual page number and whether the access is from U-mode.
+const TLB_INVALID: u64 = u64::max_value();
+
+#[derive(Copy, Clone)]
+struct TlbEntry {
+    tag: u64,
+    ppn: u64,
+}
+
+/// Translations of 4 KiB pages, as permitted by the page tables when they were walked.
+/// Flushed on sfence.vma and whenever satp, SUM or MXR change.
+pub struct Tlb {
+    entries: [[TlbEntry; TLB_SIZE]; 3],
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Tlb {
+            entries: [[TlbEntry {
+                tag: TLB_INVALID,
+                ppn: 0,
+            }; TLB_SIZE]; 3],
+        }
+    }
+    pub fn flush(&mut self) {
+        for entry in self
+            .entries
+            .iter_mut()
+            .flat_map(|entries| entries.iter_mut())
+        {
+            entry.tag = TLB_INVALID;
+        }
+    }
+}
+
+impl Hart {
+    /// Guest physical address of `va`, or the page or access fault of accessing it.
+    pub fn translate(&mut self, bus: &Bus, va: u64, access: AccessType) -> Result<u64, Exception> {
+        let levels = match self.satp >> 60 {
+            SATP_SV39 => 3,
+            SATP_SV48 => 4,
+            SATP_SV57 => 5,
+            _ => return Ok(va),
+        };
+        let tag = (va >> 12) << 1 | (self.mode == Mode::User) as u64;
+        let slot = (va >> 12) as usize % TLB_SIZE;
+        let entry = self.tlb.entries[access as usize][slot];
+        if entry.tag == tag {
+            return Ok(entry.ppn << 12 | va & 0xfff);
+        }
+        let ppn = self.walk(bus, va, access, levels)?;
+        self.tlb.entries[access as usize][slot] = TlbEntry { tag, ppn };
+        Ok(ppn << 12 | va & 0xfff)
+    }
+    // Physical page number of the 4 KiB page of `va`.
+    fn walk(&self, bus: &Bus, va: u64, access: AccessType, levels: u32) -> Result<u64, Exception> {
+        let fault = Exception {
+            cause: access.page_fault(),
+            tval: va,
+        };
+        // The bits above the virtual address must all equal its top bit.
+        let unused = 64 - (12 + 9 * levels);
+        if ((va << unused) as i64 >> unused) as u64 != va {
+            return Err(fault);
+        }
+        let mut table = (self.satp & PPN_MASK) << 12;
+        for level in (0..levels).rev() {
+            let pte_addr = table + (va >> (12 + 9 * level) & 0x1ff) * 8;
+            let pte = bus.read(pte_addr, 8).ok_or(Exception {
+                cause: access.access_fault(),
+                tval: va,
+            })?;
+            // Reserved bits, and the Svpbmt and Svnapot bits, which the guest is not told about.
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte >> 54 != 0 {
+                return Err(fault);
+            }
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = (pte >> 10 & PPN_MASK) << 12;
+                continue;
+            }
+            let allowed = match access {
+                AccessType::Fetch => pte & PTE_X != 0,
+                AccessType::Load => {
+                    pte & PTE_R != 0 || (self.sstatus & SSTATUS_MXR != 0 && pte & PTE_X != 0)
+                }
+                AccessType::Store => pte & PTE_W != 0,
+            };
+            let privileged = match self.mode {
+                Mode::User => pte & PTE_U != 0,
+                Mode::Supervisor => {
+                    pte & PTE_U == 0
+                        || (access != AccessType::Fetch && self.sstatus & SSTATUS_SUM != 0)
+                }
+            };
+            let ppn = pte >> 10 & PPN_MASK;
+            let offset_mask = (1 << (9 * level)) - 1;
+            if !allowed || !privileged || ppn & offset_mask != 0 {
+                return Err(fault);
+            }
+            // The A and D bits are kept up to date as hardware does, rather than faulting.
+            let updated = pte
+                | PTE_A
+                | if access == AccessType::Store {
+                    PTE_D
+                } else {
+                    0
+                };
+            if updated != pte && bus.compare_exchange(pte_addr, 8, pte, updated) != Some(true) {
+                // Another hart changed the entry in the meantime.
+                return self.walk(bus, va, access, levels);
+            }
+            return Ok(ppn | (va >> 12) & offset_mask);
+        }
+        Err(fault)
+    }
+}
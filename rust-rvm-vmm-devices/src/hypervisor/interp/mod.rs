@@ -0,0 +1,557 @@
+use super::{Error, Hypervisor, MemoryRegion, Result, Vcpu, VcpuExit, VcpuState};
+use crate::board::{IRQ_S_EXT, IRQ_S_SOFT, IRQ_S_TIMER};
+use crate::timer::Clock;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+mod bus;
+mod cpu;
+mod fpu;
+mod mmu;
+use bus::{Bus, Region};
+use cpu::Hart;
+// A RISC-V interpreter standing in for RVM where there is none, e.g. on the machines of
+// developers and in CI. Guests run in S and U mode on RV64GC harts with Sv39, Sv48 and Sv57,
+// and exit to the VMM where they would with RVM: for SBI calls, for loads and stores outside
+// RAM, and every so often so that the VMM can deliver interrupts. They also exit for the AIA
+// CSRs, as the VMM keeps the IMSIC interrupt files of the harts.
+
+/// Instructions run by `resume` before the vCPU yields to the VMM.
+const SLICE: usize = 10_000;
+
+pub struct Interpreter {
+    clock: Arc<dyn Clock>,
+    regions: Arc<RwLock<Vec<Region>>>,
+}
+
+impl Interpreter {
+    /// The guest reads its `time` CSR from `clock`, which should be the clock of its CLINT.
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Interpreter {
+            clock,
+            regions: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+}
+
+impl Hypervisor for Interpreter {
+    fn add_memory_region(&self, gpa: u64, len: usize) -> Result<MemoryRegion> {
+        let mut regions = self.regions.write();
+        let overlaps = regions
+            .iter()
+            .any(|region| gpa < region.gpa + region.len as u64 && region.gpa < gpa + len as u64);
+        if gpa % 4096 != 0 || len == 0 || overlaps {
+            return Err(Error::AddMemoryRegionError(-1));
+        }
+        // Allocated as doublewords, so that aligned guest accesses are aligned on the host too.
+        let words = Box::leak(alloc::vec![0u64; (len + 7) / 8].into_boxed_slice());
+        let data = unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, len) };
+        regions.push(Region {
+            gpa,
+            ptr: data.as_mut_ptr(),
+            len,
+        });
+        Ok(MemoryRegion { gpa, data })
+    }
+    fn create_vcpu(&self, entry: u64) -> Result<Box<dyn Vcpu>> {
+        Ok(Box::new(InterpVcpu {
+            hart: Box::new(Hart::new(entry)),
+            clock: Arc::clone(&self.clock),
+            regions: Arc::clone(&self.regions),
+        }))
+    }
+}
+
+struct InterpVcpu {
+    hart: Box<Hart>,
+    clock: Arc<dyn Clock>,
+    regions: Arc<RwLock<Vec<Region>>>,
+}
+
+impl Vcpu for InterpVcpu {
+    fn resume(&mut self) -> Result<VcpuExit> {
+        let regions = self.regions.read();
+        let bus = Bus::new(&regions);
+        let hart = &mut self.hart;
+        for _ in 0..SLICE {
+            if let Some(irq) = hart.pending_interrupt() {
+                hart.trap(true, irq, 0);
+            }
+            match hart.step(&bus, &*self.clock) {
+                Ok(None) => {}
+                Ok(Some(exit)) => return Ok(exit),
+                Err(exception) => hart.trap(false, exception.cause, exception.tval),
+            }
+        }
+        Ok(VcpuExit::Yield)
+    }
+    fn read_state(&mut self) -> Result<VcpuState> {
+        let mut state = VcpuState::default();
+        for (x, &val) in state.x.iter_mut().zip(self.hart.x.iter()) {
+            *x = val as usize;
+        }
+        state.pc = self.hart.pc as usize;
+        Ok(state)
+    }
+    fn write_state(&mut self, state: &VcpuState) -> Result<()> {
+        for (x, &val) in self.hart.x.iter_mut().zip(state.x.iter()).skip(1) {
+            *x = val as u64;
+        }
+        self.hart.pc = state.pc as u64;
+        Ok(())
+    }
+    fn set_interrupt(&mut self, irq: usize, level: bool) -> Result<()> {
+        match irq {
+            IRQ_S_SOFT | IRQ_S_TIMER | IRQ_S_EXT => {}
+            _ => return Err(Error::SendInterruptError(-1)),
+        }
+        if level {
+            self.hart.sip |= 1 << irq;
+        } else {
+            self.hart.sip &= !(1 << irq);
+        }
+        Ok(())
+    }
+    fn inject_exception(&mut self, cause: u64, tval: u64) -> Result<()> {
+        self.hart.trap(false, cause, tval);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::board::TIMEBASE_FREQUENCY;
+    use crate::hypervisor::MmioExit;
+    use crate::isa::*;
+    use crate::timer::ManualClock;
+    use crate::vcpu::*;
+    const RAM: u64 = 0x8000_0000;
+    // Registers by ABI name.
+    const T0: u32 = 5;
+    const T1: u32 = 6;
+    const T2: u32 = 7;
+    const A0: u32 = 10;
+    const A1: u32 = 11;
+    const A2: u32 = 12;
+    struct Asm(Vec<u8>);
+    impl Asm {
+        fn i(&mut self, inst: u32) -> &mut Self {
+            self.0.extend_from_slice(&inst.to_le_bytes());
+            self
+        }
+        fn c(&mut self, inst: u16) -> &mut Self {
+            self.0.extend_from_slice(&inst.to_le_bytes());
+            self
+        }
+    }
+    fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+        i_type(imm, rs1, 0, rd, OP_IMM)
+    }
+    fn csrrw(rd: u32, csr: u32, rs1: u32) -> u32 {
+        i_type(csr as i32, rs1, 1, rd, SYSTEM)
+    }
+    fn csrrs(rd: u32, csr: u32, rs1: u32) -> u32 {
+        i_type(csr as i32, rs1, 2, rd, SYSTEM)
+    }
+    fn load(funct3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+        i_type(imm, rs1, funct3, rd, LOAD)
+    }
+    fn store(funct3: u32, rs2: u32, rs1: u32, imm: i32) -> u32 {
+        s_type(imm, rs2, rs1, funct3, STORE)
+    }
+    fn amo(funct5: u32, rd: u32, rs1: u32, rs2: u32, funct3: u32) -> u32 {
+        r_type(funct5 << 2, rs2, rs1, funct3, rd, AMO)
+    }
+    // A vCPU at the start of RAM running `code`, and the RAM.
+    fn run(code: &Asm) -> (Box<dyn Vcpu>, &'static mut [u8]) {
+        let interpreter = Interpreter::new(Arc::new(ManualClock::new(TIMEBASE_FREQUENCY)));
+        let ram = interpreter.add_memory_region(RAM, 0x10_0000).unwrap();
+        ram.data[..code.0.len()].copy_from_slice(&code.0);
+        (interpreter.create_vcpu(RAM).unwrap(), ram.data)
+    }
+    fn set_regs(vcpu: &mut dyn Vcpu, regs: &[(u32, u64)]) {
+        let mut state = vcpu.read_state().unwrap();
+        for &(reg, val) in regs {
+            state.set_reg(reg as usize, val as usize);
+        }
+        vcpu.write_state(&state).unwrap();
+    }
+    fn reg(vcpu: &mut dyn Vcpu, reg: u32) -> u64 {
+        vcpu.read_state().unwrap().reg(reg as usize) as u64
+    }
+    #[test]
+    fn integer() {
+        let (mut vcpu, _) = run(Asm(Vec::new())
+            .i(addi(A0, 0, -7))
+            .i(addi(A1, 0, 3))
+            .i(r_type(1, A1, A0, 4, A2, OP))
+            // rem a3, a0, a1
+            .i(r_type(1, A1, A0, 6, 13, OP))
+            // divu by zero, and mulh
+            .i(r_type(1, 0, A0, 5, 14, OP))
+            .i(r_type(1, A0, A0, 1, 15, OP))
+            // addiw wraps: lui t0, 0x80000; addiw t0, t0, -1
+            .i(u_type(0x8000_0000u32 as i32, T0, LUI))
+            .i(i_type(-1, T0, 0, T0, OP_IMM_32))
+            // c.addi a0, 1; c.slli a1, 4
+            .c(0x0505)
+            .c(0x0592)
+            .i(ECALL));
+        assert_eq!(vcpu.resume().unwrap(), VcpuExit::Ecall);
+        let state = vcpu.read_state().unwrap();
+        assert_eq!(
+            state.pc as u64,
+            RAM + 4 * 9 + 2 * 2,
+            "Resumes after the ecall."
+        );
+        assert_eq!(state.x[A2 as usize] as i64, -2);
+        assert_eq!(state.x[13] as i64, -1);
+        assert_eq!(state.x[14], usize::max_value());
+        assert_eq!(state.x[15], 0);
+        assert_eq!(state.x[T0 as usize], 0x7fff_ffff);
+        assert_eq!(state.x[A0 as usize] as i64, -6);
+        assert_eq!(state.x[A1 as usize], 48);
+    }
+    #[test]
+    fn mmio() {
+        let (mut vcpu, ram) = run(Asm(Vec::new())
+            .i(store(0, A1, A0, 5))
+            .i(load(1, T0, A0, 2))
+            // Misaligned RAM accesses just work.
+            .i(store(3, A1, T2, 0x101))
+            .i(load(3, T1, T2, 0x101))
+            .i(ECALL));
+        set_regs(
+            &mut *vcpu,
+            &[(A0, 0x1000_0000), (A1, 0x1234_5678_9abc_def0), (T2, RAM)],
+        );
+        assert_eq!(
+            vcpu.resume().unwrap(),
+            VcpuExit::Mmio(MmioExit {
+                addr: 0x1000_0005,
+                size: 1,
+                read: false,
+                data: 0xf0,
+                dst_reg: 0,
+                sign_extend: false,
+                inst_len: 4,
+            })
+        );
+        // What the VMM does: step over the store.
+        let mut state = vcpu.read_state().unwrap();
+        state.pc += 4;
+        vcpu.write_state(&state).unwrap();
+        let exit = vcpu.resume().unwrap();
+        assert_eq!(
+            exit,
+            VcpuExit::Mmio(MmioExit {
+                addr: 0x1000_0002,
+                size: 2,
+                read: true,
+                data: 0,
+                dst_reg: T0 as usize,
+                sign_extend: true,
+                inst_len: 4,
+            })
+        );
+        let mut state = vcpu.read_state().unwrap();
+        state.pc += 4;
+        vcpu.write_state(&state).unwrap();
+        assert_eq!(vcpu.resume().unwrap(), VcpuExit::Ecall);
+        assert_eq!(reg(&mut *vcpu, T1), 0x1234_5678_9abc_def0);
+        assert_eq!(ram[0x101], 0xf0);
+    }
+    #[test]
+    fn aia_csrs() {
+        use crate::hypervisor::{CsrExit, CsrWrite, CSR_SISELECT, CSR_STOPEI};
+        let (mut vcpu, _) = run(Asm(Vec::new())
+            .i(csrrw(T0, CSR_SISELECT, A0))
+            .i(csrrs(T1, CSR_STOPEI, 0))
+            .i(ECALL));
+        set_regs(&mut *vcpu, &[(A0, 0x70)]);
+        assert_eq!(
+            vcpu.resume().unwrap(),
+            VcpuExit::Csr(CsrExit {
+                csr: CSR_SISELECT,
+                write: Some(CsrWrite::Write(0x70)),
+                dst_reg: T0 as usize,
+            })
+        );
+        assert_eq!(
+            vcpu.read_state().unwrap().pc as u64,
+            RAM,
+            "Left to the VMM."
+        );
+        let mut state = vcpu.read_state().unwrap();
+        state.pc += 4;
+        vcpu.write_state(&state).unwrap();
+        assert_eq!(
+            vcpu.resume().unwrap(),
+            VcpuExit::Csr(CsrExit {
+                csr: CSR_STOPEI,
+                write: None,
+                dst_reg: T1 as usize,
+            })
+        );
+    }
+    #[test]
+    fn traps() {
+        let (mut vcpu, _) = run(Asm(Vec::new())
+            // stvec = a0, sepc = a1. SPP is clear, so sret goes to U-mode.
+            .i(csrrw(0, 0x105, A0))
+            .i(csrrw(0, 0x141, A1))
+            .i(SRET)
+            .i(0)
+            // 0x10: U-mode may not read sstatus, and its ecalls go to S-mode.
+            .i(csrrs(T0, 0x100, 0))
+            .i(ECALL)
+            .i(0)
+            .i(0)
+            // 0x20: the handler returns after the trapping instruction.
+            .i(csrrs(T0, 0x142, 0))
+            .i(csrrs(T1, 0x143, 0))
+            .i(csrrs(T2, 0x100, 0))
+            .i(ECALL)
+            .i(csrrs(T2, 0x141, 0))
+            .i(addi(T2, T2, 4))
+            .i(csrrw(0, 0x141, T2))
+            .i(SRET));
+        set_regs(&mut *vcpu, &[(A0, RAM + 0x20), (A1, RAM + 0x10)]);
+        assert_eq!(vcpu.resume().unwrap(), VcpuExit::Ecall);
+        assert_eq!(reg(&mut *vcpu, T0), cpu::CAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(reg(&mut *vcpu, T1), csrrs(T0, 0x100, 0) as u64);
+        assert_eq!(reg(&mut *vcpu, T2) & cpu::SSTATUS_SPP, 0);
+        assert_eq!(vcpu.resume().unwrap(), VcpuExit::Ecall);
+        assert_eq!(reg(&mut *vcpu, T0), cpu::CAUSE_USER_ECALL);
+    }
+    #[test]
+    fn interrupts() {
+        let (mut vcpu, ram) = run(Asm(Vec::new())
+            // Vectored stvec, STIE, SIE.
+            .i(csrrw(0, 0x105, A0))
+            .i(csrrw(0, 0x104, A1))
+            .i(i_type(0x100, 2, 6, 0, SYSTEM))
+            .i(WFI)
+            .i(j_type(-4, 0, JAL)));
+        let timer = Asm(Vec::new())
+            .i(csrrs(T0, 0x142, 0))
+            .i(csrrs(T1, 0x141, 0))
+            .i(csrrs(T2, 0x100, 0))
+            .i(ECALL)
+            .0
+            .clone();
+        ram[0x114..0x114 + timer.len()].copy_from_slice(&timer);
+        set_regs(
+            &mut *vcpu,
+            &[(A0, (RAM + 0x100) | 1), (A1, 1 << IRQ_S_TIMER)],
+        );
+        assert_eq!(vcpu.resume().unwrap(), VcpuExit::Yield, "wfi yields.");
+        vcpu.set_interrupt(IRQ_S_EXT, true).unwrap();
+        vcpu.set_interrupt(IRQ_S_EXT, false).unwrap();
+        vcpu.set_interrupt(IRQ_S_TIMER, true).unwrap();
+        assert_eq!(vcpu.resume().unwrap(), VcpuExit::Ecall);
+        assert_eq!(reg(&mut *vcpu, T0), 1 << 63 | IRQ_S_TIMER as u64);
+        assert_eq!(reg(&mut *vcpu, T1), RAM + 0x10);
+        let sstatus = reg(&mut *vcpu, T2);
+        assert_eq!(
+            sstatus & (cpu::SSTATUS_SIE | cpu::SSTATUS_SPIE | cpu::SSTATUS_SPP),
+            cpu::SSTATUS_SPIE | cpu::SSTATUS_SPP
+        );
+        assert!(match vcpu.set_interrupt(3, true) {
+            Err(Error::SendInterruptError(_)) => true,
+            _ => false,
+        });
+    }
+    #[test]
+    fn paging() {
+        let (mut vcpu, ram) = run(Asm(Vec::new())
+            .i(csrrw(0, 0x105, A0))
+            .i(csrrw(0, 0x180, A1))
+            .i(load(3, T0, A2, 0))
+            .i(store(3, T0, A2, 8)));
+        let handler = Asm(Vec::new())
+            .i(csrrs(T1, 0x142, 0))
+            .i(csrrs(T2, 0x143, 0))
+            .i(ECALL)
+            .0
+            .clone();
+        ram[0x80..0x80 + handler.len()].copy_from_slice(&handler);
+        let mut pte = |table: usize, index: usize, pte: u64| {
+            let at = table + index * 8;
+            ram[at..at + 8].copy_from_slice(&pte.to_le_bytes());
+        };
+        // RAM is mapped as it is by a gigapage, and the top page of the address space to the
+        // read-only page at 0x5000 through two more tables.
+        pte(0x1000, 2, (RAM >> 12) << 10 | 0xcf);
+        pte(0x1000, 0x1ff, ((RAM + 0x2000) >> 12) << 10 | 1);
+        pte(0x2000, 0, ((RAM + 0x3000) >> 12) << 10 | 1);
+        pte(0x3000, 0, ((RAM + 0x5000) >> 12) << 10 | 3);
+        ram[0x5000..0x5008].copy_from_slice(&0x1122_3344_5566_7788u64.to_le_bytes());
+        let va = 0xffff_ffff_c000_0000u64;
+        set_regs(
+            &mut *vcpu,
+            &[
+                (A0, RAM + 0x80),
+                (A1, 8 << 60 | (RAM + 0x1000) >> 12),
+                (A2, va),
+            ],
+        );
+        assert_eq!(vcpu.resume().unwrap(), VcpuExit::Ecall);
+        assert_eq!(reg(&mut *vcpu, T0), 0x1122_3344_5566_7788);
+        assert_eq!(reg(&mut *vcpu, T1), cpu::CAUSE_STORE_PAGE_FAULT);
+        assert_eq!(reg(&mut *vcpu, T2), va + 8);
+        assert_eq!(ram[0x3000], 0x43, "Accessed, and not dirty.");
+    }
+    #[test]
+    fn atomics() {
+        let (mut vcpu, ram) = run(Asm(Vec::new())
+            .i(amo(0x00, T0, A0, A1, 2))
+            .i(amo(0x02, T1, A2, 0, 3))
+            .i(amo(0x03, T2, A2, A1, 3))
+            .i(amo(0x03, 13, A2, A1, 3))
+            .i(amo(0x14, 14, A0, 15, 2))
+            .i(amo(0x1c, 16, A0, 15, 2))
+            .i(amo(0x01, 17, A0, 0, 2))
+            .i(ECALL));
+        ram[0x100..0x104].copy_from_slice(&5u32.to_le_bytes());
+        ram[0x108..0x110].copy_from_slice(&(-3i64).to_le_bytes());
+        set_regs(
+            &mut *vcpu,
+            &[
+                (A0, RAM + 0x100),
+                (A1, 10),
+                (A2, RAM + 0x108),
+                (15, u64::max_value()),
+            ],
+        );
+        assert_eq!(vcpu.resume().unwrap(), VcpuExit::Ecall);
+        let state = vcpu.read_state().unwrap();
+        // amoadd.w, then lr.d and a successful sc.d, and an sc.d without a reservation.
+        assert_eq!(state.x[T0 as usize], 5);
+        assert_eq!(state.x[T1 as usize] as i64, -3);
+        assert_eq!(state.x[T2 as usize], 0);
+        assert_eq!(state.x[13], 1);
+        assert_eq!(ram[0x108..0x110], 10u64.to_le_bytes());
+        // amomax.w compares signed words and amomaxu.w unsigned ones. amoswap.w sign-extends.
+        assert_eq!(state.x[14], 15);
+        assert_eq!(state.x[16], 15);
+        assert_eq!(state.x[17] as i64, -1);
+        assert_eq!(ram[0x100..0x104], [0; 4]);
+    }
+    #[test]
+    fn floating_point() {
+        let (mut vcpu, ram) = run(Asm(Vec::new())
+            .i(csrrs(0, 0x100, A0))
+            .i(r_type(0x69, 2, A1, 7, 1, OP_FP))
+            .i(r_type(0x2d, 0, 1, 7, 2, OP_FP))
+            .i(r_type(0x09, 2, 2, 7, 3, OP_FP))
+            .i(r_type(0x61, 2, 3, 1, A2, OP_FP))
+            .i(r_type(0x20, 1, 2, 7, 4, OP_FP))
+            .i(s_type(0, 4, 13, 2, STORE_FP))
+            .i(i_type(0, 13, 2, 5, LOAD_FP))
+            .i(r_type(0x50, 5, 4, 2, 14, OP_FP))
+            .i(r_type(0x0d, 0, 1, 7, 6, OP_FP))
+            .i(csrrs(15, 0x001, 0))
+            .i(r_type(0x71, 0, 6, 0, 16, OP_FP))
+            .i(r_type(0x71, 0, 6, 1, 17, OP_FP))
+            .i(r_type(0x05, 6, 6, 7, 7, OP_FP))
+            .i(r_type(0x71, 0, 7, 0, T0, OP_FP))
+            .i(csrrs(T1, 0x100, 0))
+            .i(ECALL));
+        set_regs(&mut *vcpu, &[(A0, 1 << 13), (A1, 2), (13, RAM + 0x100)]);
+        assert_eq!(vcpu.resume().unwrap(), VcpuExit::Ecall);
+        let state = vcpu.read_state().unwrap();
+        // fcvt.d.l, fsqrt.d, fmul.d and fcvt.l.d rounding toward zero.
+        assert_eq!(state.x[A2 as usize], 2);
+        // fcvt.s.d, fsw, flw and feq.s.
+        let root = core::f32::consts::SQRT_2;
+        assert_eq!(ram[0x100..0x104], root.to_bits().to_le_bytes());
+        assert_eq!(state.x[14], 1);
+        // fdiv.d by zero, fmv.x.d and fclass.d: +inf. The conversion was inexact.
+        assert_eq!(state.x[15], 0x09);
+        assert_eq!(state.x[16] as u64, f64::INFINITY.to_bits());
+        assert_eq!(state.x[17], 1 << 7);
+        // inf - inf is the canonical NaN. The state is dirty.
+        assert_eq!(state.x[T0 as usize], 0x7ff8_0000_0000_0000);
+        assert_eq!(state.x[T1 as usize] as u64 >> 63, 1);
+    }
+    // Load a 32-bit constant.
+    fn li(code: &mut Asm, rd: u32, val: i32) {
+        let hi = val.wrapping_add(0x800) & !0xfff;
+        code.i(u_type(hi, rd, LUI))
+            .i(addi(rd, rd, val.wrapping_sub(hi)));
+    }
+    #[test]
+    fn smp_guest() {
+        use crate::board::builder::Board;
+        use crate::board::rcore_on_rcore::rcore_on_rcore;
+        use crate::board::rcore_on_rcore::test::StdChannelConsole;
+        use crate::board::{HartIrqLines, VmConfig};
+        use crate::irq::IrqLatch;
+        use crate::sbi::{ResetType, SBI_EXT_HSM, SBI_EXT_SRST};
+        use crate::serial::{Console, RingBufferedConsole};
+        use crate::vmm::{memory::GuestRam, Vmm};
+        const ENTRY: u64 = 0x8020_0000;
+        // Hart 0 starts hart 1, and waits for it to set the flag at 0x100 and stop. Then it
+        // prints and shuts down.
+        let mut code = Asm(Vec::new());
+        code.i(b_type(0x80, 0, A0, 1, BRANCH));
+        li(&mut code, 17, SBI_EXT_HSM as i32);
+        code.i(addi(16, 0, 0))
+            .i(addi(A0, 0, 1))
+            .i(u_type(0, A1, AUIPC))
+            .i(addi(A1, A1, -0x14))
+            .i(addi(A2, 0, 0))
+            .i(ECALL)
+            .i(u_type(0, T0, AUIPC))
+            .i(load(2, T1, T0, 0x100 - 0x24))
+            .i(b_type(-4, 0, T1, 0, BRANCH))
+            .i(u_type(0x1000_0000, T0, LUI))
+            .i(addi(T1, 0, b'o' as i32))
+            .i(store(0, T1, T0, 0))
+            .i(addi(T1, 0, b'k' as i32))
+            .i(store(0, T1, T0, 0));
+        li(&mut code, 17, SBI_EXT_SRST as i32);
+        code.i(addi(16, 0, 0))
+            .i(addi(A0, 0, 0))
+            .i(addi(A1, 0, 0))
+            .i(ECALL);
+        assert!(code.0.len() <= 0x80);
+        code.0.resize(0x80, 0);
+        code.i(u_type(0, T0, AUIPC))
+            .i(addi(T1, 0, 1))
+            .i(store(2, T1, T0, 0x80));
+        li(&mut code, 17, SBI_EXT_HSM as i32);
+        code.i(addi(16, 0, 1)).i(ECALL);
+
+        let clock = Arc::new(ManualClock::new(TIMEBASE_FREQUENCY));
+        let hypervisor = Arc::new(Interpreter::new(Arc::clone(&clock) as Arc<dyn Clock>));
+        let mem = hypervisor.add_memory_region(ENTRY, 0x1000).unwrap();
+        mem.data[..code.0.len()].copy_from_slice(&code.0);
+        let mut ram = GuestRam::new();
+        ram.add_region(&mem);
+        let stdconsole = Arc::new(StdChannelConsole::new());
+        let console: Arc<dyn Console> =
+            Arc::new(RingBufferedConsole::new(Arc::clone(&stdconsole), 16));
+        let sip: Vec<_> = (0..2).map(|_| Arc::new(IrqLatch::new())).collect();
+        let Board {
+            bus: mmio, clint, ..
+        } = rcore_on_rcore(
+            Arc::clone(&console),
+            clock,
+            sip.iter().map(HartIrqLines::latched).collect(),
+            &VmConfig::default(),
+        );
+        let vmm = Arc::new(Vmm::new(hypervisor, console, mmio, clint, sip, ram));
+        vmm.harts().start(0, ENTRY as usize, 0);
+        let second = Arc::clone(&vmm);
+        let thread =
+            std::thread::spawn(move || second.run_hart(1, &|| std::thread::yield_now()).unwrap());
+        vmm.run_hart(0, &|| std::thread::yield_now()).unwrap();
+        thread.join().unwrap();
+        assert_eq!(vmm.reset_reason(), Some((ResetType::Shutdown, 0)));
+        assert_eq!(stdconsole.output(), b"ok");
+    }
+}
@@ -0,0 +1,103 @@
+use super::*;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+// A hypervisor whose vCPUs follow scripts instead of running guest code, for testing the VMM.
+
+/// What a scripted vCPU looks like to the steps of its script.
+#[derive(Debug, Default)]
+pub struct MockCpu {
+    pub state: VcpuState,
+    /// Supervisor interrupts as last set by the VMM.
+    pub sip: u64,
+    /// Exceptions injected by the VMM, as (cause, tval). The vCPU does not take them otherwise.
+    pub exceptions: Vec<(u64, u64)>,
+    /// Calls of `read_state` and `write_state` by the VMM.
+    pub state_reads: usize,
+    pub state_writes: usize,
+}
+
+/// One `resume` of a scripted vCPU: update the vCPU as the guest would, and return the exit.
+pub type MockStep = Box<dyn FnMut(&mut MockCpu) -> VcpuExit + Send>;
+
+/// vCPUs get the scripts in the order they are created. `resume` fails once the script runs out.
+#[derive(Default)]
+pub struct MockHypervisor {
+    scripts: Mutex<VecDeque<Vec<MockStep>>>,
+    cpus: Mutex<Vec<Arc<Mutex<MockCpu>>>>,
+}
+
+impl MockHypervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Script the next vCPU created.
+    pub fn add_script(&self, steps: Vec<MockStep>) {
+        self.scripts.lock().push_back(steps);
+    }
+    /// The vCPU created `n`th, counting from 0.
+    pub fn cpu(&self, n: usize) -> Arc<Mutex<MockCpu>> {
+        Arc::clone(&self.cpus.lock()[n])
+    }
+}
+
+impl Hypervisor for MockHypervisor {
+    fn add_memory_region(&self, gpa: u64, len: usize) -> Result<MemoryRegion> {
+        Ok(MemoryRegion {
+            gpa,
+            data: Box::leak(alloc::vec![0; len].into_boxed_slice()),
+        })
+    }
+    fn create_vcpu(&self, entry: u64) -> Result<Box<dyn Vcpu>> {
+        let steps = match self.scripts.lock().pop_front() {
+            Some(steps) => steps,
+            None => return Err(Error::CreateVcpuError(-1)),
+        };
+        let cpu = Arc::new(Mutex::new(MockCpu::default()));
+        cpu.lock().state.pc = entry as usize;
+        self.cpus.lock().push(Arc::clone(&cpu));
+        Ok(Box::new(MockVcpu {
+            cpu,
+            steps: steps.into_iter().collect(),
+        }))
+    }
+}
+
+struct MockVcpu {
+    cpu: Arc<Mutex<MockCpu>>,
+    steps: VecDeque<MockStep>,
+}
+
+impl Vcpu for MockVcpu {
+    fn resume(&mut self) -> Result<VcpuExit> {
+        match self.steps.pop_front() {
+            Some(mut step) => Ok(step(&mut self.cpu.lock())),
+            None => Err(Error::ResumeError(-1)),
+        }
+    }
+    fn read_state(&mut self) -> Result<VcpuState> {
+        let mut cpu = self.cpu.lock();
+        cpu.state_reads += 1;
+        Ok(cpu.state.clone())
+    }
+    fn write_state(&mut self, state: &VcpuState) -> Result<()> {
+        let mut cpu = self.cpu.lock();
+        cpu.state_writes += 1;
+        cpu.state = state.clone();
+        Ok(())
+    }
+    fn set_interrupt(&mut self, irq: usize, level: bool) -> Result<()> {
+        let mut cpu = self.cpu.lock();
+        if level {
+            cpu.sip |= 1 << irq;
+        } else {
+            cpu.sip &= !(1 << irq);
+        }
+        Ok(())
+    }
+    fn inject_exception(&mut self, cause: u64, tval: u64) -> Result<()> {
+        self.cpu.lock().exceptions.push((cause, tval));
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use crate::vcpu::GuestRegs;
+use alloc::boxed::Box;
+#[cfg(feature = "interp")]
+pub mod interp;
+pub mod mock;
+// The interface of the VMM to the hypervisor running its guest, e.g. RVM through ioctls.
+
+/// Failure of a hypervisor operation, with the error code of the backend where there is one.
+#[derive(Debug)]
+pub enum Error {
+    OpenDeviceError(i32),
+    CreateGuestError(i32),
+    AddMemoryRegionError(i32),
+    CreateVcpuError(i32),
+    ResumeError(i32),
+    SendInterruptError(i32),
+    /// The backend cannot raise or lower supervisor interrupt `irq` of its guests.
+    UnsupportedInterrupt(usize),
+    HandleMMIOError(i32),
+    ReadStateError(i32),
+    WriteStateError(i32),
+    InjectExceptionError(i32),
+    /// No device claimed the access, and the VMM was told to stop when that happens.
+    UnhandledMmioError(MmioExit),
+    /// The vCPU stopped for a reason the VMM does not know.
+    UnknownExitError(u32),
+}
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Guest physical memory backed by host memory. Regions stay mapped for the lifetime of the VM.
+pub struct MemoryRegion {
+    pub gpa: u64,
+    pub data: &'static mut [u8],
+}
+
+/// The state of a vCPU the VMM works on: general-purpose registers and the program counter.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VcpuState {
+    pub x: [usize; 32],
+    pub pc: usize,
+}
+
+impl GuestRegs for VcpuState {
+    fn reg(&self, n: usize) -> usize {
+        self.x.reg(n)
+    }
+    fn set_reg(&mut self, n: usize, val: usize) {
+        self.x.set_reg(n, val)
+    }
+}
+
+/// A guest load or store to an address that is not RAM, decoded by the backend.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MmioExit {
+    pub addr: u64,
+    /// 1, 2, 4 or 8 bytes.
+    pub size: usize,
+    pub read: bool,
+    /// Value stored, zero-extended.
+    pub data: u64,
+    /// Destination register of loads.
+    pub dst_reg: usize,
+    /// Whether loads sign-extend.
+    pub sign_extend: bool,
+    /// Length of the instruction, to step over it.
+    pub inst_len: usize,
+}
+
+/// A guest access outside RAM that the backend did not decode. The VMM decodes the instruction
+/// at the pc of the vCPU instead.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MmioFault {
+    pub addr: u64,
+    /// satp of the guest, to find the instruction at pc with.
+    pub satp: u64,
+}
+
+/// The supervisor CSRs of the AIA that reach the IMSIC interrupt file of the hart. Backends
+/// leave them to the VMM.
+pub const CSR_SISELECT: u32 = 0x150;
+pub const CSR_SIREG: u32 = 0x151;
+pub const CSR_STOPEI: u32 = 0x15c;
+
+/// How a CSR instruction changes the CSR.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CsrWrite {
+    /// csrrw: the whole value.
+    Write(u64),
+    /// csrrs: the bits set here are set.
+    Set(u64),
+    /// csrrc: the bits set here are cleared.
+    Clear(u64),
+}
+
+/// A guest access to a CSR emulated by the VMM, decoded by the backend. CSR instructions are
+/// 4 bytes long.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CsrExit {
+    pub csr: u32,
+    /// None for csrrs and csrrc that only read.
+    pub write: Option<CsrWrite>,
+    /// Destination register of the old value.
+    pub dst_reg: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VcpuExit {
+    /// ecall from the guest. The vCPU resumes after the ecall.
+    Ecall,
+    /// The access is emulated by the VMM, which steps the vCPU over the instruction.
+    Mmio(MmioExit),
+    /// Likewise, for accesses the VMM decodes itself.
+    MmioFault(MmioFault),
+    /// The access is emulated by the VMM, which steps the vCPU over the instruction.
+    Csr(CsrExit),
+    /// The vCPU gave the host a chance to run, e.g. on a host timer interrupt.
+    Yield,
+    /// Any other reason, with a backend-specific code.
+    Unknown(u32),
+}
+
+pub trait Hypervisor: Send + Sync {
+    /// Back `len` bytes of guest physical memory at `gpa` with RAM.
+    fn add_memory_region(&self, gpa: u64, len: usize) -> Result<MemoryRegion>;
+    /// Create a vCPU starting at `entry`. It runs only when resumed.
+    fn create_vcpu(&self, entry: u64) -> Result<Box<dyn Vcpu>>;
+}
+
+pub trait Vcpu: Send {
+    /// Run the guest until the next exit.
+    fn resume(&mut self) -> Result<VcpuExit>;
+    fn read_state(&mut self) -> Result<VcpuState>;
+    fn write_state(&mut self, state: &VcpuState) -> Result<()>;
+    /// Raise or lower the supervisor interrupt with sip bit `irq`.
+    fn set_interrupt(&mut self, irq: usize, level: bool) -> Result<()>;
+    /// Make the guest take exception `cause` with `tval`, as if the instruction at pc raised it.
+    fn inject_exception(&mut self, cause: u64, tval: u64) -> Result<()>;
+}
@@ -0,0 +1,755 @@
+use super::super::*;
+use super::{InterruptSink, IrqLine, MsiSink};
+use crate::board::{hart_interrupts, DtDevice, IRQ_S_EXT};
+use crate::fdt::FdtWriter;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+/// Sources are numbered from 1. Source 0 means "no interrupt".
+pub const APLIC_MAX_SOURCES: usize = 1023;
+pub const APLIC_MAX_HARTS: usize = 16384;
+/// Interrupt priorities are 1 (highest) to 255 (lowest).
+pub const APLIC_IPRIO_MASK: u32 = 0xff;
+const APLIC_MIN_SIZE: usize = 0x4000;
+const APLIC_IDC_SIZE: usize = 32;
+
+// sourcecfg source modes.
+pub const APLIC_SM_INACTIVE: u32 = 0;
+pub const APLIC_SM_DETACHED: u32 = 1;
+pub const APLIC_SM_EDGE1: u32 = 4;
+pub const APLIC_SM_EDGE0: u32 = 5;
+pub const APLIC_SM_LEVEL1: u32 = 6;
+pub const APLIC_SM_LEVEL0: u32 = 7;
+
+// domaincfg bits.
+const DOMAINCFG_FIXED: u32 = 0x8000_0000;
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+/// Layout of the interrupt files that MSIs are sent to, as set up by machine-level firmware
+/// in the msiaddrcfg registers. The address of the file of hart `h` and guest `g` is
+/// `(base_ppn | group << (hhxs + 12) | h' << lhxs | g) << 12`, where `group` and `h'` are the
+/// upper `hhxw` and lower `lhxw` bits of `h`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MsiAddrConfig {
+    pub base_ppn: u64,
+    pub lhxs: u32,
+    pub lhxw: u32,
+    pub hhxw: u32,
+    pub hhxs: u32,
+}
+
+impl MsiAddrConfig {
+    pub fn msi_addr(&self, hart: usize, guest: usize) -> u64 {
+        let group = (hart as u64 >> self.lhxw) & ((1 << self.hhxw) - 1);
+        let hart = hart as u64 & ((1 << self.lhxw) - 1);
+        (self.base_ppn | group << (self.hhxs + 12) | hart << self.lhxs | guest as u64) << 12
+    }
+    // mmsiaddrcfgh: locked, since the guest cannot move the interrupt files.
+    fn mmsiaddrcfgh(&self) -> u32 {
+        1 << 31
+            | (self.hhxs & 0x1f) << 24
+            | (self.lhxs & 0x7) << 20
+            | (self.hhxw & 0x7) << 16
+            | (self.lhxw & 0xf) << 12
+            | (self.base_ppn >> 32) as u32 & 0xfff
+    }
+    fn smsiaddrcfgh(&self) -> u32 {
+        (self.lhxs & 0x7) << 20 | (self.base_ppn >> 32) as u32 & 0xfff
+    }
+}
+
+/// MSI delivery mode of an APLIC: where messages go and how their addresses are formed.
+pub struct AplicMsi {
+    pub sink: Arc<dyn MsiSink>,
+    pub config: MsiAddrConfig,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct AplicSource {
+    mode: u32,
+    target: u32,
+    input: bool,
+    pending: bool,
+    enabled: bool,
+}
+
+impl AplicSource {
+    fn active(&self) -> bool {
+        self.mode != APLIC_SM_INACTIVE
+    }
+    fn level_sensitive(&self) -> bool {
+        self.mode == APLIC_SM_LEVEL1 || self.mode == APLIC_SM_LEVEL0
+    }
+    // The input value after inversion for the active-low modes. Detached sources ignore their input.
+    fn rectified(&self) -> bool {
+        match self.mode {
+            APLIC_SM_EDGE1 | APLIC_SM_LEVEL1 => self.input,
+            APLIC_SM_EDGE0 | APLIC_SM_LEVEL0 => !self.input,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct AplicIdc {
+    delivery: bool,
+    force: bool,
+    threshold: u32,
+}
+
+struct AplicState {
+    domaincfg: u32,
+    genmsi: u32,
+    // Indexed by source ID. Entry 0 is unused.
+    sources: Vec<AplicSource>,
+    idcs: Vec<AplicIdc>,
+}
+
+impl AplicState {
+    fn msi_mode(&self) -> bool {
+        self.domaincfg & DOMAINCFG_DM != 0
+    }
+    // Level-sensitive sources in direct mode are pending exactly while asserted.
+    fn direct_level(&self, src: &AplicSource) -> bool {
+        !self.msi_mode() && src.level_sensitive()
+    }
+}
+
+// Advanced Platform-Level Interrupt Controller of the RISC-V Advanced Interrupt Architecture.
+// A single interrupt domain, which is the domain the guest sees at supervisor level.
+// Up to 1023 sources, delivered either
+// - directly, through one interrupt delivery control (IDC) structure and output line per hart, or
+// - as MSIs to the IMSIC interrupt files of the harts.
+// The guest selects the mode in domaincfg if both are available.
+pub struct APLIC {
+    num_sources: usize,
+    state: Mutex<AplicState>,
+    outputs: Vec<IrqLine>,
+    msi: Option<AplicMsi>,
+}
+impl APLIC {
+    /// Create an APLIC with sources `1..=num_sources`, one IDC per output line for direct delivery,
+    /// and MSI delivery if `msi` is given.
+    pub fn new(num_sources: usize, idc_outputs: Vec<IrqLine>, msi: Option<AplicMsi>) -> Self {
+        assert!(num_sources <= APLIC_MAX_SOURCES, "too many APLIC sources");
+        assert!(idc_outputs.len() <= APLIC_MAX_HARTS, "too many APLIC harts");
+        assert!(
+            !idc_outputs.is_empty() || msi.is_some(),
+            "APLIC without delivery mode"
+        );
+        let domaincfg = if idc_outputs.is_empty() {
+            DOMAINCFG_FIXED | DOMAINCFG_DM
+        } else {
+            DOMAINCFG_FIXED
+        };
+        APLIC {
+            num_sources,
+            state: Mutex::new(AplicState {
+                domaincfg,
+                genmsi: 0,
+                sources: alloc::vec![Default::default(); num_sources + 1],
+                idcs: alloc::vec![Default::default(); idc_outputs.len()],
+            }),
+            outputs: idc_outputs,
+            msi,
+        }
+    }
+    pub fn num_sources(&self) -> usize {
+        self.num_sources
+    }
+    pub fn num_idcs(&self) -> usize {
+        self.outputs.len()
+    }
+    fn source_exists(&self, id: usize) -> bool {
+        id != 0 && id <= self.num_sources
+    }
+    // The pending and enabled source targeting hart `idc` with the highest priority, as topi.
+    // Ties go to the lowest ID.
+    fn topi(state: &AplicState, idc: usize) -> u32 {
+        let threshold = state.idcs[idc].threshold;
+        let mut best: Option<(u32, usize)> = None;
+        for (id, src) in state.sources.iter().enumerate().skip(1) {
+            if !src.active() || !src.pending || !src.enabled || (src.target >> 18) as usize != idc {
+                continue;
+            }
+            let prio = src.target & APLIC_IPRIO_MASK;
+            if threshold != 0 && prio >= threshold {
+                continue;
+            }
+            if best.map_or(true, |(p, _)| prio < p) {
+                best = Some((prio, id));
+            }
+        }
+        best.map_or(0, |(prio, id)| (id as u32) << 16 | prio)
+    }
+    // Deliver whatever became deliverable. Must be called with the lock held.
+    // In MSI mode, the messages are returned so that they can be sent after the lock is released.
+    fn update(&self, state: &mut AplicState) -> Vec<(u64, u32)> {
+        let enabled = state.domaincfg & DOMAINCFG_IE != 0;
+        let mut messages = Vec::new();
+        if state.msi_mode() {
+            for output in self.outputs.iter() {
+                output.lower();
+            }
+            if let (true, Some(msi)) = (enabled, self.msi.as_ref()) {
+                for src in state.sources.iter_mut().skip(1) {
+                    if src.active() && src.pending && src.enabled {
+                        src.pending = false;
+                        let hart = (src.target >> 18) as usize;
+                        let guest = (src.target >> 12 & 0x3f) as usize;
+                        messages.push((msi.config.msi_addr(hart, guest), src.target & 0x7ff));
+                    }
+                }
+            }
+        } else {
+            for (idc, output) in self.outputs.iter().enumerate() {
+                let idc_state = state.idcs[idc];
+                output.set_level(
+                    enabled
+                        && idc_state.delivery
+                        && (idc_state.force || Self::topi(state, idc) != 0),
+                );
+            }
+        }
+        messages
+    }
+    fn send(&self, messages: Vec<(u64, u32)>) {
+        if let Some(msi) = self.msi.as_ref() {
+            for (addr, data) in messages {
+                msi.sink.send_msi(addr, data);
+            }
+        }
+    }
+    // Apply `f` to the state and deliver the result.
+    fn with_state<R>(&self, f: impl FnOnce(&mut AplicState) -> R) -> R {
+        let (ret, messages) = {
+            let mut state = self.state.lock();
+            let ret = f(&mut state);
+            let messages = self.update(&mut state);
+            (ret, messages)
+        };
+        self.send(messages);
+        ret
+    }
+    // A write to setip or setipnum.
+    fn set_pending(state: &mut AplicState, id: usize) {
+        let direct_level = match state.sources.get(id) {
+            Some(src) => state.direct_level(src),
+            None => return,
+        };
+        let src = &mut state.sources[id];
+        if !src.active() || direct_level || (src.level_sensitive() && !src.rectified()) {
+            return;
+        }
+        src.pending = true;
+    }
+    // A write to in_clrip or clripnum.
+    fn clear_pending(state: &mut AplicState, id: usize) {
+        let direct_level = match state.sources.get(id) {
+            Some(src) => state.direct_level(src),
+            None => return,
+        };
+        if !direct_level {
+            state.sources[id].pending = false;
+        }
+    }
+    fn set_enabled(state: &mut AplicState, id: usize, enabled: bool) {
+        if let Some(src) = state.sources.get_mut(id) {
+            if src.active() {
+                src.enabled = enabled;
+            }
+        }
+    }
+    fn write_sourcecfg(state: &mut AplicState, id: usize, val: u32) {
+        // No child domains, so the delegate bit is read-only zero.
+        let mode = match val & 0x7 {
+            APLIC_SM_DETACHED => APLIC_SM_DETACHED,
+            mode @ APLIC_SM_EDGE1..=APLIC_SM_LEVEL0 => mode,
+            _ => APLIC_SM_INACTIVE,
+        };
+        let msi_mode = state.msi_mode();
+        let src = &mut state.sources[id];
+        src.mode = mode;
+        if !src.active() {
+            *src = AplicSource {
+                input: src.input,
+                ..Default::default()
+            };
+        } else if src.level_sensitive() {
+            src.pending = src.rectified() && (!msi_mode || src.pending);
+        }
+    }
+    fn write_target(state: &mut AplicState, id: usize, val: u32) {
+        let msi_mode = state.msi_mode();
+        let src = &mut state.sources[id];
+        if !src.active() {
+            return;
+        }
+        src.target = if msi_mode {
+            val & 0xffff_f7ff
+        } else {
+            let prio = match val & APLIC_IPRIO_MASK {
+                0 => 1,
+                prio => prio,
+            };
+            val & 0xfffc_0000 | prio
+        };
+    }
+    fn write_domaincfg(&self, state: &mut AplicState, val: u32) {
+        let mut domaincfg = DOMAINCFG_FIXED | val & DOMAINCFG_IE;
+        // DM is writable only if both delivery modes exist.
+        if self.outputs.is_empty() || (self.msi.is_some() && val & DOMAINCFG_DM != 0) {
+            domaincfg |= DOMAINCFG_DM;
+        }
+        let direct = domaincfg & DOMAINCFG_DM == 0;
+        state.domaincfg = domaincfg;
+        if direct {
+            for src in state.sources.iter_mut().filter(|x| x.level_sensitive()) {
+                src.pending = src.rectified();
+            }
+        }
+    }
+    /// Read the claimi register of IDC `idc`: return topi and clear the pending bit of its source.
+    pub fn claim(&self, idc: usize) -> u32 {
+        if idc >= self.outputs.len() {
+            return 0;
+        }
+        self.with_state(|state| {
+            let topi = Self::topi(state, idc);
+            let id = (topi >> 16) as usize;
+            if id == 0 {
+                state.idcs[idc].force = false;
+            } else {
+                let src = &mut state.sources[id];
+                src.pending = src.level_sensitive() && src.rectified();
+            }
+            topi
+        })
+    }
+    // Bits 32*group..32*group+31 of a per-source flag.
+    fn source_bits(
+        &self,
+        state: &AplicState,
+        group: usize,
+        f: impl Fn(&AplicSource) -> bool,
+    ) -> u32 {
+        let mut bits = 0;
+        for i in 0..32 {
+            let id = group * 32 + i;
+            if let Some(src) = state.sources.get(id).filter(|_| self.source_exists(id)) {
+                if f(src) {
+                    bits |= 1 << i;
+                }
+            }
+        }
+        bits
+    }
+    fn load(&self, reg: APLICMemoryMap) -> u32 {
+        use APLICMemoryMap::*;
+        if let Idc {
+            idc,
+            reg: IDC_CLAIMI,
+        } = reg
+        {
+            return self.claim(idc);
+        }
+        let state = self.state.lock();
+        let msi = self.msi.as_ref().map(|x| x.config).unwrap_or_default();
+        match reg {
+            DomainCfg => state.domaincfg,
+            SourceCfg { source } => state.sources.get(source).map_or(0, |x| x.mode),
+            MMsiAddrCfg | SMsiAddrCfg => msi.base_ppn as u32,
+            MMsiAddrCfgH => msi.mmsiaddrcfgh(),
+            SMsiAddrCfgH => msi.smsiaddrcfgh(),
+            SetIp { group } => self.source_bits(&state, group, |x| x.pending),
+            InClrIp { group } => self.source_bits(&state, group, |x| x.rectified()),
+            SetIe { group } => self.source_bits(&state, group, |x| x.enabled),
+            GenMsi if state.msi_mode() => state.genmsi,
+            Target { source } => state.sources.get(source).map_or(0, |x| x.target),
+            Idc { idc, reg } => match state.idcs.get(idc) {
+                Some(x) => match reg {
+                    IDC_IDELIVERY => x.delivery as u32,
+                    IDC_IFORCE => x.force as u32,
+                    IDC_ITHRESHOLD => x.threshold,
+                    IDC_TOPI => Self::topi(&state, idc),
+                    _ => 0,
+                },
+                None => 0,
+            },
+            // The num registers, clrie and genmsi in direct mode read as zero.
+            _ => 0,
+        }
+    }
+    fn store(&self, reg: APLICMemoryMap, val: u32) {
+        use APLICMemoryMap::*;
+        self.with_state(|state| match reg {
+            DomainCfg => self.write_domaincfg(state, val),
+            SourceCfg { source } if self.source_exists(source) => {
+                Self::write_sourcecfg(state, source, val)
+            }
+            SetIp { group } | InClrIp { group } | SetIe { group } | ClrIe { group } => {
+                for i in 0..32 {
+                    let id = group * 32 + i;
+                    if val >> i & 1 == 0 || !self.source_exists(id) {
+                        continue;
+                    }
+                    match reg {
+                        SetIp { .. } => Self::set_pending(state, id),
+                        InClrIp { .. } => Self::clear_pending(state, id),
+                        SetIe { .. } => Self::set_enabled(state, id, true),
+                        _ => Self::set_enabled(state, id, false),
+                    }
+                }
+            }
+            SetIpNum | SetIpNumLe => Self::set_pending(state, val as usize),
+            SetIpNumBe => Self::set_pending(state, val.swap_bytes() as usize),
+            ClrIpNum => Self::clear_pending(state, val as usize),
+            SetIeNum => Self::set_enabled(state, val as usize, true),
+            ClrIeNum => Self::set_enabled(state, val as usize, false),
+            GenMsi if state.msi_mode() => {
+                // Sent right away, so never busy.
+                state.genmsi = val & 0xfffc_07ff;
+            }
+            Target { source } if self.source_exists(source) => {
+                Self::write_target(state, source, val)
+            }
+            Idc { idc, reg } => {
+                if let Some(x) = state.idcs.get_mut(idc) {
+                    match reg {
+                        IDC_IDELIVERY => x.delivery = val & 1 == 1,
+                        IDC_IFORCE => x.force = val & 1 == 1,
+                        IDC_ITHRESHOLD => x.threshold = val & APLIC_IPRIO_MASK,
+                        _ => {}
+                    }
+                }
+            }
+            // msiaddrcfg is locked, and the rest is read-only.
+            _ => {}
+        });
+        if let GenMsi = reg {
+            self.send_genmsi();
+        }
+    }
+    fn send_genmsi(&self) {
+        let genmsi = {
+            let state = self.state.lock();
+            if !state.msi_mode() {
+                return;
+            }
+            state.genmsi
+        };
+        if let Some(msi) = self.msi.as_ref() {
+            msi.sink.send_msi(
+                msi.config.msi_addr((genmsi >> 18) as usize, 0),
+                genmsi & 0x7ff,
+            );
+        }
+    }
+}
+
+// IDC registers.
+const IDC_IDELIVERY: usize = 0x00;
+const IDC_IFORCE: usize = 0x04;
+const IDC_ITHRESHOLD: usize = 0x08;
+const IDC_TOPI: usize = 0x18;
+const IDC_CLAIMI: usize = 0x1c;
+
+#[derive(Copy, Clone, Debug)]
+enum APLICMemoryMap {
+    DomainCfg,
+    SourceCfg { source: usize },
+    MMsiAddrCfg,
+    MMsiAddrCfgH,
+    SMsiAddrCfg,
+    SMsiAddrCfgH,
+    SetIp { group: usize },
+    SetIpNum,
+    InClrIp { group: usize },
+    ClrIpNum,
+    SetIe { group: usize },
+    SetIeNum,
+    ClrIe { group: usize },
+    ClrIeNum,
+    SetIpNumLe,
+    SetIpNumBe,
+    GenMsi,
+    Target { source: usize },
+    Idc { idc: usize, reg: usize },
+}
+
+impl APLICMemoryMap {
+    pub fn parse(offset: usize) -> Option<APLICMemoryMap> {
+        use APLICMemoryMap::*;
+        let group = |base: usize| {
+            if offset >= base && offset < base + 0x80 {
+                Some((offset - base) / 4)
+            } else {
+                None
+            }
+        };
+        Some(match offset {
+            0x0000 => DomainCfg,
+            0x0004..=0x0ffc => SourceCfg { source: offset / 4 },
+            0x1bc0 => MMsiAddrCfg,
+            0x1bc4 => MMsiAddrCfgH,
+            0x1bc8 => SMsiAddrCfg,
+            0x1bcc => SMsiAddrCfgH,
+            0x1cdc => SetIpNum,
+            0x1ddc => ClrIpNum,
+            0x1edc => SetIeNum,
+            0x1fdc => ClrIeNum,
+            0x2000 => SetIpNumLe,
+            0x2004 => SetIpNumBe,
+            0x3000 => GenMsi,
+            0x3004..=0x3ffc => Target {
+                source: (offset - 0x3000) / 4,
+            },
+            _ if offset >= APLIC_MIN_SIZE => Idc {
+                idc: (offset - APLIC_MIN_SIZE) / APLIC_IDC_SIZE,
+                reg: (offset - APLIC_MIN_SIZE) % APLIC_IDC_SIZE,
+            },
+            _ => {
+                if let Some(group) = group(0x1c00) {
+                    SetIp { group }
+                } else if let Some(group) = group(0x1d00) {
+                    InClrIp { group }
+                } else if let Some(group) = group(0x1e00) {
+                    SetIe { group }
+                } else if let Some(group) = group(0x1f00) {
+                    ClrIe { group }
+                } else {
+                    return None;
+                }
+            }
+        })
+    }
+}
+
+impl Device for APLIC {
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+    fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        if offset % 4 != 0 {
+            // misaligned access.
+            return None;
+        }
+        let reg = match APLICMemoryMap::parse(offset) {
+            Some(x) => x,
+            None => return Some(false),
+        };
+        if let APLICMemoryMap::Idc { idc, .. } = reg {
+            // Absent IDCs are outside the APLIC.
+            if idc >= self.outputs.len() {
+                return Some(false);
+            }
+        }
+        match access {
+            MMIOAccess::LoadWord(ret) => **ret = self.load(reg),
+            MMIOAccess::StoreWord(val) => self.store(reg, *val),
+            _ => {
+                // malformed access.
+                return None;
+            }
+        }
+        Some(true)
+    }
+    fn mmio_region_size(&self) -> usize {
+        let idcs = APLIC_IDC_SIZE * self.outputs.len();
+        APLIC_MIN_SIZE + (idcs + APLIC_MIN_SIZE - 1) / APLIC_MIN_SIZE * APLIC_MIN_SIZE
+    }
+    fn has_interrupt(&self) -> bool {
+        self.outputs.iter().any(|x| x.level())
+    }
+}
+
+// Interrupts are specified by source and sense, e.g. <10 4> for source 10, level-high. The IDCs
+// drive the external interrupts of the harts in order. For MSIs, the board links the node to
+// the IMSIC with `msi-parent`.
+impl DtDevice for APLIC {
+    fn dt_name(&self) -> &str {
+        "aplic"
+    }
+    fn write_dt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("compatible", "riscv,aplic");
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 2);
+        fdt.property_u32("riscv,num-sources", self.num_sources as u32);
+        if !self.outputs.is_empty() {
+            fdt.property_cells(
+                "interrupts-extended",
+                &hart_interrupts(self.outputs.len(), &[Some(IRQ_S_EXT)]),
+            );
+        }
+    }
+}
+
+impl InterruptSink for APLIC {
+    fn set_level(&self, id: usize, level: bool) {
+        if !self.source_exists(id) {
+            return;
+        }
+        self.with_state(|state| {
+            let direct_level = state.direct_level(&state.sources[id]);
+            let src = &mut state.sources[id];
+            let old = src.rectified();
+            src.input = level;
+            let new = src.rectified();
+            match src.mode {
+                APLIC_SM_EDGE1 | APLIC_SM_EDGE0 => src.pending |= !old && new,
+                APLIC_SM_LEVEL1 | APLIC_SM_LEVEL0 => {
+                    if direct_level || !new {
+                        src.pending = new;
+                    } else if !old {
+                        src.pending = true;
+                    }
+                }
+                _ => {}
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::irq::imsic::{MsiRouter, IMSIC, IMSIC_EIDELIVERY, IMSIC_EIE0};
+    use crate::irq::IrqLatch;
+    fn load(aplic: &APLIC, offset: usize) -> u32 {
+        let mut ret = 0;
+        assert_eq!(
+            aplic.handle_mmio(offset, &mut MMIOAccess::LoadWord(&mut ret)),
+            Some(true)
+        );
+        ret
+    }
+    fn store(aplic: &APLIC, offset: usize, val: u32) {
+        assert_eq!(
+            aplic.handle_mmio(offset, &mut MMIOAccess::StoreWord(val)),
+            Some(true)
+        );
+    }
+    fn sourcecfg(id: usize) -> usize {
+        id * 4
+    }
+    fn target(id: usize) -> usize {
+        0x3000 + id * 4
+    }
+    fn idc(hart: usize, reg: usize) -> usize {
+        0x4000 + hart * 32 + reg
+    }
+    #[test]
+    fn direct_mode() {
+        let latch = Arc::new(IrqLatch::new());
+        let aplic = Arc::new(APLIC::new(
+            31,
+            (0..2)
+                .map(|hart| IrqLine::new(Arc::clone(&latch) as Arc<dyn InterruptSink>, hart))
+                .collect(),
+            None,
+        ));
+        let line = |id| IrqLine::new(Arc::clone(&aplic) as Arc<dyn InterruptSink>, id);
+        assert_eq!(aplic.mmio_region_size(), 0x8000);
+        latch.take_changes();
+        let (uart, disk) = (line(10), line(3));
+        store(&aplic, 0, DOMAINCFG_DM | DOMAINCFG_IE);
+        assert_eq!(load(&aplic, 0), DOMAINCFG_FIXED | DOMAINCFG_IE, "No MSIs.");
+        store(&aplic, sourcecfg(10), APLIC_SM_LEVEL1);
+        store(&aplic, sourcecfg(3), APLIC_SM_EDGE1);
+        store(&aplic, target(10), 1 << 18 | 5);
+        store(&aplic, target(3), 1 << 18);
+        assert_eq!(load(&aplic, target(3)), 1 << 18 | 1, "Priority 0 reads 1.");
+        store(&aplic, 0x1edc, 10);
+        store(&aplic, 0x1edc, 3);
+        store(&aplic, idc(1, IDC_IDELIVERY), 1);
+        uart.raise();
+        assert_eq!(latch.take_changes(), Some(1 << 1), "Delivered to hart 1.");
+        disk.pulse();
+        assert_eq!(load(&aplic, idc(1, IDC_TOPI)), 3 << 16 | 1, "Priority.");
+        store(&aplic, idc(1, IDC_ITHRESHOLD), 1);
+        assert_eq!(load(&aplic, idc(1, IDC_TOPI)), 0, "Threshold.");
+        store(&aplic, idc(1, IDC_ITHRESHOLD), 0);
+        assert_eq!(load(&aplic, idc(1, IDC_CLAIMI)), 3 << 16 | 1);
+        assert_eq!(load(&aplic, idc(1, IDC_CLAIMI)), 10 << 16 | 5);
+        assert_eq!(
+            load(&aplic, idc(1, IDC_CLAIMI)),
+            10 << 16 | 5,
+            "Level source still asserted."
+        );
+        uart.lower();
+        assert_eq!(load(&aplic, idc(1, IDC_TOPI)), 0);
+        assert_eq!(latch.take_changes(), Some(0));
+        store(&aplic, 0x1cdc, 10);
+        assert_eq!(load(&aplic, 0x1c00), 0, "Level sources ignore setip.");
+        store(&aplic, sourcecfg(3), APLIC_SM_INACTIVE);
+        assert_eq!(load(&aplic, target(3)), 0);
+        assert_eq!(load(&aplic, 0x1e00), 1 << 10);
+        let mut ret = 0;
+        assert_eq!(
+            aplic.handle_mmio(idc(2, 0), &mut MMIOAccess::LoadWord(&mut ret)),
+            Some(false),
+            "No IDC for hart 2."
+        );
+    }
+    #[test]
+    fn msi_mode() {
+        let seip = Arc::new(IrqLatch::new());
+        let file = Arc::new(IMSIC::new(
+            63,
+            IrqLine::new(Arc::clone(&seip) as Arc<dyn InterruptSink>, 0),
+        ));
+        let mut router = MsiRouter::new();
+        router.add_file(0x2800_0000, Arc::clone(&file));
+        let aplic = Arc::new(APLIC::new(
+            31,
+            Vec::new(),
+            Some(AplicMsi {
+                sink: Arc::new(router),
+                config: MsiAddrConfig {
+                    base_ppn: 0x28000,
+                    ..Default::default()
+                },
+            }),
+        ));
+        let uart = IrqLine::new(Arc::clone(&aplic) as Arc<dyn InterruptSink>, 10);
+        file.write_indirect(IMSIC_EIDELIVERY, 1).unwrap();
+        file.write_indirect(IMSIC_EIE0, !0).unwrap();
+        seip.take_changes();
+        assert_eq!(load(&aplic, 0), DOMAINCFG_FIXED | DOMAINCFG_DM, "MSI only.");
+        assert_eq!(load(&aplic, 0x1bc0), 0x28000);
+        assert_eq!(load(&aplic, 0x1bc4), 1 << 31, "Locked.");
+        store(&aplic, sourcecfg(10), APLIC_SM_LEVEL1);
+        store(&aplic, target(10), 42);
+        store(&aplic, 0x1edc, 10);
+        uart.raise();
+        assert_eq!(load(&aplic, 0x1c00), 1 << 10, "Domain disabled.");
+        store(&aplic, 0, DOMAINCFG_IE);
+        assert_eq!(load(&aplic, 0x1c00), 0, "Forwarded.");
+        assert!(seip.level(0), "Delivered to the interrupt file.");
+        assert_eq!(file.claim_topei() >> 16, 42);
+        uart.raise();
+        assert!(!file.pending(42), "No new message while asserted.");
+        store(&aplic, 0x1cdc, 10);
+        assert!(file.pending(42), "Set again by setipnum.");
+        file.claim_topei();
+        uart.lower();
+        store(&aplic, 0x1cdc, 10);
+        assert!(!file.pending(42), "Not while deasserted.");
+        store(&aplic, 0x3000, 7);
+        assert!(file.pending(7), "genmsi.");
+    }
+    #[test]
+    fn msi_address() {
+        let config = MsiAddrConfig {
+            base_ppn: 0x28000,
+            lhxs: 1,
+            lhxw: 2,
+            hhxw: 1,
+            hhxs: 4,
+        };
+        assert_eq!(config.msi_addr(0, 0), 0x2800_0000);
+        assert_eq!(config.msi_addr(3, 1), 0x2800_7000);
+        assert_eq!(config.msi_addr(4, 0), 0x3800_0000);
+    }
+}
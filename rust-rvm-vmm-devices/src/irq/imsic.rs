@@ -0,0 +1,360 @@
+use super::super::*;
+use super::{InterruptSink, IrqLine, MsiSink};
+use crate::board::{hart_interrupts, DtDevice, IRQ_S_EXT};
+use crate::fdt::FdtWriter;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+/// Identities are numbered from 1. Identity 0 means "no interrupt".
+pub const IMSIC_MAX_IDS: usize = 2047;
+/// Every interrupt file occupies one page.
+pub const IMSIC_PAGE_SIZE: usize = 0x1000;
+// Registers of the interrupt file, selected through siselect.
+pub const IMSIC_EIDELIVERY: usize = 0x70;
+pub const IMSIC_EITHRESHOLD: usize = 0x72;
+pub const IMSIC_EIP0: usize = 0x80;
+pub const IMSIC_EIP63: usize = 0xbf;
+pub const IMSIC_EIE0: usize = 0xc0;
+pub const IMSIC_EIE63: usize = 0xff;
+// Offsets in the interrupt file page.
+const SETEIPNUM_LE: usize = 0x0;
+const SETEIPNUM_BE: usize = 0x4;
+
+struct ImsicFile {
+    delivery: bool,
+    threshold: u32,
+    eip: Vec<u64>,
+    eie: Vec<u64>,
+}
+
+// One IMSIC interrupt file of the RISC-V Advanced Interrupt Architecture.
+// Devices and APLICs write identities to the page of the file, and the hart reaches the file state
+// through the indirect CSRs (siselect/sireg/stopei), which the VMM emulates with
+// `modify_indirect`, `topei` and `claim_topei`.
+// The output line is asserted while delivery is enabled and `topei` is not zero.
+pub struct IMSIC {
+    num_ids: usize,
+    file: Mutex<ImsicFile>,
+    output: IrqLine,
+}
+impl IMSIC {
+    /// Create an interrupt file with identities `1..=num_ids`. `num_ids` is one less than a multiple of 64.
+    pub fn new(num_ids: usize, output: IrqLine) -> Self {
+        assert!(
+            num_ids >= 63 && num_ids <= IMSIC_MAX_IDS && (num_ids + 1) % 64 == 0,
+            "bad number of IMSIC identities"
+        );
+        let words = (num_ids + 1) / 64;
+        IMSIC {
+            num_ids,
+            file: Mutex::new(ImsicFile {
+                delivery: false,
+                threshold: 0,
+                eip: alloc::vec![0; words],
+                eie: alloc::vec![0; words],
+            }),
+            output,
+        }
+    }
+    pub fn num_ids(&self) -> usize {
+        self.num_ids
+    }
+    // The pending and enabled identity with the highest priority, i.e. the lowest number, below the threshold.
+    fn compute_topei(file: &ImsicFile) -> u32 {
+        for (word, (eip, eie)) in file.eip.iter().zip(file.eie.iter()).enumerate() {
+            let active = eip & eie;
+            if active != 0 {
+                let id = (word * 64) as u32 + active.trailing_zeros();
+                if file.threshold == 0 || id < file.threshold {
+                    return id;
+                }
+                return 0;
+            }
+        }
+        0
+    }
+    // Must be called with the lock held.
+    fn update(&self, file: &ImsicFile) {
+        self.output
+            .set_level(file.delivery && Self::compute_topei(file) != 0);
+    }
+    /// Value of the topei CSR: the identity in bits 26:16 and, as its priority, again in bits 10:0.
+    pub fn topei(&self) -> u32 {
+        let id = Self::compute_topei(&self.file.lock());
+        id << 16 | id
+    }
+    /// Write to the topei CSR: return the current topei and clear the pending bit of its identity.
+    pub fn claim_topei(&self) -> u32 {
+        let mut file = self.file.lock();
+        let id = Self::compute_topei(&file) as usize;
+        if id != 0 {
+            file.eip[id / 64] &= !(1 << (id % 64));
+            self.update(&file);
+        }
+        (id << 16 | id) as u32
+    }
+    /// Mark identity `id` pending, as a write to seteipnum does. Other values are ignored.
+    pub fn set_pending(&self, id: usize) {
+        if id == 0 || id > self.num_ids {
+            return;
+        }
+        let mut file = self.file.lock();
+        file.eip[id / 64] |= 1 << (id % 64);
+        self.update(&file);
+    }
+    pub fn pending(&self, id: usize) -> bool {
+        id != 0 && id <= self.num_ids && self.file.lock().eip[id / 64] >> (id % 64) & 1 == 1
+    }
+    // Index of the 64-bit eip/eie word selected by an even register number starting at `first`.
+    // The odd registers do not exist on RV64.
+    fn word_index(iselect: usize, first: usize) -> Option<usize> {
+        let n = iselect - first;
+        if n % 2 != 0 {
+            return None;
+        }
+        Some(n / 2)
+    }
+    /// Read an interrupt file register. Returns None for registers that do not exist.
+    pub fn read_indirect(&self, iselect: usize) -> Option<u64> {
+        Self::read_register(&self.file.lock(), iselect)
+    }
+    /// Write an interrupt file register. Bits of absent identities are ignored.
+    /// Returns None for registers that do not exist.
+    pub fn write_indirect(&self, iselect: usize, val: u64) -> Option<()> {
+        self.modify_indirect(iselect, |_| val).map(|_| ())
+    }
+    /// Replace an interrupt file register by `f` of its value, at once, as csrrs and csrrc on
+    /// sireg do. Returns the old value, or None for registers that do not exist.
+    pub fn modify_indirect(&self, iselect: usize, f: impl FnOnce(u64) -> u64) -> Option<u64> {
+        let mut file = self.file.lock();
+        let old = Self::read_register(&file, iselect)?;
+        let val = f(old);
+        match iselect {
+            IMSIC_EIDELIVERY => file.delivery = val & 1 == 1,
+            IMSIC_EITHRESHOLD => file.threshold = (val as usize & IMSIC_MAX_IDS) as u32,
+            _ => {
+                let (bits, i) = if iselect <= IMSIC_EIP63 {
+                    (&mut file.eip, Self::word_index(iselect, IMSIC_EIP0)?)
+                } else {
+                    (&mut file.eie, Self::word_index(iselect, IMSIC_EIE0)?)
+                };
+                if let Some(x) = bits.get_mut(i) {
+                    // Identity 0 does not exist.
+                    *x = if i == 0 { val & !1 } else { val };
+                }
+            }
+        }
+        self.update(&file);
+        Some(old)
+    }
+    fn read_register(file: &ImsicFile, iselect: usize) -> Option<u64> {
+        match iselect {
+            IMSIC_EIDELIVERY => Some(file.delivery as u64),
+            IMSIC_EITHRESHOLD => Some(file.threshold as u64),
+            IMSIC_EIP0..=IMSIC_EIP63 => {
+                let i = Self::word_index(iselect, IMSIC_EIP0)?;
+                Some(file.eip.get(i).copied().unwrap_or(0))
+            }
+            IMSIC_EIE0..=IMSIC_EIE63 => {
+                let i = Self::word_index(iselect, IMSIC_EIE0)?;
+                Some(file.eie.get(i).copied().unwrap_or(0))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Device for IMSIC {
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+    fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        if offset % 4 != 0 {
+            // misaligned access.
+            return None;
+        }
+        match access {
+            MMIOAccess::StoreWord(val) => match offset {
+                SETEIPNUM_LE => self.set_pending(*val as usize),
+                SETEIPNUM_BE => self.set_pending(val.swap_bytes() as usize),
+                // Reserved.
+                _ => {}
+            },
+            // The page is write-only.
+            MMIOAccess::LoadWord(ret) => **ret = 0,
+            _ => {
+                // malformed access.
+                return None;
+            }
+        }
+        Some(true)
+    }
+    fn mmio_region_size(&self) -> usize {
+        IMSIC_PAGE_SIZE
+    }
+    fn has_interrupt(&self) -> bool {
+        self.output.level()
+    }
+}
+
+/// Devices wired to an interrupt file by a line send identity `id` on every rising edge.
+impl InterruptSink for IMSIC {
+    fn set_level(&self, id: usize, level: bool) {
+        if level {
+            self.set_pending(id);
+        }
+    }
+}
+
+/// The supervisor-level interrupt files of the harts of a VM, hart `i` in page `i`, in a region
+/// of a power of two of pages, as one device tree node describes them.
+pub struct ImsicFiles {
+    files: Vec<Arc<IMSIC>>,
+}
+
+impl ImsicFiles {
+    pub fn new(files: Vec<Arc<IMSIC>>) -> Self {
+        assert!(!files.is_empty(), "no interrupt files");
+        ImsicFiles { files }
+    }
+    pub fn files(&self) -> &[Arc<IMSIC>] {
+        &self.files
+    }
+}
+
+impl Device for ImsicFiles {
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+    // Pages past the last hart are outside the files.
+    fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        match self.files.get(offset / IMSIC_PAGE_SIZE) {
+            Some(file) => file.handle_mmio(offset % IMSIC_PAGE_SIZE, access),
+            None => Some(false),
+        }
+    }
+    fn mmio_region_size(&self) -> usize {
+        self.files.len().next_power_of_two() * IMSIC_PAGE_SIZE
+    }
+    fn has_interrupt(&self) -> bool {
+        self.files.iter().any(|file| file.has_interrupt())
+    }
+}
+
+impl DtDevice for ImsicFiles {
+    fn dt_name(&self) -> &str {
+        "imsic"
+    }
+    fn write_dt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("compatible", "riscv,imsics");
+        fdt.property_cells(
+            "interrupts-extended",
+            &hart_interrupts(self.files.len(), &[Some(IRQ_S_EXT)]),
+        );
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 0);
+        fdt.property_null("msi-controller");
+        fdt.property_u32("#msi-cells", 0);
+        fdt.property_u32("riscv,num-ids", self.files[0].num_ids() as u32);
+    }
+}
+
+/// Routes MSIs to the interrupt files at the guest physical addresses they are mapped at.
+#[derive(Default)]
+pub struct MsiRouter {
+    files: Vec<(u64, Arc<IMSIC>)>,
+}
+
+impl MsiRouter {
+    pub fn new() -> Self {
+        MsiRouter { files: Vec::new() }
+    }
+    pub fn add_file(&mut self, addr: u64, file: Arc<IMSIC>) {
+        self.files.push((addr, file));
+    }
+}
+
+impl MsiSink for MsiRouter {
+    fn send_msi(&self, addr: u64, data: u32) -> bool {
+        for (base, file) in self.files.iter() {
+            if addr == *base + SETEIPNUM_LE as u64 {
+                file.set_pending(data as usize);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::irq::IrqLatch;
+    fn setup() -> (Arc<IMSIC>, Arc<IrqLatch>) {
+        let latch = Arc::new(IrqLatch::new());
+        let file = Arc::new(IMSIC::new(
+            127,
+            IrqLine::new(Arc::clone(&latch) as Arc<dyn InterruptSink>, 0),
+        ));
+        latch.take_changes();
+        (file, latch)
+    }
+    fn seteipnum(file: &IMSIC, offset: usize, val: u32) {
+        assert_eq!(
+            file.handle_mmio(offset, &mut MMIOAccess::StoreWord(val)),
+            Some(true)
+        );
+    }
+    #[test]
+    fn interrupt_file() {
+        let (file, latch) = setup();
+        seteipnum(&file, 0, 70);
+        assert!(file.pending(70));
+        assert_eq!(file.topei(), 0, "Not enabled.");
+        file.write_indirect(IMSIC_EIE0 + 2, 1 << 6 | 1 << 9)
+            .unwrap();
+        assert_eq!(file.topei(), 70 << 16 | 70);
+        assert_eq!(latch.take_changes(), None, "Delivery disabled.");
+        file.write_indirect(IMSIC_EIDELIVERY, 1).unwrap();
+        assert_eq!(latch.take_changes(), Some(1));
+        seteipnum(&file, 4, 73u32.swap_bytes());
+        file.write_indirect(IMSIC_EITHRESHOLD, 70).unwrap();
+        assert_eq!(file.topei(), 0, "Masked by the threshold.");
+        file.write_indirect(IMSIC_EITHRESHOLD, 0).unwrap();
+        assert_eq!(file.claim_topei(), 70 << 16 | 70);
+        assert_eq!(file.claim_topei(), 73 << 16 | 73);
+        assert_eq!(file.claim_topei(), 0);
+        assert_eq!(latch.take_changes(), Some(0));
+        assert_eq!(file.read_indirect(IMSIC_EIP0 + 2), Some(0));
+        assert_eq!(file.read_indirect(IMSIC_EIP0 + 1), None, "Odd on RV64.");
+        assert_eq!(file.read_indirect(IMSIC_EIP63 - 1), Some(0), "Absent.");
+        seteipnum(&file, 0, 128);
+        seteipnum(&file, 0, 0);
+        assert_eq!(file.read_indirect(IMSIC_EIP0), Some(0), "Ignored.");
+    }
+    #[test]
+    fn msi_router() {
+        let (file, _) = setup();
+        let mut router = MsiRouter::new();
+        router.add_file(0x2800_0000, Arc::clone(&file));
+        assert!(router.send_msi(0x2800_0000, 5));
+        assert!(!router.send_msi(0x2800_1000, 5));
+        assert!(file.pending(5));
+    }
+    #[test]
+    fn files() {
+        let files: Vec<_> = (0..3).map(|_| setup().0).collect();
+        let group = ImsicFiles::new(files.clone());
+        assert_eq!(group.mmio_region_size(), 4 * IMSIC_PAGE_SIZE);
+        assert_eq!(
+            group.handle_mmio(IMSIC_PAGE_SIZE, &mut MMIOAccess::StoreWord(5)),
+            Some(true)
+        );
+        assert!(files[1].pending(5) && !files[0].pending(5));
+        assert_eq!(
+            group.handle_mmio(3 * IMSIC_PAGE_SIZE, &mut MMIOAccess::StoreWord(5)),
+            Some(false),
+            "No hart 3."
+        );
+    }
+}
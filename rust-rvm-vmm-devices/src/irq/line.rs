@@ -0,0 +1,231 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering::*};
+use spin::Mutex;
+
+/// Receiver of interrupt signals, e.g. the inputs of an interrupt controller or the external interrupt pin of a hart.
+pub trait InterruptSink: Send + Sync {
+    /// The level of input `id` changed.
+    fn set_level(&self, id: usize, level: bool);
+    /// An edge-triggered request arrived on input `id`.
+    fn trigger_edge(&self, id: usize) {
+        self.set_level(id, true);
+        self.set_level(id, false);
+    }
+}
+
+/// Receiver of message-signaled interrupts, i.e. 32-bit writes of `data` to `addr`.
+pub trait MsiSink: Send + Sync {
+    /// Returns false if nothing accepts messages at `addr`.
+    fn send_msi(&self, addr: u64, data: u32) -> bool;
+}
+
+/// Output wire of a device, connected to one input of an `InterruptSink`.
+/// Level changes are forwarded only when the level actually changes.
+pub struct IrqLine {
+    sink: Option<Arc<dyn InterruptSink>>,
+    id: usize,
+    level: AtomicBool,
+}
+
+impl IrqLine {
+    pub fn new(sink: Arc<dyn InterruptSink>, id: usize) -> Self {
+        IrqLine {
+            sink: Some(sink),
+            id,
+            level: AtomicBool::new(false),
+        }
+    }
+    /// A line that is not wired to anything.
+    pub fn disconnected() -> Self {
+        IrqLine {
+            sink: None,
+            id: 0,
+            level: AtomicBool::new(false),
+        }
+    }
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    pub fn level(&self) -> bool {
+        self.level.load(SeqCst)
+    }
+    pub fn set_level(&self, level: bool) {
+        if self.level.swap(level, SeqCst) != level {
+            if let Some(sink) = self.sink.as_ref() {
+                sink.set_level(self.id, level);
+            }
+        }
+    }
+    pub fn raise(&self) {
+        self.set_level(true);
+    }
+    pub fn lower(&self) {
+        self.set_level(false);
+    }
+    /// Send an edge-triggered request.
+    pub fn pulse(&self) {
+        if let Some(sink) = self.sink.as_ref() {
+            sink.trigger_edge(self.id);
+        }
+    }
+}
+
+/// Sink latching the levels of up to 64 inputs for a consumer that polls at its own pace, e.g. a vCPU run loop.
+pub struct IrqLatch {
+    levels: AtomicU64,
+    // Inputs that saw an edge since the last `take_edges`.
+    edges: AtomicU64,
+    changed: AtomicBool,
+}
+
+impl IrqLatch {
+    pub fn new() -> Self {
+        IrqLatch {
+            levels: AtomicU64::new(0),
+            edges: AtomicU64::new(0),
+            // Let the first poll synchronize the initial state.
+            changed: AtomicBool::new(true),
+        }
+    }
+    pub fn levels(&self) -> u64 {
+        self.levels.load(SeqCst)
+    }
+    pub fn level(&self, id: usize) -> bool {
+        id < 64 && (self.levels() >> id) & 1 == 1
+    }
+    /// Return the current levels if any input changed since the last call.
+    pub fn take_changes(&self) -> Option<u64> {
+        if self.changed.swap(false, SeqCst) {
+            Some(self.levels())
+        } else {
+            None
+        }
+    }
+    /// Return the inputs that received edge-triggered requests since the last call.
+    pub fn take_edges(&self) -> u64 {
+        self.edges.swap(0, SeqCst)
+    }
+}
+
+impl Default for IrqLatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InterruptSink for IrqLatch {
+    fn set_level(&self, id: usize, level: bool) {
+        if id >= 64 {
+            return;
+        }
+        let mask = 1u64 << id;
+        let old = if level {
+            self.levels.fetch_or(mask, SeqCst)
+        } else {
+            self.levels.fetch_and(!mask, SeqCst)
+        };
+        if (old & mask != 0) != level {
+            self.changed.store(true, SeqCst);
+        }
+    }
+    fn trigger_edge(&self, id: usize) {
+        if id >= 64 {
+            return;
+        }
+        self.edges.fetch_or(1 << id, SeqCst);
+        self.changed.store(true, SeqCst);
+    }
+}
+
+/// Wired-OR of several device lines onto one interrupt line, e.g. devices sharing a PLIC source.
+/// The output is asserted while any attached line is asserted; edges are passed through.
+pub struct SharedIrq {
+    output: IrqLine,
+    // Level of each attached line, indexed by the id handed out by `attach`.
+    levels: Mutex<Vec<bool>>,
+}
+
+impl SharedIrq {
+    pub fn new(output: IrqLine) -> Arc<Self> {
+        Arc::new(SharedIrq {
+            output,
+            levels: Mutex::new(Vec::new()),
+        })
+    }
+    /// A new line for one more device sharing the output.
+    pub fn attach(self: &Arc<Self>) -> IrqLine {
+        let mut levels = self.levels.lock();
+        levels.push(false);
+        IrqLine::new(Arc::clone(self) as Arc<dyn InterruptSink>, levels.len() - 1)
+    }
+    pub fn output(&self) -> &IrqLine {
+        &self.output
+    }
+}
+
+impl InterruptSink for SharedIrq {
+    fn set_level(&self, id: usize, level: bool) {
+        let mut levels = self.levels.lock();
+        if let Some(x) = levels.get_mut(id) {
+            *x = level;
+        }
+        // Forward under the lock so that concurrent updates reach the output in order.
+        self.output.set_level(levels.iter().any(|&x| x));
+    }
+    fn trigger_edge(&self, _id: usize) {
+        self.output.pulse();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn line_to_latch() {
+        let latch = Arc::new(IrqLatch::new());
+        let line = IrqLine::new(Arc::clone(&latch) as Arc<dyn InterruptSink>, 3);
+        assert_eq!(latch.take_changes(), Some(0), "Initial synchronization.");
+        assert_eq!(latch.take_changes(), None);
+        line.raise();
+        line.raise();
+        assert!(latch.level(3));
+        assert_eq!(latch.take_changes(), Some(1 << 3));
+        assert_eq!(
+            latch.take_changes(),
+            None,
+            "Repeated raise is not a change."
+        );
+        line.lower();
+        assert_eq!(latch.take_changes(), Some(0));
+        line.pulse();
+        assert!(!line.level(), "Edges do not change the line level.");
+        assert_eq!(latch.take_changes(), Some(0), "Edge seen by the latch.");
+        assert_eq!(latch.take_edges(), 1 << 3);
+        assert_eq!(latch.take_edges(), 0);
+        IrqLine::disconnected().raise();
+    }
+    #[test]
+    fn shared_line() {
+        let latch = Arc::new(IrqLatch::new());
+        let shared = SharedIrq::new(IrqLine::new(
+            Arc::clone(&latch) as Arc<dyn InterruptSink>,
+            5,
+        ));
+        let a = shared.attach();
+        let b = shared.attach();
+        latch.take_changes();
+        a.raise();
+        b.raise();
+        assert_eq!(latch.take_changes(), Some(1 << 5));
+        a.lower();
+        assert_eq!(
+            latch.take_changes(),
+            None,
+            "Still asserted by the other device."
+        );
+        b.lower();
+        assert_eq!(latch.take_changes(), Some(0), "Released by all devices.");
+        assert!(!shared.output().level());
+    }
+}
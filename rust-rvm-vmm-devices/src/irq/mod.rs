@@ -1 +1,5 @@
+pub mod aplic;
+pub mod imsic;
+pub mod line;
 pub mod plic;
+pub use line::*;
@@ -1,107 +1,158 @@
 use super::super::*;
-use alloc::collections::BTreeMap;
-use alloc::sync::Arc;
+use super::{InterruptSink, IrqLine};
+use crate::board::{hart_interrupts, DtDevice, IRQ_S_EXT};
+use crate::fdt::FdtWriter;
+use alloc::vec::Vec;
 use core::sync::atomic::AtomicBool;
 use core::sync::atomic::AtomicU32 as AtomicReg;
 use core::sync::atomic::Ordering::*;
 use spin::Mutex;
-const MAXIMAL_INTERRUPT_GROUP: usize = 1;
+/// Sources are numbered from 1. Source 0 means "no interrupt".
+pub const PLIC_MAX_SOURCES: usize = 1023;
+pub const PLIC_MAX_CONTEXTS: usize = 15872;
+// Registers of the contexts follow the global registers, one page per context.
+const PLIC_CONTEXT_BASE: u64 = 0x200000;
+const PLIC_CONTEXT_SIZE: u64 = 0x1000;
 #[derive(Default, Debug)]
 pub struct PLICInterruptX32 {
     pub pending: AtomicReg,
+    // Current input levels of the sources.
+    pub level: AtomicReg,
+    // Gateway state: a request has been forwarded and its completion has not arrived yet.
+    pub in_flight: AtomicReg,
+    // Gateway state: an edge arrived while a request was in flight.
+    pub edge_latched: AtomicReg,
     pub priority: [AtomicReg; 32],
 }
 #[derive(Default, Debug)]
 pub struct PLICContext {
-    pub enable_bits: [AtomicReg; MAXIMAL_INTERRUPT_GROUP],
-    pub claimed_bits: [AtomicReg; MAXIMAL_INTERRUPT_GROUP],
+    pub enable_bits: Vec<AtomicReg>,
     pub threshold: AtomicReg,
     pub eip: AtomicBool,
 }
+impl PLICContext {
+    fn new(groups: usize) -> Self {
+        PLICContext {
+            enable_bits: (0..groups).map(|_| AtomicReg::new(0)).collect(),
+            threshold: AtomicReg::new(0),
+            eip: AtomicBool::new(false),
+        }
+    }
+}
 
-fn atomic_set_bit(reg: &AtomicReg, n: u8, bit: bool) {
-    reg.fetch_update(
-        |x| {
-            let mask = 1u32 << (n as u32);
-            if bit {
-                Some(x | mask)
-            } else {
-                Some(x & (!mask))
-            }
-        },
-        Relaxed,
-        Relaxed,
-    )
-    .unwrap();
+// Bits beyond the register width read as zero and ignore writes.
+fn atomic_get_bit(reg: &AtomicReg, n: u8) -> bool {
+    reg.load(Relaxed)
+        .checked_shr(n as u32)
+        .map_or(false, |x| x & 1 == 1)
 }
 
+fn atomic_set_bit(reg: &AtomicReg, n: u8, bit: bool) {
+    let mask = 1u32.checked_shl(n as u32).unwrap_or(0);
+    if bit {
+        reg.fetch_or(mask, Relaxed);
+    } else {
+        reg.fetch_and(!mask, Relaxed);
+    }
+}
 
 // PLIC interrupt handler for RISC-V.
-// Maximal 31 interrupts and 2 contexts supported.
+// Up to 1023 interrupts and 15872 contexts supported.
+// Sources signal the PLIC through `InterruptSink`, and each context drives its own output line.
+//
+// Every source sits behind a gateway that forwards at most one request at a time, shared by all contexts.
+// The gateway follows how the source is driven:
+// - `set_level`: the first assertion becomes a request. Once forwarded, the request stays pending
+//   even if the level drops, and a still-asserted level is forwarded again on completion.
+// - `trigger_edge`: every edge becomes a request. One edge arriving while a request is in flight
+//   is remembered and forwarded on completion.
 pub struct PLIC {
-    sources: BTreeMap<usize, Arc<dyn Device>>,
-    interrupts: [PLICInterruptX32; MAXIMAL_INTERRUPT_GROUP],
-    contexts: [PLICContext; 2],
-    // when claiming: lock.
-    claim_searching: Mutex<()>,
+    num_sources: usize,
+    interrupts: Vec<PLICInterruptX32>,
+    contexts: Vec<PLICContext>,
+    outputs: Vec<IrqLine>,
+    // when updating pending, claimed or eip bits: lock.
+    lock: Mutex<()>,
 }
 impl PLIC {
-    pub fn new(devices: BTreeMap<usize, Arc<dyn Device>>) -> Self {
+    /// Create a PLIC with sources `1..=num_sources` and one context per output line.
+    pub fn new(num_sources: usize, context_outputs: Vec<IrqLine>) -> Self {
+        assert!(num_sources <= PLIC_MAX_SOURCES, "too many PLIC sources");
+        assert!(
+            context_outputs.len() <= PLIC_MAX_CONTEXTS,
+            "too many PLIC contexts"
+        );
+        let groups = num_sources / 32 + 1;
         PLIC {
-            sources: devices,
-            interrupts: Default::default(),
-            contexts: Default::default(),
-            claim_searching: Mutex::new(()),
+            num_sources,
+            interrupts: (0..groups).map(|_| Default::default()).collect(),
+            contexts: (0..context_outputs.len())
+                .map(|_| PLICContext::new(groups))
+                .collect(),
+            outputs: context_outputs,
+            lock: Mutex::new(()),
         }
     }
+    pub fn num_sources(&self) -> usize {
+        self.num_sources
+    }
+    pub fn num_contexts(&self) -> usize {
+        self.contexts.len()
+    }
+    // Bits of existing sources in a group of 32. Source 0 does not exist.
+    fn source_mask(&self, group: usize) -> u32 {
+        let mut mask = !0u32;
+        if group == 0 {
+            mask &= !1;
+        }
+        let first = group * 32;
+        if first + 32 > self.num_sources + 1 {
+            let valid = (self.num_sources + 1).saturating_sub(first);
+            mask &= ((1u64 << valid) - 1) as u32;
+        }
+        mask
+    }
+    /// Whether context `ctx` has an interrupt to take. Absent contexts never do.
     pub fn eip(&self, ctx: usize) -> bool {
         self.get_context(ctx)
-            .expect("bad context")
-            .eip
-            .load(Relaxed)
-    }
-    pub fn update_eip_for_context(&self, ctx: usize) {
-        for (irq_id, d) in self.sources.iter() {
-            let (interrupt_slice, iid) = self
-                .get_interrupt_slice(*irq_id)
-                .expect("bad device irq assignment");
-            atomic_set_bit(&interrupt_slice.pending, iid as u8, d.has_interrupt())
+            .map_or(false, |context| context.eip.load(Relaxed))
+    }
+    // Recompute eip of the context and forward it to the output line.
+    // Must be called with the lock held.
+    fn update_eip_for_context(&self, ctx: usize) {
+        let eip = self.compute_eip(ctx);
+        if let (Some(context), Some(output)) = (self.get_context(ctx), self.outputs.get(ctx)) {
+            context.eip.store(eip, Relaxed);
+            output.set_level(eip);
         }
-        //let claim_lock = self.claim_searching.read();
-        let context_slice = self.get_context(ctx).expect("bad context");
-        for igroup in 0..MAXIMAL_INTERRUPT_GROUP {
-            let enabled = context_slice.enable_bits[igroup].load(Relaxed);
-            let pending = self.interrupts[igroup].pending.load(Relaxed);
-            let claimed = context_slice.claimed_bits[igroup].load(Relaxed);
-            let enabled_pending_but_unclaimed = enabled & pending & !claimed;
-            if enabled_pending_but_unclaimed != 0 {
-                for i in 0..31 {
-                    if ((enabled_pending_but_unclaimed >> i) & 1) == 1 {
-                        let priority = self.interrupts[igroup].priority[i].load(Relaxed);
-                        let threshold = context_slice.threshold.load(Relaxed);
-                        if priority > 0 && priority > threshold {
-                            context_slice.eip.store(true, Relaxed);
-                            return;
-                        }
-                    }
-                }
-            }
+    }
+    fn update_eip_for_all_contexts(&self) {
+        for ctx in 0..self.contexts.len() {
+            self.update_eip_for_context(ctx);
         }
-        context_slice.eip.store(false, Relaxed);
     }
-    pub fn claim_interrupt(&self, ctx: usize) -> usize {
-        let claim_lock = self.claim_searching.lock();
-        let context_slice = self.get_context(ctx).expect("bad context");
-        let mut highest_priority = 0;
+    fn compute_eip(&self, ctx: usize) -> bool {
+        self.highest_pending(ctx) != 0
+    }
+    // The pending and enabled source with the highest priority above the threshold, or 0.
+    // Ties go to the lowest ID.
+    fn highest_pending(&self, ctx: usize) -> usize {
+        let context_slice = match self.get_context(ctx) {
+            Some(x) => x,
+            None => return 0,
+        };
+        let mut highest_priority = context_slice.threshold.load(Relaxed);
         let mut chosen_irq = 0;
-        for igroup in 0..MAXIMAL_INTERRUPT_GROUP {
-            let enabled = context_slice.enable_bits[igroup].load(Relaxed);
+        for igroup in 0..self.interrupts.len() {
+            let enabled = context_slice
+                .enable_bits
+                .get(igroup)
+                .map_or(0, |x| x.load(Relaxed));
             let pending = self.interrupts[igroup].pending.load(Relaxed);
-            let claimed = context_slice.claimed_bits[igroup].load(Relaxed);
-            let enabled_pending_but_unclaimed = enabled & pending & !claimed;
-            if enabled_pending_but_unclaimed != 0 {
-                for i in 0..31 {
-                    if ((enabled_pending_but_unclaimed >> i) & 1) == 1 {
+            let enabled_pending = enabled & pending;
+            if enabled_pending != 0 {
+                for i in 0..32 {
+                    if ((enabled_pending >> i) & 1) == 1 {
                         let priority = self.interrupts[igroup].priority[i].load(Relaxed);
                         if priority > highest_priority {
                             highest_priority = priority;
@@ -111,31 +162,65 @@ impl PLIC {
                 }
             }
         }
+        chosen_irq
+    }
+    // Gateway: forward a request unless one is in flight already.
+    // Must be called with the lock held.
+    fn gateway_request(&self, slice: &PLICInterruptX32, iid: usize) -> bool {
+        if atomic_get_bit(&slice.in_flight, iid as u8) {
+            return false;
+        }
+        atomic_set_bit(&slice.in_flight, iid as u8, true);
+        atomic_set_bit(&slice.pending, iid as u8, true);
+        true
+    }
+    pub fn claim_interrupt(&self, ctx: usize) -> usize {
+        let _lock = self.lock.lock();
+        let chosen_irq = self.highest_pending(ctx);
         if chosen_irq == 0 {
             return 0;
         }
-        let (chosen_slice, chosen_iid) = self.get_interrupt_slice(chosen_irq).unwrap();
-        atomic_set_bit(&chosen_slice.pending, chosen_iid as u8, false);
-        atomic_set_bit(
-            &context_slice.claimed_bits[chosen_irq / 32],
-            chosen_iid as u8,
-            true,
-        );
-        drop(claim_lock);
-        //self.update_eip_for_context(ctx);
-        return chosen_irq;
+        if let Some((chosen_slice, chosen_iid)) = self.get_interrupt_slice(chosen_irq) {
+            atomic_set_bit(&chosen_slice.pending, chosen_iid as u8, false);
+        }
+        self.update_eip_for_all_contexts();
+        chosen_irq
     }
     pub fn complete_interrupt(&self, ctx: usize, irq: usize) {
-        let context_slice = self.get_context(ctx).expect("bad context");
-        atomic_set_bit(
-            &context_slice.claimed_bits[irq / 32],
-            (irq % 32) as u8,
-            false,
-        );
-        //self.update_eip_for_context(ctx);
+        let _lock = self.lock.lock();
+        let (context_slice, (interrupt_slice, iid)) =
+            match (self.get_context(ctx), self.get_interrupt_slice(irq)) {
+                (Some(c), Some(i)) => (c, i),
+                _ => return,
+            };
+        let enabled = context_slice
+            .enable_bits
+            .get(irq / 32)
+            .map_or(false, |x| atomic_get_bit(x, iid as u8));
+        // Completions for sources not enabled for the context are silently ignored,
+        // as are completions for sources without a claimed request.
+        if !enabled
+            || !atomic_get_bit(&interrupt_slice.in_flight, iid as u8)
+            || atomic_get_bit(&interrupt_slice.pending, iid as u8)
+        {
+            return;
+        }
+        atomic_set_bit(&interrupt_slice.in_flight, iid as u8, false);
+        // A source whose line is still asserted, or that saw another edge, requests service again.
+        if atomic_get_bit(&interrupt_slice.level, iid as u8)
+            || atomic_get_bit(&interrupt_slice.edge_latched, iid as u8)
+        {
+            atomic_set_bit(&interrupt_slice.edge_latched, iid as u8, false);
+            self.gateway_request(interrupt_slice, iid);
+        }
+        self.update_eip_for_all_contexts();
     }
+    /// Locate an existing source.
     #[inline]
     pub fn get_interrupt_slice(&self, id: usize) -> Option<(&PLICInterruptX32, usize)> {
+        if id == 0 || id > self.num_sources {
+            return None;
+        }
         let slice_id = id / 32;
         let in_slice_id = id % 32;
         Some((self.interrupts.get(slice_id)?, in_slice_id))
@@ -184,31 +269,36 @@ impl PLICMemoryMap {
                 return Some(ClaimComplete { ctx });
             }
         }
-        return None;
+        None
     }
 }
 
-const VS_CONTEXT: usize = 1;
 impl Device for PLIC {
     fn as_any(&self) -> &dyn core::any::Any {
         self
     }
     fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
         use PLICMemoryMap::*;
+        if offset % 4 != 0 {
+            // misaligned access.
+            return None;
+        }
         if let Some(x) = PLICMemoryMap::parse(offset) {
             match access {
                 MMIOAccess::LoadWord(ret) => match x {
                     Priority { source } => {
-                        let (interrupt_slice, iid) = self.get_interrupt_slice(source)?;
-                        **ret = interrupt_slice.priority[iid].load(Relaxed);
+                        // Absent sources read as zero.
+                        **ret = self
+                            .get_interrupt_slice(source)
+                            .map_or(0, |(slice, iid)| slice.priority[iid].load(Relaxed));
                         return Some(true);
                     }
                     EnableX32 { source_div32, ctx } => {
                         **ret = self
                             .get_context(ctx)?
                             .enable_bits
-                            .get(source_div32)?
-                            .load(Relaxed);
+                            .get(source_div32)
+                            .map_or(0, |x| x.load(Relaxed));
                         return Some(true);
                     }
                     Threshold { ctx } => {
@@ -216,46 +306,54 @@ impl Device for PLIC {
                         return Some(true);
                     }
                     ClaimComplete { ctx } => {
+                        self.get_context(ctx)?;
                         **ret = self.claim_interrupt(ctx) as u32;
                         return Some(true);
                     }
                     PendingX32 { source_div32 } => {
-                        let (interrupt_slice, _iid) =
-                            self.get_interrupt_slice(source_div32 * 32)?;
-                        **ret = interrupt_slice.pending.load(Relaxed);
+                        **ret = self
+                            .interrupts
+                            .get(source_div32)
+                            .map_or(0, |x| x.pending.load(Relaxed));
                         return Some(true);
                     }
                 },
-                MMIOAccess::StoreWord(val) => {
-                    match x {
-                        Priority { source } => {
-                            let (interrupt_slice, iid) = self.get_interrupt_slice(source)?;
+                MMIOAccess::StoreWord(val) => match x {
+                    Priority { source } => {
+                        // Writes to absent sources are ignored.
+                        if let Some((interrupt_slice, iid)) = self.get_interrupt_slice(source) {
+                            let _lock = self.lock.lock();
                             interrupt_slice.priority[iid].store(*val, Relaxed);
-                            //for ctx in 0..self.contexts.len(){
-                            //self.update_eip_for_context(ctx);
-                            //}
-                            return Some(true);
-                        }
-                        EnableX32 { source_div32, ctx } => {
-                            let ctxs = self.get_context(ctx)?;
-                            ctxs.enable_bits.get(source_div32)?.store(*val, Relaxed);
-                            //self.update_eip_for_context(ctx);
-                            return Some(true);
-                        }
-                        Threshold { ctx } => {
-                            self.get_context(ctx)?.threshold.store(*val, Relaxed);
-                            //self.update_eip_for_context(ctx);
-                            return Some(true);
+                            self.update_eip_for_all_contexts();
                         }
-                        ClaimComplete { ctx } => {
-                            self.complete_interrupt(ctx, (*val) as usize);
-                            return Some(true);
-                        }
-                        _ => {
-                            return None;
+                        return Some(true);
+                    }
+                    EnableX32 { source_div32, ctx } => {
+                        let ctxs = self.get_context(ctx)?;
+                        if let Some(enable) = ctxs.enable_bits.get(source_div32) {
+                            let _lock = self.lock.lock();
+                            enable.store(*val & self.source_mask(source_div32), Relaxed);
+                            self.update_eip_for_context(ctx);
                         }
+                        return Some(true);
                     }
-                }
+                    Threshold { ctx } => {
+                        let ctxs = self.get_context(ctx)?;
+                        let _lock = self.lock.lock();
+                        ctxs.threshold.store(*val, Relaxed);
+                        self.update_eip_for_context(ctx);
+                        return Some(true);
+                    }
+                    ClaimComplete { ctx } => {
+                        self.get_context(ctx)?;
+                        self.complete_interrupt(ctx, (*val) as usize);
+                        return Some(true);
+                    }
+                    PendingX32 { .. } => {
+                        // Pending bits are read-only.
+                        return Some(true);
+                    }
+                },
                 _ => {
                     // malformed access.
                     return None;
@@ -269,8 +367,55 @@ impl Device for PLIC {
         0x4000000
     }
     fn has_interrupt(&self) -> bool {
-        self.update_eip_for_context(VS_CONTEXT);
-        self.eip(VS_CONTEXT)
+        self.contexts.iter().any(|ctx| ctx.eip.load(Relaxed))
+    }
+}
+
+impl DtDevice for PLIC {
+    fn dt_name(&self) -> &str {
+        "plic"
+    }
+    // Up to the registers of the last context.
+    fn dt_reg_size(&self) -> u64 {
+        PLIC_CONTEXT_BASE + PLIC_CONTEXT_SIZE * self.num_contexts() as u64
+    }
+    // Contexts 2i and 2i+1 are M-mode and S-mode of hart i. The guest never sees M-mode.
+    fn write_dt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("compatible", "riscv,plic0");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_null("interrupt-controller");
+        fdt.property_cells(
+            "interrupts-extended",
+            &hart_interrupts(self.num_contexts() / 2, &[None, Some(IRQ_S_EXT)]),
+        );
+        fdt.property_u32("riscv,ndev", self.num_sources as u32);
+    }
+}
+
+impl InterruptSink for PLIC {
+    fn set_level(&self, id: usize, level: bool) {
+        let (interrupt_slice, iid) = match self.get_interrupt_slice(id) {
+            Some(x) => x,
+            None => return,
+        };
+        let _lock = self.lock.lock();
+        atomic_set_bit(&interrupt_slice.level, iid as u8, level);
+        if level && self.gateway_request(interrupt_slice, iid) {
+            self.update_eip_for_all_contexts();
+        }
+    }
+    fn trigger_edge(&self, id: usize) {
+        let (interrupt_slice, iid) = match self.get_interrupt_slice(id) {
+            Some(x) => x,
+            None => return,
+        };
+        let _lock = self.lock.lock();
+        if self.gateway_request(interrupt_slice, iid) {
+            self.update_eip_for_all_contexts();
+        } else {
+            atomic_set_bit(&interrupt_slice.edge_latched, iid as u8, true);
+        }
     }
 }
 
@@ -302,23 +447,21 @@ mod test {
             Some(())
         }
     }
-    struct MockDevice(Arc<AtomicBool>);
-    impl Device for MockDevice {
-        fn as_any(&self) -> &dyn core::any::Any {
-            self
-        }
-        fn has_interrupt(&self) -> bool {
-            self.0.load(Relaxed)
-        }
-    }
+    use crate::irq::{IrqLatch, SharedIrq};
+    use alloc::sync::Arc;
     #[test]
     fn basic_test() {
-        let flag = Arc::new(AtomicBool::new(false));
-        let mock: Arc<dyn Device> = Arc::new(MockDevice(Arc::clone(&flag)));
         const INTERRUPT_ID: usize = 10;
-        let mut tree = BTreeMap::new();
-        tree.insert(INTERRUPT_ID, mock);
-        let plic = PLIC::new(tree);
+        let latch = Arc::new(IrqLatch::new());
+        let plic = Arc::new(PLIC::new(
+            31,
+            vec![
+                IrqLine::disconnected(),
+                IrqLine::new(Arc::clone(&latch) as Arc<dyn InterruptSink>, 0),
+            ],
+        ));
+        let line = IrqLine::new(Arc::clone(&plic) as Arc<dyn InterruptSink>, INTERRUPT_ID);
+        assert_eq!(latch.take_changes(), Some(0));
         assert_eq!(
             plic.has_interrupt(),
             false,
@@ -329,8 +472,10 @@ mod test {
             .unwrap(); // enable irq for context 1.
         plic.store(INTERRUPT_ID * 4, 7).unwrap();
         assert_eq!(plic.has_interrupt(), false, "Still no interrupt.");
-        flag.store(true, Relaxed);
+        assert_eq!(latch.take_changes(), None, "Output untouched.");
+        line.raise();
         assert_eq!(plic.has_interrupt(), true, "Interrupt captured.");
+        assert_eq!(latch.take_changes(), Some(1), "Output raised.");
         let pending = plic.load(0x1000).unwrap();
         assert_eq!(pending, 1 << INTERRUPT_ID, "The irq is pending.");
         let claim = plic.load(0x201004).unwrap();
@@ -340,7 +485,8 @@ mod test {
             false,
             "Interrupt captured but claimed."
         );
-        plic.store(0x201004, claim);
+        assert_eq!(latch.take_changes(), Some(0), "Output lowered.");
+        plic.store(0x201004, claim).unwrap();
         assert_eq!(
             plic.has_interrupt(),
             true,
@@ -355,10 +501,270 @@ mod test {
         );
         let another_claim = plic.load(0x201004).unwrap();
         assert_eq!(another_claim, 0, "Can't claim one interrupt again.");
-        flag.store(false, Relaxed);
-        plic.store(0x201004, claim);
+        line.lower();
+        plic.store(0x201004, claim).unwrap();
         assert_eq!(plic.has_interrupt(), false, "Really no interrupt.");
         let claim = plic.load(0x201004).unwrap();
         assert_eq!(claim, 0, "No interrupt.");
     }
+    #[test]
+    fn many_sources_and_contexts() {
+        const CONTEXTS: usize = 5;
+        let latch = Arc::new(IrqLatch::new());
+        let plic = Arc::new(PLIC::new(
+            PLIC_MAX_SOURCES,
+            (0..CONTEXTS)
+                .map(|ctx| IrqLine::new(Arc::clone(&latch) as Arc<dyn InterruptSink>, ctx))
+                .collect(),
+        ));
+        assert_eq!(plic.num_sources(), PLIC_MAX_SOURCES);
+        assert_eq!(plic.num_contexts(), CONTEXTS);
+        let sink = Arc::clone(&plic) as Arc<dyn InterruptSink>;
+        let high = IrqLine::new(Arc::clone(&sink), PLIC_MAX_SOURCES);
+        let edge = IrqLine::new(Arc::clone(&sink), 63);
+        // Context 4: enable 1023 and 63.
+        let ctx4_enable = 0x2000 + 4 * 0x80;
+        plic.store(ctx4_enable + 31 * 4, !0).unwrap();
+        assert_eq!(
+            plic.load(ctx4_enable + 31 * 4).unwrap(),
+            !0,
+            "Sources 992 to 1023 exist."
+        );
+        plic.store(ctx4_enable + 4, 1 << 31).unwrap();
+        plic.store(ctx4_enable, !0).unwrap();
+        assert_eq!(plic.load(ctx4_enable).unwrap() & 1, 0, "Source 0 absent.");
+        plic.store(PLIC_MAX_SOURCES * 4, 1).unwrap();
+        plic.store(63 * 4, 2).unwrap();
+        assert_eq!(plic.load(PLIC_MAX_SOURCES * 4).unwrap(), 1);
+        plic.store(0, 5).unwrap();
+        assert_eq!(plic.load(0).unwrap(), 0, "Source 0 has no priority.");
+        latch.take_changes();
+        high.raise();
+        edge.pulse();
+        assert_eq!(latch.take_changes(), Some(1 << 4), "Only context 4 fires.");
+        assert_eq!(plic.load(0x1000 + 31 * 4).unwrap(), 1 << 31);
+        assert_eq!(plic.load(0x1000 + 4).unwrap(), 1 << 31);
+        let claim = 0x200000 + 4 * 0x1000 + 4;
+        assert_eq!(plic.load(claim).unwrap(), 63, "Higher priority first.");
+        assert_eq!(plic.load(claim).unwrap(), PLIC_MAX_SOURCES as u32);
+        assert_eq!(plic.load(claim).unwrap(), 0);
+        assert_eq!(latch.take_changes(), Some(0));
+        plic.store(claim, 63).unwrap();
+        plic.store(claim, PLIC_MAX_SOURCES as u32).unwrap();
+        assert_eq!(
+            latch.take_changes(),
+            Some(1 << 4),
+            "Level source re-pended."
+        );
+        high.lower();
+        assert_eq!(latch.take_changes(), None, "Forwarded request stays.");
+        assert_eq!(plic.load(claim).unwrap(), PLIC_MAX_SOURCES as u32);
+        plic.store(claim, PLIC_MAX_SOURCES as u32).unwrap();
+        assert_eq!(latch.take_changes(), Some(0));
+        assert!(!plic.has_interrupt());
+    }
+    // Conformance tests for gateway, threshold and claim semantics.
+    fn conformance_setup(contexts: usize) -> (Arc<PLIC>, Arc<IrqLatch>) {
+        let latch = Arc::new(IrqLatch::new());
+        let plic = Arc::new(PLIC::new(
+            63,
+            (0..contexts)
+                .map(|ctx| IrqLine::new(Arc::clone(&latch) as Arc<dyn InterruptSink>, ctx))
+                .collect(),
+        ));
+        latch.take_changes();
+        (plic, latch)
+    }
+    fn line(plic: &Arc<PLIC>, id: usize) -> IrqLine {
+        IrqLine::new(Arc::clone(plic) as Arc<dyn InterruptSink>, id)
+    }
+    fn enable(plic: &PLIC, ctx: usize, id: usize) {
+        let reg = 0x2000 + ctx * 0x80 + id / 32 * 4;
+        plic.store(reg, plic.load(reg).unwrap() | 1 << (id % 32))
+            .unwrap();
+    }
+    fn claim(plic: &PLIC, ctx: usize) -> usize {
+        plic.load(0x200004 + ctx * 0x1000).unwrap() as usize
+    }
+    fn complete(plic: &PLIC, ctx: usize, id: usize) {
+        plic.store(0x200004 + ctx * 0x1000, id as u32).unwrap();
+    }
+    #[test]
+    fn conformance_threshold() {
+        let (plic, latch) = conformance_setup(1);
+        let src = line(&plic, 5);
+        enable(&plic, 0, 5);
+        plic.store(5 * 4, 3).unwrap();
+        plic.store(0x200000, 3).unwrap();
+        src.raise();
+        assert_eq!(
+            latch.take_changes(),
+            None,
+            "Priority equal to threshold is masked."
+        );
+        assert_eq!(claim(&plic, 0), 0, "Masked sources cannot be claimed.");
+        assert_eq!(plic.load(0x1000).unwrap(), 1 << 5, "But stay pending.");
+        plic.store(0x200000, 2).unwrap();
+        assert_eq!(
+            latch.take_changes(),
+            Some(1),
+            "Lowering the threshold unmasks."
+        );
+        assert_eq!(claim(&plic, 0), 5);
+        complete(&plic, 0, 5);
+        plic.store(5 * 4, 0).unwrap();
+        plic.store(0x200000, 0).unwrap();
+        assert_eq!(claim(&plic, 0), 0, "Priority 0 never interrupts.");
+    }
+    #[test]
+    fn conformance_tie_breaking() {
+        let (plic, _latch) = conformance_setup(1);
+        let lines: Vec<IrqLine> = [40, 7, 33, 12].iter().map(|id| line(&plic, *id)).collect();
+        for l in lines.iter() {
+            enable(&plic, 0, l.id());
+            plic.store(l.id() * 4, 2).unwrap();
+            l.raise();
+        }
+        plic.store(33 * 4, 4).unwrap();
+        assert_eq!(claim(&plic, 0), 33, "Highest priority first.");
+        assert_eq!(claim(&plic, 0), 7, "Lowest ID among equals.");
+        assert_eq!(claim(&plic, 0), 12);
+        assert_eq!(claim(&plic, 0), 40);
+        assert_eq!(claim(&plic, 0), 0);
+    }
+    #[test]
+    fn conformance_level_gateway() {
+        let (plic, latch) = conformance_setup(2);
+        let src = line(&plic, 9);
+        enable(&plic, 0, 9);
+        enable(&plic, 1, 9);
+        plic.store(9 * 4, 1).unwrap();
+        src.raise();
+        assert_eq!(latch.take_changes(), Some(0b11), "Both contexts notified.");
+        assert_eq!(claim(&plic, 1), 9);
+        assert_eq!(
+            latch.take_changes(),
+            Some(0),
+            "Claim clears pending everywhere."
+        );
+        assert_eq!(
+            claim(&plic, 0),
+            0,
+            "One outstanding request across contexts."
+        );
+        src.lower();
+        src.raise();
+        assert_eq!(
+            latch.take_changes(),
+            None,
+            "No new request before completion."
+        );
+        complete(&plic, 1, 9);
+        assert_eq!(
+            latch.take_changes(),
+            Some(0b11),
+            "Still asserted: new request."
+        );
+        assert_eq!(claim(&plic, 0), 9);
+        src.lower();
+        complete(&plic, 0, 9);
+        assert_eq!(latch.take_changes(), Some(0));
+        assert_eq!(plic.load(0x1000).unwrap(), 0);
+        src.raise();
+        src.lower();
+        assert_eq!(
+            plic.load(0x1000).unwrap(),
+            1 << 9,
+            "A forwarded request survives deassertion."
+        );
+        assert_eq!(claim(&plic, 0), 9);
+        complete(&plic, 0, 9);
+        assert_eq!(plic.load(0x1000).unwrap(), 0);
+    }
+    #[test]
+    fn conformance_edge_gateway() {
+        let (plic, latch) = conformance_setup(1);
+        let src = line(&plic, 40);
+        enable(&plic, 0, 40);
+        plic.store(40 * 4, 1).unwrap();
+        src.pulse();
+        assert_eq!(latch.take_changes(), Some(1));
+        assert_eq!(claim(&plic, 0), 40);
+        src.pulse();
+        src.pulse();
+        assert_eq!(latch.take_changes(), Some(0), "Edges wait for completion.");
+        complete(&plic, 0, 40);
+        assert_eq!(latch.take_changes(), Some(1), "Latched edge forwarded.");
+        assert_eq!(claim(&plic, 0), 40);
+        complete(&plic, 0, 40);
+        assert_eq!(latch.take_changes(), Some(0), "Only one edge remembered.");
+        assert_eq!(claim(&plic, 0), 0);
+    }
+    #[test]
+    fn conformance_completion() {
+        let (plic, latch) = conformance_setup(2);
+        let src = line(&plic, 3);
+        enable(&plic, 0, 3);
+        plic.store(3 * 4, 1).unwrap();
+        src.raise();
+        complete(&plic, 0, 3);
+        assert_eq!(
+            claim(&plic, 0),
+            3,
+            "Completing an unclaimed source is ignored."
+        );
+        src.lower();
+        complete(&plic, 1, 3);
+        assert_eq!(latch.take_changes(), Some(0));
+        src.raise();
+        assert_eq!(
+            latch.take_changes(),
+            None,
+            "Completion from a context without the source enabled is ignored."
+        );
+        complete(&plic, 0, 3);
+        assert_eq!(latch.take_changes(), Some(1), "Completed by the claimer.");
+    }
+    #[test]
+    fn malformed_access() {
+        let (plic, latch) = conformance_setup(2);
+        assert_eq!(plic.load(0x200004 + 2 * 0x1000), None, "Absent context.");
+        assert_eq!(
+            plic.store(0x200004 + 2 * 0x1000, 1),
+            None,
+            "Absent context."
+        );
+        assert_eq!(plic.load(0x2), None, "Misaligned.");
+        assert_eq!(plic.load(0x2000 + 2 * 0x80), None, "Absent context.");
+        plic.store(0x1000, !0).unwrap();
+        assert_eq!(plic.load(0x1000), Some(0), "Pending bits are read-only.");
+        complete(&plic, 0, 0);
+        complete(&plic, 0, 64);
+        complete(&plic, 0, u32::max_value() as usize);
+        plic.set_level(usize::max_value(), true);
+        assert_eq!(latch.take_changes(), None, "Garbage is ignored.");
+    }
+    #[test]
+    fn shared_source() {
+        let (plic, latch) = conformance_setup(1);
+        let shared = SharedIrq::new(line(&plic, 4));
+        let (a, b) = (shared.attach(), shared.attach());
+        enable(&plic, 0, 4);
+        plic.store(4 * 4, 1).unwrap();
+        a.raise();
+        b.raise();
+        assert_eq!(claim(&plic, 0), 4);
+        // The handler services device a only.
+        a.lower();
+        complete(&plic, 0, 4);
+        assert_eq!(
+            claim(&plic, 0),
+            4,
+            "Requested again while device b is asserted."
+        );
+        b.lower();
+        complete(&plic, 0, 4);
+        assert_eq!(claim(&plic, 0), 0);
+        assert_eq!(latch.take_changes(), Some(0));
+    }
 }
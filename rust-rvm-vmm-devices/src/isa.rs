@@ -0,0 +1,272 @@
+// RISC-V instruction formats: opcodes, encoders, immediate decoders, the AMO operations, and the
+// expansion of compressed instructions into the 32-bit instructions they stand for. Shared by the
+// VMM, which decodes the instructions making MMIO accesses, and the interpreter.
+
+pub const LOAD: u32 = 0x03;
+pub const LOAD_FP: u32 = 0x07;
+pub const MISC_MEM: u32 = 0x0f;
+pub const OP_IMM: u32 = 0x13;
+pub const AUIPC: u32 = 0x17;
+pub const OP_IMM_32: u32 = 0x1b;
+pub const STORE: u32 = 0x23;
+pub const STORE_FP: u32 = 0x27;
+pub const AMO: u32 = 0x2f;
+pub const OP: u32 = 0x33;
+pub const LUI: u32 = 0x37;
+pub const OP_32: u32 = 0x3b;
+pub const MADD: u32 = 0x43;
+pub const MSUB: u32 = 0x47;
+pub const NMSUB: u32 = 0x4b;
+pub const NMADD: u32 = 0x4f;
+pub const OP_FP: u32 = 0x53;
+pub const BRANCH: u32 = 0x63;
+pub const JALR: u32 = 0x67;
+pub const JAL: u32 = 0x6f;
+pub const SYSTEM: u32 = 0x73;
+
+pub const ECALL: u32 = 0x0000_0073;
+pub const EBREAK: u32 = 0x0010_0073;
+pub const SRET: u32 = 0x1020_0073;
+pub const WFI: u32 = 0x1050_0073;
+
+pub fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+pub fn i_type(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm as u32 & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+pub fn s_type(imm: i32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | opcode
+}
+
+pub fn b_type(imm: i32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | opcode
+}
+
+pub fn u_type(imm: i32, rd: u32, opcode: u32) -> u32 {
+    imm as u32 & 0xffff_f000 | rd << 7 | opcode
+}
+
+pub fn j_type(imm: i32, rd: u32, opcode: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+        | rd << 7
+        | opcode
+}
+
+pub fn imm_i(inst: u32) -> i64 {
+    (inst as i32 >> 20) as i64
+}
+
+pub fn imm_s(inst: u32) -> i64 {
+    ((inst as i32 >> 25) << 5 | (inst >> 7 & 0x1f) as i32) as i64
+}
+
+pub fn imm_b(inst: u32) -> i64 {
+    ((inst as i32 >> 31) << 12
+        | ((inst >> 7 & 1) << 11) as i32
+        | ((inst >> 25 & 0x3f) << 5) as i32
+        | ((inst >> 8 & 0xf) << 1) as i32) as i64
+}
+
+pub fn imm_u(inst: u32) -> i64 {
+    (inst & 0xffff_f000) as i32 as i64
+}
+
+pub fn imm_j(inst: u32) -> i64 {
+    ((inst as i32 >> 31) << 20
+        | (inst & 0x000f_f000) as i32
+        | ((inst >> 20 & 1) << 11) as i32
+        | ((inst >> 21 & 0x3ff) << 1) as i32) as i64
+}
+
+/// What the AMO with `funct5` on `size` bytes stores, given the old value and its operand. None
+/// for LR, SC and reserved encodings. Word operands are compared signed or unsigned as they should.
+pub fn amo(funct5: u32, size: u64) -> Option<impl Fn(u64, u64) -> u64> {
+    let op: fn(u64, u64) -> u64 = match funct5 {
+        0x00 => |old, b| old.wrapping_add(b),
+        0x01 => |_, b| b,
+        0x04 => |old, b| old ^ b,
+        0x08 => |old, b| old | b,
+        0x0c => |old, b| old & b,
+        0x10 => |old, b| (old as i64).min(b as i64) as u64,
+        0x14 => |old, b| (old as i64).max(b as i64) as u64,
+        0x18 => |old, b| old.min(b),
+        0x1c => |old, b| old.max(b),
+        _ => return None,
+    };
+    let operand = move |val: u64| match (size, funct5) {
+        (4, 0x10) | (4, 0x14) => val as i32 as u64,
+        (4, _) => val as u32 as u64,
+        _ => val,
+    };
+    Some(move |old, b| op(operand(old), operand(b)))
+}
+
+/// The 32-bit instruction that a compressed RV64C instruction stands for, or None if it is
+/// illegal or reserved.
+pub fn expand_compressed(c: u16) -> Option<u32> {
+    let c = c as u32;
+    let b = |hi: u32, lo: u32| c >> lo & ((1 << (hi - lo + 1)) - 1);
+    let rd = b(11, 7);
+    let rs2 = b(6, 2);
+    // The 3-bit register fields name x8 to x15.
+    let rdp = b(4, 2) + 8;
+    let rs1p = b(9, 7) + 8;
+    let imm6 = ((b(12, 12) << 5 | b(6, 2)) as i32) << 26 >> 26;
+    let shamt = (b(12, 12) << 5 | b(6, 2)) as i32;
+    // Offsets of word and doubleword loads and stores.
+    let offset_w = (b(5, 5) << 6 | b(12, 10) << 3 | b(6, 6) << 2) as i32;
+    let offset_d = (b(6, 5) << 6 | b(12, 10) << 3) as i32;
+    let offset_j = ((b(12, 12) << 11
+        | b(8, 8) << 10
+        | b(10, 9) << 8
+        | b(6, 6) << 7
+        | b(7, 7) << 6
+        | b(2, 2) << 5
+        | b(11, 11) << 4
+        | b(5, 3) << 1) as i32)
+        << 20
+        >> 20;
+    let offset_b = ((b(12, 12) << 8 | b(6, 5) << 6 | b(2, 2) << 5 | b(11, 10) << 3 | b(4, 3) << 1)
+        as i32)
+        << 23
+        >> 23;
+    let inst = match (b(1, 0), b(15, 13)) {
+        // c.addi4spn. A zero immediate is reserved, which also makes the all-zero parcel illegal.
+        (0, 0) => {
+            let imm = b(10, 7) << 6 | b(12, 11) << 4 | b(5, 5) << 3 | b(6, 6) << 2;
+            if imm == 0 {
+                return None;
+            }
+            i_type(imm as i32, 2, 0, rdp, OP_IMM)
+        }
+        (0, 1) => i_type(offset_d, rs1p, 3, rdp, LOAD_FP),
+        (0, 2) => i_type(offset_w, rs1p, 2, rdp, LOAD),
+        (0, 3) => i_type(offset_d, rs1p, 3, rdp, LOAD),
+        (0, 5) => s_type(offset_d, rdp, rs1p, 3, STORE_FP),
+        (0, 6) => s_type(offset_w, rdp, rs1p, 2, STORE),
+        (0, 7) => s_type(offset_d, rdp, rs1p, 3, STORE),
+        (1, 0) => i_type(imm6, rd, 0, rd, OP_IMM),
+        (1, 1) if rd != 0 => i_type(imm6, rd, 0, rd, OP_IMM_32),
+        (1, 2) => i_type(imm6, 0, 0, rd, OP_IMM),
+        // c.addi16sp
+        (1, 3) if rd == 2 => {
+            let imm = ((b(12, 12) << 9 | b(4, 3) << 7 | b(5, 5) << 6 | b(2, 2) << 5 | b(6, 6) << 4)
+                as i32)
+                << 22
+                >> 22;
+            if imm == 0 {
+                return None;
+            }
+            i_type(imm, 2, 0, 2, OP_IMM)
+        }
+        (1, 3) if imm6 != 0 => u_type(imm6 << 12, rd, LUI),
+        (1, 4) => match b(11, 10) {
+            0 => i_type(shamt, rs1p, 5, rs1p, OP_IMM),
+            1 => i_type(0x400 | shamt, rs1p, 5, rs1p, OP_IMM),
+            2 => i_type(imm6, rs1p, 7, rs1p, OP_IMM),
+            _ => {
+                let (funct7, funct3, opcode) = match (b(12, 12), b(6, 5)) {
+                    (0, 0) => (0x20, 0, OP),
+                    (0, 1) => (0, 4, OP),
+                    (0, 2) => (0, 6, OP),
+                    (0, 3) => (0, 7, OP),
+                    (1, 0) => (0x20, 0, OP_32),
+                    (1, 1) => (0, 0, OP_32),
+                    _ => return None,
+                };
+                r_type(funct7, rdp, rs1p, funct3, rs1p, opcode)
+            }
+        },
+        (1, 5) => j_type(offset_j, 0, JAL),
+        (1, 6) => b_type(offset_b, 0, rs1p, 0, BRANCH),
+        (1, 7) => b_type(offset_b, 0, rs1p, 1, BRANCH),
+        (2, 0) => i_type(shamt, rd, 1, rd, OP_IMM),
+        (2, 1) => i_type(
+            (b(4, 2) << 6 | b(12, 12) << 5 | b(6, 5) << 3) as i32,
+            2,
+            3,
+            rd,
+            LOAD_FP,
+        ),
+        (2, 2) if rd != 0 => i_type(
+            (b(3, 2) << 6 | b(12, 12) << 5 | b(6, 4) << 2) as i32,
+            2,
+            2,
+            rd,
+            LOAD,
+        ),
+        (2, 3) if rd != 0 => i_type(
+            (b(4, 2) << 6 | b(12, 12) << 5 | b(6, 5) << 3) as i32,
+            2,
+            3,
+            rd,
+            LOAD,
+        ),
+        (2, 4) => match (b(12, 12), rd, rs2) {
+            (0, 0, 0) => return None,
+            // c.jr
+            (0, _, 0) => i_type(0, rd, 0, 0, JALR),
+            // c.mv
+            (0, _, _) => r_type(0, rs2, 0, 0, rd, OP),
+            (_, 0, 0) => EBREAK,
+            // c.jalr
+            (_, _, 0) => i_type(0, rd, 0, 1, JALR),
+            // c.add
+            _ => r_type(0, rs2, rd, 0, rd, OP),
+        },
+        (2, 5) => s_type((b(9, 7) << 6 | b(12, 10) << 3) as i32, rs2, 2, 3, STORE_FP),
+        (2, 6) => s_type((b(8, 7) << 6 | b(12, 9) << 2) as i32, rs2, 2, 2, STORE),
+        (2, 7) => s_type((b(9, 7) << 6 | b(12, 10) << 3) as i32, rs2, 2, 3, STORE),
+        _ => return None,
+    };
+    Some(inst)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn compressed() {
+        // Parcels and the instructions they expand to, as encoded by an assembler.
+        let expansions = [
+            (0x0505, 0x0015_0513), // c.addi a0, 1
+            (0x0592, 0x0045_9593), // c.slli a1, 4
+            (0x4522, 0x0081_2503), // c.lwsp a0, 8(sp)
+            (0xe806, 0x0011_3823), // c.sdsp ra, 16(sp)
+            (0xbff5, 0xffdf_f06f), // c.j -4
+            (0xc019, 0x0004_0363), // c.beqz s0, 6
+            (0x7139, 0xfc01_0113), // c.addi16sp sp, -64
+            (0x0808, 0x0101_0513), // c.addi4spn a0, sp, 16
+            (0x858d, 0x4035_d593), // c.srai a1, 3
+            (0x9d0d, 0x40b5_053b), // c.subw a0, a1
+            (0x2588, 0x0085_b507), // c.fld fa0, 8(a1)
+            (0x7505, 0xfffe_1537), // c.lui a0, 0xfffe1
+            (0x9502, 0x0005_00e7), // c.jalr a0
+            (0x852e, 0x00b0_0533), // c.mv a0, a1
+            (0x9002, EBREAK),      // c.ebreak
+        ];
+        for &(parcel, inst) in expansions.iter() {
+            assert_eq!(expand_compressed(parcel), Some(inst), "{:#06x}", parcel);
+        }
+        // The all-zero parcel, c.addi16sp 0, c.lwsp to x0, and c.jr x0.
+        for &parcel in [0x0000, 0x6101, 0x4002, 0x8002].iter() {
+            assert_eq!(expand_compressed(parcel), None, "{:#06x}", parcel);
+        }
+    }
+}
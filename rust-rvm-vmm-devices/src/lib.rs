@@ -2,8 +2,17 @@
 #![feature(no_more_cas)]
 pub mod board;
 pub mod device;
+pub mod fdt;
+pub mod hypervisor;
 pub mod irq;
+pub mod isa;
+pub mod power;
+pub mod sbi;
 pub mod serial;
+pub mod timer;
+pub mod vcpu;
+pub mod virtio;
+pub mod vmm;
 
 pub use device::*;
 
@@ -0,0 +1,29 @@
+use crate::sbi::ResetType;
+use spin::Mutex;
+pub mod sifive_test;
+
+/// How the guest asked for the machine to be reset, whether through SBI or a device.
+/// The VMM stops every hart once there is a request. Only the first one counts.
+pub struct ResetRequest(Mutex<Option<(ResetType, u32)>>);
+
+impl ResetRequest {
+    pub fn new() -> Self {
+        ResetRequest(Mutex::new(None))
+    }
+    /// Ask for a reset of type `reset_type`. `reason` becomes the exit status of the VMM.
+    pub fn request(&self, reset_type: ResetType, reason: u32) {
+        let mut request = self.0.lock();
+        if request.is_none() {
+            *request = Some((reset_type, reason));
+        }
+    }
+    pub fn get(&self) -> Option<(ResetType, u32)> {
+        *self.0.lock()
+    }
+}
+
+impl Default for ResetRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
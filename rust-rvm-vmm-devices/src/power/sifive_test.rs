@@ -0,0 +1,108 @@
+use super::ResetRequest;
+use crate::board::DtDevice;
+use crate::fdt::FdtWriter;
+use crate::sbi::ResetType;
+use crate::*;
+use alloc::format;
+use alloc::sync::Arc;
+pub const SIFIVE_TEST_MMIO_SIZE: usize = 0x1000;
+// Low half of the finisher register. A failure code goes in the high half.
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+// The test finisher of the QEMU `virt` machine: one write-only register at offset 0 that
+// powers off or resets the machine. Linux drives it as a syscon through the `syscon-poweroff`
+// and `syscon-reboot` nodes next to it.
+pub struct SifiveTest {
+    reset: Arc<ResetRequest>,
+}
+
+impl SifiveTest {
+    pub fn new(reset: Arc<ResetRequest>) -> Self {
+        SifiveTest { reset }
+    }
+}
+
+impl Device for SifiveTest {
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+    fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        if offset >= SIFIVE_TEST_MMIO_SIZE {
+            return Some(false);
+        }
+        match access {
+            // Only the finisher is there. Everything else reads as zero and ignores writes.
+            MMIOAccess::LoadWord(ret) => **ret = 0,
+            MMIOAccess::StoreWord(val) if offset == 0 => match *val & 0xffff {
+                FINISHER_PASS => self.reset.request(ResetType::Shutdown, 0),
+                // As with QEMU, the code becomes the exit status.
+                FINISHER_FAIL => self.reset.request(ResetType::Shutdown, *val >> 16),
+                FINISHER_RESET => self.reset.request(ResetType::ColdReboot, 0),
+                _ => {}
+            },
+            MMIOAccess::StoreWord(_) => {}
+            _ => {
+                // malformed access.
+                return None;
+            }
+        }
+        Some(true)
+    }
+    fn mmio_region_size(&self) -> usize {
+        SIFIVE_TEST_MMIO_SIZE
+    }
+}
+
+impl DtDevice for SifiveTest {
+    fn dt_name(&self) -> &str {
+        "test"
+    }
+    fn write_dt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property("compatible", b"sifive,test1\0sifive,test0\0syscon\0");
+    }
+    fn write_dt_siblings(&self, fdt: &mut FdtWriter, phandle: u32) {
+        for &(name, value) in [("poweroff", FINISHER_PASS), ("reboot", FINISHER_RESET)].iter() {
+            fdt.begin_node(name);
+            fdt.property_string("compatible", &format!("syscon-{}", name));
+            fdt.property_u32("regmap", phandle);
+            fdt.property_u32("offset", 0);
+            fdt.property_u32("value", value);
+            fdt.end_node();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn store(device: &SifiveTest, offset: usize, val: u32) -> Option<bool> {
+        device.handle_mmio(offset, &mut MMIOAccess::StoreWord(val))
+    }
+    #[test]
+    fn finisher() {
+        let reset = Arc::new(ResetRequest::new());
+        let device = SifiveTest::new(Arc::clone(&reset));
+        // Unknown values and other registers do nothing.
+        assert_eq!(store(&device, 0, 0x1234), Some(true));
+        assert_eq!(store(&device, 4, FINISHER_PASS), Some(true));
+        assert_eq!(
+            device.handle_mmio(0, &mut MMIOAccess::StoreByte(0x55)),
+            None
+        );
+        assert_eq!(
+            store(&device, SIFIVE_TEST_MMIO_SIZE, FINISHER_PASS),
+            Some(false)
+        );
+        assert_eq!(reset.get(), None);
+        assert_eq!(store(&device, 0, 3 << 16 | FINISHER_FAIL), Some(true));
+        assert_eq!(reset.get(), Some((ResetType::Shutdown, 3)));
+        // The first request stands.
+        store(&device, 0, FINISHER_PASS);
+        assert_eq!(reset.get(), Some((ResetType::Shutdown, 3)));
+        let reset = Arc::new(ResetRequest::new());
+        store(&SifiveTest::new(Arc::clone(&reset)), 0, FINISHER_RESET);
+        assert_eq!(reset.get(), Some((ResetType::ColdReboot, 0)));
+    }
+}
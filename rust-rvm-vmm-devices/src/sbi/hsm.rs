@@ -0,0 +1,103 @@
+use super::*;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use spin::Mutex;
+
+struct HartState {
+    status: usize,
+    // Start address and opaque argument of a pending start.
+    start: (usize, usize),
+}
+
+/// HSM states of the harts of a VM, shared by the threads running them.
+/// Harts begin stopped. A hart is started by `start`, from the VMM for the boot hart or from
+/// another hart through `sbi_hart_start`, and its thread picks the request up with `take_start`.
+pub struct HartStates {
+    harts: Vec<Mutex<HartState>>,
+    halted: AtomicBool,
+}
+
+impl HartStates {
+    pub fn new(num_harts: usize) -> Self {
+        HartStates {
+            harts: (0..num_harts)
+                .map(|_| {
+                    Mutex::new(HartState {
+                        status: SBI_HSM_STOPPED,
+                        start: (0, 0),
+                    })
+                })
+                .collect(),
+            halted: AtomicBool::new(false),
+        }
+    }
+    pub fn num_harts(&self) -> usize {
+        self.harts.len()
+    }
+    /// Request a stopped hart to start at `start_addr`, with its id in a0 and `opaque` in a1.
+    pub fn start(&self, hart: usize, start_addr: usize, opaque: usize) -> SbiRet {
+        let mut state = match self.harts.get(hart) {
+            Some(state) => state.lock(),
+            None => return SbiRet::error(SBI_ERR_INVALID_PARAM),
+        };
+        if state.status != SBI_HSM_STOPPED {
+            return SbiRet::error(SBI_ERR_ALREADY_AVAILABLE);
+        }
+        state.status = SBI_HSM_START_PENDING;
+        state.start = (start_addr, opaque);
+        SbiRet::success(0)
+    }
+    pub fn status(&self, hart: usize) -> SbiRet {
+        match self.harts.get(hart) {
+            Some(state) => SbiRet::success(state.lock().status),
+            None => SbiRet::error(SBI_ERR_INVALID_PARAM),
+        }
+    }
+    /// Called by the thread of `hart`: the start address and opaque argument of a pending start,
+    /// after which the hart is started.
+    pub fn take_start(&self, hart: usize) -> Option<(usize, usize)> {
+        let mut state = self.harts[hart].lock();
+        if state.status != SBI_HSM_START_PENDING {
+            return None;
+        }
+        state.status = SBI_HSM_STARTED;
+        Some(state.start)
+    }
+    /// Called by the thread of `hart` after the hart stopped itself.
+    pub fn stop(&self, hart: usize) {
+        let mut state = self.harts[hart].lock();
+        state.status = SBI_HSM_STOPPED;
+    }
+    /// Stop the whole VM, e.g. on a system reset. Every thread should return.
+    pub fn halt(&self) {
+        self.halted.store(true, SeqCst);
+    }
+    pub fn halted(&self) -> bool {
+        self.halted.load(SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn hart_states() {
+        let harts = HartStates::new(2);
+        assert_eq!(harts.status(1), SbiRet::success(SBI_HSM_STOPPED));
+        assert_eq!(harts.take_start(1), None);
+        assert_eq!(harts.start(1, 0x8020_0000, 42), SbiRet::success(0));
+        assert_eq!(harts.status(1), SbiRet::success(SBI_HSM_START_PENDING));
+        assert_eq!(
+            harts.start(1, 0x8020_0000, 42),
+            SbiRet::error(SBI_ERR_ALREADY_AVAILABLE)
+        );
+        assert_eq!(harts.take_start(1), Some((0x8020_0000, 42)));
+        assert_eq!(harts.status(1), SbiRet::success(SBI_HSM_STARTED));
+        harts.stop(1);
+        assert_eq!(harts.status(1), SbiRet::success(SBI_HSM_STOPPED));
+        assert_eq!(harts.start(2, 0, 0), SbiRet::error(SBI_ERR_INVALID_PARAM));
+        assert!(!harts.halted());
+        harts.halt();
+        assert!(harts.halted());
+    }
+}
@@ -0,0 +1,645 @@
+use crate::vcpu::*;
+pub mod hsm;
+// Supervisor Binary Interface, version 2.0, as seen by the guest.
+// `handle_ecall` decodes a call from the registers of the calling hart, runs it against an `SbiEnv`
+// provided by the VMM, and writes the result back.
+
+pub const SBI_SUCCESS: isize = 0;
+pub const SBI_ERR_FAILED: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_DENIED: isize = -4;
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+pub const SBI_ERR_ALREADY_STARTED: isize = -7;
+pub const SBI_ERR_ALREADY_STOPPED: isize = -8;
+
+pub const SBI_SPEC_VERSION: usize = 2 << 24;
+// Not a registered implementation ID.
+pub const SBI_IMPL_ID: usize = 0x52564d;
+pub const SBI_IMPL_VERSION: usize = 1;
+
+// Legacy extensions, one function each.
+pub const SBI_LEGACY_SET_TIMER: usize = 0x00;
+pub const SBI_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+pub const SBI_LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+pub const SBI_LEGACY_SHUTDOWN: usize = 0x08;
+
+pub const SBI_EXT_BASE: usize = 0x10;
+pub const SBI_EXT_TIME: usize = 0x5449_4d45;
+pub const SBI_EXT_IPI: usize = 0x0073_5049;
+pub const SBI_EXT_RFENCE: usize = 0x5246_4e43;
+pub const SBI_EXT_HSM: usize = 0x0048_534d;
+pub const SBI_EXT_SRST: usize = 0x5352_5354;
+pub const SBI_EXT_DBCN: usize = 0x4442_434e;
+
+// Bytes moved by one DBCN call. Longer writes and reads are partial, as the specification allows.
+const DBCN_CHUNK: usize = 256;
+
+// HSM hart states.
+pub const SBI_HSM_STARTED: usize = 0;
+pub const SBI_HSM_STOPPED: usize = 1;
+pub const SBI_HSM_START_PENDING: usize = 2;
+pub const SBI_HSM_STOP_PENDING: usize = 3;
+pub const SBI_HSM_SUSPENDED: usize = 4;
+pub const SBI_HSM_SUSPEND_PENDING: usize = 5;
+pub const SBI_HSM_RESUME_PENDING: usize = 6;
+
+/// Result of an SBI call, returned in a0 (error) and a1 (value).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+impl SbiRet {
+    pub fn success(value: usize) -> Self {
+        SbiRet {
+            error: SBI_SUCCESS,
+            value,
+        }
+    }
+    pub fn error(error: isize) -> Self {
+        SbiRet { error, value: 0 }
+    }
+    pub fn not_supported() -> Self {
+        Self::error(SBI_ERR_NOT_SUPPORTED)
+    }
+}
+
+/// Harts selected by an `hart_mask` and `hart_mask_base` pair.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HartMask {
+    mask: usize,
+    base: usize,
+}
+
+impl HartMask {
+    pub fn new(mask: usize, base: usize) -> Self {
+        HartMask { mask, base }
+    }
+    /// Every hart, as selected by a base of -1.
+    pub fn all() -> Self {
+        HartMask {
+            mask: 0,
+            base: usize::max_value(),
+        }
+    }
+    pub fn contains(&self, hart: usize) -> bool {
+        if self.base == usize::max_value() {
+            return true;
+        }
+        hart.checked_sub(self.base)
+            .map_or(false, |bit| bit < 64 && (self.mask >> bit) & 1 == 1)
+    }
+    // Every selected hart exists.
+    fn valid(&self, num_harts: usize) -> bool {
+        if self.base == usize::max_value() || self.mask == 0 {
+            return true;
+        }
+        let highest = 63 - self.mask.leading_zeros() as usize;
+        self.base
+            .checked_add(highest)
+            .map_or(false, |x| x < num_harts)
+    }
+}
+
+/// Fences requested by the RFENCE extension.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RemoteFence {
+    FenceI,
+    SfenceVma {
+        start: usize,
+        size: usize,
+    },
+    SfenceVmaAsid {
+        start: usize,
+        size: usize,
+        asid: usize,
+    },
+}
+
+/// System reset requested through SRST or the legacy shutdown call.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResetType {
+    Shutdown,
+    ColdReboot,
+    WarmReboot,
+}
+
+/// What the VMM does with the calling hart after the call.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SbiOutcome {
+    /// Resume the hart. The result is in its registers.
+    Resume,
+    /// The hart stopped itself through HSM.
+    HartStop,
+    /// Reset the whole system. `reason` is 0 for no reason and 1 for a system failure.
+    Reset { reset_type: ResetType, reason: u32 },
+}
+
+/// Services of the VMM behind the SBI calls. Calls that are not provided fail with SBI_ERR_NOT_SUPPORTED.
+pub trait SbiEnv {
+    /// Harts are numbered `0..num_harts()`.
+    fn num_harts(&self) -> usize {
+        1
+    }
+    fn console_putchar(&self, chr: u8);
+    fn console_getchar(&self) -> Option<u8>;
+    /// Guest memory for calls taking buffers. Without it DBCN is not available.
+    fn memory(&self) -> Option<&dyn GuestMemory> {
+        None
+    }
+    /// Program the next supervisor timer event of `hart`, and clear its pending timer interrupt.
+    fn set_timer(&self, _hart: usize, _stime_value: u64) -> SbiRet {
+        SbiRet::not_supported()
+    }
+    /// Raise a supervisor software interrupt on every selected hart.
+    fn send_ipi(&self, _harts: HartMask) -> SbiRet {
+        SbiRet::not_supported()
+    }
+    /// Run `fence` on every selected hart.
+    /// Nothing to do by default: a single hart never runs concurrently with itself.
+    fn remote_fence(&self, _fence: RemoteFence, _harts: HartMask) -> SbiRet {
+        SbiRet::success(0)
+    }
+    fn hart_start(&self, _hart: usize, _start_addr: usize, _opaque: usize) -> SbiRet {
+        SbiRet::not_supported()
+    }
+    /// HSM state of an existing hart. The calling hart is always started.
+    fn hart_status(&self, _hart: usize) -> SbiRet {
+        SbiRet::success(SBI_HSM_STARTED)
+    }
+    fn hart_suspend(
+        &self,
+        _hart: usize,
+        _suspend_type: u32,
+        _resume_addr: usize,
+        _opaque: usize,
+    ) -> SbiRet {
+        SbiRet::not_supported()
+    }
+    fn mvendorid(&self) -> usize {
+        0
+    }
+    fn marchid(&self) -> usize {
+        0
+    }
+    fn mimpid(&self) -> usize {
+        0
+    }
+}
+
+fn probe(env: &dyn SbiEnv, eid: usize) -> usize {
+    match eid {
+        SBI_EXT_DBCN => env.memory().is_some() as usize,
+        SBI_LEGACY_SET_TIMER
+        | SBI_LEGACY_CONSOLE_PUTCHAR
+        | SBI_LEGACY_CONSOLE_GETCHAR
+        | SBI_LEGACY_SHUTDOWN
+        | SBI_EXT_BASE
+        | SBI_EXT_TIME
+        | SBI_EXT_IPI
+        | SBI_EXT_RFENCE
+        | SBI_EXT_HSM
+        | SBI_EXT_SRST => 1,
+        _ => 0,
+    }
+}
+
+fn base(env: &dyn SbiEnv, fid: usize, args: &[usize; 6]) -> SbiRet {
+    SbiRet::success(match fid {
+        0 => SBI_SPEC_VERSION,
+        1 => SBI_IMPL_ID,
+        2 => SBI_IMPL_VERSION,
+        3 => probe(env, args[0]),
+        4 => env.mvendorid(),
+        5 => env.marchid(),
+        6 => env.mimpid(),
+        _ => return SbiRet::not_supported(),
+    })
+}
+
+fn rfence(env: &dyn SbiEnv, fid: usize, args: &[usize; 6]) -> SbiRet {
+    let harts = HartMask::new(args[0], args[1]);
+    let fence = match fid {
+        0 => RemoteFence::FenceI,
+        1 => RemoteFence::SfenceVma {
+            start: args[2],
+            size: args[3],
+        },
+        2 => RemoteFence::SfenceVmaAsid {
+            start: args[2],
+            size: args[3],
+            asid: args[4],
+        },
+        // HFENCE needs the hypervisor extension in the guest.
+        _ => return SbiRet::not_supported(),
+    };
+    if !harts.valid(env.num_harts()) {
+        return SbiRet::error(SBI_ERR_INVALID_PARAM);
+    }
+    env.remote_fence(fence, harts)
+}
+
+fn dbcn(env: &dyn SbiEnv, fid: usize, args: &[usize; 6]) -> SbiRet {
+    if fid == 2 {
+        env.console_putchar(args[0] as u8);
+        return SbiRet::success(0);
+    }
+    let memory = match env.memory() {
+        Some(memory) => memory,
+        None => return SbiRet::not_supported(),
+    };
+    // The high half of the address is only used on RV32.
+    if args[2] != 0 {
+        return SbiRet::error(SBI_ERR_INVALID_PARAM);
+    }
+    let (len, gpa) = (args[0], args[1] as u64);
+    let mut buf = [0u8; DBCN_CHUNK];
+    match fid {
+        0 => {
+            let mut done = 0;
+            while done < len {
+                let n = core::cmp::min(len - done, DBCN_CHUNK);
+                let chunk = &mut buf[..n];
+                match gpa.checked_add(done as u64) {
+                    Some(addr) if memory.read(addr, chunk) => {}
+                    _ => break,
+                }
+                for chr in chunk.iter() {
+                    env.console_putchar(*chr);
+                }
+                done += n;
+            }
+            if done == 0 && len != 0 {
+                SbiRet::error(SBI_ERR_INVALID_PARAM)
+            } else {
+                SbiRet::success(done)
+            }
+        }
+        1 => {
+            let chunk = &mut buf[..core::cmp::min(len, DBCN_CHUNK)];
+            // Input cannot be given back, so check the buffer before taking any.
+            if !memory.read(gpa, chunk) {
+                return SbiRet::error(SBI_ERR_INVALID_PARAM);
+            }
+            let mut count = 0;
+            while count < chunk.len() {
+                match env.console_getchar() {
+                    Some(chr) => chunk[count] = chr,
+                    None => break,
+                }
+                count += 1;
+            }
+            memory.write(gpa, &chunk[..count]);
+            SbiRet::success(count)
+        }
+        _ => SbiRet::not_supported(),
+    }
+}
+
+fn hsm(env: &dyn SbiEnv, hart: usize, fid: usize, args: &[usize; 6]) -> Result<SbiRet, SbiOutcome> {
+    Ok(match fid {
+        0 if args[0] >= env.num_harts() => SbiRet::error(SBI_ERR_INVALID_PARAM),
+        0 if args[0] == hart => SbiRet::error(SBI_ERR_ALREADY_AVAILABLE),
+        0 => env.hart_start(args[0], args[1], args[2]),
+        1 => return Err(SbiOutcome::HartStop),
+        2 if args[0] >= env.num_harts() => SbiRet::error(SBI_ERR_INVALID_PARAM),
+        2 if args[0] == hart => SbiRet::success(SBI_HSM_STARTED),
+        2 => env.hart_status(args[0]),
+        // Only the default retentive (0) and non-retentive (0x8000_0000) suspend types exist:
+        // the rest of the spec ranges are reserved or platform specific, and no platform defines any.
+        3 if args[0] != 0 && args[0] != 0x8000_0000 => SbiRet::error(SBI_ERR_INVALID_PARAM),
+        3 => env.hart_suspend(hart, args[0] as u32, args[1], args[2]),
+        _ => SbiRet::not_supported(),
+    })
+}
+
+fn srst(fid: usize, args: &[usize; 6]) -> Result<SbiRet, SbiOutcome> {
+    if fid != 0 {
+        return Ok(SbiRet::not_supported());
+    }
+    let reset_type = match args[0] {
+        0 => ResetType::Shutdown,
+        1 => ResetType::ColdReboot,
+        2 => ResetType::WarmReboot,
+        _ => return Ok(SbiRet::error(SBI_ERR_INVALID_PARAM)),
+    };
+    if args[1] > 1 {
+        return Ok(SbiRet::error(SBI_ERR_INVALID_PARAM));
+    }
+    Err(SbiOutcome::Reset {
+        reset_type,
+        reason: args[1] as u32,
+    })
+}
+
+/// Handle an ecall from `hart`, whose registers are `regs`.
+/// Results are written to a0 and a1, or only a0 for legacy calls. The program counter is left alone.
+pub fn handle_ecall(env: &dyn SbiEnv, hart: usize, regs: &mut dyn GuestRegs) -> SbiOutcome {
+    let eid = regs.reg(REG_A7);
+    let fid = regs.reg(REG_A6);
+    let mut args = [0; 6];
+    for (i, arg) in args.iter_mut().enumerate() {
+        *arg = regs.reg(REG_A0 + i);
+    }
+    // Legacy calls return a single value in a0.
+    let legacy = match eid {
+        SBI_LEGACY_SET_TIMER => Some(env.set_timer(hart, args[0] as u64).error as usize),
+        SBI_LEGACY_CONSOLE_PUTCHAR => {
+            env.console_putchar(args[0] as u8);
+            Some(0)
+        }
+        SBI_LEGACY_CONSOLE_GETCHAR => Some(env.console_getchar().map_or(!0, |x| x as usize)),
+        SBI_LEGACY_SHUTDOWN => {
+            return SbiOutcome::Reset {
+                reset_type: ResetType::Shutdown,
+                reason: 0,
+            }
+        }
+        _ => None,
+    };
+    if let Some(ret) = legacy {
+        regs.set_reg(REG_A0, ret);
+        return SbiOutcome::Resume;
+    }
+    let ret = match eid {
+        SBI_EXT_BASE => Ok(base(env, fid, &args)),
+        SBI_EXT_TIME if fid == 0 => Ok(env.set_timer(hart, args[0] as u64)),
+        SBI_EXT_IPI if fid == 0 => {
+            let harts = HartMask::new(args[0], args[1]);
+            if harts.valid(env.num_harts()) {
+                Ok(env.send_ipi(harts))
+            } else {
+                Ok(SbiRet::error(SBI_ERR_INVALID_PARAM))
+            }
+        }
+        SBI_EXT_RFENCE => Ok(rfence(env, fid, &args)),
+        SBI_EXT_HSM => hsm(env, hart, fid, &args),
+        SBI_EXT_SRST => srst(fid, &args),
+        SBI_EXT_DBCN => Ok(dbcn(env, fid, &args)),
+        _ => Ok(SbiRet::not_supported()),
+    };
+    match ret {
+        Ok(ret) => {
+            regs.set_reg(REG_A0, ret.error as usize);
+            regs.set_reg(REG_A1, ret.value);
+            SbiOutcome::Resume
+        }
+        Err(outcome) => outcome,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+    struct MockEnv {
+        output: Mutex<Vec<u8>>,
+        input: Mutex<Vec<u8>>,
+        timers: Mutex<Vec<(usize, u64)>>,
+        ipis: Mutex<Vec<HartMask>>,
+        ram: Mutex<Vec<u8>>,
+    }
+    const RAM_BASE: u64 = 0x8000_0000;
+    impl GuestMemory for MockEnv {
+        fn read(&self, gpa: u64, buf: &mut [u8]) -> bool {
+            let ram = self.ram.lock();
+            let start = gpa.wrapping_sub(RAM_BASE) as usize;
+            match ram.get(start..start.saturating_add(buf.len())) {
+                Some(src) => buf.copy_from_slice(src),
+                None => return false,
+            }
+            true
+        }
+        fn write(&self, gpa: u64, buf: &[u8]) -> bool {
+            let mut ram = self.ram.lock();
+            let start = gpa.wrapping_sub(RAM_BASE) as usize;
+            match ram.get_mut(start..start.saturating_add(buf.len())) {
+                Some(dst) => dst.copy_from_slice(buf),
+                None => return false,
+            }
+            true
+        }
+    }
+    impl MockEnv {
+        fn new() -> Self {
+            MockEnv {
+                output: Mutex::new(Vec::new()),
+                input: Mutex::new(Vec::new()),
+                timers: Mutex::new(Vec::new()),
+                ipis: Mutex::new(Vec::new()),
+                ram: Mutex::new(alloc::vec![0; 0x1000]),
+            }
+        }
+    }
+    impl SbiEnv for MockEnv {
+        fn num_harts(&self) -> usize {
+            2
+        }
+        fn console_putchar(&self, chr: u8) {
+            self.output.lock().push(chr);
+        }
+        fn console_getchar(&self) -> Option<u8> {
+            self.input.lock().pop()
+        }
+        fn set_timer(&self, hart: usize, stime_value: u64) -> SbiRet {
+            self.timers.lock().push((hart, stime_value));
+            SbiRet::success(0)
+        }
+        fn send_ipi(&self, harts: HartMask) -> SbiRet {
+            self.ipis.lock().push(harts);
+            SbiRet::success(0)
+        }
+        fn memory(&self) -> Option<&dyn GuestMemory> {
+            Some(self)
+        }
+    }
+    // Make a call and return (outcome, a0, a1). a1 starts out as 0x5a5a.
+    fn call(env: &MockEnv, eid: usize, fid: usize, args: &[usize]) -> (SbiOutcome, isize, usize) {
+        let mut regs = [0usize; 32];
+        regs[REG_A7] = eid;
+        regs[REG_A6] = fid;
+        regs[REG_A0..REG_A0 + args.len()].copy_from_slice(args);
+        regs[REG_A1] = if args.len() > 1 { args[1] } else { 0x5a5a };
+        let outcome = handle_ecall(env, 0, &mut regs);
+        (outcome, regs[REG_A0] as isize, regs[REG_A1])
+    }
+    fn ret(env: &MockEnv, eid: usize, fid: usize, args: &[usize]) -> SbiRet {
+        let (outcome, error, value) = call(env, eid, fid, args);
+        assert_eq!(outcome, SbiOutcome::Resume);
+        SbiRet { error, value }
+    }
+    #[test]
+    fn base_extension() {
+        let env = MockEnv::new();
+        assert_eq!(ret(&env, SBI_EXT_BASE, 0, &[]).value, 2 << 24);
+        assert_eq!(ret(&env, SBI_EXT_BASE, 3, &[SBI_EXT_HSM]).value, 1);
+        assert_eq!(ret(&env, SBI_EXT_BASE, 3, &[0x1234]).value, 0);
+        assert_eq!(
+            ret(&env, SBI_EXT_BASE, 7, &[]),
+            SbiRet::not_supported(),
+            "Unknown function."
+        );
+        assert_eq!(
+            ret(&env, 0x0a00_0000, 0, &[]),
+            SbiRet::not_supported(),
+            "Unknown extension."
+        );
+    }
+    #[test]
+    fn legacy() {
+        let env = MockEnv::new();
+        let (_, a0, a1) = call(&env, SBI_LEGACY_CONSOLE_PUTCHAR, 0, &[b'x' as usize]);
+        assert_eq!((a0, a1), (0, 0x5a5a), "Only a0 is written.");
+        assert_eq!(*env.output.lock(), b"x");
+        assert_eq!(call(&env, SBI_LEGACY_CONSOLE_GETCHAR, 0, &[]).1, -1);
+        env.input.lock().push(b'y');
+        assert_eq!(
+            call(&env, SBI_LEGACY_CONSOLE_GETCHAR, 0, &[]).1,
+            b'y' as isize
+        );
+        call(&env, SBI_LEGACY_SET_TIMER, 0, &[1000]);
+        assert_eq!(*env.timers.lock(), [(0, 1000)]);
+        assert_eq!(
+            call(&env, SBI_LEGACY_SHUTDOWN, 0, &[]).0,
+            SbiOutcome::Reset {
+                reset_type: ResetType::Shutdown,
+                reason: 0
+            }
+        );
+        assert_eq!(
+            call(&env, 0x04, 0, &[]).1,
+            SBI_ERR_NOT_SUPPORTED,
+            "Legacy IPI."
+        );
+    }
+    #[test]
+    fn ipi_and_fences() {
+        let env = MockEnv::new();
+        assert_eq!(ret(&env, SBI_EXT_IPI, 0, &[0b10, 0]), SbiRet::success(0));
+        assert_eq!(ret(&env, SBI_EXT_IPI, 0, &[0, !0]), SbiRet::success(0));
+        assert_eq!(
+            ret(&env, SBI_EXT_IPI, 0, &[0b100, 0]),
+            SbiRet::error(SBI_ERR_INVALID_PARAM),
+            "No hart 2."
+        );
+        let ipis = env.ipis.lock().clone();
+        assert_eq!(ipis, [HartMask::new(0b10, 0), HartMask::all()]);
+        assert!(ipis[0].contains(1) && !ipis[0].contains(0));
+        assert_eq!(
+            ret(&env, SBI_EXT_RFENCE, 1, &[1, 1, 0x1000, 0x1000]),
+            SbiRet::success(0)
+        );
+        assert_eq!(
+            ret(&env, SBI_EXT_RFENCE, 3, &[1, 0]),
+            SbiRet::not_supported(),
+            "No HFENCE."
+        );
+    }
+    #[test]
+    fn hsm_and_srst() {
+        let env = MockEnv::new();
+        assert_eq!(
+            ret(&env, SBI_EXT_HSM, 2, &[0]),
+            SbiRet::success(SBI_HSM_STARTED)
+        );
+        assert_eq!(
+            ret(&env, SBI_EXT_HSM, 2, &[2]),
+            SbiRet::error(SBI_ERR_INVALID_PARAM)
+        );
+        assert_eq!(
+            ret(&env, SBI_EXT_HSM, 0, &[0, 0x8020_0000, 0]),
+            SbiRet::error(SBI_ERR_ALREADY_AVAILABLE)
+        );
+        assert_eq!(
+            ret(&env, SBI_EXT_HSM, 0, &[1, 0x8020_0000, 0]),
+            SbiRet::not_supported()
+        );
+        assert_eq!(call(&env, SBI_EXT_HSM, 1, &[]).0, SbiOutcome::HartStop);
+        assert_eq!(
+            call(&env, SBI_EXT_SRST, 0, &[1, 1]).0,
+            SbiOutcome::Reset {
+                reset_type: ResetType::ColdReboot,
+                reason: 1
+            }
+        );
+        assert_eq!(
+            ret(&env, SBI_EXT_SRST, 0, &[3, 0]),
+            SbiRet::error(SBI_ERR_INVALID_PARAM)
+        );
+        assert_eq!(
+            ret(&env, SBI_EXT_TIME, 0, &[42]),
+            SbiRet::success(0),
+            "TIME."
+        );
+    }
+    #[test]
+    fn hart_suspend() {
+        let env = MockEnv::new();
+        for &suspend_type in [0, 0x8000_0000].iter() {
+            assert_eq!(
+                ret(&env, SBI_EXT_HSM, 3, &[suspend_type, 0x8020_0000, 0]),
+                SbiRet::not_supported(),
+                "Reaches the VMM."
+            );
+        }
+        for &suspend_type in [1, 0x1000_0000, 0x8000_0001, 0xf000_0000, 0x1_0000_0000].iter() {
+            assert_eq!(
+                ret(&env, SBI_EXT_HSM, 3, &[suspend_type, 0x8020_0000, 0]),
+                SbiRet::error(SBI_ERR_INVALID_PARAM)
+            );
+        }
+    }
+    #[test]
+    fn debug_console() {
+        let env = MockEnv::new();
+        assert_eq!(ret(&env, SBI_EXT_BASE, 3, &[SBI_EXT_DBCN]).value, 1);
+        let text = b"hello, world\n".repeat(40);
+        env.ram.lock()[0x100..0x100 + text.len()].copy_from_slice(&text);
+        let addr = RAM_BASE as usize + 0x100;
+        assert_eq!(
+            ret(&env, SBI_EXT_DBCN, 0, &[text.len(), addr, 0]),
+            SbiRet::success(text.len())
+        );
+        assert_eq!(*env.output.lock(), text);
+        assert_eq!(
+            ret(
+                &env,
+                SBI_EXT_DBCN,
+                0,
+                &[0x200, RAM_BASE as usize + 0xf00, 0]
+            ),
+            SbiRet::success(0x100),
+            "Partial write at the end of RAM."
+        );
+        assert_eq!(
+            ret(&env, SBI_EXT_DBCN, 0, &[1, 0x1000, 0]),
+            SbiRet::error(SBI_ERR_INVALID_PARAM)
+        );
+        assert_eq!(
+            ret(&env, SBI_EXT_DBCN, 0, &[1, addr, 1]),
+            SbiRet::error(SBI_ERR_INVALID_PARAM)
+        );
+        env.output.lock().clear();
+        assert_eq!(
+            ret(&env, SBI_EXT_DBCN, 2, &[b'!' as usize]),
+            SbiRet::success(0)
+        );
+        assert_eq!(*env.output.lock(), b"!");
+        env.input.lock().extend_from_slice(b"ba");
+        assert_eq!(
+            ret(&env, SBI_EXT_DBCN, 1, &[16, addr, 0]),
+            SbiRet::success(2)
+        );
+        assert_eq!(&env.ram.lock()[0x100..0x103], b"abl");
+        env.input.lock().push(b'c');
+        assert_eq!(
+            ret(&env, SBI_EXT_DBCN, 1, &[16, 0x1000, 0]),
+            SbiRet::error(SBI_ERR_INVALID_PARAM)
+        );
+        assert_eq!(*env.input.lock(), b"c", "No input lost.");
+    }
+}
@@ -1,5 +1,6 @@
 use alloc::sync::{Arc, Weak};
-use core::sync::atomic::{AtomicBool, Ordering::*};
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, AtomicBool, AtomicU8, AtomicUsize, Ordering::*};
 use spin::Mutex;
 // The intermediate interface between serial and user input.
 pub trait Console: Send + Sync {
@@ -8,83 +9,542 @@ pub trait Console: Send + Sync {
     fn write(&self, chr: u8);
     // Called by serial provider (usually in another thread) to push one character into the buffer.
     fn notify_char(&self, chr: u8);
+    // Register the listener notified when new input becomes available. Replaces any previous listener.
+    fn set_listener(&self, _listener: Weak<dyn ConsoleListener>) {}
     // downcast
     fn as_any(&self) -> &dyn core::any::Any;
 }
 
+// Notified by a console, possibly from another thread, after new input arrives.
+pub trait ConsoleListener: Send + Sync {
+    fn notify_input(&self);
+}
+
 // Abstraction for a ``consle''. Exactly enough to fit into rcore_user.
 pub trait BlockingConsole: Sync + Send {
-    fn getc(&self) -> u8;
+    // Block until a character arrives. None once `cancel_getc` has been called.
+    fn getc(&self) -> Option<u8>;
+    // Make a `getc` blocked in another task return None, and every later one too.
+    fn cancel_getc(&self);
     fn putc(&self, chr: u8);
     fn start_task<F: FnOnce() -> ()>(f: F)
     where
         F: Send + 'static;
+    // Block the calling task until `unpark` is called. Spurious wakeups are allowed.
+    fn park(&self);
+    // Wake up the task blocked in `park`, or make its next `park` return immediately.
+    fn unpark(&self);
+}
+
+pub const DEFAULT_CONSOLE_BUFFER_SIZE: usize = 4096;
+
+/// Counters describing how the input buffer has been used.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConsoleStats {
+    /// Characters accepted into the buffer.
+    pub received: usize,
+    /// Times a producer found the buffer full and had to wait.
+    pub overflows: usize,
+    /// Characters thrown away by `try_notify_char` on a full buffer.
+    pub dropped: usize,
 }
-pub struct SingleCharBufferedConsole<T: BlockingConsole + 'static> {
-    buffer: Mutex<Option<u8>>,
-    full: AtomicBool,
+
+// A slot of the ring. `seq` is `2 * pos` while the slot waits for the character of position
+// `pos`, and `2 * pos + 1` once that character is in. Doubling keeps the two apart even when a
+// lap is one slot.
+struct Slot {
+    seq: AtomicUsize,
+    chr: AtomicU8,
+}
+
+// Lock-free ring buffer, after Vyukov's bounded MPMC queue. Producers and consumers claim
+// positions by compare-and-swap on `tail` and `head`, and hand slots over through `seq`, so any
+// number of them may push and pop: the producer task, callers of `try_notify_char`, a UART and
+// the SBI console of every hart.
+struct RingBuffer {
+    slots: Vec<Slot>,
+    // Next position to consume.
+    head: AtomicUsize,
+    // Next position to produce.
+    tail: AtomicUsize,
+    producer_waiting: AtomicBool,
     closed: AtomicBool,
-    underlying: Arc<T>,
+    started: AtomicBool,
+    listener: Mutex<Option<Weak<dyn ConsoleListener>>>,
+    received: AtomicUsize,
+    overflows: AtomicUsize,
+    dropped: AtomicUsize,
 }
 
-impl<T: BlockingConsole + 'static> SingleCharBufferedConsole<T> {
-    pub fn new(underlying: Arc<T>) -> Self {
-        SingleCharBufferedConsole {
-            buffer: Mutex::new(None),
-            full: AtomicBool::new(false),
+impl RingBuffer {
+    fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "console buffer must not be empty");
+        RingBuffer {
+            slots: (0..capacity)
+                .map(|pos| Slot {
+                    seq: AtomicUsize::new(2 * pos),
+                    chr: AtomicU8::new(0),
+                })
+                .collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            producer_waiting: AtomicBool::new(false),
             closed: AtomicBool::new(false),
+            started: AtomicBool::new(false),
+            listener: Mutex::new(None),
+            received: AtomicUsize::new(0),
+            overflows: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+    fn len(&self) -> usize {
+        self.tail.load(SeqCst).wrapping_sub(self.head.load(SeqCst))
+    }
+    fn is_full(&self) -> bool {
+        self.len() >= self.slots.len()
+    }
+    fn try_push(&self, chr: u8) -> bool {
+        let mut pos = self.tail.load(Relaxed);
+        loop {
+            let slot = &self.slots[pos % self.slots.len()];
+            let free = pos.wrapping_mul(2);
+            let seq = slot.seq.load(Acquire);
+            if seq == free {
+                match self
+                    .tail
+                    .compare_exchange_weak(pos, pos.wrapping_add(1), Relaxed, Relaxed)
+                {
+                    Ok(_) => {
+                        slot.chr.store(chr, Release);
+                        slot.seq.store(free.wrapping_add(1), Release);
+                        break;
+                    }
+                    Err(tail) => pos = tail,
+                }
+            } else if (seq.wrapping_sub(free) as isize) < 0 {
+                // The character a lap ago is still in: full.
+                return false;
+            } else {
+                pos = self.tail.load(Relaxed);
+            }
+        }
+        self.received.fetch_add(1, Relaxed);
+        let listener = self.listener.lock().clone();
+        if let Some(listener) = listener.as_ref().and_then(Weak::upgrade) {
+            listener.notify_input();
+        }
+        true
+    }
+    // Push one character, parking the producer while the buffer is full. Only one producer may
+    // wait at a time, as the underlying console has one task to unpark.
+    // Returns false if the console was closed while waiting.
+    fn push<T: BlockingConsole>(&self, chr: u8, underlying: &T) -> bool {
+        let mut counted = false;
+        loop {
+            if self.closed.load(SeqCst) {
+                return false;
+            }
+            if self.try_push(chr) {
+                return true;
+            }
+            if !counted {
+                self.overflows.fetch_add(1, Relaxed);
+                counted = true;
+            }
+            self.producer_waiting.store(true, SeqCst);
+            // Re-check after announcing ourselves so that a concurrent pop cannot miss us.
+            if self.is_full() && !self.closed.load(SeqCst) {
+                underlying.park();
+            }
+            self.producer_waiting.store(false, SeqCst);
+        }
+    }
+    fn pop<T: BlockingConsole>(&self, pop: bool, underlying: &T) -> Option<u8> {
+        let mut pos = self.head.load(Relaxed);
+        loop {
+            let slot = &self.slots[pos % self.slots.len()];
+            let ready = pos.wrapping_mul(2).wrapping_add(1);
+            let seq = slot.seq.load(Acquire);
+            if seq == ready {
+                let chr = slot.chr.load(Acquire);
+                if !pop {
+                    // A peek only holds if no one took the character meanwhile.
+                    fence(Acquire);
+                    if slot.seq.load(Relaxed) == ready {
+                        return Some(chr);
+                    }
+                    pos = self.head.load(Relaxed);
+                    continue;
+                }
+                match self
+                    .head
+                    .compare_exchange_weak(pos, pos.wrapping_add(1), Relaxed, Relaxed)
+                {
+                    Ok(_) => {
+                        // The slot is free for the next lap.
+                        let next = pos.wrapping_add(self.slots.len());
+                        slot.seq.store(next.wrapping_mul(2), SeqCst);
+                        if self.producer_waiting.load(SeqCst) {
+                            underlying.unpark();
+                        }
+                        return Some(chr);
+                    }
+                    Err(head) => pos = head,
+                }
+            } else if (seq.wrapping_sub(ready) as isize) < 0 {
+                // Nothing produced here yet: empty.
+                return None;
+            } else {
+                pos = self.head.load(Relaxed);
+            }
+        }
+    }
+}
+
+/// Console with a bounded lock-free input buffer.
+/// Input is produced by the task of `start`, and may also be pushed and consumed from any number
+/// of threads. Only the listener is behind a lock, which pushes take to find it.
+pub struct RingBufferedConsole<T: BlockingConsole + 'static> {
+    buffer: Arc<RingBuffer>,
+    underlying: Arc<T>,
+}
+
+impl<T: BlockingConsole + 'static> RingBufferedConsole<T> {
+    pub fn new(underlying: Arc<T>, capacity: usize) -> Self {
+        RingBufferedConsole {
+            buffer: Arc::new(RingBuffer::new(capacity)),
             underlying,
         }
     }
     pub fn get_underlying(&self) -> Arc<T> {
         Arc::clone(&self.underlying)
     }
-    pub fn start(&self, real_self: Arc<dyn Console>) {
-        let p = Arc::downgrade(&real_self);
+    pub fn capacity(&self) -> usize {
+        self.buffer.slots.len()
+    }
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn is_full(&self) -> bool {
+        self.buffer.is_full()
+    }
+    pub fn stats(&self) -> ConsoleStats {
+        ConsoleStats {
+            received: self.buffer.received.load(Relaxed),
+            overflows: self.buffer.overflows.load(Relaxed),
+            dropped: self.buffer.dropped.load(Relaxed),
+        }
+    }
+    /// Push one character without blocking. The character is dropped if the buffer is full.
+    pub fn try_notify_char(&self, chr: u8) -> bool {
+        if self.buffer.try_push(chr) {
+            true
+        } else {
+            self.buffer.dropped.fetch_add(1, Relaxed);
+            false
+        }
+    }
+    /// Start the producer task reading from the underlying console. Only one may read it: panics
+    /// if already started.
+    /// The task exits once the console is dropped, which cancels reads of the underlying console.
+    pub fn start(&self) {
+        assert!(
+            !self.buffer.started.swap(true, SeqCst),
+            "console already started"
+        );
+        let buffer = Arc::clone(&self.buffer);
         let underlying = Arc::clone(&self.underlying);
-        drop(real_self);
-        T::start_task(move || loop {
-            let chr = underlying.getc();
-            if let Some(serial) = Weak::upgrade(&p) {
-                serial
-                    .as_any()
-                    .downcast_ref::<Self>()
-                    .unwrap()
-                    .notify_char(chr);
+        T::start_task(move || {
+            while let Some(chr) = underlying.getc() {
+                if !buffer.push(chr, &*underlying) {
+                    break;
+                }
             }
         });
     }
 }
 
-impl<T: BlockingConsole + 'static> Console for SingleCharBufferedConsole<T> {
+impl<T: BlockingConsole + 'static> Console for RingBufferedConsole<T> {
     fn as_any(&self) -> &dyn core::any::Any {
         self
     }
     fn try_read(&self, pop: bool) -> Option<u8> {
-        let mut chr = self.buffer.lock();
-        let ch = chr.as_ref().copied();
-        if pop {
-            *chr = None;
-            self.full.store(false, SeqCst);
-        }
-        ch
+        self.buffer.pop(pop, &*self.underlying)
     }
     fn write(&self, chr: u8) {
         self.underlying.putc(chr);
     }
+    // Waits while the buffer is full, in place of the producer task, which must not be started.
     fn notify_char(&self, chr: u8) {
-        while self.full.load(SeqCst) && !self.closed.load(SeqCst) {
-            // spin
-        }
-        *self.buffer.lock() = Some(chr);
-        self.full.store(true, SeqCst);
+        assert!(
+            !self.buffer.started.load(SeqCst),
+            "the producer task feeds this console"
+        );
+        self.buffer.push(chr, &*self.underlying);
+    }
+    fn set_listener(&self, listener: Weak<dyn ConsoleListener>) {
+        *self.buffer.listener.lock() = Some(listener);
     }
 }
 
-impl<T: BlockingConsole + 'static> Drop for SingleCharBufferedConsole<T> {
+impl<T: BlockingConsole + 'static> Drop for RingBufferedConsole<T> {
     fn drop(&mut self) {
-        self.closed.store(true, SeqCst);
+        self.buffer.closed.store(true, SeqCst);
+        self.underlying.cancel_getc();
+        self.underlying.unpark();
     }
 }
 
 pub mod uart16650;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::sync::Mutex;
+    use std::thread::Thread;
+    use std::time::Duration;
+    struct ChannelConsole {
+        rx: Mutex<Receiver<u8>>,
+        tx: Mutex<Sender<u8>>,
+        producer: Mutex<Option<Thread>>,
+        // Signalled whenever the producer parks.
+        parked: Mutex<Option<Sender<()>>>,
+        // Signalled whenever the producer waits for input.
+        reading: Mutex<Option<Sender<()>>>,
+        cancelled: AtomicBool,
+    }
+    impl ChannelConsole {
+        fn new() -> Self {
+            let (tx, rx) = channel();
+            ChannelConsole {
+                rx: Mutex::new(rx),
+                tx: Mutex::new(tx),
+                producer: Mutex::new(None),
+                parked: Mutex::new(None),
+                reading: Mutex::new(None),
+                cancelled: AtomicBool::new(false),
+            }
+        }
+        fn send(&self, chr: u8) {
+            self.tx.lock().unwrap().send(chr).unwrap();
+        }
+    }
+    impl BlockingConsole for ChannelConsole {
+        fn getc(&self) -> Option<u8> {
+            if let Some(reading) = self.reading.lock().unwrap().as_ref() {
+                let _ = reading.send(());
+            }
+            let rx = self.rx.lock().unwrap();
+            while !self.cancelled.load(SeqCst) {
+                if let Ok(chr) = rx.recv_timeout(Duration::from_millis(10)) {
+                    return Some(chr);
+                }
+            }
+            None
+        }
+        fn cancel_getc(&self) {
+            self.cancelled.store(true, SeqCst);
+        }
+        fn putc(&self, _chr: u8) {}
+        fn start_task<F: FnOnce() -> ()>(f: F)
+        where
+            F: Send + 'static,
+        {
+            std::thread::spawn(f);
+        }
+        fn park(&self) {
+            if let Some(parked) = self.parked.lock().unwrap().as_ref() {
+                let _ = parked.send(());
+            }
+            *self.producer.lock().unwrap() = Some(std::thread::current());
+            // The timeout covers an unpark racing with the registration above.
+            std::thread::park_timeout(Duration::from_millis(10));
+        }
+        fn unpark(&self) {
+            if let Some(producer) = self.producer.lock().unwrap().as_ref() {
+                producer.unpark();
+            }
+        }
+    }
+    #[test]
+    fn ring_buffer() {
+        let console = RingBufferedConsole::new(Arc::new(ChannelConsole::new()), 3);
+        assert_eq!(console.try_read(false), None);
+        for chr in 1..=3 {
+            assert!(console.try_notify_char(chr));
+        }
+        assert!(console.is_full());
+        assert!(!console.try_notify_char(4), "Buffer full.");
+        assert_eq!(console.try_read(false), Some(1), "Peek.");
+        assert_eq!(console.try_read(true), Some(1));
+        assert!(console.try_notify_char(5), "Wraps around.");
+        assert_eq!(console.try_read(true), Some(2));
+        assert_eq!(console.try_read(true), Some(3));
+        assert_eq!(console.try_read(true), Some(5));
+        assert_eq!(console.try_read(true), None);
+        assert_eq!(
+            console.stats(),
+            ConsoleStats {
+                received: 4,
+                overflows: 0,
+                dropped: 1
+            }
+        );
+    }
+    #[test]
+    fn concurrent_consumers() {
+        let console = Arc::new(RingBufferedConsole::new(
+            Arc::new(ChannelConsole::new()),
+            1024,
+        ));
+        for chr in 0..1024 {
+            assert!(console.try_notify_char(chr as u8));
+        }
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let console = Arc::clone(&console);
+                std::thread::spawn(move || {
+                    let mut got = Vec::new();
+                    while let Some(chr) = console.try_read(true) {
+                        got.push(chr);
+                    }
+                    got
+                })
+            })
+            .collect();
+        let mut counts = [0; 256];
+        for consumer in consumers {
+            for chr in consumer.join().unwrap() {
+                counts[chr as usize] += 1;
+            }
+        }
+        assert!(
+            counts.iter().all(|&count| count == 4),
+            "Every character read once."
+        );
+    }
+    #[test]
+    fn backpressure() {
+        let underlying = Arc::new(ChannelConsole::new());
+        let (parked_tx, parked_rx) = channel();
+        *underlying.parked.lock().unwrap() = Some(parked_tx);
+        let console = RingBufferedConsole::new(Arc::clone(&underlying), 2);
+        console.start();
+        for chr in 0..8 {
+            underlying.send(chr);
+        }
+        parked_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Producer blocks on a full buffer.");
+        assert_eq!(console.len(), 2);
+        for chr in 0..8 {
+            let mut got = console.try_read(true);
+            while got.is_none() {
+                std::thread::yield_now();
+                got = console.try_read(true);
+            }
+            assert_eq!(got, Some(chr), "Nothing lost under backpressure.");
+        }
+        let stats = console.stats();
+        assert_eq!(stats.received, 8);
+        assert_ne!(stats.overflows, 0);
+        assert_eq!(stats.dropped, 0);
+    }
+    #[test]
+    fn shutdown() {
+        let underlying = Arc::new(ChannelConsole::new());
+        let (parked_tx, parked_rx) = channel();
+        *underlying.parked.lock().unwrap() = Some(parked_tx);
+        let console = RingBufferedConsole::new(Arc::clone(&underlying), 1);
+        console.start();
+        underlying.send(1);
+        underlying.send(2);
+        parked_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Producer blocks on a full buffer.");
+        drop(console);
+        // The producer task owns the only other reference to the underlying console.
+        let mut retries = 0;
+        while Arc::strong_count(&underlying) > 1 {
+            assert!(retries < 500, "Producer task exits after drop.");
+            std::thread::sleep(Duration::from_millis(10));
+            retries += 1;
+        }
+    }
+    #[test]
+    fn shutdown_while_reading() {
+        let underlying = Arc::new(ChannelConsole::new());
+        let (reading_tx, reading_rx) = channel();
+        *underlying.reading.lock().unwrap() = Some(reading_tx);
+        let console = RingBufferedConsole::new(Arc::clone(&underlying), 4);
+        console.start();
+        reading_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Producer waits for input.");
+        drop(console);
+        let mut retries = 0;
+        while Arc::strong_count(&underlying) > 1 {
+            assert!(retries < 500, "Producer task exits after drop.");
+            std::thread::sleep(Duration::from_millis(10));
+            retries += 1;
+        }
+    }
+    #[test]
+    fn concurrent_producers() {
+        // Four producers and four consumers on a small buffer, which wraps many times.
+        let console = Arc::new(RingBufferedConsole::new(Arc::new(ChannelConsole::new()), 8));
+        let producers: Vec<_> = (0..4)
+            .map(|_| {
+                let console = Arc::clone(&console);
+                std::thread::spawn(move || {
+                    for chr in 0..=255 {
+                        while !console.try_notify_char(chr) {
+                            std::thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let console = Arc::clone(&console);
+                std::thread::spawn(move || {
+                    let mut got = Vec::new();
+                    while got.len() < 256 {
+                        match console.try_read(true) {
+                            Some(chr) => got.push(chr),
+                            None => std::thread::yield_now(),
+                        }
+                    }
+                    got
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut counts = [0; 256];
+        for consumer in consumers {
+            for chr in consumer.join().unwrap() {
+                counts[chr as usize] += 1;
+            }
+        }
+        assert!(
+            counts.iter().all(|&count| count == 4),
+            "Every character read once."
+        );
+        assert!(console.is_empty());
+        assert_eq!(console.stats().received, 1024);
+    }
+    #[test]
+    #[should_panic(expected = "console already started")]
+    fn start_twice() {
+        let console = RingBufferedConsole::new(Arc::new(ChannelConsole::new()), 4);
+        console.start();
+        console.start();
+    }
+}
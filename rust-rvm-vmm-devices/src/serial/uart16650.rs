@@ -1,40 +1,354 @@
-use super::Console;
+use super::{Console, ConsoleListener};
+use crate::board::DtDevice;
 use crate::device::MMIOAccess;
+use crate::fdt::FdtWriter;
+use crate::irq::IrqLine;
 use crate::Device;
+use alloc::collections::VecDeque;
 use alloc::sync::Arc;
-use core::sync::atomic::{AtomicBool, Ordering::*};
+use spin::Mutex;
 pub const COM_RX: usize = 0; // In:  Receive buffer (DLAB=0)
 pub const COM_TX: usize = 0; // Out: Transmit buffer (DLAB=0)
 pub const COM_DLL: usize = 0; // Out: Divisor Latch Low (DLAB=1)
 pub const COM_DLM: usize = 1; // Out: Divisor Latch High (DLAB=1)
 pub const COM_IER: usize = 1; // Out: Interrupt Enable Register
 pub const COM_IER_RDI: u8 = 0x01; // Enable receiver data interrupt
+pub const COM_IER_THRI: u8 = 0x02; // Enable transmitter holding register empty interrupt
+pub const COM_IER_RLSI: u8 = 0x04; // Enable receiver line status interrupt
+pub const COM_IER_MSI: u8 = 0x08; // Enable modem status interrupt
 pub const COM_IIR: usize = 2; // In:  Interrupt ID Register
+pub const COM_IIR_NO_INT: u8 = 0x01; // No interrupt pending
+pub const COM_IIR_MSI: u8 = 0x00; // Modem status interrupt
+pub const COM_IIR_THRI: u8 = 0x02; // Transmitter holding register empty
+pub const COM_IIR_RDI: u8 = 0x04; // Receiver data interrupt
+pub const COM_IIR_RLSI: u8 = 0x06; // Receiver line status interrupt
+pub const COM_IIR_TIMEOUT: u8 = 0x0c; // Character timeout indication
+pub const COM_IIR_ID: u8 = 0x0e; // Mask for the interrupt ID
+pub const COM_IIR_FIFO_ENABLED: u8 = 0xc0; // FIFOs enabled
 pub const COM_FCR: usize = 2; // Out: FIFO Control Register
+pub const COM_FCR_ENABLE: u8 = 0x01; // Enable FIFOs
+pub const COM_FCR_CLEAR_RCVR: u8 = 0x02; // Clear receive FIFO
+pub const COM_FCR_CLEAR_XMIT: u8 = 0x04; // Clear transmit FIFO
+pub const COM_FCR_DMA_SELECT: u8 = 0x08; // DMA mode select
+pub const COM_FCR_TRIGGER_MASK: u8 = 0xc0; // Mask for the RX trigger level
+pub const COM_FCR_TRIGGER_1: u8 = 0x00; // RX trigger at 1 byte
+pub const COM_FCR_TRIGGER_4: u8 = 0x40; // RX trigger at 4 bytes
+pub const COM_FCR_TRIGGER_8: u8 = 0x80; // RX trigger at 8 bytes
+pub const COM_FCR_TRIGGER_14: u8 = 0xc0; // RX trigger at 14 bytes
 pub const COM_LCR: usize = 3; // Out: Line Control Register
 pub const COM_LCR_DLAB: u8 = 0x80; // Divisor latch access bit
 pub const COM_LCR_WLEN8: u8 = 0x03; // Wordlength: 8 bits
 pub const COM_MCR: usize = 4; // Out: Modem Control Register
 pub const COM_MCR_RTS: u8 = 0x02; // RTS complement
 pub const COM_MCR_DTR: u8 = 0x01; // DTR complement
+pub const COM_MCR_OUT1: u8 = 0x04; // Out1 complement
 pub const COM_MCR_OUT2: u8 = 0x08; // Out2 complement
+pub const COM_MCR_LOOP: u8 = 0x10; // Enable loopback test mode
 pub const COM_LSR: usize = 5; // In:  Line Status Register
 pub const COM_LSR_DATA: u8 = 0x01; // Data available
+pub const COM_LSR_OE: u8 = 0x02; // Overrun error
 pub const COM_LSR_TXRDY: u8 = 0x20; // Transmit buffer avail
 pub const COM_LSR_TSRE: u8 = 0x40; // Transmitter off
-pub const MULTIPLIER: usize = 1 << 0;
-/// Simple serial.
-/// Just enough for rCore to run.
+pub const COM_MSR: usize = 6; // In:  Modem Status Register
+pub const COM_MSR_DCTS: u8 = 0x01; // Delta CTS
+pub const COM_MSR_DDSR: u8 = 0x02; // Delta DSR
+pub const COM_MSR_TERI: u8 = 0x04; // Trailing edge ring indicator
+pub const COM_MSR_DDCD: u8 = 0x08; // Delta DCD
+pub const COM_MSR_CTS: u8 = 0x10; // Clear to Send
+pub const COM_MSR_DSR: u8 = 0x20; // Data Set Ready
+pub const COM_MSR_RI: u8 = 0x40; // Ring Indicator
+pub const COM_MSR_DCD: u8 = 0x80; // Data Carrier Detect
+pub const COM_MSR_DELTA: u8 = 0x0f; // All delta bits
+pub const COM_SCR: usize = 7; // I/O: Scratch Register
+pub const MULTIPLIER: usize = 1; // Register stride (reg-shift = 0)
+pub const UART_FIFO_SIZE: usize = 16;
+
+/// Guest-visible register file of the 16550A.
+struct Uart16650Regs {
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    msr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    // Error bits of LSR. Cleared on LSR read.
+    lsr_errors: u8,
+    // Whether the THRE interrupt is armed. Cleared by IIR read or THR write.
+    thr_ipending: bool,
+    // Receive FIFO. Holds at most one byte (RBR) when FIFOs are disabled.
+    rx_fifo: VecDeque<u8>,
+    // No more input is immediately available behind the receive FIFO.
+    rx_idle: bool,
+}
+
+impl Uart16650Regs {
+    fn new() -> Self {
+        Uart16650Regs {
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            msr: COM_MSR_DCD | COM_MSR_DSR | COM_MSR_CTS,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            lsr_errors: 0,
+            thr_ipending: false,
+            rx_fifo: VecDeque::with_capacity(UART_FIFO_SIZE),
+            rx_idle: true,
+        }
+    }
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & COM_FCR_ENABLE != 0
+    }
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            UART_FIFO_SIZE
+        } else {
+            1
+        }
+    }
+    fn rx_trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        match self.fcr & COM_FCR_TRIGGER_MASK {
+            COM_FCR_TRIGGER_1 => 1,
+            COM_FCR_TRIGGER_4 => 4,
+            COM_FCR_TRIGGER_8 => 8,
+            _ => 14,
+        }
+    }
+    // A character arriving at the receiver shift register.
+    fn receive(&mut self, chr: u8) {
+        if self.rx_fifo.len() < self.rx_capacity() {
+            self.rx_fifo.push_back(chr);
+        } else {
+            self.lsr_errors |= COM_LSR_OE;
+            // Without FIFOs the new character overwrites RBR.
+            // With FIFOs the character in the shift register is lost.
+            if !self.fifo_enabled() {
+                self.rx_fifo.clear();
+                self.rx_fifo.push_back(chr);
+            }
+        }
+    }
+    fn dlab(&self) -> bool {
+        self.lcr & COM_LCR_DLAB != 0
+    }
+    fn loopback(&self) -> bool {
+        self.mcr & COM_MCR_LOOP != 0
+    }
+    // Recompute modem status lines, latching delta bits for changed lines.
+    fn update_msr(&mut self) {
+        let lines = if self.loopback() {
+            let mut lines = 0;
+            if self.mcr & COM_MCR_RTS != 0 {
+                lines |= COM_MSR_CTS;
+            }
+            if self.mcr & COM_MCR_DTR != 0 {
+                lines |= COM_MSR_DSR;
+            }
+            if self.mcr & COM_MCR_OUT1 != 0 {
+                lines |= COM_MSR_RI;
+            }
+            if self.mcr & COM_MCR_OUT2 != 0 {
+                lines |= COM_MSR_DCD;
+            }
+            lines
+        } else {
+            COM_MSR_DCD | COM_MSR_DSR | COM_MSR_CTS
+        };
+        let old = self.msr & !COM_MSR_DELTA;
+        let mut delta = self.msr & COM_MSR_DELTA;
+        let changed = old ^ lines;
+        if changed & COM_MSR_CTS != 0 {
+            delta |= COM_MSR_DCTS;
+        }
+        if changed & COM_MSR_DSR != 0 {
+            delta |= COM_MSR_DDSR;
+        }
+        if changed & COM_MSR_DCD != 0 {
+            delta |= COM_MSR_DDCD;
+        }
+        // Only a falling edge of RI is reported.
+        if old & COM_MSR_RI != 0 && lines & COM_MSR_RI == 0 {
+            delta |= COM_MSR_TERI;
+        }
+        self.msr = lines | delta;
+    }
+}
+
+/// NS16550A serial.
+/// Transmission is instantaneous, so THR, the transmit FIFO and the transmitter are always empty.
+/// Received characters are pulled from the console into the receive FIFO on every access
+/// and whenever the console reports new input.
 pub struct Uart16650 {
-    console: Arc<dyn Console>,
-    intr_enabled: AtomicBool,
+    core: Arc<Uart16650Core>,
 }
 impl Uart16650 {
-    pub fn new(console: Arc<dyn Console>) -> Self {
-        Uart16650 {
+    pub fn new(console: Arc<dyn Console>, irq: IrqLine) -> Self {
+        let core = Arc::new(Uart16650Core {
             console,
-            intr_enabled: AtomicBool::new(false),
+            regs: Mutex::new(Uart16650Regs::new()),
+            irq,
+        });
+        let listener: Arc<dyn ConsoleListener> = Arc::clone(&core) as _;
+        core.console.set_listener(Arc::downgrade(&listener));
+        Uart16650 { core }
+    }
+}
+
+struct Uart16650Core {
+    console: Arc<dyn Console>,
+    regs: Mutex<Uart16650Regs>,
+    irq: IrqLine,
+}
+impl Uart16650Core {
+    // Refill the receiver and drive the output line from IIR.
+    // Called with the register lock held so that line updates are ordered.
+    fn update_irq(&self, regs: &mut Uart16650Regs) {
+        self.pump(regs);
+        self.irq.set_level(self.iir(regs) != COM_IIR_NO_INT);
+    }
+    // Move console input into the receive FIFO. The console is disconnected in loopback mode.
+    fn pump(&self, regs: &mut Uart16650Regs) {
+        if regs.loopback() {
+            regs.rx_idle = true;
+            return;
+        }
+        while regs.rx_fifo.len() < regs.rx_capacity() {
+            match self.console.try_read(true) {
+                Some(chr) => regs.rx_fifo.push_back(chr),
+                None => break,
+            }
         }
+        regs.rx_idle = self.console.try_read(false).is_none();
+    }
+    fn data_ready(&self, regs: &Uart16650Regs) -> bool {
+        !regs.rx_fifo.is_empty()
+    }
+    fn read_rx(&self, regs: &mut Uart16650Regs) -> u8 {
+        regs.rx_fifo.pop_front().unwrap_or(0)
+    }
+    fn write_tx(&self, regs: &mut Uart16650Regs, val: u8) {
+        if regs.loopback() {
+            regs.receive(val);
+        } else {
+            self.console.write(val);
+        }
+        // The character leaves THR immediately.
+        regs.thr_ipending = true;
+    }
+    fn lsr(&self, regs: &Uart16650Regs) -> u8 {
+        let mut x = COM_LSR_TXRDY | COM_LSR_TSRE | regs.lsr_errors;
+        if self.data_ready(regs) {
+            x |= COM_LSR_DATA;
+        }
+        x
+    }
+    // Highest-priority pending interrupt, encoded as in IIR without the FIFO bits.
+    // The character timeout fires once input below the trigger level stops flowing.
+    fn iir(&self, regs: &Uart16650Regs) -> u8 {
+        let rx_level = regs.rx_fifo.len();
+        if regs.ier & COM_IER_RLSI != 0 && regs.lsr_errors != 0 {
+            COM_IIR_RLSI
+        } else if regs.ier & COM_IER_RDI != 0 && rx_level >= regs.rx_trigger_level() {
+            COM_IIR_RDI
+        } else if regs.ier & COM_IER_RDI != 0 && regs.fifo_enabled() && rx_level > 0 && regs.rx_idle
+        {
+            COM_IIR_TIMEOUT
+        } else if regs.ier & COM_IER_THRI != 0 && regs.thr_ipending {
+            COM_IIR_THRI
+        } else if regs.ier & COM_IER_MSI != 0 && regs.msr & COM_MSR_DELTA != 0 {
+            COM_IIR_MSI
+        } else {
+            COM_IIR_NO_INT
+        }
+    }
+    fn load(&self, offset: usize) -> u8 {
+        let mut regs = self.regs.lock();
+        let regs = &mut *regs;
+        self.pump(regs);
+        let val = match offset / MULTIPLIER {
+            COM_DLL if regs.dlab() => regs.dll,
+            COM_RX => self.read_rx(regs),
+            COM_DLM if regs.dlab() => regs.dlm,
+            COM_IER => regs.ier,
+            COM_IIR => {
+                let iir = self.iir(regs);
+                if iir == COM_IIR_THRI {
+                    regs.thr_ipending = false;
+                }
+                if regs.fifo_enabled() {
+                    iir | COM_IIR_FIFO_ENABLED
+                } else {
+                    iir
+                }
+            }
+            COM_LCR => regs.lcr,
+            COM_MCR => regs.mcr,
+            COM_LSR => {
+                let lsr = self.lsr(regs);
+                regs.lsr_errors = 0;
+                lsr
+            }
+            COM_MSR => {
+                let msr = regs.msr;
+                regs.msr &= !COM_MSR_DELTA;
+                msr
+            }
+            COM_SCR => regs.scr,
+            _ => 0,
+        };
+        self.update_irq(regs);
+        val
+    }
+    fn store(&self, offset: usize, val: u8) {
+        let mut regs = self.regs.lock();
+        let regs = &mut *regs;
+        self.pump(regs);
+        match offset / MULTIPLIER {
+            COM_DLL if regs.dlab() => regs.dll = val,
+            COM_TX => {
+                regs.thr_ipending = false;
+                self.write_tx(regs, val);
+            }
+            COM_DLM if regs.dlab() => regs.dlm = val,
+            COM_IER => {
+                let val = val & 0x0f;
+                let changed = regs.ier ^ val;
+                regs.ier = val;
+                if changed & COM_IER_THRI != 0 {
+                    // THR is always empty, so enabling THRI raises it at once.
+                    regs.thr_ipending = val & COM_IER_THRI != 0;
+                }
+            }
+            COM_FCR => {
+                // Toggling the enable bit resets both FIFOs.
+                if (regs.fcr ^ val) & COM_FCR_ENABLE != 0 || val & COM_FCR_CLEAR_RCVR != 0 {
+                    regs.rx_fifo.clear();
+                }
+                // The transmit FIFO is always empty, so COM_FCR_CLEAR_XMIT has nothing to do.
+                regs.fcr = val & (COM_FCR_ENABLE | COM_FCR_DMA_SELECT | COM_FCR_TRIGGER_MASK);
+            }
+            COM_LCR => regs.lcr = val,
+            COM_MCR => {
+                regs.mcr = val & 0x1f;
+                regs.update_msr();
+            }
+            COM_SCR => regs.scr = val,
+            // LSR and MSR writes are ignored.
+            _ => {}
+        }
+        self.update_irq(regs);
+    }
+}
+impl ConsoleListener for Uart16650Core {
+    fn notify_input(&self) {
+        self.update_irq(&mut self.regs.lock());
     }
 }
 impl Device for Uart16650 {
@@ -43,46 +357,267 @@ impl Device for Uart16650 {
     }
     fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
         match access {
-            MMIOAccess::LoadByte(ret) => match offset {
-                _ if offset == COM_RX * MULTIPLIER => {
-                    **ret = self.console.try_read(true).unwrap_or(0);
-                }
-                _ if offset == COM_LSR * MULTIPLIER => {
-                    let mut x = COM_LSR_TXRDY;
-                    if self.console.try_read(false).is_some() {
-                        x |= COM_LSR_DATA;
-                    }
-                    **ret = x;
-                }
-                _ => {
-                    **ret = 0;
-                }
-            },
-            MMIOAccess::StoreByte(val) => match offset {
-                _ if offset == COM_TX * MULTIPLIER => {
-                    self.console.write(*val);
-                }
-                _ if offset == COM_IER * MULTIPLIER => {
-                    self.intr_enabled.store(*val == COM_IER_RDI, Relaxed);
-                }
-                _ => {}
-            },
+            MMIOAccess::LoadByte(ret) => {
+                **ret = self.core.load(offset);
+            }
+            MMIOAccess::StoreByte(val) => {
+                self.core.store(offset, *val);
+            }
             _ => {
                 // malformed access.
                 return None;
             }
         }
-        // Ignored features.
         Some(true)
     }
     fn mmio_region_size(&self) -> usize {
         256
     }
     fn has_interrupt(&self) -> bool {
-        if self.intr_enabled.load(Relaxed) {
-            self.console.try_read(false).is_some()
-        } else {
-            false
+        self.core.irq.level()
+    }
+}
+
+impl DtDevice for Uart16650 {
+    fn dt_name(&self) -> &str {
+        "uart"
+    }
+    fn dt_stdout(&self) -> bool {
+        true
+    }
+    fn write_dt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("compatible", "ns16550a");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::irq::{InterruptSink, IrqLatch};
+    use alloc::collections::VecDeque;
+    use alloc::sync::Weak;
+    use alloc::vec::Vec;
+    struct MockConsole {
+        input: Mutex<VecDeque<u8>>,
+        output: Mutex<Vec<u8>>,
+        listener: Mutex<Option<Weak<dyn ConsoleListener>>>,
+    }
+    impl Console for MockConsole {
+        fn try_read(&self, pop: bool) -> Option<u8> {
+            let mut input = self.input.lock();
+            if pop {
+                input.pop_front()
+            } else {
+                input.front().copied()
+            }
+        }
+        fn write(&self, chr: u8) {
+            self.output.lock().push(chr);
+        }
+        fn notify_char(&self, chr: u8) {
+            self.input.lock().push_back(chr);
+            let listener = self.listener.lock().clone();
+            if let Some(listener) = listener.as_ref().and_then(Weak::upgrade) {
+                listener.notify_input();
+            }
+        }
+        fn set_listener(&self, listener: Weak<dyn ConsoleListener>) {
+            *self.listener.lock() = Some(listener);
+        }
+        fn as_any(&self) -> &dyn core::any::Any {
+            self
+        }
+    }
+    fn setup_with_irq(irq: IrqLine) -> (Arc<MockConsole>, Uart16650) {
+        let console = Arc::new(MockConsole {
+            input: Mutex::new(VecDeque::new()),
+            output: Mutex::new(Vec::new()),
+            listener: Mutex::new(None),
+        });
+        let uart = Uart16650::new(Arc::clone(&console) as Arc<dyn Console>, irq);
+        (console, uart)
+    }
+    fn setup() -> (Arc<MockConsole>, Uart16650) {
+        setup_with_irq(IrqLine::disconnected())
+    }
+    fn lb(uart: &Uart16650, reg: usize) -> u8 {
+        let mut ret = 0;
+        assert_eq!(
+            uart.handle_mmio(reg * MULTIPLIER, &mut MMIOAccess::LoadByte(&mut ret)),
+            Some(true)
+        );
+        ret
+    }
+    fn sb(uart: &Uart16650, reg: usize, val: u8) {
+        assert_eq!(
+            uart.handle_mmio(reg * MULTIPLIER, &mut MMIOAccess::StoreByte(val)),
+            Some(true)
+        );
+    }
+    #[test]
+    fn registers() {
+        let (_, uart) = setup();
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_NO_INT, "No interrupt at reset.");
+        assert_eq!(
+            lb(&uart, COM_LSR),
+            COM_LSR_TXRDY | COM_LSR_TSRE,
+            "Transmitter empty."
+        );
+        sb(&uart, COM_SCR, 0x5a);
+        assert_eq!(lb(&uart, COM_SCR), 0x5a, "Scratch register.");
+        sb(&uart, COM_LCR, COM_LCR_DLAB | COM_LCR_WLEN8);
+        sb(&uart, COM_DLL, 0x0c);
+        sb(&uart, COM_DLM, 0x01);
+        assert_eq!(lb(&uart, COM_DLL), 0x0c, "Divisor latch low.");
+        assert_eq!(lb(&uart, COM_DLM), 0x01, "Divisor latch high.");
+        assert_eq!(lb(&uart, COM_LCR), COM_LCR_DLAB | COM_LCR_WLEN8);
+        sb(&uart, COM_LCR, COM_LCR_WLEN8);
+        assert_eq!(lb(&uart, COM_IER), 0, "IER untouched by divisor writes.");
+        sb(&uart, COM_MCR, COM_MCR_DTR | COM_MCR_RTS | COM_MCR_OUT2);
+        assert_eq!(lb(&uart, COM_MCR), COM_MCR_DTR | COM_MCR_RTS | COM_MCR_OUT2);
+    }
+    #[test]
+    fn interrupt_priority() {
+        let (console, uart) = setup();
+        sb(&uart, COM_IER, COM_IER_RDI | COM_IER_THRI);
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_THRI, "THRE raised on enable.");
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_NO_INT, "IIR read clears THRE.");
+        assert!(!uart.has_interrupt());
+        sb(&uart, COM_TX, b'a');
+        assert_eq!(console.output.lock().as_slice(), b"a");
+        assert!(uart.has_interrupt(), "THRE after transmit.");
+        console.notify_char(b'x');
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_RDI, "RX data beats THRE.");
+        assert_eq!(lb(&uart, COM_RX), b'x');
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_THRI, "THRE still pending.");
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_NO_INT);
+        sb(&uart, COM_IER, COM_IER_RDI);
+        sb(&uart, COM_TX, b'b');
+        assert!(!uart.has_interrupt(), "THRE masked.");
+    }
+    #[test]
+    fn loopback() {
+        let (console, uart) = setup();
+        sb(&uart, COM_IER, COM_IER_RDI | COM_IER_RLSI | COM_IER_MSI);
+        sb(&uart, COM_MCR, COM_MCR_LOOP);
+        assert_eq!(
+            lb(&uart, COM_MSR),
+            COM_MSR_DCTS | COM_MSR_DDSR | COM_MSR_DDCD,
+            "Lines dropped when entering loopback."
+        );
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_NO_INT);
+        sb(&uart, COM_MCR, COM_MCR_LOOP | COM_MCR_RTS | COM_MCR_OUT1);
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_MSI, "Modem status changed.");
+        assert_eq!(lb(&uart, COM_MSR), COM_MSR_DCTS | COM_MSR_CTS | COM_MSR_RI);
+        sb(&uart, COM_MCR, COM_MCR_LOOP | COM_MCR_RTS);
+        assert_eq!(lb(&uart, COM_MSR), COM_MSR_TERI | COM_MSR_CTS);
+        console.notify_char(b'z');
+        sb(&uart, COM_TX, 0x55);
+        assert!(console.output.lock().is_empty(), "Nothing leaves the chip.");
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_RDI);
+        sb(&uart, COM_TX, 0xaa);
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_RLSI, "Overrun.");
+        assert_eq!(
+            lb(&uart, COM_LSR),
+            COM_LSR_TXRDY | COM_LSR_TSRE | COM_LSR_OE | COM_LSR_DATA
+        );
+        assert_eq!(lb(&uart, COM_RX), 0xaa);
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_NO_INT);
+        sb(&uart, COM_MCR, 0);
+        assert_eq!(lb(&uart, COM_RX), b'z', "Console input after loopback.");
+    }
+    #[test]
+    fn fifo() {
+        let (console, uart) = setup();
+        sb(&uart, COM_IER, COM_IER_RDI);
+        sb(&uart, COM_FCR, COM_FCR_ENABLE | COM_FCR_TRIGGER_4);
+        assert_eq!(
+            lb(&uart, COM_IIR),
+            COM_IIR_FIFO_ENABLED | COM_IIR_NO_INT,
+            "FIFOs enabled."
+        );
+        for chr in 0..20 {
+            console.notify_char(chr);
+        }
+        assert_eq!(
+            lb(&uart, COM_IIR),
+            COM_IIR_FIFO_ENABLED | COM_IIR_RDI,
+            "Above trigger level."
+        );
+        for chr in 0..13 {
+            assert_eq!(lb(&uart, COM_RX), chr);
+        }
+        assert_eq!(
+            lb(&uart, COM_IIR),
+            COM_IIR_FIFO_ENABLED | COM_IIR_RDI,
+            "FIFO refilled from console."
+        );
+        for chr in 13..17 {
+            assert_eq!(lb(&uart, COM_RX), chr);
         }
+        assert_eq!(
+            lb(&uart, COM_IIR),
+            COM_IIR_FIFO_ENABLED | COM_IIR_TIMEOUT,
+            "Input stopped below trigger level."
+        );
+        for chr in 17..20 {
+            assert_eq!(lb(&uart, COM_RX), chr);
+        }
+        assert_eq!(lb(&uart, COM_LSR) & COM_LSR_DATA, 0, "Drained.");
+        assert!(!uart.has_interrupt());
+    }
+    #[test]
+    fn fifo_reset() {
+        let (console, uart) = setup();
+        sb(&uart, COM_IER, COM_IER_RDI);
+        sb(&uart, COM_FCR, COM_FCR_ENABLE | COM_FCR_TRIGGER_14);
+        for chr in 0..4 {
+            console.notify_char(chr);
+        }
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_FIFO_ENABLED | COM_IIR_TIMEOUT);
+        sb(
+            &uart,
+            COM_FCR,
+            COM_FCR_ENABLE | COM_FCR_CLEAR_RCVR | COM_FCR_CLEAR_XMIT | COM_FCR_TRIGGER_14,
+        );
+        assert_eq!(lb(&uart, COM_LSR) & COM_LSR_DATA, 0, "RX FIFO cleared.");
+        assert!(!uart.has_interrupt());
+        // Loopback fills the FIFO until it overruns.
+        sb(&uart, COM_MCR, COM_MCR_LOOP);
+        for chr in 0..UART_FIFO_SIZE as u8 {
+            sb(&uart, COM_TX, chr);
+        }
+        assert_eq!(lb(&uart, COM_IIR), COM_IIR_FIFO_ENABLED | COM_IIR_RDI);
+        sb(&uart, COM_TX, 0xff);
+        assert_ne!(lb(&uart, COM_LSR) & COM_LSR_OE, 0, "Overrun.");
+        for chr in 0..UART_FIFO_SIZE as u8 {
+            assert_eq!(lb(&uart, COM_RX), chr, "Overrun character dropped.");
+        }
+        sb(&uart, COM_TX, 1);
+        sb(&uart, COM_FCR, 0);
+        assert_eq!(
+            lb(&uart, COM_IIR),
+            COM_IIR_NO_INT,
+            "Disabling resets FIFOs."
+        );
+    }
+    #[test]
+    fn irq_line() {
+        let latch = Arc::new(IrqLatch::new());
+        let (console, uart) = setup_with_irq(IrqLine::new(
+            Arc::clone(&latch) as Arc<dyn InterruptSink>,
+            0,
+        ));
+        assert_eq!(latch.take_changes(), Some(0));
+        sb(&uart, COM_IER, COM_IER_RDI);
+        assert_eq!(latch.take_changes(), None, "No change on IER write.");
+        console.notify_char(b'x');
+        assert_eq!(latch.take_changes(), Some(1), "Raised by console input.");
+        console.notify_char(b'y');
+        assert_eq!(latch.take_changes(), None, "Already raised.");
+        assert_eq!(lb(&uart, COM_RX), b'x');
+        assert_eq!(latch.take_changes(), None, "Still data in RBR.");
+        assert_eq!(lb(&uart, COM_RX), b'y');
+        assert_eq!(latch.take_changes(), Some(0), "Lowered once drained.");
     }
 }
@@ -0,0 +1,411 @@
+use super::Clock;
+use crate::board::{hart_interrupts, DtDevice, IRQ_S_SOFT, IRQ_S_TIMER};
+use crate::fdt::FdtWriter;
+use crate::irq::IrqLine;
+use crate::*;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering::*};
+use spin::Mutex;
+pub const CLINT_MAX_HARTS: usize = 4095;
+pub const CLINT_MMIO_SIZE: usize = 0x10000;
+pub const ACLINT_MTIMER_MMIO_SIZE: usize = 0x8000;
+pub const ACLINT_SWI_MMIO_SIZE: usize = 0x4000;
+// CLINT layout.
+const CLINT_MSIP: usize = 0x0;
+const CLINT_MTIMECMP: usize = 0x4000;
+const CLINT_MTIME: usize = 0xbff8;
+// ACLINT MTIMER layout.
+const ACLINT_MTIMECMP: usize = 0x0;
+const ACLINT_MTIME: usize = 0x7ff8;
+
+// Access a 64-bit register, either whole or as one of its 32-bit halves.
+// Returns the new register value for stores, or None for malformed accesses.
+fn access_u64(value: u64, offset: usize, access: &mut MMIOAccess) -> Option<Option<u64>> {
+    let shift = (offset % 8) * 8;
+    match access {
+        MMIOAccess::LoadDword(ret) if shift == 0 => **ret = value,
+        MMIOAccess::StoreDword(val) if shift == 0 => return Some(Some(*val)),
+        MMIOAccess::LoadWord(ret) if shift % 32 == 0 => **ret = (value >> shift) as u32,
+        MMIOAccess::StoreWord(val) if shift % 32 == 0 => {
+            let mask = 0xffff_ffffu64 << shift;
+            return Some(Some(value & !mask | (*val as u64) << shift));
+        }
+        _ => return None,
+    }
+    Some(None)
+}
+
+// mtime and the per-hart mtimecmp registers.
+// The timer line of a hart is asserted while mtime >= mtimecmp. Since time passes without
+// any MMIO access, the owner calls `poll` to re-evaluate the comparators.
+struct MTimer {
+    clock: Arc<dyn Clock>,
+    // Guest mtime minus clock time, so that the guest may write mtime.
+    offset: AtomicU64,
+    mtimecmp: Vec<AtomicU64>,
+    outputs: Vec<IrqLine>,
+    // when updating the comparators or the output lines: lock.
+    lock: Mutex<()>,
+}
+
+impl MTimer {
+    fn new(clock: Arc<dyn Clock>, outputs: Vec<IrqLine>) -> Self {
+        assert!(outputs.len() <= CLINT_MAX_HARTS, "too many harts");
+        MTimer {
+            clock,
+            offset: AtomicU64::new(0),
+            // No interrupt until the guest programs the comparator.
+            mtimecmp: (0..outputs.len()).map(|_| AtomicU64::new(!0)).collect(),
+            outputs,
+            lock: Mutex::new(()),
+        }
+    }
+    fn mtime(&self) -> u64 {
+        self.clock.now().wrapping_add(self.offset.load(SeqCst))
+    }
+    // Must be called with the lock held.
+    fn update(&self) {
+        let now = self.mtime();
+        for (cmp, output) in self.mtimecmp.iter().zip(self.outputs.iter()) {
+            output.set_level(now >= cmp.load(SeqCst));
+        }
+    }
+    fn poll(&self) {
+        let _lock = self.lock.lock();
+        self.update();
+    }
+    fn next_event(&self) -> Option<u64> {
+        let now = self.mtime();
+        let offset = self.offset.load(SeqCst);
+        self.mtimecmp
+            .iter()
+            .map(|x| x.load(SeqCst))
+            .filter(|&cmp| cmp > now && cmp != !0)
+            .min()
+            .map(|cmp| cmp.wrapping_sub(offset))
+    }
+    fn set_mtimecmp(&self, hart: usize, val: u64) -> bool {
+        let cmp = match self.mtimecmp.get(hart) {
+            Some(x) => x,
+            None => return false,
+        };
+        let _lock = self.lock.lock();
+        cmp.store(val, SeqCst);
+        self.update();
+        true
+    }
+    fn handle_mtime(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        let _lock = self.lock.lock();
+        if let Some(val) = access_u64(self.mtime(), offset, access)? {
+            self.offset
+                .store(val.wrapping_sub(self.clock.now()), SeqCst);
+            self.update();
+        }
+        Some(true)
+    }
+    fn handle_mtimecmp(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        let cmp = self.mtimecmp.get(offset / 8)?;
+        let _lock = self.lock.lock();
+        if let Some(val) = access_u64(cmp.load(SeqCst), offset, access)? {
+            cmp.store(val, SeqCst);
+            self.update();
+        }
+        Some(true)
+    }
+}
+
+// A 32-bit software interrupt register per hart.
+// Machine-level registers (msip) hold the level of the line. Supervisor-level registers (setssip)
+// read as zero and send an edge-triggered request for every write of 1.
+fn handle_swi(
+    outputs: &[IrqLine],
+    supervisor: bool,
+    offset: usize,
+    access: &mut MMIOAccess,
+) -> Option<bool> {
+    if offset % 4 != 0 {
+        // misaligned access.
+        return None;
+    }
+    let output = outputs.get(offset / 4)?;
+    match access {
+        MMIOAccess::LoadWord(ret) => **ret = (!supervisor && output.level()) as u32,
+        MMIOAccess::StoreWord(val) => {
+            if supervisor {
+                if *val & 1 == 1 {
+                    output.pulse();
+                }
+            } else {
+                output.set_level(*val & 1 == 1);
+            }
+        }
+        _ => {
+            // malformed access.
+            return None;
+        }
+    }
+    Some(true)
+}
+
+// SiFive-compatible core-local interruptor: msip, mtimecmp and mtime in one region.
+// Each hart has a software and a timer interrupt line.
+pub struct Clint {
+    timer: MTimer,
+    software: Vec<IrqLine>,
+}
+
+impl Clint {
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        software_outputs: Vec<IrqLine>,
+        timer_outputs: Vec<IrqLine>,
+    ) -> Self {
+        assert_eq!(
+            software_outputs.len(),
+            timer_outputs.len(),
+            "one software and one timer line per hart"
+        );
+        Clint {
+            timer: MTimer::new(clock, timer_outputs),
+            software: software_outputs,
+        }
+    }
+    pub fn num_harts(&self) -> usize {
+        self.software.len()
+    }
+    pub fn mtime(&self) -> u64 {
+        self.timer.mtime()
+    }
+    /// Re-evaluate the timer comparators against the current time.
+    pub fn poll(&self) {
+        self.timer.poll()
+    }
+    /// Clock time at which the next timer interrupt fires, if any is programmed.
+    pub fn next_event(&self) -> Option<u64> {
+        self.timer.next_event()
+    }
+    /// Program the comparator of `hart` on behalf of the guest, e.g. for SBI set_timer.
+    /// Returns false if the hart does not exist.
+    pub fn set_mtimecmp(&self, hart: usize, val: u64) -> bool {
+        self.timer.set_mtimecmp(hart, val)
+    }
+}
+
+impl Device for Clint {
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+    fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        if offset < CLINT_MTIMECMP {
+            handle_swi(&self.software, false, offset - CLINT_MSIP, access)
+        } else if offset < CLINT_MTIME {
+            self.timer.handle_mtimecmp(offset - CLINT_MTIMECMP, access)
+        } else if offset < CLINT_MTIME + 8 {
+            self.timer.handle_mtime(offset - CLINT_MTIME, access)
+        } else {
+            Some(false)
+        }
+    }
+    fn mmio_region_size(&self) -> usize {
+        CLINT_MMIO_SIZE
+    }
+    fn has_interrupt(&self) -> bool {
+        self.software
+            .iter()
+            .chain(self.timer.outputs.iter())
+            .any(|x| x.level())
+    }
+}
+
+impl DtDevice for Clint {
+    fn dt_name(&self) -> &str {
+        "clint"
+    }
+    fn write_dt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("compatible", "riscv,clint0");
+        fdt.property_cells(
+            "interrupts-extended",
+            &hart_interrupts(self.num_harts(), &[Some(IRQ_S_SOFT), Some(IRQ_S_TIMER)]),
+        );
+    }
+}
+
+// ACLINT MTIMER device: mtimecmp registers followed by mtime.
+pub struct AclintMtimer {
+    timer: MTimer,
+}
+
+impl AclintMtimer {
+    pub fn new(clock: Arc<dyn Clock>, timer_outputs: Vec<IrqLine>) -> Self {
+        AclintMtimer {
+            timer: MTimer::new(clock, timer_outputs),
+        }
+    }
+    pub fn mtime(&self) -> u64 {
+        self.timer.mtime()
+    }
+    /// Re-evaluate the timer comparators against the current time.
+    pub fn poll(&self) {
+        self.timer.poll()
+    }
+    /// Clock time at which the next timer interrupt fires, if any is programmed.
+    pub fn next_event(&self) -> Option<u64> {
+        self.timer.next_event()
+    }
+    /// Program the comparator of `hart` on behalf of the guest, e.g. for SBI set_timer.
+    /// Returns false if the hart does not exist.
+    pub fn set_mtimecmp(&self, hart: usize, val: u64) -> bool {
+        self.timer.set_mtimecmp(hart, val)
+    }
+}
+
+impl Device for AclintMtimer {
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+    fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        if offset < ACLINT_MTIME {
+            self.timer.handle_mtimecmp(offset - ACLINT_MTIMECMP, access)
+        } else if offset < ACLINT_MTIME + 8 {
+            self.timer.handle_mtime(offset - ACLINT_MTIME, access)
+        } else {
+            Some(false)
+        }
+    }
+    fn mmio_region_size(&self) -> usize {
+        ACLINT_MTIMER_MMIO_SIZE
+    }
+    fn has_interrupt(&self) -> bool {
+        self.timer.outputs.iter().any(|x| x.level())
+    }
+}
+
+// ACLINT software interrupt device, either machine-level (MSWI) or supervisor-level (SSWI).
+pub struct AclintSwi {
+    supervisor: bool,
+    outputs: Vec<IrqLine>,
+}
+
+impl AclintSwi {
+    /// MSWI: one msip register per hart holding the level of its line.
+    pub fn mswi(outputs: Vec<IrqLine>) -> Self {
+        assert!(outputs.len() <= CLINT_MAX_HARTS, "too many harts");
+        AclintSwi {
+            supervisor: false,
+            outputs,
+        }
+    }
+    /// SSWI: one setssip register per hart, sending an edge to its line on every write of 1.
+    pub fn sswi(outputs: Vec<IrqLine>) -> Self {
+        assert!(outputs.len() <= CLINT_MAX_HARTS, "too many harts");
+        AclintSwi {
+            supervisor: true,
+            outputs,
+        }
+    }
+}
+
+impl Device for AclintSwi {
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+    fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        handle_swi(&self.outputs, self.supervisor, offset, access)
+    }
+    fn mmio_region_size(&self) -> usize {
+        ACLINT_SWI_MMIO_SIZE
+    }
+    fn has_interrupt(&self) -> bool {
+        self.outputs.iter().any(|x| x.level())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::irq::{InterruptSink, IrqLatch};
+    use crate::timer::ManualClock;
+    fn lines(latch: &Arc<IrqLatch>, first: usize, n: usize) -> Vec<IrqLine> {
+        (first..first + n)
+            .map(|id| IrqLine::new(Arc::clone(latch) as Arc<dyn InterruptSink>, id))
+            .collect()
+    }
+    fn ld(dev: &dyn Device, offset: usize) -> Option<u64> {
+        let mut ret = 0;
+        dev.handle_mmio(offset, &mut MMIOAccess::LoadDword(&mut ret))?;
+        Some(ret)
+    }
+    fn sd(dev: &dyn Device, offset: usize, val: u64) -> Option<bool> {
+        dev.handle_mmio(offset, &mut MMIOAccess::StoreDword(val))
+    }
+    fn lw(dev: &dyn Device, offset: usize) -> Option<u32> {
+        let mut ret = 0;
+        dev.handle_mmio(offset, &mut MMIOAccess::LoadWord(&mut ret))?;
+        Some(ret)
+    }
+    fn sw(dev: &dyn Device, offset: usize, val: u32) -> Option<bool> {
+        dev.handle_mmio(offset, &mut MMIOAccess::StoreWord(val))
+    }
+    #[test]
+    fn clint() {
+        let clock = Arc::new(ManualClock::new(10_000_000));
+        let latch = Arc::new(IrqLatch::new());
+        // Software lines 0 and 1, timer lines 2 and 3.
+        let clint = Clint::new(
+            Arc::clone(&clock) as Arc<dyn Clock>,
+            lines(&latch, 0, 2),
+            lines(&latch, 2, 2),
+        );
+        latch.take_changes();
+        clock.set(100);
+        assert_eq!(ld(&clint, CLINT_MTIME), Some(100));
+        assert_eq!(clint.next_event(), None);
+        sd(&clint, CLINT_MTIMECMP + 8, 150).unwrap();
+        assert_eq!(clint.next_event(), Some(150));
+        clock.advance(49);
+        clint.poll();
+        assert_eq!(latch.take_changes(), None);
+        clock.advance(1);
+        clint.poll();
+        assert_eq!(latch.take_changes(), Some(1 << 3), "Timer of hart 1.");
+        // Move the comparator with two 32-bit writes, as RV32 guests do.
+        sw(&clint, CLINT_MTIMECMP + 12, 0).unwrap();
+        sw(&clint, CLINT_MTIMECMP + 8, 1000).unwrap();
+        assert_eq!(latch.take_changes(), Some(0), "Rearmed.");
+        assert_eq!(lw(&clint, CLINT_MTIMECMP + 8), Some(1000));
+        sd(&clint, CLINT_MTIME, 1000).unwrap();
+        assert_eq!(latch.take_changes(), Some(1 << 3), "mtime written.");
+        assert_eq!(ld(&clint, CLINT_MTIME), Some(1000));
+        sw(&clint, CLINT_MSIP, 1).unwrap();
+        assert_eq!(lw(&clint, CLINT_MSIP), Some(1));
+        assert_eq!(latch.take_changes(), Some(1 << 3 | 1));
+        sw(&clint, CLINT_MSIP, 0).unwrap();
+        assert!(sw(&clint, CLINT_MSIP + 8, 1).is_none(), "No hart 2.");
+        assert!(!clint.set_mtimecmp(2, 0));
+        assert!(clint.set_mtimecmp(1, !0));
+        assert_eq!(latch.take_changes(), Some(0), "Timer of hart 1 disarmed.");
+        assert!(ld(&clint, CLINT_MTIME + 4).is_none(), "Misaligned.");
+        assert!(clint
+            .handle_mmio(CLINT_MSIP, &mut MMIOAccess::StoreByte(1))
+            .is_none());
+    }
+    #[test]
+    fn aclint() {
+        let clock = Arc::new(ManualClock::new(10_000_000));
+        let latch = Arc::new(IrqLatch::new());
+        let mtimer = AclintMtimer::new(Arc::clone(&clock) as Arc<dyn Clock>, lines(&latch, 5, 1));
+        let sswi = AclintSwi::sswi(lines(&latch, 1, 1));
+        latch.take_changes();
+        sd(&mtimer, ACLINT_MTIMECMP, 10).unwrap();
+        clock.set(10);
+        mtimer.poll();
+        assert_eq!(ld(&mtimer, ACLINT_MTIME), Some(10));
+        assert_eq!(latch.take_changes(), Some(1 << 5));
+        sw(&sswi, 0, 1).unwrap();
+        assert_eq!(lw(&sswi, 0), Some(0), "setssip reads as zero.");
+        assert_eq!(latch.take_edges(), 1 << 1);
+        sw(&sswi, 0, 0).unwrap();
+        assert_eq!(latch.take_edges(), 0);
+    }
+}
@@ -0,0 +1,223 @@
+use super::Clock;
+use crate::board::DtDevice;
+use crate::fdt::FdtWriter;
+use crate::irq::IrqLine;
+use crate::*;
+use alloc::sync::Arc;
+use spin::Mutex;
+pub const GOLDFISH_RTC_MMIO_SIZE: usize = 0x1000;
+const RTC_TIME_LOW: usize = 0x00;
+const RTC_TIME_HIGH: usize = 0x04;
+const RTC_ALARM_LOW: usize = 0x08;
+const RTC_ALARM_HIGH: usize = 0x0c;
+const RTC_IRQ_ENABLED: usize = 0x10;
+const RTC_CLEAR_ALARM: usize = 0x14;
+const RTC_ALARM_STATUS: usize = 0x18;
+const RTC_CLEAR_INTERRUPT: usize = 0x1c;
+const NSEC_PER_SEC: u128 = 1_000_000_000;
+
+struct RtcState {
+    // Guest time minus time since the epoch, so that the guest may set the clock.
+    offset: u64,
+    // TIME_HIGH as of the last read of TIME_LOW.
+    time_high: u32,
+    alarm: u64,
+    alarm_armed: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+// The Goldfish real-time clock: nanoseconds since the Unix epoch, and an alarm.
+// Time passes with `clock`. There is no timer behind the alarm: it goes off at the first access
+// to the device after it expires.
+pub struct GoldfishRtc {
+    clock: Arc<dyn Clock>,
+    boot_time: u64,
+    output: IrqLine,
+    state: Mutex<RtcState>,
+}
+
+impl GoldfishRtc {
+    /// `boot_time` is the time in nanoseconds since the Unix epoch at tick 0 of `clock`.
+    pub fn new(clock: Arc<dyn Clock>, boot_time: u64, output: IrqLine) -> Self {
+        GoldfishRtc {
+            clock,
+            boot_time,
+            output,
+            state: Mutex::new(RtcState {
+                offset: 0,
+                time_high: 0,
+                alarm: 0,
+                alarm_armed: false,
+                irq_enabled: false,
+                irq_pending: false,
+            }),
+        }
+    }
+    fn since_epoch(&self) -> u64 {
+        let ticks = self.clock.now() as u128 * NSEC_PER_SEC / self.clock.frequency() as u128;
+        self.boot_time.wrapping_add(ticks as u64)
+    }
+    fn update(&self, state: &mut RtcState, now: u64) {
+        if state.alarm_armed && now >= state.alarm {
+            state.alarm_armed = false;
+            state.irq_pending = true;
+        }
+        self.output
+            .set_level(state.irq_pending && state.irq_enabled);
+    }
+}
+
+impl Device for GoldfishRtc {
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+    fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        if offset >= GOLDFISH_RTC_MMIO_SIZE {
+            return Some(false);
+        }
+        let mut state = self.state.lock();
+        let since_epoch = self.since_epoch();
+        let now = since_epoch.wrapping_add(state.offset);
+        // The alarm may have expired since the last access.
+        self.update(&mut state, now);
+        match access {
+            MMIOAccess::LoadWord(ret) => {
+                **ret = match offset {
+                    RTC_TIME_LOW => {
+                        state.time_high = (now >> 32) as u32;
+                        now as u32
+                    }
+                    RTC_TIME_HIGH => state.time_high,
+                    RTC_ALARM_LOW => state.alarm as u32,
+                    RTC_ALARM_HIGH => (state.alarm >> 32) as u32,
+                    RTC_IRQ_ENABLED => state.irq_enabled as u32,
+                    RTC_ALARM_STATUS => state.alarm_armed as u32,
+                    _ => 0,
+                }
+            }
+            MMIOAccess::StoreWord(val) => {
+                let val = *val as u64;
+                match offset {
+                    RTC_TIME_LOW => {
+                        let new = now & !0xffff_ffff | val;
+                        state.offset = new.wrapping_sub(since_epoch);
+                    }
+                    RTC_TIME_HIGH => {
+                        let new = now & 0xffff_ffff | val << 32;
+                        state.offset = new.wrapping_sub(since_epoch);
+                    }
+                    // Writing the low half arms the alarm.
+                    RTC_ALARM_LOW => {
+                        state.alarm = state.alarm & !0xffff_ffff | val;
+                        state.alarm_armed = true;
+                    }
+                    RTC_ALARM_HIGH => state.alarm = state.alarm & 0xffff_ffff | val << 32,
+                    RTC_IRQ_ENABLED => state.irq_enabled = val & 1 == 1,
+                    RTC_CLEAR_ALARM => state.alarm_armed = false,
+                    RTC_CLEAR_INTERRUPT => state.irq_pending = false,
+                    _ => {}
+                }
+            }
+            _ => {
+                // malformed access.
+                return None;
+            }
+        }
+        let now = since_epoch.wrapping_add(state.offset);
+        self.update(&mut state, now);
+        Some(true)
+    }
+    fn mmio_region_size(&self) -> usize {
+        GOLDFISH_RTC_MMIO_SIZE
+    }
+    fn has_interrupt(&self) -> bool {
+        self.output.level()
+    }
+}
+
+impl DtDevice for GoldfishRtc {
+    fn dt_name(&self) -> &str {
+        "rtc"
+    }
+    fn write_dt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("compatible", "google,goldfish-rtc");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::irq::{InterruptSink, IrqLatch};
+    use crate::timer::ManualClock;
+    const BOOT_TIME: u64 = 1_600_000_000 * NSEC_PER_SEC as u64;
+    fn lw(rtc: &GoldfishRtc, offset: usize) -> u32 {
+        let mut ret = 0;
+        assert_eq!(
+            rtc.handle_mmio(offset, &mut MMIOAccess::LoadWord(&mut ret)),
+            Some(true)
+        );
+        ret
+    }
+    fn sw(rtc: &GoldfishRtc, offset: usize, val: u32) {
+        assert_eq!(
+            rtc.handle_mmio(offset, &mut MMIOAccess::StoreWord(val)),
+            Some(true)
+        );
+    }
+    fn time(rtc: &GoldfishRtc) -> u64 {
+        let low = lw(rtc, RTC_TIME_LOW) as u64;
+        (lw(rtc, RTC_TIME_HIGH) as u64) << 32 | low
+    }
+    #[test]
+    fn rtc() {
+        let clock = Arc::new(ManualClock::new(10_000_000));
+        let latch = Arc::new(IrqLatch::new());
+        let rtc = GoldfishRtc::new(
+            Arc::clone(&clock) as Arc<dyn Clock>,
+            BOOT_TIME,
+            IrqLine::new(Arc::clone(&latch) as Arc<dyn InterruptSink>, 3),
+        );
+        assert_eq!(time(&rtc), BOOT_TIME);
+        // One tick is 100ns.
+        clock.advance(15);
+        assert_eq!(time(&rtc), BOOT_TIME + 1500);
+        // The high half stays as it was when the low half was read.
+        let low = lw(&rtc, RTC_TIME_LOW);
+        // Five seconds carry into the high half.
+        clock.advance(50_000_000);
+        assert_eq!(lw(&rtc, RTC_TIME_HIGH), ((BOOT_TIME + 1500) >> 32) as u32);
+        assert_ne!(lw(&rtc, RTC_TIME_LOW), low);
+        // Setting the clock, high half first as Linux does.
+        sw(&rtc, RTC_TIME_HIGH, 0);
+        sw(&rtc, RTC_TIME_LOW, 1000);
+        assert_eq!(time(&rtc), 1000);
+        clock.advance(10);
+        assert_eq!(time(&rtc), 2000);
+        // An alarm goes off once it expires, and interrupts while enabled.
+        sw(&rtc, RTC_ALARM_HIGH, 0);
+        sw(&rtc, RTC_ALARM_LOW, 3000);
+        sw(&rtc, RTC_IRQ_ENABLED, 1);
+        assert_eq!(lw(&rtc, RTC_ALARM_STATUS), 1);
+        assert!(!latch.level(3));
+        clock.advance(10);
+        assert_eq!(lw(&rtc, RTC_ALARM_STATUS), 0);
+        assert!(latch.level(3));
+        sw(&rtc, RTC_IRQ_ENABLED, 0);
+        assert!(!latch.level(3));
+        sw(&rtc, RTC_IRQ_ENABLED, 1);
+        assert!(rtc.has_interrupt());
+        sw(&rtc, RTC_CLEAR_INTERRUPT, 1);
+        assert!(!latch.level(3));
+        // A cleared alarm never goes off.
+        sw(&rtc, RTC_ALARM_LOW, 5000);
+        sw(&rtc, RTC_CLEAR_ALARM, 1);
+        clock.advance(100);
+        assert_eq!(lw(&rtc, RTC_ALARM_LOW), 5000);
+        assert!(!latch.level(3));
+        assert_eq!(
+            rtc.handle_mmio(RTC_TIME_LOW, &mut MMIOAccess::LoadByte(&mut 0)),
+            None
+        );
+    }
+}
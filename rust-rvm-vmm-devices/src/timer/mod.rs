@@ -0,0 +1,41 @@
+use core::sync::atomic::{AtomicU64, Ordering::*};
+// Time base shared by the guest harts.
+pub trait Clock: Send + Sync {
+    // Ticks since an arbitrary origin. Never goes backwards.
+    fn now(&self) -> u64;
+    // Ticks per second, advertised to the guest as `timebase-frequency`.
+    fn frequency(&self) -> u64;
+}
+
+/// Clock that only moves when told to, for tests and deterministic runs.
+pub struct ManualClock {
+    ticks: AtomicU64,
+    frequency: u64,
+}
+
+impl ManualClock {
+    pub fn new(frequency: u64) -> Self {
+        ManualClock {
+            ticks: AtomicU64::new(0),
+            frequency,
+        }
+    }
+    pub fn set(&self, ticks: u64) {
+        self.ticks.store(ticks, SeqCst);
+    }
+    pub fn advance(&self, ticks: u64) {
+        self.ticks.fetch_add(ticks, SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.ticks.load(SeqCst)
+    }
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+pub mod clint;
+pub mod goldfish_rtc;
@@ -0,0 +1,42 @@
+/// General-purpose registers of a guest hart, numbered as in the ISA (x0 to x31).
+pub trait GuestRegs {
+    fn reg(&self, n: usize) -> usize;
+    /// Writes to x0 are ignored.
+    fn set_reg(&mut self, n: usize, val: usize);
+}
+
+pub const REG_RA: usize = 1;
+pub const REG_SP: usize = 2;
+pub const REG_A0: usize = 10;
+pub const REG_A1: usize = 11;
+pub const REG_A2: usize = 12;
+pub const REG_A3: usize = 13;
+pub const REG_A4: usize = 14;
+pub const REG_A5: usize = 15;
+pub const REG_A6: usize = 16;
+pub const REG_A7: usize = 17;
+
+/// Plain register file, e.g. for host-side tests.
+impl GuestRegs for [usize; 32] {
+    fn reg(&self, n: usize) -> usize {
+        if n == 0 {
+            0
+        } else {
+            self[n]
+        }
+    }
+    fn set_reg(&mut self, n: usize, val: usize) {
+        if n != 0 {
+            self[n] = val;
+        }
+    }
+}
+
+/// Guest physical memory.
+pub trait GuestMemory {
+    /// Copy guest memory at `gpa` into `buf`. Nothing is copied and false is returned unless the
+    /// whole range is backed by RAM.
+    fn read(&self, gpa: u64, buf: &mut [u8]) -> bool;
+    /// Copy `buf` into guest memory at `gpa`, with the same rule as `read`.
+    fn write(&self, gpa: u64, buf: &[u8]) -> bool;
+}
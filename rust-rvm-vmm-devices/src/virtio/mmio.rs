@@ -0,0 +1,98 @@
+use crate::board::DtDevice;
+use crate::fdt::FdtWriter;
+use crate::*;
+pub const VIRTIO_MMIO_SIZE: usize = 0x1000;
+const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
+const VIRTIO_MMIO_VERSION: usize = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c;
+// "virt" in little endian.
+const VIRTIO_MAGIC: u32 = 0x7472_6976;
+// The virtio 1.0 layout of the registers.
+const VIRTIO_VERSION: u32 = 2;
+// "QEMU" in little endian, as guests written for `virt` may check.
+const VIRTIO_VENDOR: u32 = 0x554d_4551;
+
+// A virtio-mmio transport with no device plugged in. Drivers find device ID 0 and skip it.
+// The slots of a board are there so that its layout matches the machine the guest was built for.
+pub struct VirtioMmioSlot;
+
+impl Device for VirtioMmioSlot {
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+    fn handle_mmio(&self, offset: usize, access: &mut MMIOAccess) -> Option<bool> {
+        if offset >= VIRTIO_MMIO_SIZE {
+            return Some(false);
+        }
+        match access {
+            MMIOAccess::LoadWord(ret) => {
+                **ret = match offset {
+                    VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MAGIC,
+                    VIRTIO_MMIO_VERSION => VIRTIO_VERSION,
+                    // No device.
+                    VIRTIO_MMIO_DEVICE_ID => 0,
+                    VIRTIO_MMIO_VENDOR_ID => VIRTIO_VENDOR,
+                    _ => 0,
+                }
+            }
+            MMIOAccess::StoreWord(_) => {}
+            // The configuration space allows narrower accesses. There is none here.
+            MMIOAccess::LoadByte(ret) if offset >= 0x100 => **ret = 0,
+            MMIOAccess::LoadHalf(ret) if offset >= 0x100 => **ret = 0,
+            MMIOAccess::StoreByte(_) | MMIOAccess::StoreHalf(_) if offset >= 0x100 => {}
+            _ => {
+                // malformed access.
+                return None;
+            }
+        }
+        Some(true)
+    }
+    fn mmio_region_size(&self) -> usize {
+        VIRTIO_MMIO_SIZE
+    }
+}
+
+impl DtDevice for VirtioMmioSlot {
+    fn dt_name(&self) -> &str {
+        "virtio_mmio"
+    }
+    fn write_dt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("compatible", "virtio,mmio");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn lw(offset: usize) -> Option<u32> {
+        let mut ret = 0xdead;
+        VirtioMmioSlot.handle_mmio(offset, &mut MMIOAccess::LoadWord(&mut ret))?;
+        Some(ret)
+    }
+    #[test]
+    fn empty_slot() {
+        assert_eq!(lw(VIRTIO_MMIO_MAGIC_VALUE), Some(VIRTIO_MAGIC));
+        assert_eq!(lw(VIRTIO_MMIO_VERSION), Some(2));
+        assert_eq!(lw(VIRTIO_MMIO_DEVICE_ID), Some(0));
+        assert_eq!(lw(VIRTIO_MMIO_VENDOR_ID), Some(VIRTIO_VENDOR));
+        // Status writes are dropped.
+        assert_eq!(
+            VirtioMmioSlot.handle_mmio(0x70, &mut MMIOAccess::StoreWord(1)),
+            Some(true)
+        );
+        assert_eq!(lw(0x70), Some(0));
+        assert_eq!(
+            VirtioMmioSlot.handle_mmio(0, &mut MMIOAccess::LoadByte(&mut 0)),
+            None
+        );
+        assert_eq!(
+            VirtioMmioSlot.handle_mmio(0x100, &mut MMIOAccess::LoadByte(&mut 0)),
+            Some(true)
+        );
+        assert_eq!(
+            VirtioMmioSlot.handle_mmio(VIRTIO_MMIO_SIZE, &mut MMIOAccess::StoreWord(0)),
+            Some(false)
+        );
+    }
+}
@@ -0,0 +1,2 @@
+// virtio devices. So far only the MMIO transport, without any device behind it.
+pub mod mmio;
@@ -13,7 +13,7 @@ macro_rules! impl_bit_extend {
                         let max_entire_mask = !0usize;
                         let max_current_mask: Self = !0;
                         let new_mask = max_entire_mask ^ (max_current_mask as usize);
-                        val = val | new_mask;
+                        val |= new_mask;
                     }
                 }
                 return val;
@@ -0,0 +1,60 @@
+use crate::hypervisor::{Result, Vcpu, VcpuExit, VcpuState};
+use alloc::boxed::Box;
+
+/// A vCPU whose state is read from the backend at most once per exit, and written back at most
+/// once, right before it resumes. Exits that look at registers a few times, e.g. MMIO and SBI
+/// calls, then cost the backend two state transfers at most.
+pub struct CachedVcpu {
+    vcpu: Box<dyn Vcpu>,
+    state: Option<VcpuState>,
+    dirty: bool,
+}
+
+impl CachedVcpu {
+    pub fn new(vcpu: Box<dyn Vcpu>) -> Self {
+        CachedVcpu {
+            vcpu,
+            state: None,
+            dirty: false,
+        }
+    }
+    fn flush(&mut self) -> Result<()> {
+        if self.dirty {
+            if let Some(state) = &self.state {
+                self.vcpu.write_state(state)?;
+            }
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Vcpu for CachedVcpu {
+    fn resume(&mut self) -> Result<VcpuExit> {
+        self.flush()?;
+        self.state = None;
+        self.vcpu.resume()
+    }
+    fn read_state(&mut self) -> Result<VcpuState> {
+        if self.state.is_none() {
+            self.state = Some(self.vcpu.read_state()?);
+        }
+        Ok(self.state.clone().unwrap())
+    }
+    fn write_state(&mut self, state: &VcpuState) -> Result<()> {
+        if self.state.as_ref() != Some(state) {
+            self.state = Some(state.clone());
+            self.dirty = true;
+        }
+        Ok(())
+    }
+    fn set_interrupt(&mut self, irq: usize, level: bool) -> Result<()> {
+        self.vcpu.set_interrupt(irq, level)
+    }
+    fn inject_exception(&mut self, cause: u64, tval: u64) -> Result<()> {
+        // The backend changes the state, e.g. pc, from what it was told last.
+        self.flush()?;
+        self.state = None;
+        self.vcpu.inject_exception(cause, tval)
+    }
+}
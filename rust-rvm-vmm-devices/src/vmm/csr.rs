@@ -0,0 +1,69 @@
+use crate::hypervisor::{CsrExit, CsrWrite, Result, Vcpu, CSR_SIREG, CSR_SISELECT, CSR_STOPEI};
+use crate::irq::imsic::IMSIC;
+use crate::vcpu::GuestRegs;
+use alloc::sync::Arc;
+
+const CAUSE_ILLEGAL_INSTRUCTION: u64 = 2;
+/// siselect holds the numbers from 0 to 0xfff, which is as many as it must.
+const SISELECT_MASK: u64 = 0xfff;
+
+/// The AIA CSRs of a hart whose external interrupt comes from an IMSIC interrupt file.
+pub struct AiaCsrs {
+    file: Arc<IMSIC>,
+    siselect: u64,
+}
+
+impl AiaCsrs {
+    pub fn new(file: Arc<IMSIC>) -> Self {
+        AiaCsrs { file, siselect: 0 }
+    }
+}
+
+fn apply(write: CsrWrite, old: u64) -> u64 {
+    match write {
+        CsrWrite::Write(val) => val,
+        CsrWrite::Set(bits) => old | bits,
+        CsrWrite::Clear(bits) => old & !bits,
+    }
+}
+
+/// Emulate an access of a vCPU to an AIA CSR of its hart, with `csrs`. Harts without an interrupt
+/// file have no such CSRs, and sireg selects only the registers of the file. Accesses to anything
+/// else raise an illegal instruction exception in the guest.
+/// Automatically does pc-increment and register writeback.
+pub fn emulate_csr(vcpu: &mut dyn Vcpu, csrs: Option<&mut AiaCsrs>, exit: &CsrExit) -> Result<()> {
+    let csrs = match csrs {
+        Some(csrs) => csrs,
+        None => return vcpu.inject_exception(CAUSE_ILLEGAL_INSTRUCTION, 0),
+    };
+    let old = match exit.csr {
+        CSR_SISELECT => {
+            let old = csrs.siselect;
+            if let Some(write) = exit.write {
+                csrs.siselect = apply(write, old) & SISELECT_MASK;
+            }
+            Some(old)
+        }
+        CSR_SIREG => {
+            let iselect = csrs.siselect as usize;
+            match exit.write {
+                Some(write) => csrs.file.modify_indirect(iselect, |old| apply(write, old)),
+                None => csrs.file.read_indirect(iselect),
+            }
+        }
+        // Writes claim the interrupt they read, whatever the value.
+        CSR_STOPEI => Some(match exit.write {
+            Some(_) => csrs.file.claim_topei(),
+            None => csrs.file.topei(),
+        } as u64),
+        _ => None,
+    };
+    let old = match old {
+        Some(old) => old,
+        None => return vcpu.inject_exception(CAUSE_ILLEGAL_INSTRUCTION, 0),
+    };
+    let mut state = vcpu.read_state()?;
+    state.set_reg(exit.dst_reg, old as usize);
+    state.pc += 4;
+    vcpu.write_state(&state)
+}
@@ -0,0 +1,228 @@
+use crate::hypervisor::MmioExit;
+use crate::isa::*;
+use crate::vcpu::{GuestMemory, GuestRegs};
+// Decoding of guest accesses to MMIO from the instructions making them, for backends that only
+// report the address.
+
+const PPN_MASK: u64 = (1 << 44) - 1;
+
+/// What an instruction that faulted on MMIO does there.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MmioInst {
+    /// A load or store, as backends that decode instructions report it.
+    Access(MmioExit),
+    /// An AMO: the old value is loaded into `dst_reg`, sign-extended, and what the AMO with
+    /// `funct5` makes of it and `data` is stored.
+    Amo { access: MmioExit, funct5: u32 },
+    /// An SC, which stores `data` and always succeeds. An LR is just a load.
+    StoreConditional(MmioExit),
+}
+
+/// Guest physical address of `va`, walking the page tables of the guest in `ram` as `satp` says.
+/// Permissions are not checked: this only looks at instructions the guest already ran.
+pub fn translate(ram: &dyn GuestMemory, satp: u64, va: u64) -> Option<u64> {
+    let levels = match satp >> 60 {
+        0 => return Some(va),
+        8 => 3,
+        9 => 4,
+        10 => 5,
+        _ => return None,
+    };
+    let mut table = (satp & PPN_MASK) << 12;
+    for level in (0..levels).rev() {
+        let mut pte = [0u8; 8];
+        if !ram.read(table + (va >> (12 + 9 * level) & 0x1ff) * 8, &mut pte) {
+            return None;
+        }
+        let pte = u64::from_le_bytes(pte);
+        if pte & 1 == 0 {
+            return None;
+        }
+        let base = (pte >> 10 & PPN_MASK) << 12;
+        // Neither readable nor executable: a pointer to the next level.
+        if pte & 0xa == 0 {
+            table = base;
+            continue;
+        }
+        let offset_mask = (1 << (12 + 9 * level)) - 1;
+        return Some(base & !offset_mask | va & offset_mask);
+    }
+    None
+}
+
+/// The instruction at `pc`, with compressed ones expanded, and its length.
+pub fn fetch(ram: &dyn GuestMemory, satp: u64, pc: u64) -> Option<(u32, usize)> {
+    // Halves are translated one by one, as an instruction may cross a page boundary.
+    let parcel = |va: u64| {
+        let mut buf = [0u8; 2];
+        if ram.read(translate(ram, satp, va)?, &mut buf) {
+            Some(u16::from_le_bytes(buf))
+        } else {
+            None
+        }
+    };
+    let low = parcel(pc)?;
+    if low & 3 != 3 {
+        return Some((expand_compressed(low)?, 2));
+    }
+    let high = parcel(pc.wrapping_add(2))?;
+    Some(((high as u32) << 16 | low as u32, 4))
+}
+
+/// The access at `addr` of instruction `inst` of `len` bytes, with register operands from `regs`.
+/// None if it is not a load, a store or an AMO on general-purpose registers.
+pub fn decode(inst: u32, len: usize, addr: u64, regs: &dyn GuestRegs) -> Option<MmioInst> {
+    let rd = (inst >> 7 & 0x1f) as usize;
+    let funct3 = inst >> 12 & 7;
+    let rs2 = (inst >> 20 & 0x1f) as usize;
+    let src = regs.reg(rs2) as u64;
+    let access = |size: usize, read: bool| MmioExit {
+        addr,
+        size,
+        read,
+        data: if size == 8 {
+            src
+        } else {
+            src & ((1 << (8 * size)) - 1)
+        },
+        dst_reg: rd,
+        sign_extend: true,
+        inst_len: len,
+    };
+    match inst & 0x7f {
+        // lb, lh, lw, ld, lbu, lhu and lwu.
+        LOAD if funct3 != 7 => Some(MmioInst::Access(MmioExit {
+            sign_extend: funct3 < 4,
+            ..access(1 << (funct3 & 3), true)
+        })),
+        STORE if funct3 < 4 => Some(MmioInst::Access(MmioExit {
+            dst_reg: 0,
+            ..access(1 << funct3, false)
+        })),
+        AMO if funct3 == 2 || funct3 == 3 => {
+            let size = 1 << funct3;
+            match inst >> 27 {
+                2 if rs2 == 0 => Some(MmioInst::Access(access(size, true))),
+                3 => Some(MmioInst::StoreConditional(access(size, false))),
+                funct5 if amo(funct5, size as u64).is_some() => Some(MmioInst::Amo {
+                    access: access(size, true),
+                    funct5,
+                }),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hypervisor::mock::MockHypervisor;
+    use crate::hypervisor::Hypervisor;
+    use crate::vmm::memory::GuestRam;
+    const RAM: u64 = 0x8000_0000;
+    const MMIO: u64 = 0x1000_0000;
+    #[test]
+    fn accesses() {
+        let mut regs = [0usize; 32];
+        regs[9] = 0x1234_5678_9abc_def0;
+        let decoded = |parcels: &[u16], regs: &[usize; 32]| {
+            let (inst, len) = if parcels.len() == 1 {
+                (expand_compressed(parcels[0]).unwrap(), 2)
+            } else {
+                ((parcels[1] as u32) << 16 | parcels[0] as u32, 4)
+            };
+            decode(inst, len, MMIO, regs)
+        };
+        let exit = |size, read, data, dst_reg, sign_extend, inst_len| MmioExit {
+            addr: MMIO,
+            size,
+            read,
+            data,
+            dst_reg,
+            sign_extend,
+            inst_len,
+        };
+        // lhu a0, 2(a1)
+        let lhu = i_type(2, 11, 5, 10, LOAD);
+        assert_eq!(
+            decoded(&[lhu as u16, (lhu >> 16) as u16], &regs),
+            Some(MmioInst::Access(exit(2, true, 0, 10, false, 4)))
+        );
+        // c.lw a0, 0(a1)
+        assert_eq!(
+            decoded(&[0x4188], &regs),
+            Some(MmioInst::Access(exit(4, true, 0, 10, true, 2)))
+        );
+        // c.sd s1, 8(a0) and sb s1, 0(a0). Stores leave out the bits they do not store.
+        assert_eq!(
+            decoded(&[0xe504], &regs),
+            Some(MmioInst::Access(exit(8, false, regs[9] as u64, 0, true, 2)))
+        );
+        let sb = s_type(0, 9, 10, 0, STORE);
+        assert_eq!(
+            decoded(&[sb as u16, (sb >> 16) as u16], &regs),
+            Some(MmioInst::Access(exit(1, false, 0xf0, 0, true, 4)))
+        );
+        // amoor.w t0, s1, (a0) and sc.d t0, s1, (a0).
+        let amoor = r_type(0x08 << 2, 9, 10, 2, 5, AMO);
+        assert_eq!(
+            decoded(&[amoor as u16, (amoor >> 16) as u16], &regs),
+            Some(MmioInst::Amo {
+                access: exit(4, true, 0x9abc_def0, 5, true, 4),
+                funct5: 0x08,
+            })
+        );
+        let sc = r_type(0x03 << 2, 9, 10, 3, 5, AMO);
+        assert_eq!(
+            decoded(&[sc as u16, (sc >> 16) as u16], &regs),
+            Some(MmioInst::StoreConditional(exit(
+                8,
+                false,
+                regs[9] as u64,
+                5,
+                true,
+                4
+            )))
+        );
+        // c.fld fa0, 8(a1), and addi.
+        assert_eq!(decoded(&[0x2588], &regs), None);
+        let addi = i_type(1, 10, 0, 10, OP_IMM);
+        assert_eq!(decoded(&[addi as u16, (addi >> 16) as u16], &regs), None);
+    }
+    #[test]
+    fn fetch_through_page_tables() {
+        let hypervisor = MockHypervisor::new();
+        let mem = hypervisor.add_memory_region(RAM, 0x10000).unwrap();
+        let mut pte = |table: usize, index: usize, pte: u64| {
+            let at = table + index * 8;
+            mem.data[at..at + 8].copy_from_slice(&pte.to_le_bytes());
+        };
+        // Sv39: RAM as a gigapage at its own address, and two pages at the top of the address
+        // space, in the other order.
+        pte(0x1000, 2, (RAM >> 12) << 10 | 0xcf);
+        pte(0x1000, 0x1ff, ((RAM + 0x2000) >> 12) << 10 | 1);
+        pte(0x2000, 0, ((RAM + 0x3000) >> 12) << 10 | 1);
+        pte(0x3000, 0, ((RAM + 0x5000) >> 12) << 10 | 0xb);
+        pte(0x3000, 1, ((RAM + 0x4000) >> 12) << 10 | 0xb);
+        // A 4-byte instruction across the two pages, then a compressed one.
+        let lw = i_type(0, 11, 2, 10, LOAD);
+        mem.data[0x5ffe..0x6000].copy_from_slice(&(lw as u16).to_le_bytes());
+        mem.data[0x4000..0x4002].copy_from_slice(&((lw >> 16) as u16).to_le_bytes());
+        mem.data[0x4002..0x4004].copy_from_slice(&0x4188u16.to_le_bytes());
+        let mut ram = GuestRam::new();
+        ram.add_region(&mem);
+        let satp = 8 << 60 | (RAM + 0x1000) >> 12;
+        let va = 0xffff_ffff_c000_0000;
+        assert_eq!(translate(&ram, satp, RAM + 0x1234), Some(RAM + 0x1234));
+        assert_eq!(translate(&ram, satp, va + 0x1008), Some(RAM + 0x4008));
+        assert_eq!(fetch(&ram, satp, va + 0xffe), Some((lw, 4)));
+        assert_eq!(
+            fetch(&ram, satp, va + 0x1002),
+            Some((expand_compressed(0x4188).unwrap(), 2))
+        );
+        assert_eq!(fetch(&ram, satp, va + 0x2000), None, "Not mapped.");
+        assert_eq!(fetch(&ram, 0, RAM + 0x4002).map(|(_, len)| len), Some(2));
+    }
+}
@@ -0,0 +1,458 @@
+use super::memory::GuestRam;
+use crate::vcpu::GuestMemory;
+use alloc::vec::Vec;
+use core::ops::Range;
+// Guest kernel images: ELF64 files, loaded segment by segment, Linux `Image` files, and flat
+// binaries. Also the initrd that goes with a Linux kernel.
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+// The RISC-V `Image` header: text_offset at 8, image_size at 16 and the magics at 48 and 56. The
+// first one is deprecated, but kernels before 5.x only have that.
+const IMAGE_HEADER_SIZE: usize = 64;
+const IMAGE_MAGIC: &[u8] = b"RISCV\0\0\0";
+const IMAGE_MAGIC2: &[u8] = b"RSC\x05";
+const PAGE_SIZE: u64 = 4096;
+/// How far into RAM the initrd goes at least, so that the kernel does not run over it while it
+/// sets itself up. QEMU does the same.
+const INITRD_DISTANCE: u64 = 128 * 1024 * 1024;
+/// Room for the device tree, the largest one Linux takes.
+pub const FDT_MAX_SIZE: u64 = 0x20_0000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The file ends where its headers say there is more.
+    Truncated,
+    /// An ELF file, but not a little-endian RV64 one.
+    UnsupportedElf,
+    /// A segment does not fit in a RAM region of the guest.
+    OutsideRam { gpa: u64, len: u64 },
+    /// A segment takes more of the file than of memory.
+    BadSegment { index: usize },
+    /// Nothing to load.
+    NoSegments,
+}
+
+/// Where an image went.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LoadedImage {
+    /// Guest physical address to start the boot hart at.
+    pub entry: u64,
+    /// The first address above everything loaded.
+    pub end: u64,
+}
+
+/// What the header of a Linux `Image` says about where it goes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// Offset of the image from the start of RAM.
+    pub text_offset: u64,
+    /// Memory the kernel takes from where it is loaded, BSS included. 0 if not known.
+    pub image_size: u64,
+}
+
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(ELF_MAGIC)
+}
+
+/// The header of `image` if it is a RISC-V Linux `Image`.
+pub fn image_header(image: &[u8]) -> Option<ImageHeader> {
+    if image.len() < IMAGE_HEADER_SIZE
+        || (&image[48..56] != IMAGE_MAGIC && &image[56..60] != IMAGE_MAGIC2)
+    {
+        return None;
+    }
+    Some(ImageHeader {
+        text_offset: field(image, 8, 8).ok()?,
+        image_size: field(image, 16, 8).ok()?,
+    })
+}
+
+/// Load `image` into `ram`: at the physical addresses of its segments if it is an ELF file, at
+/// its text_offset from `ram_base` if it is a Linux `Image`, and at `ram_base` otherwise.
+pub fn load_image(image: &[u8], ram_base: u64, ram: &GuestRam) -> Result<LoadedImage, LoadError> {
+    if is_elf(image) {
+        load_elf(image, ram)
+    } else if let Some(header) = image_header(image) {
+        load_linux(image, &header, ram_base, ram)
+    } else {
+        load_flat(image, ram_base, ram)
+    }
+}
+
+/// Copy a Linux `Image` with `header` to its text_offset from `ram_base`, where it also starts.
+/// The memory it takes beyond the file must be RAM as well, but is left to the kernel to clear.
+pub fn load_linux(
+    image: &[u8],
+    header: &ImageHeader,
+    ram_base: u64,
+    ram: &GuestRam,
+) -> Result<LoadedImage, LoadError> {
+    let gpa = ram_base.wrapping_add(header.text_offset);
+    let len = header.image_size.max(image.len() as u64);
+    let end = ram_base
+        .checked_add(header.text_offset)
+        .and_then(|gpa| gpa.checked_add(len));
+    let end = match end {
+        Some(end) if ram.contains(gpa, len as usize) => end,
+        _ => return Err(LoadError::OutsideRam { gpa, len }),
+    };
+    ram.write(gpa, image);
+    Ok(LoadedImage { entry: gpa, end })
+}
+
+/// Where an initrd of `len` bytes goes in the RAM region at `ram_base` of `ram_size` bytes, with
+/// `kernel` loaded there: on a page boundary, half way into RAM, but no further than 128 MiB, and
+/// above the kernel in any case.
+pub fn initrd_gpa(kernel: &LoadedImage, ram_base: u64, ram_size: u64) -> u64 {
+    let gpa = (ram_base + (ram_size / 2).min(INITRD_DISTANCE)).max(kernel.end);
+    (gpa + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Where the device tree goes in the RAM at `ram`: the last `FDT_MAX_SIZE` bytes, as on QEMU, out of
+/// the way of the kernel and the initrd. The guest must be told to keep off it with a memory
+/// reservation, and the images must be loaded below it.
+pub fn fdt_region(ram: &Range<u64>) -> Range<u64> {
+    ram.end - FDT_MAX_SIZE..ram.end
+}
+
+/// Copy `initrd` to `gpa`, and return the guest physical addresses it takes, for
+/// `linux,initrd-start` and `linux,initrd-end`.
+pub fn load_initrd(initrd: &[u8], gpa: u64, ram: &GuestRam) -> Result<Range<u64>, LoadError> {
+    load_flat(initrd, gpa, ram).map(|loaded| gpa..loaded.end)
+}
+
+/// Copy `image` to `gpa`, where it also starts.
+pub fn load_flat(image: &[u8], gpa: u64, ram: &GuestRam) -> Result<LoadedImage, LoadError> {
+    if !ram.write(gpa, image) {
+        return Err(LoadError::OutsideRam {
+            gpa,
+            len: image.len() as u64,
+        });
+    }
+    Ok(LoadedImage {
+        entry: gpa,
+        end: gpa + image.len() as u64,
+    })
+}
+
+fn field(image: &[u8], offset: usize, size: usize) -> Result<u64, LoadError> {
+    let bytes = offset
+        .checked_add(size)
+        .and_then(|end| image.get(offset..end))
+        .ok_or(LoadError::Truncated)?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |val, &byte| val << 8 | byte as u64))
+}
+
+struct Segment {
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+/// Load the PT_LOAD segments of an ELF64 `image` at their physical addresses, with what is not
+/// in the file zeroed. The entry point is translated to a physical address by the segment that
+/// holds it, for kernels linked to run at another virtual address.
+pub fn load_elf(image: &[u8], ram: &GuestRam) -> Result<LoadedImage, LoadError> {
+    if image.len() < EHDR_SIZE {
+        return Err(LoadError::Truncated);
+    }
+    if image[4] != ELFCLASS64 || image[5] != ELFDATA2LSB || field(image, 18, 2)? != EM_RISCV as u64
+    {
+        return Err(LoadError::UnsupportedElf);
+    }
+    let entry = field(image, 24, 8)?;
+    let phoff = field(image, 32, 8)? as usize;
+    let phentsize = field(image, 54, 2)? as usize;
+    let phnum = field(image, 56, 2)? as usize;
+    if phentsize < PHDR_SIZE {
+        return Err(LoadError::UnsupportedElf);
+    }
+    let mut segments = Vec::new();
+    for index in 0..phnum {
+        let phdr = index
+            .checked_mul(phentsize)
+            .and_then(|at| at.checked_add(phoff))
+            .ok_or(LoadError::Truncated)?;
+        if field(image, phdr, 4)? != PT_LOAD as u64 {
+            continue;
+        }
+        let segment = Segment {
+            offset: field(image, phdr + 8, 8)?,
+            vaddr: field(image, phdr + 16, 8)?,
+            paddr: field(image, phdr + 24, 8)?,
+            filesz: field(image, phdr + 32, 8)?,
+            memsz: field(image, phdr + 40, 8)?,
+        };
+        if segment.filesz > segment.memsz {
+            return Err(LoadError::BadSegment { index });
+        }
+        if segment
+            .offset
+            .checked_add(segment.filesz)
+            .map_or(true, |end| end > image.len() as u64)
+        {
+            return Err(LoadError::Truncated);
+        }
+        // Checked before anything is written, so that a bad image leaves RAM alone.
+        if !ram.contains(segment.paddr, segment.memsz as usize) {
+            return Err(LoadError::OutsideRam {
+                gpa: segment.paddr,
+                len: segment.memsz,
+            });
+        }
+        segments.push(segment);
+    }
+    if segments.is_empty() {
+        return Err(LoadError::NoSegments);
+    }
+    let zeros = [0u8; 4096];
+    let mut end = 0;
+    let mut entry_pa = entry;
+    for segment in segments.iter() {
+        let data = &image[segment.offset as usize..(segment.offset + segment.filesz) as usize];
+        ram.write(segment.paddr, data);
+        let mut zeroed = segment.filesz;
+        while zeroed < segment.memsz {
+            let len = (segment.memsz - zeroed).min(zeros.len() as u64);
+            ram.write(segment.paddr + zeroed, &zeros[..len as usize]);
+            zeroed += len;
+        }
+        end = end.max(segment.paddr + segment.memsz);
+        if entry.wrapping_sub(segment.vaddr) < segment.memsz {
+            entry_pa = segment.paddr + (entry - segment.vaddr);
+        }
+    }
+    Ok(LoadedImage {
+        entry: entry_pa,
+        end,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hypervisor::mock::MockHypervisor;
+    use crate::hypervisor::Hypervisor;
+    const RAM: u64 = 0x8020_0000;
+    const VIRT: u64 = 0xffff_ffff_c020_0000;
+    // An RV64 ELF file with `segments` of (paddr, data, memsz), linked at VIRT + paddr - RAM.
+    fn elf(entry: u64, segments: &[(u64, &[u8], u64)]) -> Vec<u8> {
+        let mut file = vec![0u8; EHDR_SIZE + PHDR_SIZE * segments.len()];
+        file[..4].copy_from_slice(ELF_MAGIC);
+        file[4] = ELFCLASS64;
+        file[5] = ELFDATA2LSB;
+        file[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        file[24..32].copy_from_slice(&entry.to_le_bytes());
+        file[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        file[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        file[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        for (i, &(paddr, data, memsz)) in segments.iter().enumerate() {
+            let offset = file.len() as u64;
+            file.extend_from_slice(data);
+            let phdr = &mut file[EHDR_SIZE + i * PHDR_SIZE..][..PHDR_SIZE];
+            phdr[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            for (at, val) in [
+                (8, offset),
+                (16, VIRT + (paddr - RAM)),
+                (24, paddr),
+                (32, data.len() as u64),
+                (40, memsz),
+            ]
+            .iter()
+            {
+                phdr[*at..*at + 8].copy_from_slice(&val.to_le_bytes());
+            }
+        }
+        file
+    }
+    fn ram() -> (GuestRam, &'static mut [u8]) {
+        let mem = MockHypervisor::new()
+            .add_memory_region(RAM, 0x4000)
+            .unwrap();
+        for byte in mem.data.iter_mut() {
+            *byte = 0xff;
+        }
+        let mut ram = GuestRam::new();
+        ram.add_region(&mem);
+        (ram, mem.data)
+    }
+    #[test]
+    fn elf_segments() {
+        let (ram, data) = ram();
+        let image = elf(
+            VIRT + 0x1002,
+            &[(RAM + 0x1000, b"text", 4), (RAM + 0x2000, b"data", 0x1800)],
+        );
+        assert!(is_elf(&image));
+        assert_eq!(
+            load_image(&image, RAM, &ram),
+            Ok(LoadedImage {
+                entry: RAM + 0x1002,
+                end: RAM + 0x3800,
+            })
+        );
+        assert_eq!(&data[0x1000..0x1004], b"text");
+        assert_eq!(&data[0x2000..0x2004], b"data");
+        assert!(data[0x2004..0x3800].iter().all(|&byte| byte == 0), "BSS");
+        assert_eq!(data[0x3800], 0xff);
+        assert_eq!(data[0], 0xff, "Nothing loaded outside the segments.");
+    }
+    #[test]
+    fn bad_elf() {
+        let (ram, data) = ram();
+        // The second segment runs past the end of RAM. Nothing is loaded.
+        let image = elf(VIRT, &[(RAM, b"text", 4), (RAM + 0x3000, b"data", 0x1001)]);
+        assert_eq!(
+            load_elf(&image, &ram),
+            Err(LoadError::OutsideRam {
+                gpa: RAM + 0x3000,
+                len: 0x1001,
+            })
+        );
+        assert_eq!(data[0], 0xff);
+        let image = elf(VIRT, &[(RAM, b"text", 2)]);
+        assert_eq!(
+            load_elf(&image, &ram),
+            Err(LoadError::BadSegment { index: 0 })
+        );
+        let image = elf(VIRT, &[(RAM, b"text", 4)]);
+        assert_eq!(
+            load_elf(&image[..image.len() - 1], &ram),
+            Err(LoadError::Truncated)
+        );
+        assert_eq!(load_elf(&image[..60], &ram), Err(LoadError::Truncated));
+        // Program headers at the end of the address space.
+        let mut far = image.clone();
+        far[32..40].copy_from_slice(&u64::max_value().to_le_bytes());
+        assert_eq!(load_elf(&far, &ram), Err(LoadError::Truncated));
+        assert_eq!(load_elf(&elf(VIRT, &[]), &ram), Err(LoadError::NoSegments));
+        let mut image = image;
+        image[18] = 62;
+        assert_eq!(load_elf(&image, &ram), Err(LoadError::UnsupportedElf));
+    }
+    #[test]
+    fn flat() {
+        let (ram, data) = ram();
+        assert_eq!(
+            load_image(b"\x13\x05\x10\x00", RAM + 0x100, &ram),
+            Ok(LoadedImage {
+                entry: RAM + 0x100,
+                end: RAM + 0x104,
+            })
+        );
+        assert_eq!(&data[0x100..0x104], b"\x13\x05\x10\x00");
+        assert_eq!(
+            load_flat(&[0; 0x100], RAM + 0x3f80, &ram),
+            Err(LoadError::OutsideRam {
+                gpa: RAM + 0x3f80,
+                len: 0x100,
+            })
+        );
+    }
+    // A Linux `Image` of `len` bytes, with a header that says `text_offset` and `image_size`.
+    fn linux(text_offset: u64, image_size: u64, len: usize) -> Vec<u8> {
+        let mut image = vec![0x5au8; len];
+        image[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        image[16..24].copy_from_slice(&image_size.to_le_bytes());
+        image[48..56].copy_from_slice(IMAGE_MAGIC);
+        image[56..60].copy_from_slice(IMAGE_MAGIC2);
+        image
+    }
+    #[test]
+    fn linux_image() {
+        let (ram, data) = ram();
+        let image = linux(0x1000, 0x2000, 0x100);
+        assert_eq!(
+            image_header(&image),
+            Some(ImageHeader {
+                text_offset: 0x1000,
+                image_size: 0x2000,
+            })
+        );
+        assert_eq!(
+            load_image(&image, RAM, &ram),
+            Ok(LoadedImage {
+                entry: RAM + 0x1000,
+                end: RAM + 0x3000,
+            })
+        );
+        assert_eq!(&data[0x1000..0x1100], &image[..]);
+        assert_eq!(data[0x1100], 0xff, "The kernel clears its own BSS.");
+        // Only the old magic, and no image_size: the file is all there is.
+        let mut old = linux(0, 0, 0x80);
+        old[56..60].copy_from_slice(&[0; 4]);
+        assert_eq!(
+            load_image(&old, RAM, &ram),
+            Ok(LoadedImage {
+                entry: RAM,
+                end: RAM + 0x80,
+            })
+        );
+        // The BSS would run past the end of RAM. Nothing is loaded.
+        let image = linux(0x3000, 0x1001, 0x100);
+        assert_eq!(
+            load_image(&image, RAM, &ram),
+            Err(LoadError::OutsideRam {
+                gpa: RAM + 0x3000,
+                len: 0x1001,
+            })
+        );
+        assert_eq!(data[0x3000], 0xff);
+        // Offsets and sizes past the end of the address space.
+        let image = linux(u64::max_value() - 0xff, 0, 0x100);
+        assert_eq!(
+            load_image(&image, RAM, &ram),
+            Err(LoadError::OutsideRam {
+                gpa: RAM - 0x100,
+                len: 0x100,
+            })
+        );
+        let image = linux(0x1000, u64::max_value(), 0x100);
+        assert_eq!(
+            load_image(&image, RAM, &ram),
+            Err(LoadError::OutsideRam {
+                gpa: RAM + 0x1000,
+                len: u64::max_value(),
+            })
+        );
+        let mut image = linux(0, 0, 0x80);
+        image[48..60].copy_from_slice(&[0; 12]);
+        assert_eq!(image_header(&image), None);
+        assert_eq!(image_header(&linux(0, 0, 0x80)[..63]), None);
+    }
+    #[test]
+    fn initrd() {
+        let (ram, data) = ram();
+        let kernel = LoadedImage {
+            entry: RAM,
+            end: RAM + 0x1234,
+        };
+        // Half of a small RAM, at most 128 MiB in, and never under the kernel.
+        assert_eq!(initrd_gpa(&kernel, RAM, 0x4000), RAM + 0x2000);
+        assert_eq!(initrd_gpa(&kernel, RAM, 1 << 30), RAM + (128 << 20));
+        assert_eq!(initrd_gpa(&kernel, RAM, 0x2000), RAM + 0x2000);
+        assert_eq!(
+            load_initrd(b"initrd", RAM + 0x2000, &ram),
+            Ok(RAM + 0x2000..RAM + 0x2006)
+        );
+        assert_eq!(&data[0x2000..0x2006], b"initrd");
+        assert_eq!(
+            load_initrd(&[0; 0x2001], RAM + 0x2000, &ram),
+            Err(LoadError::OutsideRam {
+                gpa: RAM + 0x2000,
+                len: 0x2001,
+            })
+        );
+    }
+}
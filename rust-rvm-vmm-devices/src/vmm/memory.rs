@@ -0,0 +1,63 @@
+use crate::hypervisor::MemoryRegion;
+use crate::vcpu::GuestMemory;
+use alloc::vec::Vec;
+
+/// The RAM regions added to the VM, as the VMM sees them.
+/// Regions are mapped for the lifetime of the VM, so only their host addresses are kept.
+#[derive(Default)]
+pub struct GuestRam {
+    // (guest physical address, host address, length)
+    regions: Vec<(u64, usize, usize)>,
+}
+
+impl GuestRam {
+    pub fn new() -> Self {
+        GuestRam {
+            regions: Vec::new(),
+        }
+    }
+    pub fn add_region(&mut self, region: &MemoryRegion) {
+        self.regions
+            .push((region.gpa, region.data.as_ptr() as usize, region.data.len()));
+    }
+    /// Whether the `len` bytes at `gpa` lie in one region.
+    pub fn contains(&self, gpa: u64, len: usize) -> bool {
+        self.host_addr(gpa, len).is_some()
+    }
+    // Host address of `len` bytes at `gpa`, if they lie in one region.
+    fn host_addr(&self, gpa: u64, len: usize) -> Option<usize> {
+        for &(base, host, size) in self.regions.iter() {
+            let offset = match gpa.checked_sub(base) {
+                Some(offset) => offset as usize,
+                None => continue,
+            };
+            if offset <= size && len <= size - offset {
+                return Some(host + offset);
+            }
+        }
+        None
+    }
+}
+
+impl GuestMemory for GuestRam {
+    fn read(&self, gpa: u64, buf: &mut [u8]) -> bool {
+        match self.host_addr(gpa, buf.len()) {
+            Some(src) => {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(src as *const u8, buf.as_mut_ptr(), buf.len())
+                };
+                true
+            }
+            None => false,
+        }
+    }
+    fn write(&self, gpa: u64, buf: &[u8]) -> bool {
+        match self.host_addr(gpa, buf.len()) {
+            Some(dst) => {
+                unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), dst as *mut u8, buf.len()) };
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use super::bits::BitExtendToUsize;
+use super::decode::{decode, fetch, MmioInst};
+use crate::hypervisor::Error::{HandleMMIOError, UnhandledMmioError};
+use crate::hypervisor::{MmioExit, MmioFault, Result, Vcpu};
+use crate::isa::amo;
+use crate::vcpu::{GuestMemory, GuestRegs};
+use crate::{Device, MMIOAccess};
+
+const CAUSE_LOAD_ACCESS: u64 = 5;
+const CAUSE_STORE_ACCESS: u64 = 7;
+
+/// What the VMM does with guest accesses that no device claims.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnhandledMmio {
+    /// Raise a load or store access fault in the guest, as buses of real boards do. stval is the
+    /// guest physical address, as the VMM knows no other.
+    AccessFault,
+    /// Loads read zero, and stores are dropped.
+    ReadZeroWriteIgnore,
+    /// Stop the VMM with `UnhandledMmioError`.
+    Abort,
+}
+
+impl Default for UnhandledMmio {
+    fn default() -> Self {
+        UnhandledMmio::AccessFault
+    }
+}
+
+fn load<T: BitExtendToUsize>(vcpu: &mut dyn Vcpu, exit: &MmioExit, val: T) -> Result<()> {
+    let mut state = vcpu.read_state()?;
+    state.set_reg(exit.dst_reg, val.to_usize(exit.sign_extend));
+    state.pc += exit.inst_len;
+    vcpu.write_state(&state)
+}
+
+fn step(vcpu: &mut dyn Vcpu, exit: &MmioExit) -> Result<()> {
+    let mut state = vcpu.read_state()?;
+    state.pc += exit.inst_len;
+    vcpu.write_state(&state)
+}
+
+fn unclaimed(vcpu: &mut dyn Vcpu, exit: &MmioExit, unhandled: UnhandledMmio) -> Result<()> {
+    match unhandled {
+        UnhandledMmio::AccessFault if exit.read => {
+            vcpu.inject_exception(CAUSE_LOAD_ACCESS, exit.addr)
+        }
+        UnhandledMmio::AccessFault => vcpu.inject_exception(CAUSE_STORE_ACCESS, exit.addr),
+        UnhandledMmio::ReadZeroWriteIgnore if exit.read => load(vcpu, exit, 0u64),
+        UnhandledMmio::ReadZeroWriteIgnore => step(vcpu, exit),
+        UnhandledMmio::Abort => Err(UnhandledMmioError(*exit)),
+    }
+}
+
+/// Emulate an MMIO access of a vCPU on `bus`, and accesses no device claims as `unhandled` says.
+/// Automatically does pc-increment, register writeback and sign/zero extension.
+pub fn emulate_mmio(
+    vcpu: &mut dyn Vcpu,
+    bus: &dyn Device,
+    exit: &MmioExit,
+    unhandled: UnhandledMmio,
+) -> Result<()> {
+    let addr = exit.addr as usize;
+    match (exit.size, exit.read) {
+        // writes
+        (1, false) => {
+            if let Some(true) = bus.handle_mmio(addr, &mut MMIOAccess::StoreByte(exit.data as u8)) {
+                return step(vcpu, exit);
+            }
+            unclaimed(vcpu, exit, unhandled)
+        }
+        (2, false) => {
+            if let Some(true) = bus.handle_mmio(addr, &mut MMIOAccess::StoreHalf(exit.data as u16))
+            {
+                return step(vcpu, exit);
+            }
+            unclaimed(vcpu, exit, unhandled)
+        }
+        (4, false) => {
+            if let Some(true) = bus.handle_mmio(addr, &mut MMIOAccess::StoreWord(exit.data as u32))
+            {
+                return step(vcpu, exit);
+            }
+            unclaimed(vcpu, exit, unhandled)
+        }
+        (8, false) => {
+            if let Some(true) = bus.handle_mmio(addr, &mut MMIOAccess::StoreDword(exit.data)) {
+                return step(vcpu, exit);
+            }
+            unclaimed(vcpu, exit, unhandled)
+        }
+        // reads
+        (1, true) => {
+            let mut val = 0u8;
+            if let Some(true) = bus.handle_mmio(addr, &mut MMIOAccess::LoadByte(&mut val)) {
+                return load(vcpu, exit, val);
+            }
+            unclaimed(vcpu, exit, unhandled)
+        }
+        (2, true) => {
+            let mut val = 0u16;
+            if let Some(true) = bus.handle_mmio(addr, &mut MMIOAccess::LoadHalf(&mut val)) {
+                return load(vcpu, exit, val);
+            }
+            unclaimed(vcpu, exit, unhandled)
+        }
+        (4, true) => {
+            let mut val = 0u32;
+            if let Some(true) = bus.handle_mmio(addr, &mut MMIOAccess::LoadWord(&mut val)) {
+                return load(vcpu, exit, val);
+            }
+            unclaimed(vcpu, exit, unhandled)
+        }
+        (8, true) => {
+            let mut val = 0u64;
+            if let Some(true) = bus.handle_mmio(addr, &mut MMIOAccess::LoadDword(&mut val)) {
+                return load(vcpu, exit, val);
+            }
+            unclaimed(vcpu, exit, unhandled)
+        }
+        _ => Err(HandleMMIOError(-1)),
+    }
+}
+
+// Words and doublewords on `bus`, for AMOs and SCs, which access nothing else.
+fn bus_load(bus: &dyn Device, addr: usize, size: usize) -> Option<u64> {
+    let handled = |access| bus.handle_mmio(addr, access) == Some(true);
+    match size {
+        4 => {
+            let mut val = 0u32;
+            if handled(&mut MMIOAccess::LoadWord(&mut val)) {
+                return Some(val as u64);
+            }
+        }
+        8 => {
+            let mut val = 0u64;
+            if handled(&mut MMIOAccess::LoadDword(&mut val)) {
+                return Some(val);
+            }
+        }
+        _ => {}
+    }
+    None
+}
+
+fn bus_store(bus: &dyn Device, addr: usize, size: usize, val: u64) -> bool {
+    let access = match size {
+        4 => MMIOAccess::StoreWord(val as u32),
+        8 => MMIOAccess::StoreDword(val),
+        _ => return false,
+    };
+    bus.handle_mmio(addr, &mut { access }) == Some(true)
+}
+
+/// Emulate an MMIO access that the backend did not decode, from the instruction at the pc of the
+/// vCPU, which is looked up in `ram`. Like `emulate_mmio`, but AMOs work as well.
+pub fn emulate_mmio_fault(
+    vcpu: &mut dyn Vcpu,
+    bus: &dyn Device,
+    ram: &dyn GuestMemory,
+    fault: &MmioFault,
+    unhandled: UnhandledMmio,
+) -> Result<()> {
+    let mut state = vcpu.read_state()?;
+    let (inst, len) = fetch(ram, fault.satp, state.pc as u64).ok_or(HandleMMIOError(-1))?;
+    let (exit, val) = match decode(inst, len, fault.addr, &state).ok_or(HandleMMIOError(-1))? {
+        MmioInst::Access(exit) => return emulate_mmio(vcpu, bus, &exit, unhandled),
+        // AMOs are not atomic with respect to other harts, which devices do not tell apart.
+        MmioInst::Amo { access, funct5 } => {
+            let addr = access.addr as usize;
+            let op = amo(funct5, access.size as u64).ok_or(HandleMMIOError(-1))?;
+            // AMOs fault as stores do.
+            let store = MmioExit {
+                read: false,
+                ..access
+            };
+            let old = match bus_load(bus, addr, access.size) {
+                Some(old) if bus_store(bus, addr, access.size, op(old, access.data)) => old,
+                _ if unhandled == UnhandledMmio::ReadZeroWriteIgnore => 0,
+                _ => return unclaimed(vcpu, &store, unhandled),
+            };
+            let old = if access.size == 4 {
+                (old as u32).to_usize(true)
+            } else {
+                old as usize
+            };
+            (access, old)
+        }
+        MmioInst::StoreConditional(access) => {
+            if !bus_store(bus, access.addr as usize, access.size, access.data)
+                && unhandled != UnhandledMmio::ReadZeroWriteIgnore
+            {
+                return unclaimed(vcpu, &access, unhandled);
+            }
+            (access, 0)
+        }
+    };
+    state.set_reg(exit.dst_reg, val);
+    state.pc += exit.inst_len;
+    vcpu.write_state(&state)
+}
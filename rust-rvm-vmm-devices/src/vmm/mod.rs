@@ -0,0 +1,654 @@
+use crate::board::{IRQ_S_EXT, IRQ_S_SOFT, IRQ_S_TIMER};
+use crate::hypervisor::{Error, Hypervisor, Result, Vcpu, VcpuExit};
+use crate::irq::imsic::IMSIC;
+use crate::irq::{InterruptSink, IrqLatch, IrqLine};
+use crate::power::ResetRequest;
+use crate::sbi::hsm::HartStates;
+use crate::sbi::{handle_ecall, ResetType, SbiOutcome};
+use crate::serial::Console;
+use crate::timer::clint::Clint;
+use crate::vcpu::{GuestRegs, REG_A0, REG_A1};
+use crate::MMIOBank;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+mod bits;
+pub mod cached;
+pub mod csr;
+pub mod decode;
+pub mod loader;
+pub mod memory;
+pub mod mmio;
+pub mod sbi;
+use cached::CachedVcpu;
+use csr::{emulate_csr, AiaCsrs};
+use memory::GuestRam;
+use mmio::{emulate_mmio, emulate_mmio_fault, UnhandledMmio};
+use sbi::VmmSbi;
+
+/// Everything the threads running the harts of the guest share.
+pub struct Vmm {
+    hypervisor: Arc<dyn Hypervisor>,
+    mmio: MMIOBank,
+    clint: Arc<Clint>,
+    sbi: VmmSbi,
+    // Supervisor interrupts of every hart, driven by the devices and IPIs.
+    sip: Vec<Arc<IrqLatch>>,
+    // Interrupt files of the harts that have one, by hart.
+    imsic_files: Vec<Arc<IMSIC>>,
+    unhandled_mmio: UnhandledMmio,
+    reset: Arc<ResetRequest>,
+}
+
+enum VcpuStop {
+    HartStop,
+    Halt,
+}
+
+impl Vmm {
+    /// Hart `i` takes its interrupts from `sip[i]`, which the devices of `mmio` and the CLINT drive.
+    pub fn new(
+        hypervisor: Arc<dyn Hypervisor>,
+        console: Arc<dyn Console>,
+        mmio: MMIOBank,
+        clint: Arc<Clint>,
+        sip: Vec<Arc<IrqLatch>>,
+        ram: GuestRam,
+    ) -> Self {
+        let sbi = VmmSbi {
+            console,
+            clint: Arc::clone(&clint),
+            ram,
+            harts: HartStates::new(sip.len()),
+            ipis: sip
+                .iter()
+                .map(|latch| IrqLine::new(Arc::clone(latch) as Arc<dyn InterruptSink>, IRQ_S_SOFT))
+                .collect(),
+        };
+        Vmm {
+            hypervisor,
+            mmio,
+            clint,
+            sbi,
+            sip,
+            imsic_files: Vec::new(),
+            unhandled_mmio: UnhandledMmio::default(),
+            reset: Arc::new(ResetRequest::new()),
+        }
+    }
+    /// What to do with accesses to addresses that no device claims. Guests take access faults
+    /// unless told otherwise.
+    pub fn set_unhandled_mmio(&mut self, policy: UnhandledMmio) {
+        self.unhandled_mmio = policy;
+    }
+    /// The IMSIC interrupt files of the harts, on boards with an APLIC. Hart `i` reaches
+    /// `files[i]` through its AIA CSRs, which harts without a file do not have.
+    pub fn set_imsic_files(&mut self, files: Vec<Arc<IMSIC>>) {
+        self.imsic_files = files;
+    }
+    /// Where devices ask for the machine to be reset, as the guest may through SBI. Every hart
+    /// stops on the first exit after a request.
+    pub fn set_reset_request(&mut self, reset: Arc<ResetRequest>) {
+        self.reset = reset;
+    }
+    pub fn harts(&self) -> &HartStates {
+        &self.sbi.harts
+    }
+    /// How the guest asked to be reset, once it did.
+    pub fn reset_reason(&self) -> Option<(ResetType, u32)> {
+        self.reset.get()
+    }
+    /// Run hart `hart` until the guest resets or shuts down. The hart starts when `harts()` says so,
+    /// and its vCPU is created the first time it starts. `nap` is called while the hart waits.
+    pub fn run_hart(&self, hart: usize, nap: &dyn Fn()) -> Result<()> {
+        let harts = &self.sbi.harts;
+        let mut vcpu: Option<CachedVcpu> = None;
+        // Lines as last sent to the hypervisor. They start out low.
+        let mut levels = 0;
+        let mut csrs = self
+            .imsic_files
+            .get(hart)
+            .map(|file| AiaCsrs::new(Arc::clone(file)));
+        loop {
+            let (start_addr, opaque) = loop {
+                if harts.halted() {
+                    return Ok(());
+                }
+                if let Some(start) = harts.take_start(hart) {
+                    break start;
+                }
+                nap();
+            };
+            if vcpu.is_none() {
+                vcpu = Some(CachedVcpu::new(
+                    self.hypervisor.create_vcpu(start_addr as u64)?,
+                ));
+            }
+            let vcpu = vcpu.as_mut().unwrap();
+            let mut state = vcpu.read_state()?;
+            state.pc = start_addr;
+            state.set_reg(REG_A0, hart);
+            state.set_reg(REG_A1, opaque);
+            vcpu.write_state(&state)?;
+            match self.run_vcpu(hart, vcpu, &mut levels, &mut csrs) {
+                Ok(VcpuStop::HartStop) => harts.stop(hart),
+                Ok(VcpuStop::Halt) => {
+                    harts.halt();
+                    return Ok(());
+                }
+                Err(e) => {
+                    harts.halt();
+                    return Err(e);
+                }
+            }
+        }
+    }
+    fn run_vcpu(
+        &self,
+        hart: usize,
+        vcpu: &mut dyn Vcpu,
+        levels: &mut u64,
+        csrs: &mut Option<AiaCsrs>,
+    ) -> Result<VcpuStop> {
+        let sip = &self.sip[hart];
+        loop {
+            if self.sbi.harts.halted() || self.reset.get().is_some() {
+                return Ok(VcpuStop::Halt);
+            }
+            // Deadline check: guest timers fire on the first exit after they expire, so they are
+            // only as precise as the exits. Under RVM that is the next host tick or Yield; under
+            // the interpreter it is the end of the instruction budget or a wfi.
+            self.clint.poll();
+            // Lines raised by other harts and devices are only latched; nothing kicks this vCPU out
+            // of the guest. A hart busy in the guest sees an IPI or CLINT edge at its next exit, so
+            // the same bound as for timers applies.
+            if let Some(new) = sip.take_changes() {
+                let edges = sip.take_edges();
+                for &irq in [IRQ_S_SOFT, IRQ_S_TIMER, IRQ_S_EXT].iter() {
+                    let mask = 1 << irq;
+                    // IPIs are edges. They raise SSIP, which the guest clears itself.
+                    if (new ^ *levels) & mask != 0 || edges & mask != 0 {
+                        vcpu.set_interrupt(irq, (new | edges) & mask != 0)?;
+                    }
+                }
+                *levels = new;
+            }
+            match vcpu.resume()? {
+                VcpuExit::Ecall => {
+                    let mut state = vcpu.read_state()?;
+                    let outcome = handle_ecall(&self.sbi, hart, &mut state);
+                    vcpu.write_state(&state)?;
+                    match outcome {
+                        SbiOutcome::Resume => {}
+                        SbiOutcome::HartStop => return Ok(VcpuStop::HartStop),
+                        SbiOutcome::Reset { reset_type, reason } => {
+                            self.reset.request(reset_type, reason);
+                            return Ok(VcpuStop::Halt);
+                        }
+                    }
+                }
+                VcpuExit::Mmio(exit) => emulate_mmio(vcpu, &self.mmio, &exit, self.unhandled_mmio)?,
+                VcpuExit::MmioFault(fault) => emulate_mmio_fault(
+                    vcpu,
+                    &self.mmio,
+                    &self.sbi.ram,
+                    &fault,
+                    self.unhandled_mmio,
+                )?,
+                VcpuExit::Csr(exit) => emulate_csr(vcpu, csrs.as_mut(), &exit)?,
+                VcpuExit::Yield => {
+                    // inject interrupt.
+                }
+                VcpuExit::Unknown(code) => return Err(Error::UnknownExitError(code)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::board::builder::Board;
+    use crate::board::rcore_on_rcore::rcore_on_rcore;
+    use crate::board::rcore_on_rcore::test::StdChannelConsole;
+    use crate::board::rcore_on_rcore_aia::rcore_on_rcore_aia;
+    use crate::board::{HartIrqLines, VmConfig, TIMEBASE_FREQUENCY};
+    use crate::hypervisor::mock::{MockCpu, MockHypervisor, MockStep};
+    use crate::hypervisor::{CsrExit, CsrWrite, MmioExit, MmioFault};
+    use crate::hypervisor::{CSR_SIREG, CSR_SISELECT, CSR_STOPEI};
+    use crate::irq::imsic::{IMSIC_EIDELIVERY, IMSIC_EIE0};
+    use crate::sbi::*;
+    use crate::serial::uart16650::{COM_LSR, COM_LSR_TXRDY, COM_TX, MULTIPLIER};
+    use crate::serial::RingBufferedConsole;
+    use crate::timer::ManualClock;
+    use crate::vcpu::{REG_A6, REG_A7};
+    use core::sync::atomic::{AtomicBool, Ordering::SeqCst};
+    const ENTRY: usize = 0x8020_0000;
+    const FDT: usize = 0xa000_0000;
+    const SERIAL_MMIO: u64 = 0x1000_0000;
+    const CLINT_MMIO: u64 = 0x200_0000;
+    // A VMM on scripted vCPUs, with a page of RAM at the entry point.
+    fn setup(
+        num_harts: usize,
+    ) -> (
+        Arc<MockHypervisor>,
+        Arc<Vmm>,
+        Arc<StdChannelConsole>,
+        &'static mut [u8],
+    ) {
+        setup_with(
+            num_harts,
+            UnhandledMmio::default(),
+            false,
+            Arc::new(ResetRequest::new()),
+        )
+    }
+    // `setup` on the board with an APLIC if `aia`, with its resets requested through `reset`.
+    fn setup_with(
+        num_harts: usize,
+        unhandled_mmio: UnhandledMmio,
+        aia: bool,
+        reset: Arc<ResetRequest>,
+    ) -> (
+        Arc<MockHypervisor>,
+        Arc<Vmm>,
+        Arc<StdChannelConsole>,
+        &'static mut [u8],
+    ) {
+        let hypervisor = Arc::new(MockHypervisor::new());
+        let mem = hypervisor.add_memory_region(ENTRY as u64, 0x1000).unwrap();
+        let mut ram = GuestRam::new();
+        ram.add_region(&mem);
+        let stdconsole = Arc::new(StdChannelConsole::new());
+        let console: Arc<dyn Console> =
+            Arc::new(RingBufferedConsole::new(Arc::clone(&stdconsole), 16));
+        let sip: Vec<_> = (0..num_harts).map(|_| Arc::new(IrqLatch::new())).collect();
+        let clock = Arc::new(ManualClock::new(TIMEBASE_FREQUENCY));
+        let harts = sip.iter().map(HartIrqLines::latched).collect();
+        let config = VmConfig::default();
+        let build = if aia {
+            rcore_on_rcore_aia
+        } else {
+            rcore_on_rcore
+        };
+        let Board {
+            bus: mmio,
+            clint,
+            imsic_files,
+            ..
+        } = build(Arc::clone(&console), clock, harts, &config);
+        let mut vmm = Vmm::new(
+            Arc::clone(&hypervisor) as Arc<dyn Hypervisor>,
+            console,
+            mmio,
+            clint,
+            sip,
+            ram,
+        );
+        vmm.set_unhandled_mmio(unhandled_mmio);
+        vmm.set_imsic_files(imsic_files);
+        vmm.set_reset_request(reset);
+        let vmm = Arc::new(vmm);
+        vmm.harts().start(0, ENTRY, FDT);
+        (hypervisor, vmm, stdconsole, mem.data)
+    }
+    // A step that checks the vCPU with `check` and then makes an SBI call.
+    fn ecall(
+        check: impl Fn(&MockCpu) + Send + 'static,
+        eid: usize,
+        fid: usize,
+        args: &'static [usize],
+    ) -> MockStep {
+        Box::new(move |cpu| {
+            check(cpu);
+            cpu.state.x[REG_A7] = eid;
+            cpu.state.x[REG_A6] = fid;
+            cpu.state.x[REG_A0..REG_A0 + args.len()].copy_from_slice(args);
+            VcpuExit::Ecall
+        })
+    }
+    fn mmio(check: impl Fn(&MockCpu) + Send + 'static, exit: MmioExit) -> MockStep {
+        Box::new(move |cpu| {
+            check(cpu);
+            VcpuExit::Mmio(exit)
+        })
+    }
+    fn csr(
+        check: impl Fn(&MockCpu) + Send + 'static,
+        csr: u32,
+        write: Option<CsrWrite>,
+    ) -> MockStep {
+        Box::new(move |cpu| {
+            check(cpu);
+            VcpuExit::Csr(CsrExit {
+                csr,
+                write,
+                dst_reg: 5,
+            })
+        })
+    }
+    fn byte_access(addr: u64, read: bool, data: u64) -> MmioExit {
+        MmioExit {
+            addr,
+            size: 1,
+            read,
+            data,
+            dst_reg: 5,
+            sign_extend: false,
+            inst_len: 4,
+        }
+    }
+    #[test]
+    fn boot_hart() {
+        let (hypervisor, vmm, stdconsole, _) = setup(1);
+        hypervisor.add_script(vec![
+            ecall(
+                |cpu| {
+                    assert_eq!(cpu.state.pc, ENTRY);
+                    assert_eq!((cpu.state.x[REG_A0], cpu.state.x[REG_A1]), (0, FDT));
+                },
+                SBI_EXT_DBCN,
+                2,
+                &[b'h' as usize],
+            ),
+            mmio(
+                |cpu| assert_eq!(cpu.state.x[REG_A0], 0),
+                byte_access(
+                    SERIAL_MMIO + (COM_TX * MULTIPLIER) as u64,
+                    false,
+                    b'i' as u64,
+                ),
+            ),
+            mmio(
+                |_| {},
+                byte_access(SERIAL_MMIO + (COM_LSR * MULTIPLIER) as u64, true, 0),
+            ),
+            ecall(
+                |cpu| {
+                    assert_ne!(cpu.state.x[5] as u8 & COM_LSR_TXRDY, 0);
+                    assert_eq!(cpu.state.pc, ENTRY + 8, "Stepped over the accesses.");
+                },
+                SBI_EXT_TIME,
+                0,
+                &[0],
+            ),
+            ecall(
+                |cpu| assert_eq!(cpu.sip, 1 << IRQ_S_TIMER, "Timer expired."),
+                SBI_EXT_IPI,
+                0,
+                &[1, 0],
+            ),
+            ecall(
+                |cpu| assert_eq!(cpu.sip, 1 << IRQ_S_TIMER | 1 << IRQ_S_SOFT),
+                SBI_EXT_SRST,
+                0,
+                &[0, 0],
+            ),
+        ]);
+        vmm.run_hart(0, &|| {}).unwrap();
+        assert_eq!(vmm.reset_reason(), Some((ResetType::Shutdown, 0)));
+        assert_eq!(stdconsole.output(), b"hi");
+        assert!(vmm.harts().halted());
+    }
+    #[test]
+    fn secondary_hart() {
+        let (hypervisor, vmm, _, _) = setup(2);
+        let started = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&started);
+        hypervisor.add_script(vec![
+            ecall(|_| {}, SBI_EXT_HSM, 0, &[1, 0x8040_0000, 7]),
+            Box::new(move |cpu| {
+                assert_eq!(cpu.state.x[REG_A0], 0, "Started.");
+                while !flag.load(SeqCst) {
+                    std::thread::yield_now();
+                }
+                VcpuExit::Yield
+            }),
+            ecall(|_| {}, SBI_EXT_SRST, 0, &[0, 0]),
+        ]);
+        let flag = Arc::clone(&started);
+        hypervisor.add_script(vec![Box::new(move |cpu| {
+            assert_eq!(cpu.state.pc, 0x8040_0000);
+            assert_eq!((cpu.state.x[REG_A0], cpu.state.x[REG_A1]), (1, 7));
+            flag.store(true, SeqCst);
+            cpu.state.x[REG_A7] = SBI_EXT_HSM;
+            cpu.state.x[REG_A6] = 1;
+            VcpuExit::Ecall
+        })]);
+        let second = Arc::clone(&vmm);
+        let thread =
+            std::thread::spawn(move || second.run_hart(1, &|| std::thread::yield_now()).unwrap());
+        vmm.run_hart(0, &|| std::thread::yield_now()).unwrap();
+        thread.join().unwrap();
+        assert!(started.load(SeqCst));
+        assert_eq!(vmm.reset_reason(), Some((ResetType::Shutdown, 0)));
+    }
+    #[test]
+    fn undecoded_mmio() {
+        use crate::isa::{r_type, AMO};
+        let (hypervisor, vmm, _, ram) = setup(1);
+        // amoor.w t0, t1, (a0), then c.lw a0, 0(a1), on the IPI register of hart 0.
+        let amoor = r_type(0x08 << 2, 6, 10, 2, 5, AMO);
+        ram[..4].copy_from_slice(&amoor.to_le_bytes());
+        ram[4..6].copy_from_slice(&0x4188u16.to_le_bytes());
+        let fault = VcpuExit::MmioFault(MmioFault {
+            addr: CLINT_MMIO,
+            satp: 0,
+        });
+        hypervisor.add_script(vec![
+            Box::new(move |cpu| {
+                cpu.state.x[5] = 7;
+                cpu.state.x[6] = 1;
+                fault
+            }),
+            Box::new(move |cpu| {
+                assert_eq!(cpu.state.x[5], 0, "The old value.");
+                assert_eq!(cpu.state.pc, ENTRY + 4);
+                assert_eq!(cpu.sip, 1 << IRQ_S_SOFT);
+                fault
+            }),
+            ecall(
+                |cpu| {
+                    assert_eq!(cpu.state.x[REG_A0], 1);
+                    assert_eq!(cpu.state.pc, ENTRY + 6);
+                },
+                SBI_EXT_SRST,
+                0,
+                &[0, 0],
+            ),
+        ]);
+        vmm.run_hart(0, &|| {}).unwrap();
+        assert_eq!(vmm.reset_reason(), Some((ResetType::Shutdown, 0)));
+    }
+    #[test]
+    fn state_transfers() {
+        use crate::isa::{s_type, STORE};
+        let (hypervisor, vmm, stdconsole, ram) = setup(1);
+        let tx = SERIAL_MMIO + (COM_TX * MULTIPLIER) as u64;
+        // sb t0, 0(a0) at the entry, decoded by the VMM.
+        ram[..4].copy_from_slice(&s_type(0, 5, 10, 0, STORE).to_le_bytes());
+        let mut steps: Vec<MockStep> = b"abc"
+            .iter()
+            .map(|&c| mmio(|_| {}, byte_access(tx, false, c as u64)))
+            .collect();
+        steps.push(Box::new(move |cpu| {
+            cpu.state.pc = ENTRY;
+            cpu.state.x[5] = b'd' as usize;
+            VcpuExit::MmioFault(MmioFault { addr: tx, satp: 0 })
+        }));
+        // One read and at most one write per exit, the ones to start the hart included.
+        steps.push(ecall(
+            |cpu| assert_eq!((cpu.state_reads, cpu.state_writes), (5, 5)),
+            SBI_EXT_SRST,
+            0,
+            &[0, 0],
+        ));
+        hypervisor.add_script(steps);
+        vmm.run_hart(0, &|| {}).unwrap();
+        assert_eq!(stdconsole.output(), b"abcd");
+    }
+    #[test]
+    fn unhandled_mmio() {
+        const NOWHERE: u64 = 0x4000_0000;
+        // Access faults, with the vCPU left at the access.
+        let (hypervisor, vmm, _, _) = setup(1);
+        hypervisor.add_script(vec![
+            mmio(|_| {}, byte_access(NOWHERE, true, 0)),
+            mmio(
+                |cpu| assert_eq!(cpu.exceptions, [(5, NOWHERE)]),
+                byte_access(NOWHERE, false, 1),
+            ),
+            ecall(
+                |cpu| {
+                    assert_eq!(cpu.exceptions, [(5, NOWHERE), (7, NOWHERE)]);
+                    assert_eq!(cpu.state.pc, ENTRY);
+                },
+                SBI_EXT_SRST,
+                0,
+                &[0, 0],
+            ),
+        ]);
+        vmm.run_hart(0, &|| {}).unwrap();
+        assert_eq!(vmm.reset_reason(), Some((ResetType::Shutdown, 0)));
+
+        let (hypervisor, vmm, _, _) = setup_with(
+            1,
+            UnhandledMmio::ReadZeroWriteIgnore,
+            false,
+            Arc::new(ResetRequest::new()),
+        );
+        hypervisor.add_script(vec![
+            Box::new(|cpu| {
+                cpu.state.x[5] = 7;
+                VcpuExit::Mmio(byte_access(NOWHERE, true, 0))
+            }),
+            mmio(
+                |cpu| assert_eq!((cpu.state.x[5], cpu.state.pc), (0, ENTRY + 4)),
+                byte_access(NOWHERE, false, 1),
+            ),
+            ecall(
+                |cpu| {
+                    assert!(cpu.exceptions.is_empty());
+                    assert_eq!(cpu.state.pc, ENTRY + 8);
+                },
+                SBI_EXT_SRST,
+                0,
+                &[0, 0],
+            ),
+        ]);
+        vmm.run_hart(0, &|| {}).unwrap();
+        assert_eq!(vmm.reset_reason(), Some((ResetType::Shutdown, 0)));
+
+        let (hypervisor, vmm, _, _) = setup_with(
+            1,
+            UnhandledMmio::Abort,
+            false,
+            Arc::new(ResetRequest::new()),
+        );
+        hypervisor.add_script(vec![mmio(|_| {}, byte_access(NOWHERE, true, 0))]);
+        match vmm.run_hart(0, &|| {}) {
+            Err(Error::UnhandledMmioError(exit)) => assert_eq!(exit, byte_access(NOWHERE, true, 0)),
+            x => panic!("unexpected {:?}", x),
+        }
+        assert!(vmm.harts().halted());
+    }
+    #[test]
+    fn aia_csrs() {
+        // Harts without an interrupt file have no AIA CSRs.
+        let (hypervisor, vmm, _, _) = setup(1);
+        hypervisor.add_script(vec![
+            csr(|_| {}, CSR_STOPEI, None),
+            ecall(
+                |cpu| {
+                    assert_eq!(cpu.exceptions, [(2, 0)]);
+                    assert_eq!(cpu.state.pc, ENTRY);
+                },
+                SBI_EXT_SRST,
+                0,
+                &[0, 0],
+            ),
+        ]);
+        vmm.run_hart(0, &|| {}).unwrap();
+
+        let (hypervisor, vmm, _, _) = setup_with(
+            1,
+            UnhandledMmio::default(),
+            true,
+            Arc::new(ResetRequest::new()),
+        );
+        let file = Arc::clone(&vmm.imsic_files[0]);
+        let device = Arc::clone(&file);
+        hypervisor.add_script(vec![
+            csr(
+                |_| {},
+                CSR_SISELECT,
+                Some(CsrWrite::Write(IMSIC_EIE0 as u64)),
+            ),
+            csr(
+                |cpu| assert_eq!(cpu.state.x[5], 0, "The old siselect."),
+                CSR_SIREG,
+                Some(CsrWrite::Set(1 << 5 | 1 << 7)),
+            ),
+            csr(|_| {}, CSR_SIREG, Some(CsrWrite::Clear(1 << 7))),
+            csr(
+                |cpu| assert_eq!(cpu.state.x[5], 1 << 5 | 1 << 7),
+                CSR_SISELECT,
+                Some(CsrWrite::Write(IMSIC_EIDELIVERY as u64)),
+            ),
+            csr(|_| {}, CSR_SIREG, Some(CsrWrite::Write(1))),
+            Box::new(move |_| {
+                device.set_pending(5);
+                VcpuExit::Csr(CsrExit {
+                    csr: CSR_STOPEI,
+                    write: None,
+                    dst_reg: 5,
+                })
+            }),
+            csr(
+                |cpu| {
+                    assert_eq!(cpu.state.x[5], 5 << 16 | 5);
+                    assert_eq!(cpu.sip, 1 << IRQ_S_EXT);
+                },
+                CSR_STOPEI,
+                Some(CsrWrite::Write(0)),
+            ),
+            // siselect selects no register of the file.
+            csr(
+                |cpu| {
+                    assert_eq!(cpu.state.x[5], 5 << 16 | 5, "Claimed.");
+                    assert_eq!(cpu.sip, 0);
+                },
+                CSR_SISELECT,
+                Some(CsrWrite::Write(0x30)),
+            ),
+            csr(|_| {}, CSR_SIREG, None),
+            ecall(
+                |cpu| {
+                    assert_eq!(cpu.exceptions, [(2, 0)]);
+                    assert_eq!(cpu.state.pc, ENTRY + 4 * 8);
+                },
+                SBI_EXT_SRST,
+                0,
+                &[0, 0],
+            ),
+        ]);
+        vmm.run_hart(0, &|| {}).unwrap();
+        assert!(!file.pending(5));
+        assert_eq!(file.read_indirect(IMSIC_EIE0), Some(1 << 5));
+    }
+    #[test]
+    fn device_reset() {
+        // A device asks for a reset while the hart runs. It stops on its next exit, and so does
+        // every other hart.
+        let reset = Arc::new(ResetRequest::new());
+        let (hypervisor, vmm, _, _) =
+            setup_with(1, UnhandledMmio::default(), false, Arc::clone(&reset));
+        let request = Arc::clone(&reset);
+        hypervisor.add_script(vec![Box::new(move |_| {
+            request.request(ResetType::ColdReboot, 0);
+            VcpuExit::Yield
+        })]);
+        vmm.run_hart(0, &|| {}).unwrap();
+        assert_eq!(vmm.reset_reason(), Some((ResetType::ColdReboot, 0)));
+        assert!(vmm.harts().halted());
+    }
+}
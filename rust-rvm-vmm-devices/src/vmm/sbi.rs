@@ -0,0 +1,61 @@
+use crate::irq::IrqLine;
+use crate::sbi::hsm::HartStates;
+use crate::sbi::{HartMask, RemoteFence, SbiEnv, SbiRet, SBI_ERR_INVALID_PARAM};
+use crate::serial::Console;
+use crate::timer::clint::Clint;
+use crate::vcpu::GuestMemory;
+use crate::vmm::memory::GuestRam;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// SBI services offered to the guest by the VMM.
+pub struct VmmSbi {
+    pub console: Arc<dyn Console>,
+    pub clint: Arc<Clint>,
+    pub ram: GuestRam,
+    pub harts: HartStates,
+    /// Software interrupt of every hart, pulsed by IPIs.
+    pub ipis: Vec<IrqLine>,
+}
+
+impl SbiEnv for VmmSbi {
+    fn num_harts(&self) -> usize {
+        self.harts.num_harts()
+    }
+    fn console_putchar(&self, chr: u8) {
+        self.console.write(chr);
+    }
+    fn console_getchar(&self) -> Option<u8> {
+        self.console.try_read(true)
+    }
+    fn memory(&self) -> Option<&dyn GuestMemory> {
+        Some(&self.ram)
+    }
+    fn set_timer(&self, hart: usize, stime_value: u64) -> SbiRet {
+        if self.clint.set_mtimecmp(hart, stime_value) {
+            SbiRet::success(0)
+        } else {
+            SbiRet::error(SBI_ERR_INVALID_PARAM)
+        }
+    }
+    fn send_ipi(&self, harts: HartMask) -> SbiRet {
+        // Delivered when each target next exits; see `Vmm::run_vcpu`.
+        for (hart, ipi) in self.ipis.iter().enumerate() {
+            if harts.contains(hart) {
+                ipi.pulse();
+            }
+        }
+        SbiRet::success(0)
+    }
+    fn remote_fence(&self, _fence: RemoteFence, _harts: HartMask) -> SbiRet {
+        // Other harts are not interrupted. This relies on the hypervisor flushing the guest TLB
+        // whenever it enters a vCPU, as RVM does.
+        SbiRet::success(0)
+    }
+    fn hart_start(&self, hart: usize, start_addr: usize, opaque: usize) -> SbiRet {
+        self.harts.start(hart, start_addr, opaque)
+    }
+    fn hart_status(&self, hart: usize) -> SbiRet {
+        self.harts.status(hart)
+    }
+}
@@ -0,0 +1,24 @@
+use crate::devices::timer::Clock;
+
+/// Host `time` CSR, so that guest time runs at the pace of the host. The guest reads that CSR
+/// too, so `frequency` must be the `timebase-frequency` of the host.
+pub struct RcoreClock {
+    frequency: u64,
+}
+
+impl RcoreClock {
+    pub fn new(frequency: u64) -> Self {
+        RcoreClock { frequency }
+    }
+}
+
+impl Clock for RcoreClock {
+    fn now(&self) -> u64 {
+        let time: u64;
+        unsafe { llvm_asm!("rdtime $0" : "=r"(time)) };
+        time
+    }
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
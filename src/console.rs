@@ -1,5 +1,6 @@
 use crate::devices::serial::BlockingConsole;
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering::SeqCst};
 use rcore_user::io::*;
 use rcore_user::syscall::*;
 use rcore_user::thread::spawn;
@@ -7,22 +8,7 @@ pub fn putc_uart2(fd: usize, c: u8) {
     sys_write(fd, &c, 1);
 }
 
-pub fn getc_uart2(fd: usize) -> u8 {
-    let mut c = 0u8;
-    loop {
-        sys_sleep(100);
-        let len = sys_read(fd, &mut c, 1);
-        match len {
-            1 => {
-                //println!("character {} fetched", c);
-                return c;
-            }
-            0 => continue,
-            _ => panic!("read uart2 len = {}", len),
-        }
-    }
-}
-pub struct RcoreConsole(usize);
+pub struct RcoreConsole(usize, AtomicBool);
 
 impl RcoreConsole {
     pub fn new() -> Self {
@@ -30,9 +16,9 @@ impl RcoreConsole {
         if fd < 0 {
             panic!("can't open second serial.");
         }
-        RcoreConsole(fd as usize)
+        RcoreConsole(fd as usize, AtomicBool::new(false))
     }
-    pub fn try_getc(&self) -> Option<u8> {
+    fn try_getc(&self) -> Option<u8> {
         let mut c = 0u8;
         let len = sys_read(self.0, &mut c, 1);
         match len {
@@ -47,8 +33,19 @@ impl RcoreConsole {
 }
 
 impl BlockingConsole for RcoreConsole {
-    fn getc(&self) -> u8 {
-        getc_uart2(self.0)
+    fn getc(&self) -> Option<u8> {
+        // Reads of the serial port do not block, and rcore_user has nothing to wait on, so poll it
+        // until input comes or reads are cancelled.
+        while !self.1.load(SeqCst) {
+            sys_sleep(100);
+            if let Some(c) = self.try_getc() {
+                return Some(c);
+            }
+        }
+        None
+    }
+    fn cancel_getc(&self) {
+        self.1.store(true, SeqCst);
     }
     fn putc(&self, chr: u8) {
         putc_uart2(self.0, chr)
@@ -59,12 +56,18 @@ impl BlockingConsole for RcoreConsole {
     {
         spawn(f);
     }
+    fn park(&self) {
+        // No wait queue is exposed to user space, so the producer naps instead of blocking, and
+        // looks for room again after each nap. There is no one to wake.
+        sys_sleep(10);
+    }
+    fn unpark(&self) {}
 }
 pub fn start_rcore_serial() -> Arc<dyn devices::serial::Console> {
     use devices::serial::*;
     let stdconsole = Arc::new(RcoreConsole::new());
-    let console: Arc<dyn Console> =
-        Arc::new(SingleCharBufferedConsole::new(Arc::clone(&stdconsole)));
-
-    console
+    let console = RingBufferedConsole::new(stdconsole, DEFAULT_CONSOLE_BUFFER_SIZE);
+    // The producer task is the only reader of the serial port.
+    console.start();
+    Arc::new(console)
 }
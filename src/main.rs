@@ -1,31 +1,47 @@
 #![no_std]
 #![no_main]
+#![feature(llvm_asm)]
 
 #[macro_use]
 extern crate rcore_user;
 extern crate alloc;
 extern crate core;
 extern crate rvm;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::fmt::Write;
+mod clock;
 mod console;
 mod rvm_io;
 
 extern crate rust_rvm_vmm_devices as devices;
 
-fn read_file(path: &str, buf: &mut [u8]) -> Result<usize, i32> {
+/// All of the file at `path`.
+fn read_file(path: &str) -> Result<Vec<u8>, i32> {
     use rcore_user::io::*;
     use rcore_user::syscall::*;
+    const CHUNK: usize = 1 << 20;
     let fd = sys_open(path, O_RDONLY);
     if fd < 0 {
         return Err(fd);
     }
-    let len = sys_read(fd as usize, buf.as_mut_ptr(), buf.len());
-    sys_close(fd as usize);
-    if len < 0 {
-        return Err(len);
+    let mut data = Vec::new();
+    loop {
+        let len = data.len();
+        data.resize(len + CHUNK, 0);
+        let read = sys_read(fd as usize, data[len..].as_mut_ptr(), CHUNK);
+        if read <= 0 {
+            data.truncate(len);
+            sys_close(fd as usize);
+            if read < 0 {
+                return Err(read);
+            }
+            return Ok(data);
+        }
+        data.truncate(len + read as usize);
     }
-    return Ok(len as usize);
 }
 use devices::serial::Console;
 pub struct HeaplessWrite<T: AsRef<dyn Console>>(T);
@@ -37,79 +53,128 @@ impl<T: AsRef<dyn Console>> core::fmt::Write for HeaplessWrite<T> {
         Ok(())
     }
 }
-use devices::Device;
-fn rvm_main() -> rvm_io::Result<()> {
+use devices::board::{Chosen, HartIrqLines, VmConfig, IRQ_S_TIMER, TIMEBASE_FREQUENCY};
+use devices::hypervisor::{Error, Hypervisor};
+use devices::irq::IrqLatch;
+use devices::vmm::loader::{fdt_region, initrd_gpa, load_image, load_initrd, FDT_MAX_SIZE};
+use devices::vmm::memory::GuestRam;
+use devices::vmm::Vmm;
+use rcore_user::syscall::sys_sleep;
+/// Number of harts of the guest, each run by a thread of its own.
+const NUM_HARTS: usize = 2;
+const VM_INITRD_PATH: &str = "/vmm/initrd";
+const VM_CMDLINE_PATH: &str = "/vmm/cmdline";
+/// `timebase-frequency` of the host harts, in decimal. rCore does not pass it on, so hosts other
+/// than QEMU virt must copy theirs here from their device tree.
+const VM_TIMEBASE_PATH: &str = "/vmm/timebase";
+fn rvm_main() -> devices::hypervisor::Result<()> {
     rcore_user::syscall::enlarge_heap();
     println!("rust-rvm-vmm starting");
-    let vm = Arc::new(rvm_io::RVM::new("/dev/rvm")?);
+    let vm: Arc<dyn Hypervisor> = Arc::new(rvm_io::RVM::new("/dev/rvm")?);
     let vm_image_path = "/vmm/rcore";
     let console = console::start_rcore_serial();
-    let (mmio, irc, fdt) = devices::board::rcore_on_rcore::rcore_on_rcore(Arc::clone(&console));
+    // Supervisor interrupts of the vCPUs, driven by the PLIC, the CLINT and IPIs.
+    let sip: Vec<_> = (0..NUM_HARTS).map(|_| Arc::new(IrqLatch::new())).collect();
 
     let mut writer = HeaplessWrite(&console);
     write!(writer, "hello, vmm").unwrap();
-    let mem = vm.add_memory_region(0x80200000, 384 * 1024 * 1024)?;
-    read_file(vm_image_path, mem.data).unwrap();
-
-    let fdt_mem = vm.add_memory_region(0xa0000000, (fdt.len() + 4095) / 4096 * 4096)?;
+    let image = match read_file(vm_image_path) {
+        Ok(image) => image,
+        Err(e) => {
+            println!("Can't read {}: error {}", vm_image_path, e);
+            return Ok(());
+        }
+    };
+    // RAM ends with the device tree. The images get the rest, and can't run over it.
+    let ram_range = 0x80200000..0x80200000 + 384 * 1024 * 1024;
+    let fdt_gpa = fdt_region(&ram_range).start;
+    let mem = vm.add_memory_region(ram_range.start, (fdt_gpa - ram_range.start) as usize)?;
+    let mut ram = GuestRam::new();
+    ram.add_region(&mem);
+    // ELF kernels go where their segments say, Linux `Image`s at their text_offset into RAM, and
+    // flat ones to the start of RAM.
+    let kernel = match load_image(&image, mem.gpa, &ram) {
+        Ok(kernel) => kernel,
+        Err(e) => {
+            println!("Can't load {}: {:?}", vm_image_path, e);
+            return Ok(());
+        }
+    };
+    // A Linux guest may come with an initrd and a command line. Both are optional.
+    let mut config = VmConfig {
+        memory: vec![ram_range],
+        reserved: vec![fdt_gpa..fdt_gpa + FDT_MAX_SIZE],
+        chosen: Chosen::default(),
+    };
+    if let Ok(cmdline) = read_file(VM_CMDLINE_PATH) {
+        config.chosen.bootargs = Some(String::from_utf8_lossy(&cmdline).trim_end().into());
+    }
+    if let Ok(initrd) = read_file(VM_INITRD_PATH) {
+        let gpa = initrd_gpa(&kernel, mem.gpa, mem.data.len() as u64);
+        match load_initrd(&initrd, gpa, &ram) {
+            Ok(range) => config.chosen.initrd = Some(range),
+            Err(e) => {
+                println!("Can't load {}: {:?}", VM_INITRD_PATH, e);
+                return Ok(());
+            }
+        }
+    }
+    let timebase = match read_file(VM_TIMEBASE_PATH) {
+        Ok(text) => match String::from_utf8_lossy(&text).trim().parse() {
+            Ok(timebase) => timebase,
+            Err(_) => {
+                println!("Can't parse {}", VM_TIMEBASE_PATH);
+                return Ok(());
+            }
+        },
+        Err(_) => TIMEBASE_FREQUENCY,
+    };
+    let board = devices::board::rcore_on_rcore::rcore_on_rcore(
+        Arc::clone(&console),
+        Arc::new(clock::RcoreClock::new(timebase)),
+        sip.iter().map(HartIrqLines::latched).collect(),
+        &config,
+    );
+    let fdt = &board.fdt;
+    assert!(fdt.len() as u64 <= FDT_MAX_SIZE, "device tree too large");
+    let fdt_mem = vm.add_memory_region(fdt_gpa, FDT_MAX_SIZE as usize)?;
     unsafe { core::ptr::copy_nonoverlapping(fdt.as_ptr(), fdt_mem.data.as_mut_ptr(), fdt.len()) };
-    let vcpu = vm.create_vcpu(0x80200000)?;
-    vm.modify_state(vcpu, |state| {
-        state.ctx.a0 = 0;
-        state.ctx.a1 = fdt_mem.gpa as usize;
-        Ok(())
-    })?;
-    // new thread for read
-    use console::RcoreConsole;
-    use devices::serial::SingleCharBufferedConsole;
-
-    let sc = console
-        .as_any()
-        .downcast_ref::<SingleCharBufferedConsole<RcoreConsole>>()
-        .unwrap();
+    ram.add_region(&fdt_mem);
+    let vmm = Arc::new(Vmm::new(
+        vm,
+        Arc::clone(&console),
+        board.bus,
+        board.clint,
+        sip,
+        ram,
+    ));
+    // The boot hart starts at the kernel with the device tree. The others wait for sbi_hart_start.
+    vmm.harts()
+        .start(0, kernel.entry as usize, fdt_mem.gpa as usize);
 
     println!("starting");
 
     rcore_user::ulib::sleep(1);
-    loop {
-        if let None = sc.try_read(false) {
-            let rc = sc.get_underlying();
-            if let Some(x) = rc.try_getc() {
-                sc.notify_char(x);
-            }
-        }
-        vm.set_interrupt_state(vcpu, false, irc.has_interrupt())
-            .unwrap();
-        let packet = vm.resume(vcpu)?;
-        match packet.kind {
-            rvm::RvmExitPacketKind::GuestEcall => {
-                let ecall = unsafe { &packet.inner.ecall };
-                if ecall.eid == 1 {
-                    console.write(ecall.arg0 as u8);
-                } else {
-                    write!(
-                        writer,
-                        "[vmm] Bad ecall eid={} fid={}. Ignore.\n",
-                        ecall.eid, ecall.fid
-                    )
-                    .unwrap();
+    for hart in 0..NUM_HARTS {
+        let vmm = Arc::clone(&vmm);
+        rcore_user::thread::spawn(move || {
+            match vmm.run_hart(hart, &|| {
+                sys_sleep(10);
+            }) {
+                Ok(()) => {}
+                Err(Error::UnsupportedInterrupt(IRQ_S_TIMER)) => {
+                    println!("This RVM can't inject timer interrupts. It needs vectors 4 and 5 (STIP) of RVM_VCPU_INTERRUPT.");
                 }
+                Err(x) => println!("Error in RVM on hart {}: {:?}", hart, x),
             }
-            rvm::RvmExitPacketKind::GuestMmio => {
-                let mmio_packet = unsafe { &packet.inner.mmio };
-                vm.handle_mmio_fault_with(vcpu, &mmio_packet, |access| {
-                    //println!("mmio packet {:?}", mmio_packet);
-                    mmio.handle_mmio(mmio_packet.addr as usize, access)
-                })?;
-            }
-            rvm::RvmExitPacketKind::GuestYield => {
-                // inject interrupt.
-            }
-            _ => {
-                println!("Bad exit. Exit.");
-                break;
-            }
-        }
+        });
+    }
+    // The console feeds itself. Wait for the guest to be gone.
+    while !vmm.harts().halted() {
+        sys_sleep(10);
+    }
+    if let Some((reset_type, reason)) = vmm.reset_reason() {
+        println!("Guest reset {:?} (reason {}). Exit.", reset_type, reason);
     }
     Ok(())
 }
@@ -1,10 +1,13 @@
-mod bits;
 mod rcore;
+use alloc::boxed::Box;
+use devices::board::{IRQ_S_EXT, IRQ_S_SOFT, IRQ_S_TIMER};
+use devices::hypervisor::{
+    Error::*, Hypervisor, MemoryRegion, MmioExit, MmioFault, Result, Vcpu, VcpuExit, VcpuState,
+};
+use devices::vcpu::GuestRegs;
 use rcore::*;
 use rcore_user::io::*;
 use rcore_user::syscall::*;
-use rvm::MmioPacket;
-use rvm::RvmExitPacket;
 pub const RVM_IO: usize = 0xAE00;
 pub const RVM_GUEST_CREATE: usize = RVM_IO + 0x01;
 pub const RVM_GUEST_ADD_MEMORY_REGION: usize = RVM_IO + 0x02;
@@ -19,36 +22,25 @@ const RVM_RISCV_SET_SSIP: u32 = 0;
 const RVM_RISCV_CLEAR_SSIP: u32 = 1;
 const RVM_RISCV_SET_SEIP: u32 = 2;
 const RVM_RISCV_CLEAR_SEIP: u32 = 3;
+// Timer injection needs an RVM that supports it; older ones reject these vectors. Every vCPU
+// tries them once when created, so that such an RVM fails the boot rather than a running guest.
+const RVM_RISCV_SET_STIP: u32 = 4;
+const RVM_RISCV_CLEAR_STIP: u32 = 5;
+
+const SSTATUS_SIE: usize = 1 << 1;
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
 
 pub struct RVM {
     fd: usize,
     vmid: usize,
 }
 
-pub struct MemoryRegion<'a> {
-    pub gpa: u64,
-    pub data: &'a mut [u8],
-}
-
-#[derive(Debug)]
-pub enum RVMError {
-    OpenRVMDeviceError(i32),
-    CreateGuestError(i32),
-    AddMemoryRegionError(i32),
-    CreateVcpuError(i32),
-    ResumeError(i32),
-    SendInterruptError(i32),
-    HandleMMIOError(i32),
-    ReadStateError(i32),
-    WriteStateError(i32),
-}
-use RVMError::*;
-pub type Result<T> = core::result::Result<T, RVMError>;
 impl RVM {
     pub fn new(path: &str) -> Result<RVM> {
         let fd = sys_open(path, O_RDWR);
         if fd < 0 {
-            return Err(OpenRVMDeviceError(fd));
+            return Err(OpenDeviceError(fd));
         }
         let fd = fd as usize;
         let vmid = sys_ioctl(fd as usize, RVM_GUEST_CREATE, 0);
@@ -58,7 +50,10 @@ impl RVM {
         let vmid = vmid as usize;
         return Ok(RVM { fd, vmid });
     }
-    pub fn add_memory_region(&self, gpa: u64, len: usize) -> Result<MemoryRegion> {
+}
+
+impl Hypervisor for RVM {
+    fn add_memory_region(&self, gpa: u64, len: usize) -> Result<MemoryRegion> {
         let args = RvmGuestAddMemoryRegionArgs {
             guest_start_paddr: gpa,
             memory_size: len as u64,
@@ -81,7 +76,7 @@ impl RVM {
             });
         }
     }
-    pub fn create_vcpu(&self, entry: u64) -> Result<u16> {
+    fn create_vcpu(&self, entry: u64) -> Result<Box<dyn Vcpu>> {
         let args = RvmVcpuCreateArgs {
             vmid: self.vmid as u16,
             entry,
@@ -90,21 +85,27 @@ impl RVM {
         if ret < 0 {
             return Err(CreateVcpuError(ret));
         }
-        return Ok(ret as u16);
-    }
-    pub fn resume(&self, vcpu_id: u16) -> Result<RvmExitPacket> {
-        let mut args: RvmVcpuResumeArgs = unsafe { core::mem::MaybeUninit::uninit().assume_init() };
-        args.vcpu_id = vcpu_id;
-        let ret = sys_ioctl(self.fd, RVM_VCPU_RESUME, &mut args as *mut _ as usize);
-        if ret < 0 {
-            return Err(ResumeError(ret));
-        } else {
-            return Ok(args.packet);
-        }
+        let mut vcpu = Box::new(RvmVcpu {
+            fd: self.fd,
+            id: ret as u16,
+            state: None,
+        });
+        vcpu.set_interrupt(IRQ_S_TIMER, false)?;
+        return Ok(vcpu);
     }
-    fn interrupt(&self, vcpu_id: u16, arg: u32) -> Result<()> {
+}
+
+pub struct RvmVcpu {
+    fd: usize,
+    id: u16,
+    // The full state as RVM last gave or took it, until the vCPU resumes.
+    state: Option<rvm::VcpuState>,
+}
+
+impl RvmVcpu {
+    fn interrupt(&self, arg: u32) -> Result<()> {
         let args = RvmVcpuInterruptArgs {
-            vcpu_id,
+            vcpu_id: self.id,
             vector: arg,
         };
         let ret = sys_ioctl(self.fd, RVM_VCPU_INTERRUPT, &args as *const _ as usize);
@@ -113,176 +114,176 @@ impl RVM {
         }
         return Ok(());
     }
-    pub fn set_interrupt_state(&self, vcpu_id: u16, sip: bool, eip: bool) -> Result<()> {
-        if sip {
-            self.interrupt(vcpu_id, RVM_RISCV_SET_SSIP)?;
-        } else {
-            self.interrupt(vcpu_id, RVM_RISCV_CLEAR_SSIP)?;
-        }
-        if eip {
-            self.interrupt(vcpu_id, RVM_RISCV_SET_SEIP)?;
-        } else {
-            self.interrupt(vcpu_id, RVM_RISCV_CLEAR_SEIP)?;
+    fn rvm_state(&mut self) -> Result<&mut rvm::VcpuState> {
+        if self.state.is_none() {
+            let mut vcpu_state: core::mem::MaybeUninit<rvm::VcpuState> =
+                core::mem::MaybeUninit::zeroed();
+            let args = RvmVcpuStateArgs {
+                vcpu_id: self.id,
+                kind: rvm::VcpuReadWriteKind::VcpuState as u32,
+                user_buf_ptr: vcpu_state.as_mut_ptr() as usize as u64,
+                buf_size: core::mem::size_of::<rvm::VcpuState>() as u64,
+            };
+            let ret = sys_ioctl(self.fd, RVM_VCPU_READ_STATE, &args as *const _ as usize);
+            if ret != 0 {
+                return Err(ReadStateError(ret));
+            }
+            self.state = Some(unsafe { vcpu_state.assume_init() });
         }
-        Ok(())
+        Ok(self.state.as_mut().unwrap())
     }
-    pub fn modify_state<T>(
-        &self,
-        vcpu_id: u16,
-        f: impl FnOnce(&mut rvm::VcpuState) -> Result<T>,
-    ) -> Result<T> {
-        let mut vcpu_state: core::mem::MaybeUninit<rvm::VcpuState> =
-            core::mem::MaybeUninit::zeroed();
+    // Give RVM the state back, as the VMM changed it.
+    fn write_rvm_state(&mut self) -> Result<()> {
+        let (fd, id) = (self.fd, self.id);
+        let vcpu_state = self.rvm_state()?;
         let args = RvmVcpuStateArgs {
-            vcpu_id: vcpu_id as u16,
+            vcpu_id: id,
             kind: rvm::VcpuReadWriteKind::VcpuState as u32,
-            user_buf_ptr: vcpu_state.as_mut_ptr() as usize as u64,
+            user_buf_ptr: vcpu_state as *const _ as usize as u64,
             buf_size: core::mem::size_of::<rvm::VcpuState>() as u64,
         };
-        let ret = sys_ioctl(self.fd, RVM_VCPU_READ_STATE, &args as *const _ as usize);
-        if ret != 0 {
-            return Err(ReadStateError(ret));
-        }
-        let mut vcpu_state = unsafe { vcpu_state.assume_init() };
-        let val = f(&mut vcpu_state)?;
-        let args = RvmVcpuStateArgs {
-            vcpu_id: vcpu_id as u16,
-            kind: rvm::VcpuReadWriteKind::VcpuState as u32,
-            user_buf_ptr: &vcpu_state as *const _ as usize as u64,
-            buf_size: core::mem::size_of::<rvm::VcpuState>() as u64,
-        };
-        let ret = sys_ioctl(self.fd, RVM_VCPU_WRITE_STATE, &args as *const _ as usize);
+        let ret = sys_ioctl(fd, RVM_VCPU_WRITE_STATE, &args as *const _ as usize);
         if ret != 0 {
             return Err(WriteStateError(ret));
         }
-        Ok(val)
+        Ok(())
     }
-    fn incr_pc(&self, vcpu_id: u16, pc_incr: usize) -> Result<()> {
-        self.modify_state(vcpu_id, |state| {
-            //println!("increasing pc {} by {}", state.ctx.sepc, pc_incr);
-            state.ctx.sepc += pc_incr;
-            Ok(())
+}
+
+impl Vcpu for RvmVcpu {
+    fn resume(&mut self) -> Result<VcpuExit> {
+        self.state = None;
+        let mut args: RvmVcpuResumeArgs = unsafe { core::mem::MaybeUninit::uninit().assume_init() };
+        args.vcpu_id = self.id;
+        let ret = sys_ioctl(self.fd, RVM_VCPU_RESUME, &mut args as *mut _ as usize);
+        if ret < 0 {
+            return Err(ResumeError(ret));
+        }
+        let packet = args.packet;
+        Ok(match packet.kind {
+            rvm::RvmExitPacketKind::GuestEcall => VcpuExit::Ecall,
+            rvm::RvmExitPacketKind::GuestMmio => {
+                let mmio = unsafe { &packet.inner.mmio };
+                match mmio.access_size {
+                    1 | 2 | 4 | 8 if mmio.inst_len != 0 => VcpuExit::Mmio(MmioExit {
+                        addr: mmio.addr,
+                        size: mmio.access_size as usize,
+                        read: mmio.read,
+                        data: mmio.data as u64,
+                        dst_reg: mmio.dstreg as usize,
+                        sign_extend: mmio.extension,
+                        inst_len: mmio.inst_len as usize,
+                    }),
+                    // RVM did not decode the instruction. The VMM does.
+                    _ => VcpuExit::MmioFault(MmioFault {
+                        addr: mmio.addr,
+                        satp: self.rvm_state()?.ctx.satp as u64,
+                    }),
+                }
+            }
+            rvm::RvmExitPacketKind::GuestYield => VcpuExit::Yield,
+            kind => VcpuExit::Unknown(kind as u32),
         })
     }
-    fn assign_value_to_register_and_incr_pc<T: bits::BitExtendToUsize>(
-        &self,
-        vcpu_id: u16,
-        reg_id: u8,
-        val: T,
-        sign_extension: bool,
-        pc_incr: usize,
-    ) -> Result<()> {
-        self.modify_state(vcpu_id, |state| {
-            let converted_val = val.to_usize(sign_extension);
-            //println!("writing val {} to reg {} and increasing pc {} by {}", converted_val, reg_id, state.ctx.sepc, pc_incr);
-            state.ctx.set(reg_id, converted_val);
-            state.ctx.sepc += pc_incr;
-            Ok(())
-        })
+    fn read_state(&mut self) -> Result<VcpuState> {
+        let vcpu_state = self.rvm_state()?;
+        let regs = RvmRegs(&mut *vcpu_state);
+        let mut state = VcpuState::default();
+        for n in 1..32 {
+            state.set_reg(n, regs.reg(n));
+        }
+        state.pc = vcpu_state.ctx.sepc;
+        Ok(state)
     }
-    // Handle mmio fault in parsed form.
-    // Automatically does pc-increment, register writeback and sign/zero extension for you.
-    pub fn handle_mmio_fault_with(
-        &self,
-        vcpu_id: u16,
-        packet: &MmioPacket,
-        handler: impl FnOnce(&mut devices::MMIOAccess) -> Option<bool>,
-    ) -> Result<()> {
-        let insn_len = packet.inst_len as usize;
-        match (packet.access_size, packet.read) {
-            // writes
-            (1, false) => {
-                if let Some(true) = handler(&mut devices::MMIOAccess::StoreByte(packet.data as u8))
-                {
-                    return self.incr_pc(vcpu_id, insn_len);
-                } else {
-                    return Err(HandleMMIOError(5));
-                }
-            }
-            (2, false) => {
-                if let Some(true) = handler(&mut devices::MMIOAccess::StoreHalf(packet.data as u16))
-                {
-                    return self.incr_pc(vcpu_id, insn_len);
-                } else {
-                    return Err(HandleMMIOError(5));
-                }
-            }
-            (4, false) => {
-                if let Some(true) = handler(&mut devices::MMIOAccess::StoreWord(packet.data as u32))
-                {
-                    return self.incr_pc(vcpu_id, insn_len);
-                } else {
-                    return Err(HandleMMIOError(5));
-                }
-            }
-            (8, false) => {
-                if let Some(true) =
-                    handler(&mut devices::MMIOAccess::StoreDword(packet.data as u64))
-                {
-                    return self.incr_pc(vcpu_id, insn_len);
-                } else {
-                    return Err(HandleMMIOError(5));
-                }
-            }
-            // reads
-            (1, true) => {
-                let mut val = 0;
-                if let Some(true) = handler(&mut devices::MMIOAccess::LoadByte(&mut val)) {
-                    return self.assign_value_to_register_and_incr_pc(
-                        vcpu_id,
-                        packet.dstreg,
-                        val,
-                        packet.extension,
-                        insn_len,
-                    );
-                } else {
-                    return Err(HandleMMIOError(1));
-                }
-            }
-            (2, true) => {
-                let mut val = 0;
-                if let Some(true) = handler(&mut devices::MMIOAccess::LoadHalf(&mut val)) {
-                    return self.assign_value_to_register_and_incr_pc(
-                        vcpu_id,
-                        packet.dstreg,
-                        val,
-                        packet.extension,
-                        insn_len,
-                    );
-                } else {
-                    return Err(HandleMMIOError(2));
-                }
-            }
-            (4, true) => {
-                let mut val = 0;
-                if let Some(true) = handler(&mut devices::MMIOAccess::LoadWord(&mut val)) {
-                    return self.assign_value_to_register_and_incr_pc(
-                        vcpu_id,
-                        packet.dstreg,
-                        val,
-                        packet.extension,
-                        insn_len,
-                    );
-                } else {
-                    return Err(HandleMMIOError(3));
-                }
-            }
-            (8, true) => {
-                let mut val = 0;
-                if let Some(true) = handler(&mut devices::MMIOAccess::LoadDword(&mut val)) {
-                    return self.assign_value_to_register_and_incr_pc(
-                        vcpu_id,
-                        packet.dstreg,
-                        val,
-                        packet.extension,
-                        insn_len,
-                    );
-                } else {
-                    return Err(HandleMMIOError(4));
-                }
-            }
-            _ => {}
+    fn write_state(&mut self, state: &VcpuState) -> Result<()> {
+        // RVM keeps more than the VMM sees, so the rest is written back as it was.
+        let vcpu_state = self.rvm_state()?;
+        let mut regs = RvmRegs(&mut *vcpu_state);
+        for n in 1..32 {
+            regs.set_reg(n, state.reg(n));
+        }
+        vcpu_state.ctx.sepc = state.pc;
+        self.write_rvm_state()
+    }
+    fn set_interrupt(&mut self, irq: usize, level: bool) -> Result<()> {
+        let vector = match (irq, level) {
+            (IRQ_S_SOFT, true) => RVM_RISCV_SET_SSIP,
+            (IRQ_S_SOFT, false) => RVM_RISCV_CLEAR_SSIP,
+            (IRQ_S_TIMER, true) => RVM_RISCV_SET_STIP,
+            (IRQ_S_TIMER, false) => RVM_RISCV_CLEAR_STIP,
+            (IRQ_S_EXT, true) => RVM_RISCV_SET_SEIP,
+            (IRQ_S_EXT, false) => RVM_RISCV_CLEAR_SEIP,
+            _ => return Err(UnsupportedInterrupt(irq)),
+        };
+        match self.interrupt(vector) {
+            Err(SendInterruptError(_)) if irq == IRQ_S_TIMER => Err(UnsupportedInterrupt(irq)),
+            result => result,
+        }
+    }
+    fn inject_exception(&mut self, cause: u64, tval: u64) -> Result<()> {
+        // RVM has no call for it, so the VMM traps the guest itself, as the hart would: into
+        // VS-mode at the base of vstvec, vectored or not, with interrupts off.
+        let ctx = &mut self.rvm_state()?.ctx;
+        // SPP of sstatus is the mode the guest left, VS or VU.
+        let mut vsstatus = ctx.vsstatus & !(SSTATUS_SIE | SSTATUS_SPIE | SSTATUS_SPP);
+        if ctx.vsstatus & SSTATUS_SIE != 0 {
+            vsstatus |= SSTATUS_SPIE;
+        }
+        vsstatus |= ctx.sstatus & SSTATUS_SPP;
+        ctx.vsstatus = vsstatus;
+        ctx.vsepc = ctx.sepc;
+        ctx.vscause = cause as usize;
+        ctx.vstval = tval as usize;
+        ctx.sstatus |= SSTATUS_SPP;
+        ctx.sepc = ctx.vstvec & !3;
+        self.write_rvm_state()
+    }
+}
+
+/// Registers of a vCPU state read from RVM.
+struct RvmRegs<'a>(pub &'a mut rvm::VcpuState);
+
+impl<'a> GuestRegs for RvmRegs<'a> {
+    fn reg(&self, n: usize) -> usize {
+        let ctx = &self.0.ctx;
+        match n {
+            1 => ctx.ra,
+            2 => ctx.sp,
+            3 => ctx.gp,
+            4 => ctx.tp,
+            5 => ctx.t0,
+            6 => ctx.t1,
+            7 => ctx.t2,
+            8 => ctx.s0,
+            9 => ctx.s1,
+            10 => ctx.a0,
+            11 => ctx.a1,
+            12 => ctx.a2,
+            13 => ctx.a3,
+            14 => ctx.a4,
+            15 => ctx.a5,
+            16 => ctx.a6,
+            17 => ctx.a7,
+            18 => ctx.s2,
+            19 => ctx.s3,
+            20 => ctx.s4,
+            21 => ctx.s5,
+            22 => ctx.s6,
+            23 => ctx.s7,
+            24 => ctx.s8,
+            25 => ctx.s9,
+            26 => ctx.s10,
+            27 => ctx.s11,
+            28 => ctx.t3,
+            29 => ctx.t4,
+            30 => ctx.t5,
+            31 => ctx.t6,
+            _ => 0,
+        }
+    }
+    fn set_reg(&mut self, n: usize, val: usize) {
+        if n != 0 && n < 32 {
+            self.0.ctx.set(n as u8, val);
         }
-        Err(HandleMMIOError(-1))
     }
 }